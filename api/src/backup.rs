@@ -0,0 +1,66 @@
+#[cfg(feature = "server")]
+use backend::{
+    backup,
+    config::ConfigInterface,
+    database::{with_conn, with_write_conn},
+    library,
+};
+use dioxus::prelude::*;
+#[cfg(feature = "server")]
+use shared::types::AppConfig;
+use shared::types::BackupSnapshot;
+use std::path::PathBuf;
+
+/// Backup directory of the active library
+#[cfg(feature = "server")]
+fn active_backup_dir() -> anyhow::Result<PathBuf> {
+    let library = library::require_active()?;
+    let cfg = AppConfig::read()?.backup;
+    Ok(backup::backup_dir(&cfg, library.dir()))
+}
+
+#[server]
+pub async fn list_backups() -> Result<Vec<BackupSnapshot>, ServerFnError> {
+    let dir = active_backup_dir().map_err(ServerFnError::new)?;
+    backup::list_snapshots(&dir).map_err(ServerFnError::new)
+}
+
+/// Take a snapshot of the active library right away
+#[server]
+pub async fn create_backup() -> Result<Vec<BackupSnapshot>, ServerFnError> {
+    let dir = active_backup_dir().map_err(ServerFnError::new)?;
+    let snapshot_dir = dir.clone();
+    with_conn(move |conn| backup::snapshot(conn, &snapshot_dir, "manual"))
+        .await
+        .map_err(ServerFnError::new)?;
+    backup::list_snapshots(&dir).map_err(ServerFnError::new)
+}
+
+/// Replace the active library's database with the snapshot `name`
+#[server]
+pub async fn restore_backup(name: String) -> Result<(), ServerFnError> {
+    let dir = active_backup_dir().map_err(ServerFnError::new)?;
+    let path = backup::snapshot_path(&dir, &name).map_err(ServerFnError::new)?;
+    with_write_conn(move |conn| backup::restore(conn, &path, &dir))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+#[server]
+pub async fn delete_backup(name: String) -> Result<Vec<BackupSnapshot>, ServerFnError> {
+    let dir = active_backup_dir().map_err(ServerFnError::new)?;
+    let path = backup::snapshot_path(&dir, &name).map_err(ServerFnError::new)?;
+    std::fs::remove_file(path).map_err(ServerFnError::new)?;
+    backup::list_snapshots(&dir).map_err(ServerFnError::new)
+}
+
+/// Write a zip archive of the active library (database, covers and book files) to `dest`,
+/// returning the path of the written archive
+#[server]
+pub async fn export_library_archive(dest: PathBuf) -> Result<PathBuf, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_conn(move |conn| backup::export_archive(conn, &library_dir, &dest))
+        .await
+        .map_err(ServerFnError::new)
+}
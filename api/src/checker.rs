@@ -0,0 +1,46 @@
+#[cfg(feature = "server")]
+use backend::{checker, database::with_write_conn, library, search};
+use dioxus::prelude::*;
+use shared::types::{EpubCheck, EpubCheckReport};
+
+/// Check the EPUB files of the books of the active library matching `query`, storing the
+/// results. Files checked before are only checked again if they changed or `recheck` is set.
+#[server]
+pub async fn check_epubs(query: String, recheck: bool) -> Result<EpubCheckReport, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = EpubCheckReport::default();
+        for book in search::matching_books(conn, &query)? {
+            match checker::check(conn, &library_dir, book, recheck) {
+                Ok(Some(check)) => {
+                    report.checked += 1;
+                    if !check.problems.is_empty() {
+                        report.books.push(check);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let title: String =
+                        conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+                            row.get(0)
+                        })?;
+                    report.failed.push(format!("{title}: {e:#}"));
+                }
+            }
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Fix the simple problems of the EPUB file of `book`, keeping the original, and check it again
+#[server]
+pub async fn repair_epub(book: i64) -> Result<EpubCheck, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| checker::repair(conn, &library_dir, book))
+        .await
+        .map_err(ServerFnError::new)
+}
@@ -1,7 +1,7 @@
 #[cfg(feature = "backend")]
 use backend::config::ConfigInterface;
 use dioxus::prelude::*;
-use shared::types::AppConfig;
+use shared::types::{AppConfig, LibraryEntry};
 use std::path::PathBuf;
 
 #[server]
@@ -28,14 +28,25 @@ pub async fn apply_config(config: AppConfig) -> Result<(), ServerFnError> {
     let path = config
         .data_dir
         .ok_or(ServerFnError::new("Database path not configured yet!"))?;
-    if backend::database::DB_PATH.get().is_none() {
-        if let Err(e) = backend::database::set_db_path(path.clone()) {
+    let already_active = backend::library::active().is_some_and(|lib| lib.dir() == path);
+    if !already_active {
+        // opening a library runs its migrations, so keep it off the async runtime
+        let dir = path.clone();
+        let opened = tokio::task::spawn_blocking(move || backend::database::set_db_path(dir))
+            .await
+            .map_err(ServerFnError::new)?;
+        if let Err(e) = opened {
             tracing::error!("Failed to initialize DB using configured directory {path:?}: {e}");
-            return Err(ServerFnError::new(
-                "Failed to initialize DB at path {path:?}",
-            ));
+            return Err(ServerFnError::new(format!(
+                "Failed to initialize DB at path {path:?}"
+            )));
         }
     }
+    backend::backup::start_scheduler();
+    backend::jobs::start();
+    backend::convert::start_source_check();
+    backend::automation::start();
+    backend::metadata::configure(&config.metadata);
 
     Ok(())
 }
@@ -85,8 +96,10 @@ pub async fn apply_config(config: AppConfig) -> Result<(), ServerFnError> {
 //
 #[server]
 pub async fn write_path(path: PathBuf) -> Result<(), ServerFnError> {
-    backend::database::set_db_path(path).map_err(ServerFnError::new)?;
-    Ok(())
+    tokio::task::spawn_blocking(move || backend::database::set_db_path(path))
+        .await
+        .map_err(ServerFnError::new)?
+        .map_err(ServerFnError::new)
 }
 //
 // // Server wrapper around the backend::config::init_config function which was previously
@@ -100,3 +113,31 @@ pub async fn write_path(path: PathBuf) -> Result<(), ServerFnError> {
 //         .map_err(ServerFnError::new)?;
 //     Ok(())
 // }
+
+#[server]
+pub async fn list_libraries() -> Result<Vec<LibraryEntry>, ServerFnError> {
+    let config = AppConfig::read().map_err(ServerFnError::new)?;
+    Ok(config.libraries)
+}
+
+#[server]
+pub async fn add_library(name: String, path: PathBuf) -> Result<Vec<LibraryEntry>, ServerFnError> {
+    let dir = backend::database::resolve_data_dir(path).map_err(ServerFnError::new)?;
+    let config = backend::library::register(&name, dir).map_err(ServerFnError::new)?;
+    Ok(config.libraries)
+}
+
+#[server]
+pub async fn remove_library(name: String) -> Result<Vec<LibraryEntry>, ServerFnError> {
+    let config = backend::library::unregister(&name).map_err(ServerFnError::new)?;
+    Ok(config.libraries)
+}
+
+/// Close the active library and open the registered library `name` instead
+#[server]
+pub async fn switch_library(name: String) -> Result<(), ServerFnError> {
+    tokio::task::spawn_blocking(move || backend::library::switch(&name).map(|_| ()))
+        .await
+        .map_err(ServerFnError::new)?
+        .map_err(ServerFnError::new)
+}
@@ -0,0 +1,24 @@
+#[cfg(feature = "server")]
+use backend::{convert, database::with_write_conn, library, search};
+use dioxus::prelude::*;
+
+/// Queue jobs converting the books of the active library matching `query` into `target`.
+/// Books without a file to convert from are left out. Returns the number of queued jobs.
+#[server]
+pub async fn convert_books(query: String, target: String) -> Result<usize, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut queued = 0;
+        for book in search::matching_books(conn, &query)? {
+            if convert::can_convert(conn, &library_dir, book, &target)
+                && convert::enqueue(conn, book, &target)?.is_some()
+            {
+                queued += 1;
+            }
+        }
+        Ok(queued)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
@@ -0,0 +1,105 @@
+#[cfg(feature = "server")]
+use backend::{
+    covers,
+    database::{with_conn, with_write_conn},
+    library, metadata,
+};
+use dioxus::prelude::*;
+#[cfg(feature = "server")]
+use shared::types::MetadataQuery;
+use shared::types::{CoverCrop, CoverOption, CoverSource, SeriesCover};
+
+/// Possible covers of a book: its current cover, images inside its files and covers offered by
+/// the metadata providers
+#[server]
+pub async fn cover_options(book: i64) -> Result<Vec<CoverOption>, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    let (mut options, current) = with_conn(move |conn| {
+        Ok((
+            covers::local_options(conn, &library_dir, book)?,
+            metadata::current_metadata(conn, book)?,
+        ))
+    })
+    .await
+    .map_err(ServerFnError::new)?;
+    // Providers may block on requests, keep them off the async runtime
+    let provider_options = tokio::task::spawn_blocking(move || {
+        let query = MetadataQuery {
+            title: current.title,
+            author: current.authors.into_iter().next(),
+            isbn: current.isbn,
+        };
+        metadata::fetch_covers(&query)
+            .into_iter()
+            .filter_map(|candidate| {
+                match covers::provider_option(&candidate.provider, &candidate.url) {
+                    Ok(option) => Some(option),
+                    Err(e) => {
+                        tracing::warn!("Skipping cover {}: {e:#}", candidate.url);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(ServerFnError::new)?;
+    options.extend(provider_options);
+    Ok(options)
+}
+
+/// Preview of an image given by URL or uploaded, to be offered as cover
+#[server]
+pub async fn preview_cover(source: CoverSource) -> Result<CoverOption, ServerFnError> {
+    tokio::task::spawn_blocking(move || covers::external_option(source))
+        .await
+        .map_err(ServerFnError::new)?
+        .map_err(ServerFnError::new)
+}
+
+/// Save the image from `source` as the cover of a book, cropped to the standard aspect ratio
+/// if `crop` is given
+#[server]
+pub async fn save_cover(
+    book: i64,
+    source: CoverSource,
+    crop: Option<CoverCrop>,
+) -> Result<(), ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    // Download before taking the write connection
+    let downloaded = match &source {
+        CoverSource::Url(url) | CoverSource::Provider { url, .. } => {
+            let url = url.clone();
+            Some(
+                tokio::task::spawn_blocking(move || covers::fetch(&url))
+                    .await
+                    .map_err(ServerFnError::new)?
+                    .map_err(ServerFnError::new)?,
+            )
+        }
+        _ => None,
+    };
+    with_write_conn(move |conn| {
+        let image = match downloaded {
+            Some(image) => image,
+            None => covers::source_image(conn, &library_dir, book, &source)?,
+        };
+        covers::save(conn, &library_dir, book, &image, crop)?;
+        crate::metadata::embed_after_edit(conn, &library_dir, &[book]);
+        Ok(())
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Covers of all books in the series of `book`, in series order
+#[server]
+pub async fn series_covers(book: i64) -> Result<Vec<SeriesCover>, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_conn(move |conn| covers::series_covers(conn, &library_dir, book))
+        .await
+        .map_err(ServerFnError::new)
+}
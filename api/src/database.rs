@@ -52,9 +52,9 @@ pub struct BookRecord {
     authors_sort: Vec<String>,
     series_and_volume: Vec<SeriesAndVolume>,
     number_of_pages: u32,
-    goodreads_id: u64,
+    goodreads_id: Option<u64>,
     date_added: DateTime<Utc>,
-    date_published: DateTime<Utc>,
+    date_published: Option<DateTime<Utc>>,
     date_modified: DateTime<Utc>,
 }
 
@@ -82,6 +82,10 @@ impl Display for SeriesAndVolume {
 }
 
 impl BookRecord {
+    pub fn get_id(&self) -> usize {
+        self.book_id
+    }
+
     pub fn get_title(&self) -> String {
         self.title.clone()
     }
@@ -102,20 +106,14 @@ impl BookRecord {
         self.date_added
     }
 
-    pub fn get_date_published(&self) -> DateTime<Utc> {
+    pub fn get_date_published(&self) -> Option<DateTime<Utc>> {
         self.date_published
     }
 }
 
-#[server]
-pub async fn list_books() -> Result<BookRecords, ServerFnError> {
-    #[cfg(not(feature = "server"))]
-    {
-        // The actual query runs only on the server. The client (wasm) gets the
-        // serialized result back so this branch should never execute meaningfully.
-        return Ok(BookRecords { records: vec![] });
-    }
-    #[cfg(feature = "server")]
+/// All books of the library with their authors and series, oldest first
+#[cfg(feature = "server")]
+pub(crate) fn load_book_records(conn: &rusqlite::Connection) -> anyhow::Result<Vec<BookRecord>> {
     let query = r#"WITH series_info AS (
                 SELECT
                     bsl.book,
@@ -149,31 +147,44 @@ pub async fn list_books() -> Result<BookRecords, ServerFnError> {
                 JOIN authors_info ON authors_info.book = books.id
             ORDER BY
                 books.date_added ASC"#;
+    let mut stmt = conn.prepare(query)?;
+    let rows = stmt.query_map([], |row| {
+        let authors_json_str: String = row.get("authors")?;
+        let authors_sort_json_str: String = row.get("authors_sort")?;
+        let series_json_str: String = row.get("series_and_volume").unwrap_or_default();
+        Ok(BookRecord {
+            book_id: row.get("id")?,
+            title: row.get("title")?,
+            sort: row.get("sort")?,
+            authors: serde_json::from_str(&authors_json_str).unwrap(),
+            authors_sort: serde_json::from_str(&authors_sort_json_str).unwrap(),
+            series_and_volume: serde_json::from_str(&series_json_str).unwrap_or(vec![]),
+            number_of_pages: row.get("number_of_pages")?,
+            date_added: row.get("date_added")?,
+            date_published: row.get("date_published")?,
+            date_modified: row.get("last_modified")?,
+            goodreads_id: row.get("goodreads_id")?,
+        })
+    })?;
+    rows.collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("Failed to map SQL data to Rust structs!"))
+}
+
+#[server]
+pub async fn list_books() -> Result<BookRecords, ServerFnError> {
+    #[cfg(not(feature = "server"))]
+    {
+        // The actual query runs only on the server. The client (wasm) gets the
+        // serialized result back so this branch should never execute meaningfully.
+        return Ok(BookRecords { records: vec![] });
+    }
     #[cfg(feature = "server")]
-    let books = with_conn(|conn| {
-        let mut stmt = conn.prepare(query)?;
-        let rows = stmt.query_map([], |row| {
-            let authors_json_str: String = row.get("authors")?;
-            let authors_sort_json_str: String = row.get("authors_sort")?;
-            let series_json_str: String = row.get("series_and_volume").unwrap_or_default();
-            Ok(BookRecord {
-                book_id: row.get("id")?,
-                title: row.get("title")?,
-                sort: row.get("sort")?,
-                authors: serde_json::from_str(&authors_json_str).unwrap(),
-                authors_sort: serde_json::from_str(&authors_sort_json_str).unwrap(),
-                series_and_volume: serde_json::from_str(&series_json_str).unwrap_or(vec![]),
-                number_of_pages: row.get("number_of_pages")?,
-                date_added: row.get("date_added")?,
-                date_published: row.get("date_published")?,
-                date_modified: row.get("last_modified")?,
-                goodreads_id: row.get("goodreads_id")?,
-            })
-        })?;
-        rows.collect::<Result<Vec<_>, _>>()
-            .map_err(|_| anyhow!("Failed to map SQL data to Rust structs!"))
-    })
-    .map_err(|e| -> ServerFnError<NoCustomError> { ServerFnError::ServerError(e.to_string()) })?;
+    let books =
+        with_conn(load_book_records)
+            .await
+            .map_err(|e| -> ServerFnError<NoCustomError> {
+                ServerFnError::ServerError(e.to_string())
+            })?;
 
     #[cfg(feature = "server")]
     return Ok(BookRecords { records: books });
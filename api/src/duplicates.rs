@@ -0,0 +1,40 @@
+#[cfg(feature = "server")]
+use backend::{
+    database::{with_conn, with_write_conn},
+    duplicates, library,
+};
+use dioxus::prelude::*;
+use shared::types::DuplicateGroup;
+
+/// Groups of probable duplicates in the active library, hashing the files that were added or
+/// changed since the last search first
+#[server]
+pub async fn find_duplicates() -> Result<Vec<DuplicateGroup>, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    // Hashing takes a while, so only the storing holds the write connection
+    let hashes = with_conn(move |conn| duplicates::hash_stale_files(conn, &library_dir))
+        .await
+        .map_err(ServerFnError::new)?;
+    with_write_conn(move |conn| {
+        duplicates::record_hashes(conn, &hashes)?;
+        duplicates::find_all(conn)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Merge the books `others` of the active library into `keep`, deleting them
+#[server]
+pub async fn merge_books(keep: i64, others: Vec<i64>) -> Result<(), ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let tx = conn.transaction()?;
+        let files = duplicates::merge(&tx, &library_dir, keep, &others)?;
+        tx.commit()?;
+        files.apply(&library_dir)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
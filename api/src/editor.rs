@@ -0,0 +1,60 @@
+#[cfg(feature = "server")]
+use backend::{
+    database::{with_conn, with_write_conn},
+    editor, library, normalize,
+};
+use dioxus::prelude::*;
+
+/// Content documents of the EPUB of `book` in reading order, as paths inside the archive
+#[server]
+pub async fn epub_documents(book: i64) -> Result<Vec<String>, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_conn(move |conn| editor::documents(conn, &library_dir, book))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// XHTML source of the content document `path` of the EPUB of `book`
+#[server]
+pub async fn epub_document(book: i64, path: String) -> Result<String, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_conn(move |conn| editor::read_document(conn, &library_dir, book, &path))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Why `content` is not well-formed XHTML, `None` if it is
+#[server]
+pub async fn check_xhtml(content: String) -> Result<Option<String>, ServerFnError> {
+    Ok(editor::check_well_formed(&content)
+        .err()
+        .map(|e| format!("{e:#}")))
+}
+
+/// Save `content` as the content document `path` of the EPUB of `book`, keeping the original
+/// EPUB the first time
+#[server]
+pub async fn save_epub_document(
+    book: i64,
+    path: String,
+    content: String,
+) -> Result<(), ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| editor::save_document(conn, &library_dir, book, &path, &content))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Put back the original EPUB of `book`, undoing all edits and formatting. Returns whether
+/// there was one.
+#[server]
+pub async fn revert_epub(book: i64) -> Result<bool, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| normalize::revert(conn, &library_dir, book))
+        .await
+        .map_err(ServerFnError::new)
+}
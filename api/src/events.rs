@@ -0,0 +1,28 @@
+use dioxus::prelude::server_fn::codec::{StreamingText, TextStream};
+use dioxus::prelude::*;
+
+/// Stream of newline-delimited, JSON-encoded `shared::types::LibraryEvent`s, one for every
+/// change to the active library's database. The stream stays open until the client disconnects.
+#[server(output = StreamingText)]
+pub async fn library_events() -> Result<TextStream, ServerFnError> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let events = backend::watcher::subscribe();
+    let stream = futures::stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let line = serde_json::to_string(&event)
+                        .map(|json| json + "\n")
+                        .map_err(ServerFnError::new);
+                    return Some((line, events));
+                }
+                // Missed events carry no information beyond "something changed", and the
+                // next received event says just that
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(TextStream::new(stream))
+}
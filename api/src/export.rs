@@ -0,0 +1,81 @@
+#[cfg(feature = "server")]
+use crate::database::{load_book_records, BookRecord};
+#[cfg(feature = "server")]
+use backend::{database::with_conn, export, library, search};
+use dioxus::prelude::*;
+#[cfg(feature = "server")]
+use shared::types::ReadingEntry;
+use shared::types::{LibraryExportFormat, LibraryExportOptions, ReadingExportFormat};
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Write the reading history of the active library as a Goodreads or StoryGraph CSV file to
+/// `dest`, returning the path of the written file
+#[server]
+pub async fn export_reading_history(
+    format: ReadingExportFormat,
+    dest: PathBuf,
+) -> Result<PathBuf, ServerFnError> {
+    with_conn(move |conn| export::export_readings(conn, format, &dest))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Write the books of the active library matching `options.query` (all books if it is empty) to
+/// `dest` as CSV, versioned JSON or a static HTML catalog, returning the path of the written file
+/// or catalog directory
+#[server]
+pub async fn export_library(
+    format: LibraryExportFormat,
+    options: LibraryExportOptions,
+    dest: PathBuf,
+) -> Result<PathBuf, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_conn(move |conn| {
+        let ids = search::matching_books(conn, &options.query)?;
+        match format {
+            LibraryExportFormat::Csv => {
+                export::export_csv(conn, &ids, options.include_readings, &dest)
+            }
+            LibraryExportFormat::Html => {
+                export::export_html(conn, &library_dir, &ids, options.include_readings, &dest)
+            }
+            LibraryExportFormat::Json => {
+                let mut readings = if options.include_readings {
+                    export::readings(conn)?
+                } else {
+                    Default::default()
+                };
+                let mut by_id: HashMap<i64, BookRecord> = load_book_records(conn)?
+                    .into_iter()
+                    .map(|record| (record.get_id() as i64, record))
+                    .collect();
+                let records: Vec<ExportedRecord> = ids
+                    .iter()
+                    .filter_map(|id| by_id.remove(id).map(|record| (id, record)))
+                    .map(|(id, record)| ExportedRecord {
+                        readings: options
+                            .include_readings
+                            .then(|| readings.remove(id).unwrap_or_default()),
+                        record,
+                    })
+                    .collect();
+                export::export_json(&records, &dest)
+            }
+        }
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// A record of the JSON export, with its reading history if requested
+#[cfg(feature = "server")]
+#[derive(serde::Serialize)]
+struct ExportedRecord {
+    #[serde(flatten)]
+    record: BookRecord,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    readings: Option<Vec<ReadingEntry>>,
+}
@@ -0,0 +1,67 @@
+#[cfg(feature = "server")]
+use backend::{config::ConfigInterface, database::with_write_conn, library, normalize, search};
+use dioxus::prelude::*;
+#[cfg(feature = "server")]
+use rusqlite::Connection;
+#[cfg(feature = "server")]
+use shared::types::AppConfig;
+use shared::types::FormattingReport;
+
+/// Count the outcome of formatting, or reverting, `book` in `report`
+#[cfg(feature = "server")]
+fn record(
+    conn: &Connection,
+    report: &mut FormattingReport,
+    book: i64,
+    outcome: anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    match outcome {
+        Ok(true) => report.changed += 1,
+        Ok(false) => report.unchanged += 1,
+        Err(e) => {
+            let title: String =
+                conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+                    row.get(0)
+                })?;
+            report.failed.push(format!("{title}: {e:#}"));
+        }
+    }
+    Ok(())
+}
+
+/// Normalize the EPUB files of the books of the active library matching `query` with the
+/// configured formatting steps, keeping the originals
+#[server]
+pub async fn format_books(query: String) -> Result<FormattingReport, ServerFnError> {
+    let options = AppConfig::read().map_err(ServerFnError::new)?.formatting;
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = FormattingReport::default();
+        for book in search::matching_books(conn, &query)? {
+            let outcome = normalize::normalize(conn, &library_dir, book, &options);
+            record(conn, &mut report, book, outcome)?;
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Put back the original EPUB files of the books of the active library matching `query` that
+/// were formatted
+#[server]
+pub async fn revert_formatting(query: String) -> Result<FormattingReport, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = FormattingReport::default();
+        for book in search::matching_books(conn, &query)? {
+            let outcome = normalize::revert(conn, &library_dir, book);
+            record(conn, &mut report, book, outcome)?;
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
@@ -0,0 +1,191 @@
+#[cfg(feature = "server")]
+use backend::{
+    config::ConfigInterface,
+    database::{with_conn, with_write_conn},
+    filenames,
+    import::{self, calibre, calibre_web, files, goodreads, reading_log, FileTransfer},
+    jobs, library,
+};
+use dioxus::prelude::*;
+#[cfg(feature = "server")]
+use shared::types::{AppConfig, JobTask};
+use shared::types::{
+    DuplicateAction, ImportReport, Job, PatternTest, ReadingLogMapping, ReadingLogRow,
+    ReadingLogSheet,
+};
+use std::path::PathBuf;
+
+/// What each of the file name `patterns` extracts from the file name `sample`
+#[server]
+pub async fn test_filename_patterns(
+    patterns: Vec<String>,
+    sample: String,
+) -> Result<Vec<PatternTest>, ServerFnError> {
+    Ok(filenames::test(&patterns, &sample))
+}
+
+/// Import the Calibre library at `path` into the active library. Book files are hard linked
+/// instead of copied if `link_files` is set.
+#[server]
+pub async fn import_calibre_library(
+    path: PathBuf,
+    link_files: bool,
+) -> Result<ImportReport, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    let transfer = if link_files {
+        FileTransfer::HardLink
+    } else {
+        FileTransfer::Copy
+    };
+    tokio::task::spawn_blocking(move || {
+        let mut report = calibre::import(library.pool(), &path, &library_dir, transfer)?;
+        import::write_report(&library_dir, "calibre", &mut report)?;
+        Ok::<_, anyhow::Error>(report)
+    })
+    .await
+    .map_err(ServerFnError::new)?
+    .map_err(ServerFnError::new)
+}
+
+/// Queue a job importing the Calibre library at `path` like [`import_calibre_library`] does,
+/// for libraries too large to wait for
+#[server]
+pub async fn queue_calibre_import(
+    path: PathBuf,
+    link_files: bool,
+) -> Result<Option<Job>, ServerFnError> {
+    with_write_conn(move |conn| {
+        let title = format!("Import Calibre library {}", path.display());
+        let task = JobTask::ImportCalibre { path, link_files };
+        jobs::enqueue(conn, task, &title)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Import the book file at `path`, or all book files in the directory `path`, as new books of
+/// the active library, with the metadata read from the files or else parsed from their names by
+/// the configured file name patterns. Files are hard linked instead of copied if `link_files`
+/// is set. Files that duplicate a book of the library are handled as `on_duplicate` says, and
+/// EPUB files are normalized if the formatting configuration asks for it on import.
+#[server]
+pub async fn import_book_files(
+    path: PathBuf,
+    link_files: bool,
+    on_duplicate: DuplicateAction,
+) -> Result<ImportReport, ServerFnError> {
+    let config = AppConfig::read().map_err(ServerFnError::new)?;
+    let patterns = config.import.filename_patterns;
+    let formatting = config.formatting.on_import.then_some(config.formatting);
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    let transfer = if link_files {
+        FileTransfer::HardLink
+    } else {
+        FileTransfer::Copy
+    };
+    tokio::task::spawn_blocking(move || {
+        let mut report = files::import(
+            library.pool(),
+            &path,
+            &library_dir,
+            transfer,
+            &patterns,
+            on_duplicate,
+            formatting.as_ref(),
+        )?;
+        import::write_report(&library_dir, "files", &mut report)?;
+        Ok::<_, anyhow::Error>(report)
+    })
+    .await
+    .map_err(ServerFnError::new)?
+    .map_err(ServerFnError::new)
+}
+
+/// Queue a job importing the book files at `path` like [`import_book_files`] does, for imports
+/// too large to wait for
+#[server]
+pub async fn queue_book_import(
+    path: PathBuf,
+    link_files: bool,
+    on_duplicate: DuplicateAction,
+) -> Result<Option<Job>, ServerFnError> {
+    with_write_conn(move |conn| {
+        let title = format!("Import {}", path.display());
+        let task = JobTask::ImportFiles {
+            path,
+            link_files,
+            on_duplicate,
+        };
+        jobs::enqueue(conn, task, &title)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Import the read states and shelves of a Calibre-Web `app.db` at `path` into the active
+/// library. The Calibre library it belongs to must have been imported before.
+#[server]
+pub async fn import_calibre_web(path: PathBuf) -> Result<ImportReport, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = calibre_web::import(conn, &path)?;
+        import::write_report(&library_dir, "calibre-web", &mut report)?;
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Headers and first rows of the CSV or spreadsheet reading log at `path`, with a guessed
+/// column mapping
+#[server]
+pub async fn inspect_reading_log(path: PathBuf) -> Result<ReadingLogSheet, ServerFnError> {
+    reading_log::inspect(&path).map_err(ServerFnError::new)
+}
+
+/// Rows of the reading log at `path` matched to books of the active library, with the reasons
+/// why rows would be skipped
+#[server]
+pub async fn preview_reading_log(
+    path: PathBuf,
+    mapping: ReadingLogMapping,
+) -> Result<Vec<ReadingLogRow>, ServerFnError> {
+    with_conn(move |conn| reading_log::preview(conn, &path, &mapping))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Import the readings of the reading log at `path` that matched a book without conflicts
+#[server]
+pub async fn import_reading_log(
+    path: PathBuf,
+    mapping: ReadingLogMapping,
+) -> Result<ImportReport, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = reading_log::import(conn, &path, &mapping)?;
+        import::write_report(&library_dir, "reading-log", &mut report)?;
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Import a Goodreads library export CSV at `path`: Goodreads IDs, readings and shelves of the
+/// books that are in the active library
+#[server]
+pub async fn import_goodreads(path: PathBuf) -> Result<ImportReport, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = goodreads::import(conn, &path)?;
+        import::write_report(&library_dir, "goodreads", &mut report)?;
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
@@ -0,0 +1,70 @@
+#[cfg(feature = "server")]
+use backend::{
+    database::{with_conn, with_write_conn},
+    jobs, library,
+};
+use dioxus::prelude::server_fn::codec::{StreamingText, TextStream};
+use dioxus::prelude::*;
+use shared::types::{Job, JobLogEntry};
+
+/// The jobs of the active library, newest first
+#[server]
+pub async fn list_jobs() -> Result<Vec<Job>, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    with_conn(move |conn| jobs::list(conn, &library))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// The log of job `id`, so far if it is still running
+#[server]
+pub async fn job_log(id: i64) -> Result<Vec<JobLogEntry>, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    with_conn(move |conn| jobs::log(conn, &library, id))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Cancel job `id` of the active library; a running job stops at its next check
+#[server]
+pub async fn cancel_job(id: i64) -> Result<Job, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    with_write_conn(move |conn| jobs::cancel(conn, &library, id))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Queue failed or cancelled job `id` of the active library again
+#[server]
+pub async fn retry_job(id: i64) -> Result<Job, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    with_write_conn(move |conn| jobs::retry(conn, &library, id))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Stream of newline-delimited, JSON-encoded `shared::types::Job`s, one for every new job and
+/// every change of a job's state or progress. The stream stays open until the client
+/// disconnects.
+#[server(output = StreamingText)]
+pub async fn job_events() -> Result<TextStream, ServerFnError> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let events = jobs::subscribe();
+    let stream = futures::stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(job) => {
+                    let line = serde_json::to_string(&job)
+                        .map(|json| json + "\n")
+                        .map_err(ServerFnError::new);
+                    return Some((line, events));
+                }
+                // Progress updates that were missed are superseded by the next one
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(TextStream::new(stream))
+}
@@ -1,2 +1,18 @@
+pub mod backup;
+pub mod checker;
 pub mod config;
+pub mod convert;
+pub mod covers;
 pub mod database;
+pub mod duplicates;
+pub mod editor;
+pub mod events;
+pub mod export;
+pub mod formatting;
+pub mod import;
+pub mod jobs;
+pub mod metadata;
+pub mod readings;
+pub mod replace;
+pub mod rules;
+pub mod statistics;
@@ -0,0 +1,158 @@
+#[cfg(feature = "server")]
+use backend::{
+    config::ConfigInterface,
+    covers,
+    database::{with_conn, with_write_conn},
+    embed, library, metadata, search,
+};
+use dioxus::prelude::*;
+#[cfg(feature = "server")]
+use rusqlite::Connection;
+#[cfg(feature = "server")]
+use shared::types::AppConfig;
+use shared::types::{EmbedReport, MetadataChange, MetadataField, MetadataProposal};
+#[cfg(feature = "server")]
+use std::path::Path;
+
+/// Embed the metadata of edited books into their EPUB files if that is configured. Failures
+/// are only logged, the edit itself has succeeded.
+#[cfg(feature = "server")]
+pub(crate) fn embed_after_edit(conn: &Connection, library_dir: &Path, books: &[i64]) {
+    match AppConfig::read() {
+        Ok(config) if config.metadata.embed_on_edit => {}
+        Ok(_) => return,
+        Err(e) => {
+            tracing::warn!("Not embedding metadata, the config is unreadable: {e:#}");
+            return;
+        }
+    }
+    for book in books {
+        if let Err(e) = embed::embed(conn, library_dir, *book) {
+            tracing::warn!("Embedding metadata into the files of book {book} failed: {e:#}");
+        }
+    }
+}
+
+/// Metadata proposed by the registered providers for the books of the active library matching
+/// `query`, merged according to the configured strategy
+#[server]
+pub async fn propose_metadata(query: String) -> Result<Vec<MetadataProposal>, ServerFnError> {
+    let strategy = AppConfig::read()
+        .map_err(ServerFnError::new)?
+        .metadata
+        .merge;
+    let books = with_conn(move |conn| {
+        search::matching_books(conn, &query)?
+            .into_iter()
+            .map(|book| {
+                let has_cover =
+                    conn.query_row("SELECT has_cover FROM books WHERE id = ?1", [book], |row| {
+                        row.get(0)
+                    })?;
+                Ok((
+                    book,
+                    metadata::current_metadata(conn, book)?,
+                    metadata::locked_fields(conn, book)?,
+                    has_cover,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(ServerFnError::new)?;
+    // Providers may block on requests, keep them off the async runtime
+    tokio::task::spawn_blocking(move || {
+        books
+            .into_iter()
+            .map(|(book, current, locked, has_cover)| {
+                metadata::propose(book, current, locked, has_cover, &strategy)
+            })
+            .collect()
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Apply reviewed metadata changes to the active library, one book at a time. Returns the
+/// number of books changed.
+#[server]
+pub async fn apply_metadata(changes: Vec<MetadataChange>) -> Result<usize, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    // Download the accepted covers before taking the write connection
+    let fetched = changes.clone();
+    let covers = tokio::task::spawn_blocking(move || {
+        fetched
+            .iter()
+            .map(|change| {
+                change
+                    .cover
+                    .as_deref()
+                    .map(covers::fetch)
+                    .transpose()
+                    .map_err(|e| e.context(format!("Fetching the cover of book {}", change.book)))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(ServerFnError::new)?
+    .map_err(ServerFnError::new)?;
+    with_write_conn(move |conn| {
+        let mut books = Vec::new();
+        for (change, cover) in changes.iter().zip(&covers) {
+            if let Err(e) = metadata::apply(conn, &library_dir, change, cover.as_deref()) {
+                // The changes before the failing one are committed, so their files need updating
+                embed_after_edit(conn, &library_dir, &books);
+                return Err(e.context(format!(
+                    "Applied {} of {} changes, book {} failed",
+                    books.len(),
+                    changes.len(),
+                    change.book
+                )));
+            }
+            books.push(change.book);
+        }
+        embed_after_edit(conn, &library_dir, &books);
+        Ok(changes.len())
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Lock or unlock a field of a book, so providers don't replace its value
+#[server]
+pub async fn lock_metadata_field(
+    book: i64,
+    field: MetadataField,
+    locked: bool,
+) -> Result<(), ServerFnError> {
+    with_write_conn(move |conn| metadata::set_locked(conn, book, field, locked))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Write the metadata and cover of the books of the active library matching `query` into
+/// their EPUB files, leaving the rest of the files untouched
+#[server]
+pub async fn embed_metadata(query: String) -> Result<EmbedReport, ServerFnError> {
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = EmbedReport::default();
+        for book in search::matching_books(conn, &query)? {
+            match embed::embed(conn, &library_dir, book) {
+                Ok(files) => report.files += files,
+                Err(e) => {
+                    let title: String =
+                        conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+                            row.get(0)
+                        })?;
+                    report.failed.push(format!("{title}: {e:#}"));
+                }
+            }
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
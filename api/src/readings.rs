@@ -0,0 +1,11 @@
+#[cfg(feature = "server")]
+use backend::{books, database::with_write_conn};
+use dioxus::prelude::*;
+
+/// Mark `book` as read now, ending the reading in progress if there is one
+#[server]
+pub async fn finish_reading(book: i64) -> Result<(), ServerFnError> {
+    with_write_conn(move |conn| books::finish_reading(conn, book))
+        .await
+        .map_err(ServerFnError::new)
+}
@@ -0,0 +1,69 @@
+#[cfg(feature = "server")]
+use backend::{
+    database::{with_conn, with_write_conn},
+    library, replace, search,
+};
+use dioxus::prelude::*;
+use shared::types::{BookMatches, ReplacementReport, TextReplacement};
+
+/// Matches of `replacement` in the EPUB text of the books of the active library matching
+/// `query`, with context, for review before anything is written. Books without matches are
+/// left out.
+#[server]
+pub async fn preview_replacement(
+    query: String,
+    replacement: TextReplacement,
+) -> Result<Vec<BookMatches>, ServerFnError> {
+    let regex = replace::compile(&replacement).map_err(ServerFnError::new)?;
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_conn(move |conn| {
+        let mut found = Vec::new();
+        for book in search::matching_books(conn, &query)? {
+            match replace::find(conn, &library_dir, book, &regex, &replacement) {
+                Ok(Some(matches)) if matches.total > 0 || !matches.skipped.is_empty() => {
+                    found.push(matches)
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Searching book {book} failed: {e:#}"),
+            }
+        }
+        Ok(found)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Replace the matches of `replacement` in the EPUB text of `books`, keeping the original EPUB
+/// files
+#[server]
+pub async fn apply_replacement(
+    books: Vec<i64>,
+    replacement: TextReplacement,
+) -> Result<ReplacementReport, ServerFnError> {
+    let regex = replace::compile(&replacement).map_err(ServerFnError::new)?;
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    with_write_conn(move |conn| {
+        let mut report = ReplacementReport::default();
+        for book in books {
+            match replace::replace(conn, &library_dir, book, &regex, &replacement) {
+                Ok(0) => {}
+                Ok(count) => {
+                    report.books += 1;
+                    report.replacements += count;
+                }
+                Err(e) => {
+                    let title: String =
+                        conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+                            row.get(0)
+                        })?;
+                    report.failed.push(format!("{title}: {e:#}"));
+                }
+            }
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
@@ -0,0 +1,39 @@
+#[cfg(feature = "server")]
+use backend::{
+    automation,
+    database::{with_conn, with_write_conn},
+};
+use dioxus::prelude::*;
+use shared::types::{Rule, RuleRun};
+
+/// The automation rules of the active library
+#[server]
+pub async fn list_rules() -> Result<Vec<Rule>, ServerFnError> {
+    with_conn(automation::list)
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Create the rule, if its ID is 0, or replace the rule with its ID. Returns the saved rule.
+#[server]
+pub async fn save_rule(rule: Rule) -> Result<Rule, ServerFnError> {
+    with_write_conn(move |conn| automation::save(conn, &rule))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// Delete rule `id` with its logged runs
+#[server]
+pub async fn delete_rule(id: i64) -> Result<(), ServerFnError> {
+    with_write_conn(move |conn| automation::delete(conn, id))
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// The logged runs of all rules, newest first
+#[server]
+pub async fn rule_runs() -> Result<Vec<RuleRun>, ServerFnError> {
+    with_conn(automation::runs)
+        .await
+        .map_err(ServerFnError::new)
+}
@@ -0,0 +1,57 @@
+#[cfg(feature = "server")]
+use backend::{
+    config::ConfigInterface,
+    database::{with_conn, with_write_conn},
+    library, search, statistics,
+};
+use dioxus::prelude::*;
+#[cfg(feature = "server")]
+use shared::types::AppConfig;
+use shared::types::{BookStatistics, StatisticsReport};
+
+/// Compute word, character and page counts of the books of the active library matching
+/// `query` (all books if it is empty) from their content and store them
+#[server]
+pub async fn compute_statistics(query: String) -> Result<StatisticsReport, ServerFnError> {
+    let words_per_page = AppConfig::read()
+        .map_err(ServerFnError::new)?
+        .statistics
+        .words_per_page;
+    let library = library::require_active().map_err(ServerFnError::new)?;
+    let library_dir = library.dir().to_path_buf();
+    // Reading the files takes a while, so only the storing holds the write connection
+    let report = with_conn(move |conn| {
+        let mut report = StatisticsReport::default();
+        for book in search::matching_books(conn, &query)? {
+            let title: String =
+                conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+                    row.get(0)
+                })?;
+            match statistics::compute(conn, &library_dir, book, words_per_page) {
+                Ok(computed) => report.computed.push((title, computed)),
+                Err(e) => report.failed.push(format!("{title}: {e:#}")),
+            }
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)?;
+    with_write_conn(move |conn| {
+        let tx = conn.transaction()?;
+        for (_, computed) in &report.computed {
+            statistics::store(&tx, computed)?;
+        }
+        tx.commit()?;
+        Ok(report)
+    })
+    .await
+    .map_err(ServerFnError::new)
+}
+
+/// Stored statistics of a book, if they were computed
+#[server]
+pub async fn load_book_statistics(book: i64) -> Result<Option<BookStatistics>, ServerFnError> {
+    with_conn(move |conn| statistics::load(conn, book))
+        .await
+        .map_err(ServerFnError::new)
+}
@@ -0,0 +1,518 @@
+use crate::library::{self, Library};
+use crate::{books, convert, metadata, search};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use shared::types::{Rule, RuleAction, RuleRun, RuleTrigger};
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How deep events caused by the actions of rules may nest, so that rules triggering each
+/// other (e.g., two rules adding tags on "Tag added") cannot loop forever
+const MAX_DEPTH: u8 = 3;
+/// Number of rule runs kept in the log, older ones are deleted
+const KEPT_RUNS: usize = 500;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+const RULE_COLUMNS: &str = "id, name, enabled, trigger, tag, condition, action";
+
+static BUS: Lazy<broadcast::Sender<BookEvent>> = Lazy::new(|| broadcast::channel(1024).0);
+static HANDLER: Once = Once::new();
+
+thread_local! {
+    /// Depth of the events emitted on this thread: 0 unless the action of a rule is running
+    static DEPTH: Cell<u8> = const { Cell::new(0) };
+}
+
+/// Something that happened to a book of a library, which the rules with its trigger react to
+#[derive(Debug, Clone)]
+pub struct BookEvent {
+    /// Database of the library the book belongs to
+    pub db: PathBuf,
+    pub trigger: RuleTrigger,
+    pub book: i64,
+    /// The added tag, for [`RuleTrigger::TagAdded`]
+    pub tag: Option<String>,
+    /// 0 for events caused by the user, one more than the event that ran the rule for events
+    /// caused by the action of a rule
+    pub depth: u8,
+}
+
+pub fn trigger_name(trigger: RuleTrigger) -> &'static str {
+    match trigger {
+        RuleTrigger::BookImported => "book_imported",
+        RuleTrigger::ReadingFinished => "reading_finished",
+        RuleTrigger::MetadataChanged => "metadata_changed",
+        RuleTrigger::TagAdded => "tag_added",
+    }
+}
+
+fn parse_trigger(name: &str) -> Result<RuleTrigger> {
+    RuleTrigger::ALL
+        .into_iter()
+        .find(|trigger| trigger_name(*trigger) == name)
+        .ok_or_else(|| anyhow!("Unknown rule trigger {name:?}"))
+}
+
+/// Subscribe to the events of all libraries. Events are only delivered while the receiver
+/// keeps up; see [`broadcast`].
+pub fn subscribe() -> broadcast::Receiver<BookEvent> {
+    BUS.subscribe()
+}
+
+/// Publish that `trigger` occurred for `book` of the library `conn` is connected to. Rules run
+/// later, once the connection's write lock is free, so this can be called inside transactions.
+/// Events of in-memory databases are dropped.
+pub fn emit(conn: &Connection, trigger: RuleTrigger, book: i64, tag: Option<&str>) {
+    let Some(db) = conn.path().filter(|path| !path.is_empty()) else {
+        return;
+    };
+    let depth = DEPTH.with(Cell::get);
+    if depth > MAX_DEPTH {
+        tracing::warn!(
+            "Not running rules for {trigger} of book {book}, rules triggered each other {MAX_DEPTH} times"
+        );
+        return;
+    }
+    // Fails only if nobody listens, e.g., before [`start`]
+    let _ = BUS.send(BookEvent {
+        db: PathBuf::from(db),
+        trigger,
+        book,
+        tag: tag.map(str::to_string),
+        depth,
+    });
+}
+
+fn read_rule(row: &Row) -> Result<Rule> {
+    let trigger: String = row.get(3)?;
+    let action: String = row.get(6)?;
+    Ok(Rule {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        enabled: row.get(2)?,
+        trigger: parse_trigger(&trigger)?,
+        tag: row.get(4)?,
+        condition: row.get(5)?,
+        action: serde_json::from_str(&action)
+            .with_context(|| format!("Reading the action of rule {:?}", row.get::<_, i64>(0)))?,
+    })
+}
+
+/// All rules, in the order they were created
+pub fn list(conn: &Connection) -> Result<Vec<Rule>> {
+    let mut stmt = conn.prepare(&format!("SELECT {RULE_COLUMNS} FROM rules ORDER BY id"))?;
+    let mut rows = stmt.query([])?;
+    let mut rules = Vec::new();
+    while let Some(row) = rows.next()? {
+        rules.push(read_rule(row)?);
+    }
+    Ok(rules)
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Rule> {
+    let mut stmt = conn.prepare(&format!("SELECT {RULE_COLUMNS} FROM rules WHERE id = ?1"))?;
+    let mut rows = stmt.query([id])?;
+    match rows.next()? {
+        Some(row) => read_rule(row),
+        None => Err(anyhow!("There is no rule with ID {id}")),
+    }
+}
+
+/// Check that the rule can run and trim its text fields
+fn validate(rule: &Rule) -> Result<Rule> {
+    let name = rule.name.trim();
+    if name.is_empty() {
+        return Err(anyhow!("The rule needs a name"));
+    }
+    let condition = rule.condition.trim();
+    search::parse(condition).context("The condition is no valid search query")?;
+    let tag = match rule.trigger {
+        RuleTrigger::TagAdded => rule
+            .tag
+            .as_deref()
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string),
+        _ => None,
+    };
+    let action = match &rule.action {
+        RuleAction::AddTag { tag } if tag.trim().is_empty() => {
+            return Err(anyhow!("The tag to add is missing"))
+        }
+        RuleAction::AddTag { tag } => RuleAction::AddTag {
+            tag: tag.trim().to_string(),
+        },
+        RuleAction::MoveToShelf { shelf } if shelf.trim().is_empty() => {
+            return Err(anyhow!("The shelf to move to is missing"))
+        }
+        RuleAction::MoveToShelf { shelf } => RuleAction::MoveToShelf {
+            shelf: shelf.trim().to_string(),
+        },
+        RuleAction::SendToDevice { path } if !path.is_absolute() => {
+            return Err(anyhow!("The device needs an absolute path, not {path:?}"))
+        }
+        RuleAction::RunWebhook { url }
+            if !(url.trim().starts_with("http://") || url.trim().starts_with("https://")) =>
+        {
+            return Err(anyhow!("The webhook needs an http:// or https:// URL"))
+        }
+        RuleAction::RunWebhook { url } => RuleAction::RunWebhook {
+            url: url.trim().to_string(),
+        },
+        action => action.clone(),
+    };
+    Ok(Rule {
+        id: rule.id,
+        name: name.to_string(),
+        enabled: rule.enabled,
+        trigger: rule.trigger,
+        tag,
+        condition: condition.to_string(),
+        action,
+    })
+}
+
+/// Create the rule, if its ID is 0, or replace the rule with its ID. Returns the saved rule.
+pub fn save(conn: &Connection, rule: &Rule) -> Result<Rule> {
+    let rule = validate(rule)?;
+    let action = serde_json::to_string(&rule.action)?;
+    let trigger = trigger_name(rule.trigger);
+    let id = if rule.id == 0 {
+        conn.execute(
+            "INSERT INTO rules (name, enabled, trigger, tag, condition, action)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                rule.name,
+                rule.enabled,
+                trigger,
+                rule.tag,
+                rule.condition,
+                action
+            ],
+        )?;
+        conn.last_insert_rowid()
+    } else {
+        let updated = conn.execute(
+            "UPDATE rules SET name = ?2, enabled = ?3, trigger = ?4, tag = ?5, condition = ?6,
+             action = ?7 WHERE id = ?1",
+            params![
+                rule.id,
+                rule.name,
+                rule.enabled,
+                trigger,
+                rule.tag,
+                rule.condition,
+                action
+            ],
+        )?;
+        if updated == 0 {
+            return Err(anyhow!("There is no rule with ID {}", rule.id));
+        }
+        rule.id
+    };
+    get(conn, id)
+}
+
+/// Delete rule `id` with its runs
+pub fn delete(conn: &Connection, id: i64) -> Result<()> {
+    if conn.execute("DELETE FROM rules WHERE id = ?1", [id])? == 0 {
+        return Err(anyhow!("There is no rule with ID {id}"));
+    }
+    Ok(())
+}
+
+/// The logged runs of all rules, newest first
+pub fn runs(conn: &Connection) -> Result<Vec<RuleRun>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.rule, rules.name, r.book, r.title, r.trigger, r.at, r.success, r.message
+         FROM rule_runs r JOIN rules ON rules.id = r.rule ORDER BY r.id DESC",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut runs = Vec::new();
+    while let Some(row) = rows.next()? {
+        let trigger: String = row.get(4)?;
+        runs.push(RuleRun {
+            rule: row.get(0)?,
+            rule_name: row.get(1)?,
+            book: row.get(2)?,
+            title: row.get(3)?,
+            trigger: parse_trigger(&trigger)?,
+            at: row.get(5)?,
+            success: row.get(6)?,
+            message: row.get(7)?,
+        });
+    }
+    Ok(runs)
+}
+
+fn record_run(
+    conn: &Connection,
+    rule: &Rule,
+    event: &BookEvent,
+    title: &str,
+    outcome: &Result<String>,
+) -> Result<()> {
+    let (success, message) = match outcome {
+        Ok(message) => (true, message.clone()),
+        Err(e) => (false, format!("{e:#}")),
+    };
+    conn.execute(
+        "INSERT INTO rule_runs (rule, book, title, trigger, at, success, message)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            rule.id,
+            event.book,
+            title,
+            trigger_name(event.trigger),
+            Utc::now(),
+            success,
+            message
+        ],
+    )?;
+    conn.execute(
+        "DELETE FROM rule_runs WHERE id NOT IN (SELECT id FROM rule_runs ORDER BY id DESC LIMIT ?1)",
+        [KEPT_RUNS],
+    )?;
+    Ok(())
+}
+
+/// Whether `rule` reacts to `event`, not looking at its condition
+fn reacts_to(rule: &Rule, event: &BookEvent) -> bool {
+    rule.enabled
+        && rule.trigger == event.trigger
+        && match (&rule.tag, &event.tag) {
+            (Some(wanted), Some(added)) => wanted.eq_ignore_ascii_case(added),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+}
+
+/// Whether `book` matches the condition of `rule`
+fn matches(conn: &Connection, rule: &Rule, book: i64) -> Result<bool> {
+    let query = format!("id:{book} {}", rule.condition);
+    Ok(!search::matching_books(conn, &query)?.is_empty())
+}
+
+/// Add `tag` to the book; adding it causes a "Tag added" event
+fn add_tag(conn: &Connection, book: i64, tag: &str) -> Result<String> {
+    let has_tag: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM books_tags_link l JOIN tags t ON t.id = l.tag
+         WHERE l.book = ?1 AND t.name = ?2)",
+        params![book, tag],
+        |row| row.get(0),
+    )?;
+    if has_tag {
+        return Ok(format!("The book already has the tag {tag:?}"));
+    }
+    books::link_tag(conn, book, books::tag_id(conn, tag)?)?;
+    conn.execute(
+        "UPDATE books SET last_modified = CURRENT_TIMESTAMP WHERE id = ?1",
+        [book],
+    )?;
+    emit(conn, RuleTrigger::TagAdded, book, Some(tag));
+    Ok(format!("Added the tag {tag:?}"))
+}
+
+/// Put the book on the library-wide collection `shelf`, taking it off the other library-wide
+/// collections
+fn move_to_shelf(conn: &mut Connection, book: i64, shelf: &str) -> Result<String> {
+    let tx = conn.transaction()?;
+    let collection = books::collection_id(&tx, shelf, None, false)?;
+    let removed = tx.execute(
+        "DELETE FROM books_collections_link WHERE book = ?1 AND collection IN
+         (SELECT id FROM collections WHERE user IS NULL AND id != ?2)",
+        params![book, collection],
+    )?;
+    let position: i64 = tx.query_row(
+        "SELECT COALESCE(MAX(position), 0) + 1 FROM books_collections_link WHERE collection = ?1",
+        [collection],
+        |row| row.get(0),
+    )?;
+    let added = books::add_to_collection(&tx, book, collection, position, None)?;
+    tx.commit()?;
+    Ok(match (added, removed) {
+        (false, 0) => format!("The book already is on the shelf {shelf:?}"),
+        (_, 0) => format!("Put the book on the shelf {shelf:?}"),
+        (_, removed) => {
+            format!("Moved the book to the shelf {shelf:?} from {removed} other shelves")
+        }
+    })
+}
+
+/// The file of the book to send to a device: KEPUB, else EPUB, else its first file. Returns its
+/// path and the name to give it on the device.
+fn device_file(conn: &Connection, library_dir: &Path, book: i64) -> Result<(PathBuf, String)> {
+    let dir: String = conn.query_row("SELECT path FROM books WHERE id = ?1", [book], |row| {
+        row.get(0)
+    })?;
+    let (format, name): (String, String) = conn
+        .query_row(
+            "SELECT format, name FROM data WHERE book = ?1
+             ORDER BY CASE format WHEN 'KEPUB' THEN 0 WHEN 'EPUB' THEN 1 ELSE 2 END, id LIMIT 1",
+            [book],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .ok_or_else(|| anyhow!("The book has no files"))?;
+    let extension = format.to_lowercase();
+    let path = library_dir.join(dir).join(format!("{name}.{extension}"));
+    // Kobo readers only use their own renderer for files named like this
+    let device_name = match format.as_str() {
+        "KEPUB" => format!("{name}.kepub.epub"),
+        _ => format!("{name}.{extension}"),
+    };
+    Ok((path, device_name))
+}
+
+fn send_to_device(library: &Library, book: i64, device: &Path) -> Result<String> {
+    if !device.is_dir() {
+        return Err(anyhow!("No device is mounted at {device:?}"));
+    }
+    let (source, name) = library
+        .pool()
+        .read(|conn| device_file(conn, library.dir(), book))?;
+    let target = device.join(&name);
+    std::fs::copy(&source, &target).with_context(|| format!("Copying {source:?} to {target:?}"))?;
+    Ok(format!("Copied {name:?} to {device:?}"))
+}
+
+fn run_webhook(library: &Library, rule: &Rule, event: &BookEvent, url: &str) -> Result<String> {
+    let metadata = library
+        .pool()
+        .read(|conn| metadata::current_metadata(conn, event.book))?;
+    let payload = serde_json::json!({
+        "rule": rule.name,
+        "trigger": event.trigger,
+        "tag": event.tag,
+        "library": library.name(),
+        "book": event.book,
+        "metadata": metadata,
+    });
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(concat!("IronScribe/", env!("CARGO_PKG_VERSION")))
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&payload)?)
+        .send()
+        .with_context(|| format!("Requesting {url}"))?
+        .error_for_status()?;
+    Ok(format!("{url} answered {}", response.status()))
+}
+
+/// Run the action of `rule` for the book of `event`, returning what it did
+fn run_action(library: &Library, rule: &Rule, event: &BookEvent) -> Result<String> {
+    let book = event.book;
+    match &rule.action {
+        RuleAction::AddTag { tag } => library.pool().write(|conn| add_tag(conn, book, tag)),
+        RuleAction::MoveToShelf { shelf } => library
+            .pool()
+            .write(|conn| move_to_shelf(conn, book, shelf)),
+        RuleAction::ConvertToKepub => library.pool().write(|conn| {
+            if !convert::can_convert(conn, library.dir(), book, "KEPUB") {
+                return Err(anyhow!(
+                    "The book has no file that can be converted into KEPUB"
+                ));
+            }
+            Ok(match convert::enqueue(conn, book, "KEPUB")? {
+                Some(job) => format!("Queued job {} converting into KEPUB", job.id),
+                None => "The conversion into KEPUB is queued already".to_string(),
+            })
+        }),
+        RuleAction::RenameFiles => library.pool().write(|conn| {
+            let tx = conn.transaction()?;
+            books::rename_files(&tx, library.dir(), book)?;
+            let dir: String =
+                tx.query_row("SELECT path FROM books WHERE id = ?1", [book], |row| {
+                    row.get(0)
+                })?;
+            tx.commit()?;
+            Ok(format!("The files are in {dir:?}"))
+        }),
+        RuleAction::SendToDevice { path } => send_to_device(library, book, path),
+        RuleAction::RunWebhook { url } => run_webhook(library, rule, event, url),
+    }
+}
+
+/// Run the rules of the active library that react to `event` for its book
+fn handle(event: &BookEvent) -> Result<()> {
+    let Some(library) = library::active() else {
+        return Ok(());
+    };
+    if library.db_path() != event.db {
+        return Ok(());
+    }
+    let rules = library.pool().read(list)?;
+    for rule in rules.iter().filter(|rule| reacts_to(rule, event)) {
+        // Checked with the write connection, so that the transaction that emitted the event is
+        // committed and its changes are seen
+        let applies = library.pool().write(|conn| {
+            let title: Option<String> = conn
+                .query_row(
+                    "SELECT title FROM books WHERE id = ?1",
+                    [event.book],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(title) = title else {
+                return Ok(None);
+            };
+            let matched = matches(conn, rule, event.book);
+            Ok(Some((title, matched)))
+        })?;
+        let Some((title, matched)) = applies else {
+            // The book was deleted or its import rolled back
+            return Ok(());
+        };
+        let outcome = match matched {
+            Ok(false) => continue,
+            Ok(true) => {
+                DEPTH.with(|depth| depth.set(event.depth + 1));
+                let outcome = run_action(&library, rule, event);
+                DEPTH.with(|depth| depth.set(0));
+                outcome
+            }
+            Err(e) => Err(e.context("Checking the condition failed")),
+        };
+        if let Err(e) = &outcome {
+            tracing::warn!("Rule {:?} failed for book {}: {e:#}", rule.name, event.book);
+        }
+        library
+            .pool()
+            .write(|conn| record_run(conn, rule, event, &title, &outcome))?;
+    }
+    Ok(())
+}
+
+/// Start the background task running the rules for emitted events, one event at a time. Must be
+/// called from within the tokio runtime; calling it more than once has no effect.
+pub fn start() {
+    HANDLER.call_once(|| {
+        let mut events = BUS.subscribe();
+        tokio::spawn(async move {
+            use broadcast::error::RecvError;
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Rules missed {missed} events, too many happened at once");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                match tokio::task::spawn_blocking(move || handle(&event)).await {
+                    Ok(Err(e)) => tracing::error!("Running rules failed: {e:#}"),
+                    Err(e) => tracing::error!("Running rules panicked: {e}"),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+    });
+}
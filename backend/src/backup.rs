@@ -0,0 +1,351 @@
+use crate::config::ConfigInterface;
+use crate::jobs::{self, JobContext};
+use crate::{library, migrations};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use rusqlite::{backup::Backup, Connection, OpenFlags};
+use shared::types::{AppConfig, BackupConfig, BackupSnapshot, JobTask};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::time::Duration;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const SNAPSHOT_PREFIX: &str = "library-";
+const SNAPSHOT_EXTENSION: &str = "db";
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+/// Timestamps of snapshots have milliseconds, so that snapshots taken right after each other
+/// (e.g., before a restore and before the migration following it) get different names
+const SNAPSHOT_TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+/// Label of snapshots taken by the scheduler; only these are subject to retention
+pub const SCHEDULED_LABEL: &str = "scheduled";
+/// How often the scheduler checks whether a new snapshot is due
+const SCHEDULER_TICK: Duration = Duration::from_secs(10 * 60);
+
+static SCHEDULER: Once = Once::new();
+
+/// Directory snapshots of the library in `library_dir` are written to
+pub fn backup_dir(cfg: &BackupConfig, library_dir: &Path) -> PathBuf {
+    cfg.dir
+        .clone()
+        .unwrap_or_else(|| library_dir.join("backups"))
+}
+
+/// Backup directory for the database at `db_path`, read from the config if there is one
+pub fn backup_dir_for_db(db_path: &Path) -> Result<PathBuf> {
+    let library_dir = db_path
+        .parent()
+        .ok_or_else(|| anyhow!("Database path {db_path:?} has no parent directory"))?;
+    let cfg = AppConfig::read().map(|c| c.backup).unwrap_or_default();
+    Ok(backup_dir(&cfg, library_dir))
+}
+
+/// Write a consistent copy of the database behind `conn` into `dir` using SQLite's online
+/// backup API. Other connections may keep reading and writing while the copy is made.
+pub fn snapshot(conn: &Connection, dir: &Path, label: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating backup directory {dir:?}"))?;
+    let mut created = Utc::now();
+    let path = loop {
+        let timestamp = created.format(SNAPSHOT_TIMESTAMP_FORMAT);
+        let path = dir.join(format!(
+            "{SNAPSHOT_PREFIX}{timestamp}-{label}.{SNAPSHOT_EXTENSION}"
+        ));
+        if !path.exists() {
+            break path;
+        }
+        created += chrono::Duration::milliseconds(1);
+    };
+
+    let mut dst =
+        Connection::open(&path).with_context(|| format!("Creating backup file {path:?}"))?;
+    let backup = Backup::new(conn, &mut dst)?;
+    backup
+        .run_to_completion(256, Duration::from_millis(10), None)
+        .with_context(|| format!("Writing snapshot {path:?}"))?;
+    tracing::info!("Wrote {label} snapshot to {:?}", path);
+    Ok(path)
+}
+
+/// List the snapshots in `dir`, newest first. Files not named like a snapshot are ignored.
+pub fn list_snapshots(dir: &Path) -> Result<Vec<BackupSnapshot>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Reading {dir:?}"))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some((created, label)) = parse_snapshot_name(&name) {
+            snapshots.push(BackupSnapshot {
+                name,
+                label,
+                created,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    snapshots.sort_by(|a, b| b.created.cmp(&a.created));
+    Ok(snapshots)
+}
+
+fn parse_snapshot_name(name: &str) -> Option<(DateTime<Utc>, String)> {
+    let stem = name
+        .strip_prefix(SNAPSHOT_PREFIX)?
+        .strip_suffix(&format!(".{SNAPSHOT_EXTENSION}"))?;
+    // Snapshots written before timestamps had milliseconds have none
+    let (timestamp, format, label) = match stem.get(15..16) {
+        Some(".") => (stem.get(..19)?, SNAPSHOT_TIMESTAMP_FORMAT, stem.get(20..)?),
+        _ => (stem.get(..15)?, TIMESTAMP_FORMAT, stem.get(16..)?),
+    };
+    let created = NaiveDateTime::parse_from_str(timestamp, format).ok()?;
+    Some((created.and_utc(), label.to_string()))
+}
+
+/// Resolve a snapshot name coming from a client to a file inside `dir`
+pub fn snapshot_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    if parse_snapshot_name(name).is_none() || name.contains(['/', '\\']) {
+        return Err(anyhow!("{name:?} is not a valid snapshot name"));
+    }
+    let path = dir.join(name);
+    if !path.is_file() {
+        return Err(anyhow!("Snapshot {name:?} does not exist"));
+    }
+    Ok(path)
+}
+
+/// Delete scheduled snapshots that fall outside the retention rules: for each of the last
+/// `keep_daily` days and `keep_weekly` weeks that have snapshots, the newest one is kept.
+/// Manual, pre-migration and pre-restore snapshots are never deleted automatically.
+pub fn prune(dir: &Path, cfg: &BackupConfig) -> Result<Vec<PathBuf>> {
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    let mut removed = Vec::new();
+    for snapshot in list_snapshots(dir)?
+        .into_iter()
+        .filter(|s| s.label == SCHEDULED_LABEL)
+    {
+        let day = snapshot.created.date_naive();
+        let week = day.iso_week();
+        let mut keep = false;
+        if !days.contains(&day) && days.len() < cfg.keep_daily {
+            days.insert(day);
+            keep = true;
+        }
+        if !weeks.contains(&(week.year(), week.week())) && weeks.len() < cfg.keep_weekly {
+            weeks.insert((week.year(), week.week()));
+            keep = true;
+        }
+        if !keep {
+            let path = dir.join(&snapshot.name);
+            std::fs::remove_file(&path).with_context(|| format!("Deleting snapshot {path:?}"))?;
+            tracing::debug!("Pruned snapshot {:?}", path);
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+/// Replace the contents of the database behind `conn` with the snapshot at `snapshot_path`.
+/// The current state is saved as a "pre-restore" snapshot first, and the restored database is
+/// migrated to the current schema afterwards.
+pub fn restore(conn: &mut Connection, snapshot_path: &Path, backup_dir: &Path) -> Result<()> {
+    let src = Connection::open_with_flags(snapshot_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Opening snapshot {snapshot_path:?}"))?;
+    let version = migrations::current_version(&src)?;
+    if version > migrations::latest_version() {
+        return Err(anyhow!(
+            "Snapshot has schema version {version}, which is newer than this version of IronScribe supports"
+        ));
+    }
+
+    snapshot(conn, backup_dir, "pre-restore")?;
+    {
+        let backup = Backup::new(&src, conn)?;
+        backup
+            .run_to_completion(256, Duration::from_millis(10), None)
+            .with_context(|| format!("Restoring snapshot {snapshot_path:?}"))?;
+    }
+    migrations::run(conn)?;
+    tracing::info!("Restored library database from {:?}", snapshot_path);
+    Ok(())
+}
+
+/// Write a zip archive of the whole library: a fresh snapshot of the database stored as
+/// `library.db`, plus every other file in the library directory (covers, book files, ...).
+/// Backups and the live database files are left out. If `dest` is a directory, a timestamped
+/// file name is chosen inside it.
+pub fn export_archive(conn: &Connection, library_dir: &Path, dest: &Path) -> Result<PathBuf> {
+    let dest = if dest.is_dir() {
+        let timestamp = Utc::now().format(TIMESTAMP_FORMAT);
+        dest.join(format!("ironscribe-{timestamp}.zip"))
+    } else {
+        dest.to_path_buf()
+    };
+
+    let staging = std::env::temp_dir().join(format!(
+        "ironscribe-export-{}-{}",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    ));
+    let snapshot_path = snapshot(conn, &staging, "export")?;
+    let result = write_archive(&snapshot_path, library_dir, &dest);
+    if let Err(e) = std::fs::remove_dir_all(&staging) {
+        tracing::warn!("Failed to clean up staging directory {:?}: {e}", staging);
+    }
+    result?;
+    tracing::info!("Exported library archive to {:?}", dest);
+    Ok(dest)
+}
+
+fn write_archive(snapshot_path: &Path, library_dir: &Path, dest: &Path) -> Result<()> {
+    let file = File::create(dest).with_context(|| format!("Creating archive {dest:?}"))?;
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true);
+
+    zip.start_file("library.db", options)?;
+    std::io::copy(&mut File::open(snapshot_path)?, &mut zip)?;
+
+    let cfg = AppConfig::read().map(|c| c.backup).unwrap_or_default();
+    let excluded_dir = backup_dir(&cfg, library_dir);
+    let mut pending = vec![library_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Reading {dir:?}"))? {
+            let path = entry?.path();
+            if path == excluded_dir || path == dest {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let relative = path.strip_prefix(library_dir)?;
+            let name = relative.to_string_lossy().replace('\\', "/");
+            if ["library.db", "library.db-wal", "library.db-shm"].contains(&name.as_str()) {
+                continue;
+            }
+            zip.start_file(name, options)?;
+            std::io::copy(
+                &mut File::open(&path).with_context(|| format!("Reading {path:?}"))?,
+                &mut zip,
+            )?;
+        }
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Start the background task queueing scheduled snapshots of the active library. Must be called
+/// from within the tokio runtime; calling it more than once has no effect.
+pub fn start_scheduler() {
+    SCHEDULER.call_once(|| {
+        tokio::spawn(async {
+            loop {
+                match tokio::task::spawn_blocking(run_scheduled).await {
+                    Ok(Err(e)) => tracing::error!("Scheduled backup failed: {e:#}"),
+                    Err(e) => tracing::error!("Scheduled backup panicked: {e}"),
+                    Ok(Ok(())) => {}
+                }
+                tokio::time::sleep(SCHEDULER_TICK).await;
+            }
+        });
+    });
+}
+
+/// Queue a scheduled snapshot of the active library if the last one is older than the
+/// configured interval
+fn run_scheduled() -> Result<()> {
+    let cfg = AppConfig::read()?.backup;
+    if cfg.interval_hours == 0 {
+        return Ok(());
+    }
+    let Some(library) = library::active() else {
+        return Ok(());
+    };
+    let dir = backup_dir(&cfg, library.dir());
+    let last = list_snapshots(&dir)?
+        .into_iter()
+        .find(|s| s.label == SCHEDULED_LABEL)
+        .map(|s| s.created);
+    let interval = chrono::Duration::hours(cfg.interval_hours.into());
+    if last.is_none_or(|created| Utc::now() - created >= interval) {
+        let task = JobTask::Backup {
+            label: SCHEDULED_LABEL.to_string(),
+        };
+        library
+            .pool()
+            .write(|conn| jobs::enqueue(conn, task, "Scheduled backup"))?;
+    }
+    Ok(())
+}
+
+/// Take a snapshot labelled `label` of the library of a background job. After scheduled
+/// snapshots, the retention rules are applied.
+pub(crate) fn run_job(ctx: &JobContext, label: &str) -> Result<()> {
+    let cfg = AppConfig::read()?.backup;
+    let dir = backup_dir(&cfg, ctx.library().dir());
+    let path = ctx
+        .library()
+        .pool()
+        .read(|conn| snapshot(conn, &dir, label))?;
+    ctx.log(format!("Wrote {path:?}"));
+    if label == SCHEDULED_LABEL {
+        ctx.progress(1, 2);
+        for removed in prune(&dir, &cfg)? {
+            ctx.log(format!("Removed {removed:?}"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_keeps_the_newest_snapshot_of_recent_days_and_weeks() {
+        let dir = std::env::temp_dir().join(format!("ironscribe-prune-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let names = [
+            // Monday of week 43
+            "library-20261019-180000.000-scheduled.db",
+            "library-20261019-060000.000-scheduled.db",
+            // Sunday and Saturday of week 42
+            "library-20261018-120000.000-scheduled.db",
+            "library-20261017-120000.000-scheduled.db",
+            // Saturday of week 41, written before timestamps had milliseconds
+            "library-20261010-120000-scheduled.db",
+            // Only scheduled snapshots are pruned
+            "library-20260101-120000.000-pre-restore.db",
+        ];
+        for name in names {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let cfg = BackupConfig {
+            keep_daily: 2,
+            keep_weekly: 2,
+            ..Default::default()
+        };
+
+        let mut removed = prune(&dir, &cfg).unwrap();
+        removed.sort();
+        let mut kept: Vec<String> = list_snapshots(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        kept.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            removed,
+            [names[4], names[3], names[1]].map(|name| dir.join(name))
+        );
+        assert_eq!(kept, [names[5], names[2], names[0]]);
+    }
+}
@@ -0,0 +1,516 @@
+use crate::automation;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::{BookMetadata, RuleTrigger};
+use std::path::{Path, PathBuf};
+
+/// Leading articles that are moved to the end of a title for sorting
+const TITLE_ARTICLES: &[&str] = &["The ", "A ", "An "];
+
+/// Sort key of a title, e.g., "The Way of Kings" becomes "Way of Kings, The"
+pub fn title_sort(title: &str) -> String {
+    let title = title.trim();
+    for article in TITLE_ARTICLES {
+        if let Some(rest) = title.strip_prefix(article) {
+            if !rest.is_empty() {
+                return format!("{rest}, {}", article.trim_end());
+            }
+        }
+    }
+    title.to_string()
+}
+
+/// Sort key of an author name, e.g., "Brandon Sanderson" becomes "Sanderson, Brandon". Names
+/// that already contain a comma are assumed to be in sort order.
+pub fn author_sort(name: &str) -> String {
+    let name = name.trim();
+    if name.contains(',') {
+        return name.to_string();
+    }
+    match name.rsplit_once(' ') {
+        Some((first, last)) => format!("{last}, {first}"),
+        None => name.to_string(),
+    }
+}
+
+/// Values of a book that is about to be added to the library
+#[derive(Debug, Clone, Default)]
+pub struct NewBook {
+    pub title: String,
+    /// Derived from the title if not given
+    pub sort: Option<String>,
+    /// Now if not given
+    pub date_added: Option<DateTime<Utc>>,
+    pub date_published: Option<DateTime<Utc>>,
+    /// Now if not given
+    pub last_modified: Option<DateTime<Utc>>,
+    pub number_of_pages: u32,
+    pub goodreads_id: Option<i64>,
+    /// Directory of the book's files, relative to the library directory
+    pub path: String,
+    pub has_cover: bool,
+    pub uuid: Option<String>,
+}
+
+pub fn insert_book(conn: &Connection, book: &NewBook) -> Result<i64> {
+    let sort = book.sort.clone().unwrap_or_else(|| title_sort(&book.title));
+    conn.execute(
+        "INSERT INTO books (title, sort, date_added, date_published, last_modified, number_of_pages, goodreads_id, path, has_cover, uuid)
+         VALUES (?1, ?2, COALESCE(?3, CURRENT_TIMESTAMP), ?4, COALESCE(?5, CURRENT_TIMESTAMP), ?6, ?7, ?8, ?9, ?10)",
+        params![
+            book.title,
+            sort,
+            book.date_added,
+            book.date_published,
+            book.last_modified,
+            book.number_of_pages,
+            book.goodreads_id,
+            book.path,
+            book.has_cover,
+            book.uuid,
+        ],
+    )
+    .with_context(|| format!("Inserting book {:?}", book.title))?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// ID of the author called `name`, created if it doesn't exist yet
+pub fn author_id(conn: &Connection, name: &str, sort: Option<&str>) -> Result<i64> {
+    let existing = conn
+        .query_row("SELECT id FROM authors WHERE name = ?1", [name], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    let sort = sort
+        .map(str::to_string)
+        .unwrap_or_else(|| author_sort(name));
+    conn.execute(
+        "INSERT INTO authors (name, sort) VALUES (?1, ?2)",
+        params![name, sort],
+    )
+    .with_context(|| format!("Inserting author {name:?}"))?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// ID of the series called `name`, created if it doesn't exist yet
+pub fn series_id(conn: &Connection, name: &str, sort: Option<&str>) -> Result<i64> {
+    let existing = conn
+        .query_row("SELECT id FROM series WHERE name = ?1", [name], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    let sort = sort.map(str::to_string).unwrap_or_else(|| title_sort(name));
+    conn.execute(
+        "INSERT INTO series (name, sort) VALUES (?1, ?2)",
+        params![name, sort],
+    )
+    .with_context(|| format!("Inserting series {name:?}"))?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// ID of the tag called `name`, created if it doesn't exist yet
+pub fn tag_id(conn: &Connection, name: &str) -> Result<i64> {
+    conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", [name])?;
+    Ok(
+        conn.query_row("SELECT id FROM tags WHERE name = ?1", [name], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+pub fn link_author(conn: &Connection, book: i64, author: i64) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO books_authors_link (book, author) VALUES (?1, ?2)",
+        params![book, author],
+    )?;
+    Ok(())
+}
+
+/// Add the book to a series. Returns false if another book already occupies that entry.
+pub fn link_series(conn: &Connection, book: i64, series: i64, entry: f64) -> Result<bool> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO books_series_link (book, series, entry) VALUES (?1, ?2, ?3)",
+        params![book, series, entry],
+    )?;
+    Ok(inserted > 0)
+}
+
+pub fn link_tag(conn: &Connection, book: i64, tag: i64) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO books_tags_link (book, tag) VALUES (?1, ?2)",
+        params![book, tag],
+    )?;
+    Ok(())
+}
+
+/// Set an identifier such as `isbn` or `goodreads`, replacing a previous value of the same type
+pub fn set_identifier(conn: &Connection, book: i64, kind: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO identifiers (book, type, val) VALUES (?1, ?2, ?3)
+         ON CONFLICT(book, type) DO UPDATE SET val = excluded.val",
+        params![book, kind, value],
+    )?;
+    Ok(())
+}
+
+/// Set the description of a book
+pub fn set_comment(conn: &Connection, book: i64, text: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO comments (book, text) VALUES (?1, ?2)
+         ON CONFLICT(book) DO UPDATE SET text = excluded.text",
+        params![book, text],
+    )?;
+    Ok(())
+}
+
+/// Register a file of the book. `name` is the file name without extension inside the book's
+/// directory, `format` the upper-case extension.
+pub fn add_format(conn: &Connection, book: i64, format: &str, name: &str, size: u64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO data (book, format, name, uncompressed_size) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(book, format) DO UPDATE SET name = excluded.name, uncompressed_size = excluded.uncompressed_size",
+        params![book, format.to_uppercase(), name, size],
+    )?;
+    Ok(())
+}
+
+/// ID of the user called `name`, created if it doesn't exist yet
+pub fn user_id(conn: &Connection, name: &str) -> Result<i64> {
+    conn.execute("INSERT OR IGNORE INTO users (name) VALUES (?1)", [name])?;
+    Ok(
+        conn.query_row("SELECT id FROM users WHERE name = ?1", [name], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+/// ID of the collection called `name` owned by `user`, created if it doesn't exist yet.
+/// Collections without a user belong to the whole library.
+pub fn collection_id(
+    conn: &Connection,
+    name: &str,
+    user: Option<i64>,
+    is_public: bool,
+) -> Result<i64> {
+    let existing = conn
+        .query_row(
+            "SELECT id FROM collections WHERE name = ?1 AND user IS ?2",
+            params![name, user],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    conn.execute(
+        "INSERT INTO collections (name, user, is_public) VALUES (?1, ?2, ?3)",
+        params![name, user, is_public],
+    )
+    .with_context(|| format!("Inserting collection {name:?}"))?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Add the book to a collection. Returns false if it is already part of it.
+pub fn add_to_collection(
+    conn: &Connection,
+    book: i64,
+    collection: i64,
+    position: i64,
+    date_added: Option<DateTime<Utc>>,
+) -> Result<bool> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO books_collections_link (book, collection, position, date_added)
+         VALUES (?1, ?2, ?3, COALESCE(?4, CURRENT_TIMESTAMP))",
+        params![book, collection, position, date_added],
+    )?;
+    Ok(inserted > 0)
+}
+
+/// Record a reading of the book. Readings without an end date are still in progress. Imports
+/// record the reading history with this, so it runs no rules; see [`finish_reading`].
+pub fn add_reading(
+    conn: &Connection,
+    book: i64,
+    user: Option<i64>,
+    start_date: Option<DateTime<Utc>>,
+    end_date: Option<DateTime<Utc>>,
+    rating: Option<u8>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO read_books (book, user, start_date, end_date, rating)
+         VALUES (?1, ?2, COALESCE(?3, CURRENT_TIMESTAMP), ?4, ?5)",
+        params![book, user, start_date, end_date, rating],
+    )?;
+    Ok(())
+}
+
+/// Mark the book as read now: the reading in progress ends, or a finished reading is recorded
+/// if there is none. The rules for finished readings run once this is committed.
+pub fn finish_reading(conn: &mut Connection, book: i64) -> Result<()> {
+    let tx = conn.transaction()?;
+    let now = Utc::now();
+    let ended = tx.execute(
+        "UPDATE read_books SET end_date = ?2
+         WHERE rowid = (SELECT MAX(rowid) FROM read_books WHERE book = ?1 AND end_date IS NULL)",
+        params![book, now],
+    )?;
+    if ended == 0 {
+        add_reading(&tx, book, None, Some(now), Some(now), None)?;
+    }
+    tx.commit()?;
+    automation::emit(conn, RuleTrigger::ReadingFinished, book, None);
+    Ok(())
+}
+
+/// Whether a reading of the book that ended at `end_date` (or is still in progress if `None`)
+/// was recorded before
+pub fn reading_exists(
+    conn: &Connection,
+    book: i64,
+    end_date: Option<DateTime<Utc>>,
+) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM read_books WHERE book = ?1 AND end_date IS ?2)",
+        params![book, end_date],
+        |row| row.get(0),
+    )?)
+}
+
+/// Characters that are not allowed in file names on at least one supported platform
+const RESERVED_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Make `name` safe to use as a file or directory name
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if RESERVED_CHARS.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .take(100)
+        .collect();
+    let name = name.trim().trim_end_matches('.').trim_end();
+    if name.is_empty() {
+        "Unknown".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Directory of a book's files relative to the library directory, laid out like Calibre does:
+/// `Author/Title (id)`
+pub fn book_dir(title: &str, author: Option<&str>, book: i64) -> String {
+    format!(
+        "{}/{} ({book})",
+        sanitize_file_name(author.unwrap_or("Unknown")),
+        sanitize_file_name(title)
+    )
+}
+
+/// Name of a book's files without extension: `Title - Author`
+pub fn book_file_name(title: &str, author: Option<&str>) -> String {
+    sanitize_file_name(&format!("{title} - {}", author.unwrap_or("Unknown")))
+}
+
+/// Replace the metadata of a book. Empty fields are cleared. Bumps `last_modified`.
+///
+/// The book's files are not moved: call [`rename_files`] after committing, so that a failing
+/// later step of the transaction cannot leave the files where the database doesn't expect them.
+pub fn update_book(conn: &Connection, book: i64, metadata: &BookMetadata) -> Result<()> {
+    let title = metadata
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .ok_or_else(|| anyhow!("Book {book} needs a title"))?;
+    let updated = conn.execute(
+        "UPDATE books SET title = ?2, sort = ?3, date_published = ?4, number_of_pages = ?5,
+         goodreads_id = ?6, last_modified = CURRENT_TIMESTAMP WHERE id = ?1",
+        params![
+            book,
+            title,
+            title_sort(title),
+            metadata.date_published,
+            metadata.number_of_pages.unwrap_or(0),
+            metadata.goodreads_id,
+        ],
+    )?;
+    if updated == 0 {
+        return Err(anyhow!("There is no book with ID {book}"));
+    }
+
+    conn.execute("DELETE FROM books_authors_link WHERE book = ?1", [book])?;
+    for name in &metadata.authors {
+        link_author(conn, book, author_id(conn, name, None)?)?;
+    }
+    conn.execute("DELETE FROM books_series_link WHERE book = ?1", [book])?;
+    if let Some(name) = &metadata.series {
+        let entry = metadata.series_index.unwrap_or(1.0);
+        if !link_series(conn, book, series_id(conn, name, None)?, entry)? {
+            return Err(anyhow!(
+                "Another book is already number {entry} of the series {name:?}"
+            ));
+        }
+    }
+    conn.execute("DELETE FROM books_tags_link WHERE book = ?1", [book])?;
+    for name in &metadata.tags {
+        link_tag(conn, book, tag_id(conn, name)?)?;
+    }
+    match &metadata.description {
+        Some(text) => set_comment(conn, book, text)?,
+        None => {
+            conn.execute("DELETE FROM comments WHERE book = ?1", [book])?;
+        }
+    }
+    for (kind, value) in [
+        ("isbn", metadata.isbn.clone()),
+        ("goodreads", metadata.goodreads_id.map(|id| id.to_string())),
+    ] {
+        match value {
+            Some(value) => set_identifier(conn, book, kind, &value)?,
+            None => {
+                conn.execute(
+                    "DELETE FROM identifiers WHERE book = ?1 AND type = ?2",
+                    params![book, kind],
+                )?;
+            }
+        }
+    }
+    conn.execute_batch(
+        "DELETE FROM authors WHERE id NOT IN (SELECT author FROM books_authors_link);
+         DELETE FROM series WHERE id NOT IN (SELECT series FROM books_series_link);
+         DELETE FROM tags WHERE id NOT IN (SELECT tag FROM books_tags_link);",
+    )?;
+    Ok(())
+}
+
+/// Move the directory and files of a book to the names derived from its title and first author,
+/// and assign a directory to a new book. Files that were moved are moved back if a later move or
+/// updating the database fails.
+pub fn rename_files(conn: &Connection, library_dir: &Path, book: i64) -> Result<()> {
+    let mut moves = Vec::new();
+    conn.execute_batch("SAVEPOINT rename_files")?;
+    match move_files(conn, library_dir, book, &mut moves) {
+        Ok(()) => {
+            conn.execute_batch("RELEASE rename_files")?;
+            Ok(())
+        }
+        Err(e) => {
+            conn.execute_batch("ROLLBACK TO rename_files; RELEASE rename_files")?;
+            for (from, to) in moves.into_iter().rev() {
+                let undone = from
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|()| std::fs::rename(&to, &from));
+                if let Err(undo) = undone {
+                    tracing::error!("Could not move {to:?} back to {from:?}: {undo}");
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// See [`rename_files`]. Every completed move is added to `moves` as a pair of source and target.
+fn move_files(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    moves: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    let (title, old_dir): (String, String) = conn.query_row(
+        "SELECT title, path FROM books WHERE id = ?1",
+        [book],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let author: Option<String> = conn
+        .query_row(
+            "SELECT a.name FROM books_authors_link bal JOIN authors a ON a.id = bal.author
+             WHERE bal.book = ?1 ORDER BY bal.rowid LIMIT 1",
+            [book],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let new_dir = book_dir(&title, author.as_deref(), book);
+    let (old_path, new_path) = (library_dir.join(&old_dir), library_dir.join(&new_dir));
+
+    if new_dir != old_dir {
+        if new_path.exists() {
+            return Err(anyhow!(
+                "Cannot move the files of book {book}, {new_path:?} exists"
+            ));
+        }
+        if !old_dir.is_empty() && old_path.is_dir() {
+            if let Some(parent) = new_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(&old_path, &new_path)
+                .with_context(|| format!("Moving {old_path:?} to {new_path:?}"))?;
+            moves.push((old_path.clone(), new_path.clone()));
+            // Remove the author directory if this was the author's last book
+            if let Some(parent) = old_path.parent() {
+                if parent != library_dir {
+                    let _ = std::fs::remove_dir(parent);
+                }
+            }
+        }
+        conn.execute(
+            "UPDATE books SET path = ?2 WHERE id = ?1",
+            params![book, new_dir],
+        )?;
+    }
+
+    let new_name = book_file_name(&title, author.as_deref());
+    let mut stmt = conn.prepare("SELECT format, name FROM data WHERE book = ?1")?;
+    let files = stmt
+        .query_map([book], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (format, name) in files {
+        if name == new_name {
+            continue;
+        }
+        let extension = format.to_lowercase();
+        let from = new_path.join(format!("{name}.{extension}"));
+        if from.is_file() {
+            let to = new_path.join(format!("{new_name}.{extension}"));
+            std::fs::rename(&from, &to).with_context(|| format!("Renaming {from:?}"))?;
+            moves.push((from, to));
+        }
+        conn.execute(
+            "UPDATE data SET name = ?3 WHERE book = ?1 AND format = ?2",
+            params![book, format, new_name],
+        )?;
+    }
+    Ok(())
+}
+
+/// Replace the cover of a book with `image`
+pub fn set_cover(conn: &Connection, library_dir: &Path, book: i64, image: &[u8]) -> Result<()> {
+    let dir: String = conn.query_row("SELECT path FROM books WHERE id = ?1", [book], |row| {
+        row.get(0)
+    })?;
+    if dir.is_empty() {
+        return Err(anyhow!("Book {book} has no directory for its cover"));
+    }
+    let dir = library_dir.join(dir);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("cover.jpg"), image)
+        .with_context(|| format!("Writing the cover of book {book}"))?;
+    conn.execute(
+        "UPDATE books SET has_cover = 1, last_modified = CURRENT_TIMESTAMP WHERE id = ?1",
+        [book],
+    )?;
+    Ok(())
+}
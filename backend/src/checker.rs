@@ -0,0 +1,621 @@
+use crate::duplicates::Fingerprint;
+use crate::editor;
+use crate::epub::{attribute, resolve_href, Epub, MIMETYPE};
+use crate::normalize::{self, toc, Changes};
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use regex::Regex;
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::{EpubCheck, EpubProblem, EpubProblemKind};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::{CompressionMethod, ZipArchive};
+
+/// `url(…)` in style sheets, the address in one of the three groups
+static CSS_URL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"url\(\s*(?:"([^"]*)"|'([^']*)'|([^)'"\s]*))\s*\)"#).expect("valid regex")
+});
+
+/// Name of a kind of problem as stored in the `epub_problems` table
+pub fn kind_name(kind: EpubProblemKind) -> &'static str {
+    match kind {
+        EpubProblemKind::Archive => "archive",
+        EpubProblemKind::Mimetype => "mimetype",
+        EpubProblemKind::Manifest => "manifest",
+        EpubProblemKind::Spine => "spine",
+        EpubProblemKind::MissingResource => "missing_resource",
+        EpubProblemKind::BrokenLink => "broken_link",
+        EpubProblemKind::MalformedXhtml => "malformed_xhtml",
+        EpubProblemKind::MissingCover => "missing_cover",
+        EpubProblemKind::MissingNav => "missing_nav",
+    }
+}
+
+/// Problems found so far, each reported once even if it occurs several times
+#[derive(Default)]
+struct Problems(Vec<EpubProblem>);
+
+impl Problems {
+    fn add(&mut self, kind: EpubProblemKind, location: &str, message: String) {
+        let problem = EpubProblem {
+            kind,
+            location: location.to_string(),
+            message,
+        };
+        if !self.0.contains(&problem) {
+            self.0.push(problem);
+        }
+    }
+}
+
+/// A reference from a document to another archive entry
+struct Reference {
+    href: String,
+    /// A link the reader follows, as opposed to a resource that is loaded with the document
+    link: bool,
+}
+
+/// Whether `href` leads outside the archive, e.g., `https://…`, `mailto:…` or `data:…`
+fn is_external(href: &str) -> bool {
+    let scheme = href.split(['/', '?', '#']).next().unwrap_or_default();
+    scheme.contains(':') || href.starts_with("//")
+}
+
+/// What `element` refers to, if anything
+fn reference(element: &BytesStart) -> Option<Reference> {
+    let (attr, link) = match element.local_name().as_ref() {
+        b"a" | b"area" => ("href", true),
+        // `image` of SVG takes `href` or `xlink:href`, which `attribute` both matches
+        b"link" | b"image" => ("href", false),
+        b"img" | b"audio" | b"video" | b"source" | b"track" | b"script" | b"embed" | b"iframe" => {
+            ("src", false)
+        }
+        b"object" => ("data", false),
+        // The `content` of NCX navigation points
+        b"content" => ("src", true),
+        _ => return None,
+    };
+    let href = attribute(element, attr)?;
+    let href = href.trim();
+    (!href.is_empty() && !is_external(href)).then(|| Reference {
+        href: href.to_string(),
+        link,
+    })
+}
+
+/// IDs and references of a document, as far as it can be read
+fn scan_document(xml: &str) -> (HashSet<String>, Vec<Reference>) {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().check_end_names = false;
+    let mut ids = HashSet::new();
+    let mut references = Vec::new();
+    while let Ok(event) = reader.read_event() {
+        match event {
+            Event::Start(e) | Event::Empty(e) => {
+                ids.extend(attribute(&e, "id"));
+                // Old XHTML anchors are named
+                if e.local_name().as_ref() == b"a" {
+                    ids.extend(attribute(&e, "name"));
+                }
+                references.extend(reference(&e));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    (ids, references)
+}
+
+/// Addresses in the `url(…)` of a style sheet that lead into the archive
+fn stylesheet_references(css: &str) -> Vec<Reference> {
+    CSS_URL
+        .captures_iter(css)
+        .filter_map(|captures| {
+            let href = (1..=3).find_map(|i| captures.get(i))?.as_str().trim();
+            (!href.is_empty() && !is_external(href)).then(|| Reference {
+                href: href.to_string(),
+                link: false,
+            })
+        })
+        .collect()
+}
+
+fn check_mimetype(archive: &mut ZipArchive<File>, problems: &mut Problems) -> Result<()> {
+    let Some(index) = archive.index_for_name("mimetype") else {
+        problems.add(
+            EpubProblemKind::Mimetype,
+            "",
+            "The archive has no mimetype entry".to_string(),
+        );
+        return Ok(());
+    };
+    if index != 0 {
+        problems.add(
+            EpubProblemKind::Mimetype,
+            "mimetype",
+            "The mimetype entry is not the first entry of the archive".to_string(),
+        );
+    }
+    let mut entry = archive.by_index(index)?;
+    if entry.compression() != CompressionMethod::Stored {
+        problems.add(
+            EpubProblemKind::Mimetype,
+            "mimetype",
+            "The mimetype entry is compressed".to_string(),
+        );
+    }
+    let mut content = Vec::new();
+    entry.read_to_end(&mut content)?;
+    if content != MIMETYPE.as_bytes() {
+        problems.add(
+            EpubProblemKind::Mimetype,
+            "mimetype",
+            format!(
+                "The mimetype entry contains {:?} instead of {MIMETYPE:?}",
+                String::from_utf8_lossy(&content)
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Check the EPUB at `path`: the ZIP structure and the `mimetype` entry, that the manifest and
+/// spine agree with each other and the archive, resources and links that lead nowhere, the
+/// well-formedness of content documents, and whether there is a cover and a table of contents.
+/// Errors only if the file can't be read at all.
+pub fn check_file(path: &Path) -> Result<Vec<EpubProblem>> {
+    let mut problems = Problems::default();
+    match ZipArchive::new(File::open(path)?) {
+        Ok(mut archive) => check_mimetype(&mut archive, &mut problems)?,
+        Err(e) => {
+            problems.add(
+                EpubProblemKind::Archive,
+                "",
+                format!("Not a valid ZIP archive: {e}"),
+            );
+            return Ok(problems.0);
+        }
+    }
+    let mut epub = match Epub::open(path) {
+        Ok(epub) => epub,
+        Err(e) => {
+            problems.add(EpubProblemKind::Archive, "", format!("{e:#}"));
+            return Ok(problems.0);
+        }
+    };
+    let opf_path = epub.opf_path.clone();
+    let names: HashSet<String> = epub.entry_names().into_iter().collect();
+
+    for item in &epub.manifest {
+        if !names.contains(&item.path) {
+            problems.add(
+                EpubProblemKind::Manifest,
+                &opf_path,
+                format!(
+                    "Item {:?} refers to the missing file {:?}",
+                    item.id, item.href
+                ),
+            );
+        }
+    }
+    if epub.spine.is_empty() {
+        problems.add(
+            EpubProblemKind::Archive,
+            &opf_path,
+            "The spine is empty, so there is nothing to read".to_string(),
+        );
+    }
+    for id in &epub.spine {
+        if epub.item(id).is_none() {
+            problems.add(
+                EpubProblemKind::Spine,
+                &opf_path,
+                format!("The spine refers to the undeclared item {id:?}"),
+            );
+        }
+    }
+    if epub.cover().is_none() {
+        problems.add(
+            EpubProblemKind::MissingCover,
+            &opf_path,
+            "No cover image is declared".to_string(),
+        );
+    }
+    let info = toc::package_info(&epub.read_opf()?)?;
+    if info.epub3 && epub.nav().is_none() {
+        problems.add(
+            EpubProblemKind::MissingNav,
+            &opf_path,
+            "No navigation document is declared".to_string(),
+        );
+    } else if !info.epub3 && epub.ncx().is_none() {
+        problems.add(
+            EpubProblemKind::MissingNav,
+            &opf_path,
+            "No NCX table of contents is declared".to_string(),
+        );
+    }
+
+    // Read everything first, so links can be checked against the IDs of every document
+    let mut ids: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut references: Vec<(String, Vec<Reference>)> = Vec::new();
+    let items: Vec<_> = epub
+        .manifest
+        .iter()
+        .filter(|item| names.contains(&item.path))
+        .cloned()
+        .collect();
+    for item in items {
+        let is_ncx = epub.ncx().is_some_and(|ncx| ncx.id == item.id);
+        if item.is_content_document() {
+            let Ok(xhtml) = String::from_utf8(epub.read(&item.path)?) else {
+                problems.add(
+                    EpubProblemKind::MalformedXhtml,
+                    &item.path,
+                    "Not UTF-8".to_string(),
+                );
+                continue;
+            };
+            if let Err(e) = editor::check_well_formed(&xhtml) {
+                problems.add(EpubProblemKind::MalformedXhtml, &item.path, e.to_string());
+            }
+            let (found, refs) = scan_document(&xhtml);
+            ids.insert(item.path.clone(), found);
+            references.push((item.path, refs));
+        } else if is_ncx {
+            let ncx = String::from_utf8_lossy(&epub.read(&item.path)?).into_owned();
+            references.push((item.path, scan_document(&ncx).1));
+        } else if item.media_type == "text/css" {
+            let css = String::from_utf8_lossy(&epub.read(&item.path)?).into_owned();
+            references.push((item.path, stylesheet_references(&css)));
+        }
+    }
+
+    for (document, refs) in references {
+        for Reference { href, link } in refs {
+            let (target, fragment) = match href.split_once('#') {
+                Some(("", fragment)) => (document.clone(), Some(fragment)),
+                Some((_, fragment)) => (resolve_href(&document, &href), Some(fragment)),
+                None => (resolve_href(&document, &href), None),
+            };
+            let target = target.split('?').next().unwrap_or_default().to_string();
+            if !link {
+                if !names.contains(&target) {
+                    problems.add(
+                        EpubProblemKind::MissingResource,
+                        &document,
+                        format!("{href:?} is not in the archive"),
+                    );
+                }
+            } else if !names.contains(&target) {
+                problems.add(
+                    EpubProblemKind::BrokenLink,
+                    &document,
+                    format!("{href:?} leads to a file that is not in the archive"),
+                );
+            } else if let (Some(fragment), Some(found)) = (fragment, ids.get(&target)) {
+                if !fragment.is_empty() && !found.contains(fragment) {
+                    problems.add(
+                        EpubProblemKind::BrokenLink,
+                        &document,
+                        format!("{href:?} leads to an anchor that does not exist"),
+                    );
+                }
+            }
+        }
+    }
+    Ok(problems.0)
+}
+
+/// Replace the stored problems of the file `data`, which has the `fingerprint` now, with
+/// `problems`
+fn store(
+    conn: &Connection,
+    data: i64,
+    fingerprint: &Fingerprint,
+    problems: &[EpubProblem],
+) -> Result<()> {
+    conn.execute("DELETE FROM epub_problems WHERE data = ?1", [data])?;
+    conn.execute(
+        "INSERT OR REPLACE INTO epub_checks (data, size, modified) VALUES (?1, ?2, ?3)",
+        params![data, fingerprint.size, fingerprint.modified],
+    )?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO epub_problems (data, kind, location, message) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for problem in problems {
+        stmt.execute(params![
+            data,
+            kind_name(problem.kind),
+            problem.location,
+            problem.message
+        ])?;
+    }
+    Ok(())
+}
+
+/// The fingerprint the file `data` had when it was checked, `None` if it wasn't checked or
+/// before fingerprints were recorded
+fn checked_fingerprint(conn: &Connection, data: i64) -> Result<Option<Fingerprint>> {
+    let recorded = conn
+        .query_row(
+            "SELECT size, modified FROM epub_checks WHERE data = ?1",
+            [data],
+            |row| Ok((row.get::<_, u64>(0)?, row.get::<_, Option<i64>>(1)?)),
+        )
+        .optional()?;
+    Ok(recorded.and_then(|(size, modified)| {
+        Some(Fingerprint {
+            size,
+            modified: modified?,
+            sha256: None,
+        })
+    }))
+}
+
+/// The stored problems of the file `data`, which has the `fingerprint` now, `None` if it wasn't
+/// checked or has changed since
+fn stored(
+    conn: &Connection,
+    data: i64,
+    fingerprint: &Fingerprint,
+) -> Result<Option<Vec<EpubProblem>>> {
+    match checked_fingerprint(conn, data)? {
+        Some(recorded) if !recorded.differs(fingerprint) => {}
+        _ => return Ok(None),
+    }
+    let mut stmt = conn.prepare(
+        "SELECT kind, location, message FROM epub_problems WHERE data = ?1 ORDER BY rowid",
+    )?;
+    let rows = stmt.query_map([data], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    let mut problems = Vec::new();
+    for row in rows {
+        let (name, location, message) = row?;
+        if let Some(kind) = EpubProblemKind::ALL
+            .into_iter()
+            .find(|k| kind_name(*k) == name)
+        {
+            problems.push(EpubProblem {
+                kind,
+                location,
+                message,
+            });
+        }
+    }
+    Ok(Some(problems))
+}
+
+/// The problems of the EPUB of `book`, `None` if it has none. Stored results are used unless
+/// the file changed since or `recheck` is set, otherwise the file is checked and the results
+/// stored.
+pub fn check(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    recheck: bool,
+) -> Result<Option<EpubCheck>> {
+    let Some((_, data, _)) = normalize::epub_file(conn, book)? else {
+        return Ok(None);
+    };
+    let path = editor::epub_path(conn, library_dir, book)?;
+    let fingerprint = Fingerprint::of(&path)?;
+    let problems = match stored(conn, data, &fingerprint)?.filter(|_| !recheck) {
+        Some(problems) => problems,
+        None => {
+            let problems = check_file(&path)?;
+            store(conn, data, &fingerprint, &problems)?;
+            problems
+        }
+    };
+    let title = conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+        row.get(0)
+    })?;
+    Ok(Some(EpubCheck {
+        book,
+        title,
+        problems,
+    }))
+}
+
+/// The changes that fix the repairable `problems` of the EPUB at `path`: manifest items whose
+/// file is missing and spine entries without item are removed, and a missing table of contents
+/// is generated. A wrong `mimetype` entry needs no change, as writing the archive anew puts it
+/// right.
+fn repairs(path: &Path, problems: &[EpubProblem]) -> Result<Changes> {
+    let mut epub = Epub::open(path)?;
+    let names: HashSet<String> = epub.entry_names().into_iter().collect();
+    let mut removed: HashSet<String> = epub
+        .manifest
+        .iter()
+        .filter(|item| !names.contains(&item.path))
+        .map(|item| item.id.clone())
+        .collect();
+    removed.extend(
+        epub.spine
+            .iter()
+            .filter(|id| epub.item(id).is_none())
+            .cloned(),
+    );
+    // The table of contents is generated from what remains
+    epub.manifest.retain(|item| !removed.contains(&item.id));
+    epub.spine.retain(|id| !removed.contains(id));
+
+    let mut changes = Changes::default();
+    let mut toc_changes = toc::TocChanges::default();
+    if problems
+        .iter()
+        .any(|p| p.kind == EpubProblemKind::MissingNav)
+    {
+        let info = toc::package_info(&epub.read_opf()?)?;
+        toc_changes = toc::regenerate(&mut epub, &BTreeMap::new(), &info)?;
+        changes.replaced.append(&mut toc_changes.replaced);
+    }
+    if !removed.is_empty() || !toc_changes.added.is_empty() || toc_changes.spine_toc.is_some() {
+        let opf = normalize::rewrite_package(
+            &epub.read_opf()?,
+            &removed,
+            &toc_changes.added,
+            toc_changes.spine_toc.as_deref(),
+        )?;
+        changes
+            .replaced
+            .insert(epub.opf_path.clone(), opf.into_bytes());
+    }
+    Ok(changes)
+}
+
+/// Fix the problems of the EPUB of `book` that can be fixed without guessing at its content,
+/// see [`EpubProblemKind::repairable`], then check it again. The EPUB as it was before is kept,
+/// see [`normalize::apply`].
+pub fn repair(conn: &Connection, library_dir: &Path, book: i64) -> Result<EpubCheck> {
+    let path = editor::epub_path(conn, library_dir, book)?;
+    let problems = check_file(&path)?;
+    if problems.iter().any(|p| p.kind.repairable()) {
+        normalize::apply(conn, library_dir, book, &repairs(&path, &problems)?)?;
+    }
+    check(conn, library_dir, book, true)?.ok_or_else(|| anyhow!("Book {book} has no EPUB file"))
+}
+
+/// IDs of the files with stored problems that haven't changed since they were checked, going by
+/// their size and modification time on disk. Files missing on disk are left out.
+pub fn current_problems(conn: &Connection, library_dir: &Path) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare(
+        "SELECT d.id, b.path, d.name FROM data d JOIN books b ON b.id = d.book
+         WHERE d.format = 'EPUB' AND d.id IN (SELECT data FROM epub_problems)",
+    )?;
+    let files = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut current = Vec::new();
+    for (data, dir, name) in files {
+        let path = library_dir.join(dir).join(format!("{name}.epub"));
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(recorded) = checked_fingerprint(conn, data)? {
+            if !recorded.differs(&Fingerprint::of(&path)?) {
+                current.push(data);
+            }
+        }
+    }
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub;
+
+    const PACKAGE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>Broken</dc:title></metadata>
+<manifest>
+<item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml"/>
+<item id="lost" href="lost.xhtml" media-type="application/xhtml+xml"/>
+</manifest>
+<spine><itemref idref="chapter"/><itemref idref="ghost"/></spine>
+</package>
+"#;
+
+    const CHAPTER: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Broken</title></head>
+<body>
+<p id="start"><img src="gone.png" alt=""/></p>
+<p><a href="chapter.xhtml#start">Up</a> <a href="#nowhere">Down</a> <a href="other.xhtml">On</a></p>
+<p>Never closed
+</body>
+</html>
+"##;
+
+    #[test]
+    fn broken_epub_has_its_problems_found() {
+        let dir = std::env::temp_dir().join(format!("checker-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("broken.epub");
+        let entries = BTreeMap::from([
+            ("OEBPS/content.opf".to_string(), PACKAGE.as_bytes().to_vec()),
+            (
+                "OEBPS/chapter.xhtml".to_string(),
+                CHAPTER.as_bytes().to_vec(),
+            ),
+        ]);
+        epub::create(&path, "OEBPS/content.opf", &entries).unwrap();
+        let not_zip = dir.join("not-zip.epub");
+        std::fs::write(&not_zip, "plain text").unwrap();
+
+        let problems = check_file(&path).unwrap();
+        let archive_problems = check_file(&not_zip).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let found = |kind: EpubProblemKind| -> Vec<(&str, &str)> {
+            problems
+                .iter()
+                .filter(|p| p.kind == kind)
+                .map(|p| (p.location.as_str(), p.message.as_str()))
+                .collect()
+        };
+        assert_eq!(found(EpubProblemKind::Mimetype), []);
+        assert_eq!(found(EpubProblemKind::Archive), []);
+        assert_eq!(
+            found(EpubProblemKind::Manifest),
+            [(
+                "OEBPS/content.opf",
+                r#"Item "lost" refers to the missing file "lost.xhtml""#
+            )]
+        );
+        assert_eq!(
+            found(EpubProblemKind::Spine),
+            [(
+                "OEBPS/content.opf",
+                r#"The spine refers to the undeclared item "ghost""#
+            )]
+        );
+        assert_eq!(found(EpubProblemKind::MissingCover).len(), 1);
+        assert_eq!(found(EpubProblemKind::MissingNav).len(), 1);
+        assert_eq!(
+            found(EpubProblemKind::MissingResource),
+            [("OEBPS/chapter.xhtml", r#""gone.png" is not in the archive"#)]
+        );
+        assert_eq!(
+            found(EpubProblemKind::BrokenLink),
+            [
+                (
+                    "OEBPS/chapter.xhtml",
+                    r##""#nowhere" leads to an anchor that does not exist"##
+                ),
+                (
+                    "OEBPS/chapter.xhtml",
+                    r#""other.xhtml" leads to a file that is not in the archive"#
+                ),
+            ]
+        );
+        assert_eq!(
+            found(EpubProblemKind::MalformedXhtml)
+                .iter()
+                .map(|(location, _)| *location)
+                .collect::<Vec<_>>(),
+            ["OEBPS/chapter.xhtml"]
+        );
+
+        assert_eq!(archive_problems.len(), 1);
+        assert_eq!(archive_problems[0].kind, EpubProblemKind::Archive);
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::{anyhow, Context};
 use directories::ProjectDirs;
 use shared::types::AppConfig;
-use std::fs::{create_dir_all, File};
+use std::fs::create_dir_all;
 use std::path::{Path, PathBuf};
 
 pub trait ConfigInterface: Sized {
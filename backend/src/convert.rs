@@ -0,0 +1,458 @@
+pub mod fb2;
+pub mod kepub;
+
+use crate::config::ConfigInterface;
+use crate::duplicates::Fingerprint;
+use crate::embed::{self, EmbeddedMetadata};
+use crate::jobs::{self, JobContext};
+use crate::{books, library};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::{AppConfig, Job, JobTask};
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::time::Duration;
+
+/// Source and target formats of the supported conversions. For a target, the first source the
+/// book has a file in is used.
+pub const CONVERSIONS: &[(&str, &str)] = &[
+    ("EPUB", "KEPUB"),
+    ("FB2", "EPUB"),
+    ("FBZ", "EPUB"),
+    ("AZW3", "EPUB"),
+    ("MOBI", "EPUB"),
+    ("AZW", "EPUB"),
+    ("KEPUB", "EPUB"),
+];
+
+/// How often the sources of converted files are checked for changes
+const SOURCE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+static SOURCE_CHECK: Once = Once::new();
+
+/// A file of a book
+struct BookFile {
+    data: i64,
+    format: String,
+    name: String,
+    path: PathBuf,
+}
+
+/// The directory of `book` and its files
+fn files(conn: &Connection, library_dir: &Path, book: i64) -> Result<(PathBuf, Vec<BookFile>)> {
+    let dir: String = conn
+        .query_row("SELECT path FROM books WHERE id = ?1", [book], |row| {
+            row.get(0)
+        })
+        .optional()?
+        .ok_or_else(|| anyhow!("There is no book with ID {book}"))?;
+    let dir = library_dir.join(dir);
+    let mut stmt = conn.prepare("SELECT id, format, name FROM data WHERE book = ?1")?;
+    let files = stmt
+        .query_map([book], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .map(|row| {
+            let (data, format, name) = row?;
+            let path = dir.join(format!("{name}.{}", format.to_lowercase()));
+            Ok(BookFile {
+                data,
+                format,
+                name,
+                path,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((dir, files))
+}
+
+fn is_converted(conn: &Connection, data: i64) -> Result<bool> {
+    Ok(conn
+        .query_row("SELECT 1 FROM conversions WHERE data = ?1", [data], |_| {
+            Ok(())
+        })
+        .optional()?
+        .is_some())
+}
+
+/// The file of `book` to convert into `target`. A file in `target` that was not made by a
+/// conversion is never replaced.
+fn source_file(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    target: &str,
+) -> Result<(PathBuf, BookFile)> {
+    let (dir, mut files) = files(conn, library_dir, book)?;
+    if let Some(existing) = files.iter().find(|f| f.format == target) {
+        if !is_converted(conn, existing.data)? {
+            return Err(anyhow!(
+                "The book already has a {target} file that was not converted from another format"
+            ));
+        }
+    }
+    let index = CONVERSIONS
+        .iter()
+        .filter(|(_, to)| *to == target)
+        .find_map(|(from, _)| files.iter().position(|f| f.format == *from))
+        .ok_or_else(|| anyhow!("The book has no file that can be converted into {target}"))?;
+    Ok((dir, files.swap_remove(index)))
+}
+
+/// Whether `book` has a file that can be converted into `target`
+pub fn can_convert(conn: &Connection, library_dir: &Path, book: i64, target: &str) -> bool {
+    source_file(conn, library_dir, book, target).is_ok()
+}
+
+/// Everything a conversion needs from the database, read before converting so that the write
+/// connection isn't held meanwhile
+struct Plan {
+    book: i64,
+    source: BookFile,
+    target: String,
+    dir: PathBuf,
+    metadata: EmbeddedMetadata,
+    cover: Option<Vec<u8>>,
+}
+
+fn plan(conn: &Connection, library_dir: &Path, book: i64, target: &str) -> Result<Plan> {
+    let (dir, source) = source_file(conn, library_dir, book, target)?;
+    let has_cover: bool =
+        conn.query_row("SELECT has_cover FROM books WHERE id = ?1", [book], |row| {
+            row.get(0)
+        })?;
+    let cover = if has_cover {
+        std::fs::read(dir.join("cover.jpg")).ok()
+    } else {
+        None
+    };
+    Ok(Plan {
+        book,
+        source,
+        target: target.to_string(),
+        dir,
+        metadata: embed::load(conn, book)?,
+        cover,
+    })
+}
+
+/// Convert with Calibre's `ebook-convert`, which picks the formats from the file extensions
+fn ebook_convert(source: &Path, target: &Path) -> Result<()> {
+    let program = AppConfig::read()?.conversion.ebook_convert;
+    let output = std::process::Command::new(&program)
+        .arg(source)
+        .arg(target)
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow!(
+                "Converting this format needs Calibre's ebook-convert, which was not found at {program:?}; set its path in the settings"
+            ),
+            _ => anyhow!("Running {program:?}: {e}"),
+        })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().rfind(|line| !line.trim().is_empty());
+        return Err(anyhow!(
+            "ebook-convert failed: {}",
+            reason.unwrap_or("no error message")
+        ));
+    }
+    Ok(())
+}
+
+fn convert_file(
+    source_format: &str,
+    target_format: &str,
+    source: &Path,
+    target: &Path,
+) -> Result<()> {
+    match (source_format, target_format) {
+        ("EPUB", "KEPUB") => kepub::to_kepub(source, target),
+        ("KEPUB", "EPUB") => kepub::to_epub(source, target),
+        ("FB2" | "FBZ", "EPUB") => fb2::to_epub(source, target),
+        ("MOBI" | "AZW" | "AZW3", "EPUB") => ebook_convert(source, target),
+        _ => Err(anyhow!(
+            "Converting {source_format} into {target_format} is not supported"
+        )),
+    }
+}
+
+/// Convert the file, embedding the library's metadata and cover into the result. Returns the
+/// size of the new file and the fingerprint of the source it was made from.
+fn run(plan: &Plan) -> Result<(u64, Fingerprint)> {
+    let fingerprint = Fingerprint::hashed(&plan.source.path)?;
+    let extension = plan.target.to_lowercase();
+    let temp = plan
+        .dir
+        .join(format!("{}.converting.{extension}", plan.source.name));
+    let target = plan.dir.join(format!("{}.{extension}", plan.source.name));
+    let converted = convert_file(&plan.source.format, &plan.target, &plan.source.path, &temp)
+        .and_then(|_| embed::embed_into_epub(&temp, &plan.metadata, plan.cover.as_deref()));
+    if let Err(e) = converted {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    std::fs::rename(&temp, &target).with_context(|| format!("Moving {temp:?} to {target:?}"))?;
+    Ok((std::fs::metadata(&target)?.len(), fingerprint))
+}
+
+/// Add the converted file to the book and record where it came from
+fn finish(conn: &Connection, plan: &Plan, size: u64, source: &Fingerprint) -> Result<()> {
+    books::add_format(conn, plan.book, &plan.target, &plan.source.name, size)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO conversions (data, source, source_size, source_modified, source_sha256, converted_at)
+         SELECT id, ?3, ?4, ?5, ?6, ?7 FROM data WHERE book = ?1 AND format = ?2",
+        params![
+            plan.book,
+            plan.target,
+            plan.source.data,
+            source.size,
+            source.modified,
+            source.sha256,
+            Utc::now(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// A converted file whose source was checked for changes
+enum SourceCheck {
+    /// The source was written but its content is the same, so only the fingerprint is updated
+    Touched { data: i64, source: Fingerprint },
+    /// The content of the source changed to `source` and `format` of `book` must be converted
+    /// again
+    Changed {
+        data: i64,
+        book: i64,
+        format: String,
+        source: Fingerprint,
+    },
+}
+
+/// Check the sources of all converted files, hashing only those whose size or modification time
+/// differ from when they were converted. Sources missing on disk are left out, as are those the
+/// conversion was queued again for already, whether that failed or is still to run.
+fn check_sources(conn: &Connection, library_dir: &Path) -> Result<Vec<SourceCheck>> {
+    let mut stmt = conn.prepare(
+        "SELECT c.data, d.book, d.format, b.path, s.name, s.format, c.source_size, c.source_modified, c.source_sha256,
+            c.requeued_size, c.requeued_modified
+         FROM conversions c
+         JOIN data d ON d.id = c.data
+         JOIN data s ON s.id = c.source
+         JOIN books b ON b.id = d.book",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                library_dir.join(row.get::<_, String>(3)?).join(format!(
+                    "{}.{}",
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?.to_lowercase()
+                )),
+                Fingerprint {
+                    size: row.get(6)?,
+                    modified: row.get(7)?,
+                    sha256: row.get(8)?,
+                },
+                row.get::<_, Option<u64>>(9)?
+                    .zip(row.get::<_, Option<i64>>(10)?)
+                    .map(|(size, modified)| Fingerprint {
+                        size,
+                        modified,
+                        sha256: None,
+                    }),
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut checks = Vec::new();
+    for (data, book, format, path, recorded, requeued) in rows {
+        if !path.is_file() {
+            continue;
+        }
+        let now = Fingerprint::of(&path)?;
+        if !recorded.differs(&now) || requeued.is_some_and(|requeued| !requeued.differs(&now)) {
+            continue;
+        }
+        let current = Fingerprint::hashed(&path)?;
+        if current.sha256 == recorded.sha256 {
+            checks.push(SourceCheck::Touched {
+                data,
+                source: current,
+            });
+        } else {
+            checks.push(SourceCheck::Changed {
+                data,
+                book,
+                format,
+                source: current,
+            });
+        }
+    }
+    Ok(checks)
+}
+
+/// Title of `book`, to describe its jobs
+fn title(conn: &Connection, book: i64) -> Result<String> {
+    Ok(
+        conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+/// Queue a job converting `book` into `target`. Returns `None` if the same conversion is queued
+/// or running already.
+pub fn enqueue(conn: &Connection, book: i64, target: &str) -> Result<Option<Job>> {
+    let title = format!("Convert {} into {target}", title(conn, book)?);
+    let task = JobTask::Convert {
+        book,
+        target: target.to_string(),
+    };
+    jobs::enqueue(conn, task, &title)
+}
+
+/// Run the conversion job of `book` into `target`
+pub(crate) fn run_job(ctx: &JobContext, book: i64, target: &str) -> Result<()> {
+    let library = ctx.library();
+    let plan = library
+        .pool()
+        .read(|conn| plan(conn, library.dir(), book, target))?;
+    ctx.log(format!(
+        "Converting {} {:?} into {target}",
+        plan.source.format, plan.source.name
+    ));
+    ctx.progress(1, 4);
+    ctx.check_cancelled()?;
+    let (size, fingerprint) = run(&plan)?;
+    ctx.progress(3, 4);
+    ctx.check_cancelled()?;
+    library
+        .pool()
+        .write(|conn| finish(conn, &plan, size, &fingerprint))?;
+    ctx.log(format!("Added {target} with {size} bytes"));
+    Ok(())
+}
+
+/// Queue conversions again whose source changed in the active library
+fn queue_changed() -> Result<()> {
+    let Some(library) = library::active() else {
+        return Ok(());
+    };
+    let checks = library
+        .pool()
+        .read(|conn| check_sources(conn, library.dir()))?;
+    for check in checks {
+        library.pool().write(|conn| {
+            match check {
+                SourceCheck::Touched { data, source } => {
+                    conn.execute(
+                        "UPDATE conversions SET source_size = ?2, source_modified = ?3 WHERE data = ?1",
+                        params![data, source.size, source.modified],
+                    )?;
+                }
+                SourceCheck::Changed {
+                    data,
+                    book,
+                    format,
+                    source,
+                } => {
+                    tracing::info!("The source of {format} of book {book} changed");
+                    enqueue(conn, book, &format)?;
+                    conn.execute(
+                        "UPDATE conversions SET requeued_size = ?2, requeued_modified = ?3 WHERE data = ?1",
+                        params![data, source.size, source.modified],
+                    )?;
+                }
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Start the background task that regularly queues conversions again whose source changed.
+/// Must be called from within the tokio runtime; calling it more than once has no effect.
+pub fn start_source_check() {
+    SOURCE_CHECK.call_once(|| {
+        tokio::spawn(async {
+            loop {
+                match tokio::task::spawn_blocking(queue_changed).await {
+                    Ok(Err(e)) => {
+                        tracing::error!("Checking the sources of conversions failed: {e:#}")
+                    }
+                    Err(e) => tracing::error!("Checking the sources of conversions panicked: {e}"),
+                    Ok(Ok(())) => {}
+                }
+                tokio::time::sleep(SOURCE_CHECK_INTERVAL).await;
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::books::NewBook;
+    use crate::migrations;
+
+    /// A library in a new temporary directory with one book, whose EPUB was converted into
+    /// KEPUB when it had a different size, and the path of the EPUB
+    fn converted_library(name: &str) -> (Connection, PathBuf) {
+        let library_dir =
+            std::env::temp_dir().join(format!("convert-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&library_dir);
+        std::fs::create_dir_all(library_dir.join("book")).unwrap();
+        let source = library_dir.join("book/book.epub");
+        std::fs::write(&source, "changed content").unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        let book = books::insert_book(
+            &conn,
+            &NewBook {
+                title: "Book".to_string(),
+                path: "book".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        books::add_format(&conn, book, "EPUB", "book", 15).unwrap();
+        books::add_format(&conn, book, "KEPUB", "book", 20).unwrap();
+        conn.execute(
+            "INSERT INTO conversions (data, source, source_size, source_modified, source_sha256, converted_at)
+             SELECT k.id, e.id, 3, 0, 'old', 'then' FROM data k, data e
+             WHERE k.format = 'KEPUB' AND e.format = 'EPUB'",
+            [],
+        )
+        .unwrap();
+        (conn, library_dir)
+    }
+
+    #[test]
+    fn changed_source_is_queued_again_once() {
+        let (conn, library_dir) = converted_library("requeue");
+        let checks = check_sources(&conn, &library_dir).unwrap();
+        let [SourceCheck::Changed { data, source, .. }] = checks.as_slice() else {
+            panic!("the changed source was not noticed");
+        };
+        // As if queuing it again recorded it, and the conversion then failed
+        conn.execute(
+            "UPDATE conversions SET requeued_size = ?2, requeued_modified = ?3 WHERE data = ?1",
+            params![data, source.size, source.modified],
+        )
+        .unwrap();
+        assert!(check_sources(&conn, &library_dir).unwrap().is_empty());
+
+        std::fs::write(library_dir.join("book/book.epub"), "changed once more").unwrap();
+        assert_eq!(check_sources(&conn, &library_dir).unwrap().len(), 1);
+        std::fs::remove_dir_all(&library_dir).unwrap();
+    }
+}
@@ -0,0 +1,662 @@
+use crate::epub::{self, Epub};
+use crate::formats::{self, fb2::Fb2Reader, FormatReader};
+use crate::normalize::{self, toc, xhtml::resolve_reference};
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+const OPF_PATH: &str = "OEBPS/content.opf";
+
+const STYLESHEET: &str = "p { margin: 0; text-indent: 1.5em; }
+h1, h2, h3, h4, h5, h6 { text-align: center; margin: 1em 0; }
+.book-title { text-align: center; font-size: 1.6em; margin: 2em 0; }
+.book-title p, .title p, .subtitle, .text-author, .verse, .empty-line { text-indent: 0; }
+.subtitle { text-align: center; font-weight: bold; margin: 1em 0; }
+.epigraph { margin: 1em 0 1em 30%; font-style: italic; }
+.cite { margin: 1em 2em; }
+.text-author { text-align: right; font-style: italic; }
+.poem { margin: 1em 2em; }
+.stanza { margin: 1em 0; }
+.image { text-align: center; margin: 1em 0; }
+.image img { max-width: 100%; }
+a.note { vertical-align: super; font-size: 0.75em; }
+";
+
+/// A node of the FB2 document
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+struct Element {
+    /// Local name, e.g., `section`
+    name: String,
+    /// Local names and unescaped values, so `l:href` is `href`
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    fn new(start: &BytesStart) -> Self {
+        let attributes = start
+            .attributes()
+            .flatten()
+            .map(|a| {
+                let value = String::from_utf8_lossy(&a.value).into_owned();
+                let value = quick_xml::escape::unescape(&value)
+                    .map(|v| v.into_owned())
+                    .unwrap_or(value);
+                (
+                    String::from_utf8_lossy(a.key.local_name().as_ref()).into_owned(),
+                    value,
+                )
+            })
+            .collect();
+        Self {
+            name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+            attributes,
+            children: Vec::new(),
+        }
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    /// The text of the element, whitespace collapsed
+    fn text(&self) -> String {
+        fn collect(element: &Element, text: &mut String) {
+            for node in &element.children {
+                match node {
+                    Node::Element(child) => {
+                        collect(child, text);
+                        text.push(' ');
+                    }
+                    Node::Text(t) => text.push_str(t),
+                }
+            }
+        }
+        let mut text = String::new();
+        collect(self, &mut text);
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    fn push_text(&mut self, text: &str) {
+        match self.children.last_mut() {
+            Some(Node::Text(last)) => last.push_str(text),
+            _ => self.children.push(Node::Text(text.to_string())),
+        }
+    }
+}
+
+/// The root element of the FB2 document
+fn parse(document: &str) -> Result<Element> {
+    let mut reader = Reader::from_str(document);
+    reader.config_mut().check_end_names = false;
+    let mut stack = vec![Element {
+        name: String::new(),
+        attributes: Vec::new(),
+        children: Vec::new(),
+    }];
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => stack.push(Element::new(&e)),
+            Event::Empty(e) => {
+                let element = Element::new(&e);
+                if let Some(parent) = stack.last_mut() {
+                    parent.children.push(Node::Element(element));
+                }
+            }
+            Event::End(_) if stack.len() > 1 => {
+                let element = stack.pop().expect("more than the document on the stack");
+                if let Some(parent) = stack.last_mut() {
+                    parent.children.push(Node::Element(element));
+                }
+            }
+            Event::Text(e) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.push_text(&e.decode()?);
+                }
+            }
+            Event::GeneralRef(e) => {
+                let text = match resolve_reference(&e)? {
+                    Some(resolved) => resolved,
+                    None => format!("&{};", e.decode()?),
+                };
+                if let Some(parent) = stack.last_mut() {
+                    parent.push_text(&text);
+                }
+            }
+            Event::CData(e) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.push_text(&String::from_utf8_lossy(&e));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    // Elements left open at the end are closed
+    while stack.len() > 1 {
+        let element = stack.pop().expect("more than the document on the stack");
+        if let Some(parent) = stack.last_mut() {
+            parent.children.push(Node::Element(element));
+        }
+    }
+    let document = stack.pop().expect("the document on the stack");
+    document
+        .children
+        .into_iter()
+        .find_map(|node| match node {
+            Node::Element(root) if root.name == "FictionBook" => Some(root),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No FictionBook element"))
+}
+
+/// A file name for the binary `id`, with an extension for `content_type` if it lacks one
+fn image_file_name(id: &str, content_type: &str, taken: &HashSet<String>) -> String {
+    let mut name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !name.contains('.') {
+        let extension = match content_type {
+            "image/png" => "png",
+            "image/gif" => "gif",
+            "image/svg+xml" => "svg",
+            _ => "jpg",
+        };
+        name = format!("{name}.{extension}");
+    }
+    let mut unique = name.clone();
+    let mut counter = 1;
+    while taken.contains(&unique) {
+        counter += 1;
+        unique = format!("{counter}-{name}");
+    }
+    unique
+}
+
+/// Record the file each ID under `element` ends up in
+fn collect_ids(element: &Element, file: &str, files: &mut HashMap<String, String>) {
+    if let Some(id) = element.attribute("id") {
+        files.insert(id.to_string(), file.to_string());
+    }
+    for child in element.elements() {
+        collect_ids(child, file, files);
+    }
+}
+
+/// Writes FB2 markup as XHTML
+struct Renderer<'a> {
+    /// File name of each image in `Images/`, by binary ID
+    images: &'a HashMap<String, String>,
+    /// Content document each ID is in
+    files: &'a HashMap<String, String>,
+    /// The content document being written
+    file: &'a str,
+    /// Whether section titles become headings, which the table of contents is made of
+    headings: bool,
+    heading_count: usize,
+    /// Depth of inline content, where images are not put into blocks of their own
+    inline: usize,
+    out: String,
+}
+
+impl Renderer<'_> {
+    fn children(&mut self, element: &Element, depth: usize) {
+        for node in &element.children {
+            match node {
+                Node::Text(text) => self.out.push_str(&escape(text.as_str())),
+                Node::Element(child) => self.element(child, depth),
+            }
+        }
+    }
+
+    fn open(&mut self, tag: &str, class: Option<&str>, element: &Element) {
+        self.out.push('<');
+        self.out.push_str(tag);
+        if let Some(class) = class {
+            self.out.push_str(&format!(r#" class="{class}""#));
+        }
+        if let Some(id) = element.attribute("id") {
+            self.out.push_str(&format!(r#" id="{}""#, escape(id)));
+        }
+        for name in ["colspan", "rowspan"] {
+            if let Some(value) = element.attribute(name) {
+                self.out
+                    .push_str(&format!(r#" {name}="{}""#, escape(value)));
+            }
+        }
+        self.out.push('>');
+    }
+
+    fn wrap(&mut self, tag: &str, class: Option<&str>, element: &Element, depth: usize) {
+        let inline = !matches!(tag, "div" | "blockquote" | "table" | "tr");
+        self.open(tag, class, element);
+        self.inline += usize::from(inline);
+        self.children(element, depth);
+        self.inline -= usize::from(inline);
+        self.out.push_str(&format!("</{tag}>"));
+    }
+
+    fn element(&mut self, element: &Element, depth: usize) {
+        match element.name.as_str() {
+            "section" => self.section(element, depth + 1),
+            "title" => self.wrap("div", Some("title"), element, depth),
+            "p" => self.wrap("p", None, element, depth),
+            "subtitle" | "text-author" => self.wrap("p", Some(&element.name), element, depth),
+            "v" => self.wrap("p", Some("verse"), element, depth),
+            "epigraph" | "cite" => self.wrap("blockquote", Some(&element.name), element, depth),
+            "annotation" | "poem" | "stanza" => {
+                self.wrap("div", Some(&element.name), element, depth)
+            }
+            "empty-line" => self.out.push_str(r#"<p class="empty-line">&#160;</p>"#),
+            "image" => self.image(element),
+            "a" => self.link(element, depth),
+            "emphasis" => self.wrap("em", None, element, depth),
+            "strong" => self.wrap("strong", None, element, depth),
+            "strikethrough" => self.wrap("del", None, element, depth),
+            "sub" | "sup" | "code" | "table" | "tr" | "th" | "td" => {
+                self.wrap(&element.name, None, element, depth)
+            }
+            "style" => self.wrap("span", None, element, depth),
+            _ => self.children(element, depth),
+        }
+    }
+
+    /// A section at `depth`, 1 for the top level, whose title becomes a heading of that level
+    fn section(&mut self, section: &Element, depth: usize) {
+        self.open("div", Some("section"), section);
+        for node in &section.children {
+            match node {
+                Node::Element(title) if title.name == "title" && self.headings => {
+                    self.heading(title, depth)
+                }
+                Node::Element(child) => self.element(child, depth),
+                Node::Text(text) => self.out.push_str(&escape(text.as_str())),
+            }
+        }
+        self.out.push_str("</div>");
+    }
+
+    fn heading(&mut self, title: &Element, depth: usize) {
+        let level = depth.clamp(1, 6);
+        self.heading_count += 1;
+        self.out.push_str(&format!(
+            r#"<h{level} id="heading-{}" class="title">"#,
+            self.heading_count
+        ));
+        self.inline += 1;
+        for (index, line) in title.elements().filter(|e| e.name == "p").enumerate() {
+            if index > 0 {
+                self.out.push_str("<br/>");
+            }
+            self.children(line, depth);
+        }
+        self.inline -= 1;
+        self.out.push_str(&format!("</h{level}>"));
+    }
+
+    fn image(&mut self, image: &Element) {
+        let Some(file) = image
+            .attribute("href")
+            .and_then(|href| self.images.get(href.trim_start_matches('#')))
+        else {
+            return;
+        };
+        let img = format!(
+            r#"<img src="../Images/{}" alt="{}"/>"#,
+            escape(file.as_str()),
+            escape(image.attribute("alt").unwrap_or_default())
+        );
+        if self.inline > 0 {
+            self.out.push_str(&img);
+        } else {
+            self.open("div", Some("image"), image);
+            self.out.push_str(&img);
+            self.out.push_str("</div>");
+        }
+    }
+
+    fn link(&mut self, link: &Element, depth: usize) {
+        let href = link.attribute("href").unwrap_or_default();
+        let href = match href.strip_prefix('#') {
+            Some(id) => match self.files.get(id) {
+                Some(file) if file != self.file => format!("{file}#{id}"),
+                _ => format!("#{id}"),
+            },
+            None => href.to_string(),
+        };
+        self.out
+            .push_str(&format!(r#"<a href="{}""#, escape(href.as_str())));
+        if link.attribute("type") == Some("note") {
+            self.out.push_str(r#" class="note""#);
+        }
+        self.out.push('>');
+        self.inline += 1;
+        self.children(link, depth);
+        self.inline -= 1;
+        self.out.push_str("</a>");
+    }
+}
+
+fn xhtml_document(title: &str, language: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="{language}" lang="{language}">
+<head>
+  <title>{title}</title>
+  <link rel="stylesheet" type="text/css" href="../Styles/fb2.css"/>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        title = escape(title),
+        language = escape(language)
+    )
+}
+
+/// What goes into one content document
+enum Part<'a> {
+    /// Title, epigraphs and images of the main body before its first section
+    Front(Vec<&'a Node>),
+    Section(&'a Element),
+    /// A body besides the main one, usually notes
+    Notes(&'a Element),
+}
+
+/// Convert the FictionBook at `source`, plain (FB2) or zipped (FBZ), into an EPUB 3 at
+/// `target`. Every top-level section of the main body becomes a content document, and the
+/// table of contents is made from the section titles.
+pub fn to_epub(source: &Path, target: &Path) -> Result<()> {
+    let document = formats::fb2::load(source)?;
+    let metadata = Fb2Reader.read(source)?.metadata;
+    let root = parse(&document)?;
+    let title_info = root
+        .child("description")
+        .and_then(|description| description.child("title-info"));
+    let language = title_info
+        .and_then(|info| info.child("lang"))
+        .map(Element::text)
+        .filter(|lang| !lang.is_empty())
+        .unwrap_or_else(|| "und".to_string());
+    let uid = root
+        .child("description")
+        .and_then(|description| description.child("document-info"))
+        .and_then(|info| info.child("id"))
+        .map(Element::text)
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| format!("urn:sha256:{:x}", Sha256::digest(document.as_bytes())));
+    let title = metadata
+        .title
+        .clone()
+        .unwrap_or_else(|| "Untitled".to_string());
+
+    let mut entries = BTreeMap::new();
+    let mut manifest = String::new();
+    let mut spine = String::new();
+
+    let mut images = BTreeMap::new();
+    let mut taken = HashSet::new();
+    for binary in root.elements().filter(|e| e.name == "binary") {
+        let (Some(id), Some(content_type)) =
+            (binary.attribute("id"), binary.attribute("content-type"))
+        else {
+            continue;
+        };
+        if !content_type.starts_with("image/") {
+            continue;
+        }
+        let data: String = binary
+            .text()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let Ok(data) = base64::engine::general_purpose::STANDARD.decode(data) else {
+            tracing::warn!("Leaving out the undecodable image {id:?} of {source:?}");
+            continue;
+        };
+        let file = image_file_name(id, content_type, &taken);
+        taken.insert(file.clone());
+        images.insert(id.to_string(), (file, content_type.to_string(), data));
+    }
+    let cover_id = title_info
+        .and_then(|info| info.child("coverpage"))
+        .and_then(|coverpage| coverpage.child("image"))
+        .and_then(|image| image.attribute("href"))
+        .map(|href| href.trim_start_matches('#').to_string())
+        .filter(|id| images.contains_key(id));
+    for (index, (id, (file, content_type, data))) in images.iter().enumerate() {
+        let properties = if Some(id) == cover_id.as_ref() {
+            r#" properties="cover-image""#
+        } else {
+            ""
+        };
+        manifest.push_str(&format!(
+            r#"
+    <item id="image-{}" href="Images/{}" media-type="{}"{properties}/>"#,
+            index + 1,
+            escape(file.as_str()),
+            escape(content_type.as_str())
+        ));
+        entries.insert(format!("OEBPS/Images/{file}"), data.clone());
+    }
+    let image_files: HashMap<String, String> = images
+        .iter()
+        .map(|(id, (file, _, _))| (id.clone(), file.clone()))
+        .collect();
+
+    let bodies: Vec<&Element> = root.elements().filter(|e| e.name == "body").collect();
+    let main = bodies
+        .iter()
+        .position(|body| body.attribute("name").is_none())
+        .unwrap_or(0);
+    let mut parts: Vec<(String, Part)> = Vec::new();
+    if let Some(body) = bodies.get(main) {
+        let front: Vec<&Node> = body
+            .children
+            .iter()
+            .filter(|node| match node {
+                Node::Element(e) => e.name != "section",
+                Node::Text(_) => false,
+            })
+            .collect();
+        if !front.is_empty() {
+            parts.push(("front.xhtml".to_string(), Part::Front(front)));
+        }
+        for section in body.elements().filter(|e| e.name == "section") {
+            parts.push((
+                format!("chapter-{}.xhtml", parts.len() + 1),
+                Part::Section(section),
+            ));
+        }
+    }
+    for (index, body) in bodies.iter().enumerate() {
+        if index != main {
+            parts.push((format!("notes-{}.xhtml", index + 1), Part::Notes(body)));
+        }
+    }
+    if parts.is_empty() {
+        return Err(anyhow!("{source:?} has no content"));
+    }
+
+    let mut files = HashMap::new();
+    for (file, part) in &parts {
+        match part {
+            Part::Front(nodes) => {
+                for node in nodes {
+                    if let Node::Element(element) = node {
+                        collect_ids(element, file, &mut files);
+                    }
+                }
+            }
+            Part::Section(element) | Part::Notes(element) => collect_ids(element, file, &mut files),
+        }
+    }
+
+    if let Some(cover) = cover_id.as_ref().and_then(|id| image_files.get(id)) {
+        let body = format!(
+            r#"<div class="image"><img src="../Images/{}" alt="{}"/></div>"#,
+            escape(cover.as_str()),
+            escape(title.as_str())
+        );
+        entries.insert(
+            "OEBPS/Text/cover.xhtml".to_string(),
+            xhtml_document(&title, &language, &body).into_bytes(),
+        );
+        manifest.push_str(
+            r#"
+    <item id="cover" href="Text/cover.xhtml" media-type="application/xhtml+xml"/>"#,
+        );
+        spine.push_str(
+            r#"
+    <itemref idref="cover"/>"#,
+        );
+    }
+    for (index, (file, part)) in parts.iter().enumerate() {
+        let mut renderer = Renderer {
+            images: &image_files,
+            files: &files,
+            file,
+            headings: !matches!(part, Part::Notes(_)),
+            heading_count: 0,
+            inline: 0,
+            out: String::new(),
+        };
+        let document_title = match part {
+            Part::Front(nodes) => {
+                for node in nodes {
+                    match node {
+                        Node::Element(e) if e.name == "title" => {
+                            renderer.wrap("div", Some("book-title"), e, 0)
+                        }
+                        Node::Element(e) => renderer.element(e, 0),
+                        Node::Text(_) => {}
+                    }
+                    renderer.out.push('\n');
+                }
+                title.clone()
+            }
+            Part::Section(section) => {
+                renderer.section(section, 1);
+                section
+                    .child("title")
+                    .map(Element::text)
+                    .filter(|t| !t.is_empty())
+                    .unwrap_or_else(|| title.clone())
+            }
+            Part::Notes(body) => {
+                let heading = body
+                    .child("title")
+                    .map(Element::text)
+                    .filter(|t| !t.is_empty())
+                    .unwrap_or_else(|| "Notes".to_string());
+                renderer.out.push_str(&format!(
+                    r#"<h1 id="heading-notes">{}</h1>"#,
+                    escape(heading.as_str())
+                ));
+                for child in body.elements().filter(|e| e.name != "title") {
+                    renderer.element(child, 1);
+                }
+                heading
+            }
+        };
+        entries.insert(
+            format!("OEBPS/Text/{file}"),
+            xhtml_document(&document_title, &language, &renderer.out).into_bytes(),
+        );
+        manifest.push_str(&format!(
+            r#"
+    <item id="text-{}" href="Text/{file}" media-type="application/xhtml+xml"/>"#,
+            index + 1
+        ));
+        spine.push_str(&format!(
+            r#"
+    <itemref idref="text-{}"/>"#,
+            index + 1
+        ));
+    }
+    entries.insert(
+        "OEBPS/Styles/fb2.css".to_string(),
+        STYLESHEET.as_bytes().to_vec(),
+    );
+    manifest.push_str(
+        r#"
+    <item id="style" href="Styles/fb2.css" media-type="text/css"/>"#,
+    );
+
+    let creators: String = metadata
+        .authors
+        .iter()
+        .map(|author| format!("\n    <dc:creator>{}</dc:creator>", escape(author.as_str())))
+        .collect();
+    let modified = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ");
+    let opf = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">{uid}</dc:identifier>
+    <dc:title>{title}</dc:title>{creators}
+    <dc:language>{language}</dc:language>
+    <meta property="dcterms:modified">{modified}</meta>
+  </metadata>
+  <manifest>{manifest}
+  </manifest>
+  <spine>{spine}
+  </spine>
+</package>
+"#,
+        uid = escape(uid.as_str()),
+        title = escape(title.as_str()),
+        language = escape(language.as_str()),
+    );
+    entries.insert(OPF_PATH.to_string(), opf.into_bytes());
+    epub::create(target, OPF_PATH, &entries)?;
+
+    // The table of contents is made from the headings, as it is when regenerated
+    let mut epub = Epub::open(target)?;
+    let opf = epub.read_opf()?;
+    let info = toc::package_info(&opf)?;
+    let mut toc_changes = toc::regenerate(&mut epub, &BTreeMap::new(), &info)?;
+    let opf = normalize::rewrite_package(
+        &opf,
+        &HashSet::new(),
+        &toc_changes.added,
+        toc_changes.spine_toc.as_deref(),
+    )?;
+    toc_changes
+        .replaced
+        .insert(OPF_PATH.to_string(), opf.into_bytes());
+    epub::replace_entries(target, &toc_changes.replaced)?;
+    Ok(())
+}
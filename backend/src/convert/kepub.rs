@@ -0,0 +1,367 @@
+use crate::epub::{self, attribute, Epub, BLOCK_ELEMENTS};
+use crate::normalize::xhtml::resolve_reference;
+use anyhow::{Context, Result};
+use quick_xml::escape::partial_escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+const KOBO_SPAN_CLASS: &str = "koboSpan";
+
+/// IDs of the two `div`s that Kobo readers expect around the content of the body
+const WRAPPER_IDS: &[&str] = &["book-columns", "book-inner"];
+
+/// Class of the style element with fixes for Kobo readers
+const STYLE_CLASS: &str = "kobostylehacks";
+
+const KOBO_STYLE: &str = r#"<style type="text/css" class="kobostylehacks">div#book-inner { margin-top: 0; margin-bottom: 0; }</style>"#;
+
+/// Elements whose text is not split into spans
+const SKIPPED_ELEMENTS: &[&[u8]] = &[b"head", b"script", b"style", b"pre", b"svg", b"math"];
+
+/// Characters that end a sentence, and those that still belong to it after them, e.g., closing
+/// quotes
+const SENTENCE_ENDS: &[char] = &['.', '!', '?', '…'];
+const SENTENCE_CLOSERS: &[char] = &['.', '!', '?', '…', '"', '\'', '”', '’', '»', ')', ']'];
+
+/// `text` split after the ends of sentences, each part keeping the whitespace that follows it.
+/// A period not followed by whitespace, as in `3.14`, doesn't end a sentence.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if !SENTENCE_ENDS.contains(&c) {
+            continue;
+        }
+        while chars
+            .next_if(|(_, next)| SENTENCE_CLOSERS.contains(next))
+            .is_some()
+        {}
+        let mut end = None;
+        while let Some((i, space)) = chars.next_if(|(_, next)| next.is_whitespace()) {
+            end = Some(i + space.len_utf8());
+        }
+        if let Some(end) = end {
+            parts.push(&text[start..end]);
+            start = end;
+        }
+    }
+    if start < text.len() {
+        parts.push(&text[start..]);
+    }
+    parts
+}
+
+/// Adds the Kobo markup to a content document
+struct Spanner {
+    writer: Writer<Vec<u8>>,
+    /// Text read since the last markup, as written and with references resolved
+    raw: String,
+    text: String,
+    in_body: bool,
+    skipped: usize,
+    paragraph: usize,
+    segment: usize,
+}
+
+impl Spanner {
+    fn open_span(&mut self) {
+        if self.paragraph == 0 {
+            self.paragraph = 1;
+        }
+        self.segment += 1;
+        let span = format!(
+            r#"<span class="{KOBO_SPAN_CLASS}" id="kobo.{}.{}">"#,
+            self.paragraph, self.segment
+        );
+        self.writer.get_mut().extend_from_slice(span.as_bytes());
+    }
+
+    fn close_span(&mut self) {
+        self.writer.get_mut().extend_from_slice(b"</span>");
+    }
+
+    /// Write the text read so far, each sentence in a span unless it is only whitespace
+    fn flush(&mut self) {
+        let raw = std::mem::take(&mut self.raw);
+        let text = std::mem::take(&mut self.text);
+        if !self.in_body || self.skipped > 0 || text.trim().is_empty() {
+            self.writer.get_mut().extend_from_slice(raw.as_bytes());
+            return;
+        }
+        let content = text.trim_start();
+        let leading = &text[..text.len() - content.len()];
+        self.writer
+            .get_mut()
+            .extend_from_slice(partial_escape(leading).as_bytes());
+        for sentence in sentences(content) {
+            self.open_span();
+            self.writer
+                .get_mut()
+                .extend_from_slice(partial_escape(sentence).as_bytes());
+            self.close_span();
+        }
+    }
+
+    fn start(&mut self, element: &BytesStart) {
+        let name = element.local_name();
+        if SKIPPED_ELEMENTS.contains(&name.as_ref()) {
+            self.skipped += 1;
+        }
+        if BLOCK_ELEMENTS.contains(&name.as_ref()) {
+            self.paragraph += 1;
+            self.segment = 0;
+        }
+    }
+}
+
+/// The content document with its sentences and images wrapped in Kobo spans and the content of
+/// the body in the `div`s Kobo readers expect, `None` if it has Kobo markup already
+fn add_kobo_markup(xhtml: &str) -> Result<Option<String>> {
+    if xhtml.contains(KOBO_SPAN_CLASS) {
+        return Ok(None);
+    }
+    let mut reader = Reader::from_str(xhtml);
+    reader.config_mut().check_end_names = false;
+    let mut s = Spanner {
+        writer: Writer::new(Vec::with_capacity(xhtml.len() * 2)),
+        raw: String::new(),
+        text: String::new(),
+        in_body: false,
+        skipped: 0,
+        paragraph: 0,
+        segment: 0,
+    };
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Text(e) => {
+                let text = e.decode()?;
+                s.raw.push_str(&text);
+                s.text.push_str(&text);
+                continue;
+            }
+            Event::GeneralRef(e) => {
+                let name = e.decode()?;
+                match resolve_reference(e)? {
+                    Some(resolved) => {
+                        s.raw.push_str(&format!("&{name};"));
+                        s.text.push_str(&resolved);
+                    }
+                    // Entities of a DTD are written as they are
+                    None => {
+                        s.flush();
+                        s.writer
+                            .get_mut()
+                            .extend_from_slice(format!("&{name};").as_bytes());
+                    }
+                }
+                continue;
+            }
+            _ => s.flush(),
+        }
+        match event {
+            Event::Start(e) if e.local_name().as_ref() == b"body" => {
+                s.writer.write_event(Event::Start(e))?;
+                s.in_body = true;
+                let wrappers: String = WRAPPER_IDS
+                    .iter()
+                    .map(|id| format!(r#"<div id="{id}">"#))
+                    .collect();
+                s.writer.get_mut().extend_from_slice(wrappers.as_bytes());
+            }
+            Event::End(e) if e.local_name().as_ref() == b"body" => {
+                s.writer
+                    .get_mut()
+                    .extend_from_slice("</div>".repeat(WRAPPER_IDS.len()).as_bytes());
+                s.writer.write_event(Event::End(e))?;
+                s.in_body = false;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"head" => {
+                s.skipped = s.skipped.saturating_sub(1);
+                s.writer.get_mut().extend_from_slice(KOBO_STYLE.as_bytes());
+                s.writer.write_event(Event::End(e))?;
+            }
+            Event::Start(e) => {
+                s.start(&e);
+                s.writer.write_event(Event::Start(e))?;
+            }
+            Event::End(e) => {
+                if SKIPPED_ELEMENTS.contains(&e.local_name().as_ref()) {
+                    s.skipped = s.skipped.saturating_sub(1);
+                }
+                s.writer.write_event(Event::End(e))?;
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"img" && s.in_body && s.skipped == 0 => {
+                s.open_span();
+                s.writer.write_event(Event::Empty(e))?;
+                s.close_span();
+            }
+            Event::Empty(e) => {
+                if BLOCK_ELEMENTS.contains(&e.local_name().as_ref()) {
+                    s.paragraph += 1;
+                    s.segment = 0;
+                }
+                s.writer.write_event(Event::Empty(e))?;
+            }
+            Event::Eof => break,
+            event => s.writer.write_event(event)?,
+        }
+    }
+    Ok(Some(String::from_utf8(s.writer.into_inner())?))
+}
+
+/// Whether `element` is Kobo markup that [`add_kobo_markup`] added
+fn is_kobo_markup(element: &BytesStart) -> bool {
+    match element.local_name().as_ref() {
+        b"span" => attribute(element, "class").is_some_and(|c| c == KOBO_SPAN_CLASS),
+        b"div" => attribute(element, "id").is_some_and(|id| WRAPPER_IDS.contains(&id.as_str())),
+        _ => false,
+    }
+}
+
+/// The content document without Kobo markup, `None` if it has none
+fn remove_kobo_markup(xhtml: &str) -> Result<Option<String>> {
+    if !xhtml.contains(KOBO_SPAN_CLASS) && !xhtml.contains(STYLE_CLASS) {
+        return Ok(None);
+    }
+    let mut reader = Reader::from_str(xhtml);
+    let mut writer = Writer::new(Vec::with_capacity(xhtml.len()));
+    // Whether each open element is Kobo markup and left out
+    let mut open: Vec<bool> = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e)
+                if e.local_name().as_ref() == b"style"
+                    && attribute(&e, "class").is_some_and(|c| c == STYLE_CLASS) =>
+            {
+                reader.read_to_end(e.name())?;
+            }
+            Event::Start(e) => {
+                let kobo = is_kobo_markup(&e);
+                open.push(kobo);
+                if !kobo {
+                    writer.write_event(Event::Start(e))?;
+                }
+            }
+            Event::End(e) => {
+                if !open.pop().unwrap_or(false) {
+                    writer.write_event(Event::End(e))?;
+                }
+            }
+            Event::Empty(e) if is_kobo_markup(&e) => {}
+            Event::Eof => break,
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(Some(String::from_utf8(writer.into_inner())?))
+}
+
+/// Copy the EPUB at `source` to `target` with `convert` applied to its content documents
+fn convert_documents(
+    source: &Path,
+    target: &Path,
+    convert: fn(&str) -> Result<Option<String>>,
+) -> Result<()> {
+    std::fs::copy(source, target).with_context(|| format!("Copying {source:?}"))?;
+    let mut epub = Epub::open(target)?;
+    let documents: Vec<String> = epub
+        .manifest
+        .iter()
+        .filter(|item| item.is_content_document())
+        .map(|item| item.path.clone())
+        .collect();
+    let mut replaced = BTreeMap::new();
+    for path in documents {
+        let xhtml = String::from_utf8_lossy(&epub.read(&path)?).into_owned();
+        if let Some(converted) = convert(&xhtml).with_context(|| format!("Converting {path}"))? {
+            replaced.insert(path, converted.into_bytes());
+        }
+    }
+    epub::rewrite_entries(target, &replaced, &BTreeSet::new())?;
+    Ok(())
+}
+
+/// Convert the EPUB at `source` into a KEPUB for Kobo readers at `target`. Sentences and
+/// images are wrapped in `koboSpan`s, which Kobo readers use for reading position, highlights
+/// and statistics.
+pub fn to_kepub(source: &Path, target: &Path) -> Result<()> {
+    convert_documents(source, target, add_kobo_markup)
+}
+
+/// Convert the KEPUB at `source` back into a plain EPUB at `target`
+pub fn to_epub(source: &Path, target: &Path) -> Result<()> {
+    convert_documents(source, target, remove_kobo_markup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>One</title><style>p { margin: 0; }</style></head>
+<body>
+<h1>Chapter One</h1>
+<p>It was 3.14 o&apos;clock. &#8220;Really?&#8221; she asked.  Nobody knew 1 &lt; 2.</p>
+<p><img src="map.png" alt="Map"/></p>
+<pre>Left. As it is.</pre>
+</body>
+</html>
+"#;
+
+    const PACKAGE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>One</dc:title></metadata>
+<manifest>
+<item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml"/>
+<item id="map" href="map.png" media-type="image/png"/>
+</manifest>
+<spine><itemref idref="chapter"/></spine>
+</package>
+"#;
+
+    #[test]
+    fn kepub_converts_back_into_the_original_epub() {
+        let dir = std::env::temp_dir().join(format!("kepub-round-trip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let entries = BTreeMap::from([
+            ("OEBPS/content.opf".to_string(), PACKAGE.as_bytes().to_vec()),
+            (
+                "OEBPS/chapter.xhtml".to_string(),
+                CHAPTER.as_bytes().to_vec(),
+            ),
+            ("OEBPS/map.png".to_string(), b"PNG".to_vec()),
+        ]);
+        let (epub_path, kepub_path, back_path) = (
+            dir.join("book.epub"),
+            dir.join("book.kepub"),
+            dir.join("back.epub"),
+        );
+        epub::create(&epub_path, "OEBPS/content.opf", &entries).unwrap();
+
+        to_kepub(&epub_path, &kepub_path).unwrap();
+        to_epub(&kepub_path, &back_path).unwrap();
+        let read = |path: &Path| {
+            let mut epub = Epub::open(path).unwrap();
+            String::from_utf8(epub.read("OEBPS/chapter.xhtml").unwrap()).unwrap()
+        };
+        let (kepub, back) = (read(&kepub_path), read(&back_path));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(kepub.contains(r#"<div id="book-columns"><div id="book-inner">"#));
+        assert!(kepub.contains(r#"<span class="koboSpan" id="kobo.2.2">“Really?” </span>"#));
+        assert!(kepub.contains(r#"<span class="koboSpan" id="kobo.3.1"><img"#));
+        assert!(kepub.contains("<pre>Left. As it is.</pre>"));
+        assert!(kepub.contains(r#"id="kobo.2.4">Nobody knew 1 &lt; 2.</span></p>"#));
+        // References are written as the characters they stand for, except where XML needs them
+        let resolved = CHAPTER
+            .replace("&apos;", "'")
+            .replace("&#8220;", "“")
+            .replace("&#8221;", "”");
+        assert_eq!(back, resolved);
+    }
+}
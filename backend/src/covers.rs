@@ -0,0 +1,302 @@
+use crate::config::ConfigInterface;
+use crate::{books, epub::Epub, metadata};
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat};
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::{AppConfig, CoverCrop, CoverOption, CoverSource, SeriesCover};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Width divided by height of a standard cover (2:3)
+pub const COVER_ASPECT_RATIO: f64 = 2.0 / 3.0;
+
+/// Heights of the thumbnails kept for every cover
+pub const THUMBNAIL_HEIGHTS: [u32; 2] = [240, 480];
+
+/// Height of the previews shown while picking a cover
+const PREVIEW_HEIGHT: u32 = 240;
+
+/// Images inside book files that are smaller than this in either dimension are assumed to be
+/// decorations rather than covers
+const MIN_COVER_SIZE: u32 = 150;
+
+pub fn thumbnail_dir(library_dir: &Path) -> PathBuf {
+    library_dir.join("thumbnails")
+}
+
+pub fn thumbnail_path(library_dir: &Path, book: i64, height: u32) -> PathBuf {
+    thumbnail_dir(library_dir).join(format!("{book}-{height}.jpg"))
+}
+
+/// Directory of the book's files relative to the library directory
+fn book_path(conn: &Connection, book: i64) -> Result<String> {
+    conn.query_row("SELECT path FROM books WHERE id = ?1", [book], |row| {
+        row.get(0)
+    })
+    .optional()?
+    .ok_or_else(|| anyhow!("There is no book with ID {book}"))
+}
+
+/// Image data of the cover candidate at `url`, which may come from a client. HTTP(S) URLs are
+/// downloaded. Other URLs are only read from disk if they point into the directory of a fixture
+/// provider, whose candidates are local files, so that clients can't have any file read.
+pub fn fetch(url: &str) -> Result<Vec<u8>> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return metadata::http().get(url);
+    }
+    let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Reading {path:?}"))?;
+    let fixture_dirs = AppConfig::read()?.metadata.fixture_dirs;
+    if !fixture_dirs
+        .iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .any(|dir| path.starts_with(dir))
+    {
+        return Err(anyhow!(
+            "{url:?} is neither an HTTP(S) URL nor a cover of a fixture provider"
+        ));
+    }
+    std::fs::read(&path).with_context(|| format!("Reading {path:?}"))
+}
+
+fn decode(image: &[u8]) -> Result<DynamicImage> {
+    image::load_from_memory(image).context("Unsupported or damaged image")
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut data = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut data, ImageFormat::Jpeg)?;
+    Ok(data.into_inner())
+}
+
+/// Cut the image down to the standard cover aspect ratio
+pub fn crop(image: &DynamicImage, crop: CoverCrop) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let position = crop.position.clamp(0.0, 1.0) as f64;
+    let target_width = (height as f64 * COVER_ASPECT_RATIO).round() as u32;
+    if target_width < width {
+        let x = ((width - target_width) as f64 * position).round() as u32;
+        image.crop_imm(x, 0, target_width, height)
+    } else {
+        let target_height = ((width as f64 / COVER_ASPECT_RATIO).round() as u32).min(height);
+        let y = ((height - target_height) as f64 * position).round() as u32;
+        image.crop_imm(0, y, width, target_height)
+    }
+}
+
+/// A downscaled copy of the image as `data:` URL, with the size of the original
+pub fn preview(image: &[u8]) -> Result<(u32, u32, String)> {
+    let image = decode(image)?;
+    let (width, height) = image.dimensions();
+    let small = image.resize(u32::MAX, PREVIEW_HEIGHT.min(height), FilterType::Triangle);
+    Ok((width, height, data_url(&encode_jpeg(&small)?)))
+}
+
+fn data_url(jpeg: &[u8]) -> String {
+    format!(
+        "data:image/jpeg;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(jpeg)
+    )
+}
+
+/// Replace the thumbnails of a book's cover
+pub fn write_thumbnails(library_dir: &Path, book: i64, image: &DynamicImage) -> Result<()> {
+    std::fs::create_dir_all(thumbnail_dir(library_dir))?;
+    for height in THUMBNAIL_HEIGHTS {
+        let thumbnail = image.resize(u32::MAX, height.min(image.height()), FilterType::Lanczos3);
+        std::fs::write(
+            thumbnail_path(library_dir, book, height),
+            encode_jpeg(&thumbnail)?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Save `image` as the cover of a book, cropped if requested, and regenerate its thumbnails
+pub fn save(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    image: &[u8],
+    crop_to: Option<CoverCrop>,
+) -> Result<()> {
+    let mut image = decode(image)?;
+    if let Some(crop_to) = crop_to {
+        image = crop(&image, crop_to);
+    }
+    books::set_cover(conn, library_dir, book, &encode_jpeg(&image)?)?;
+    write_thumbnails(library_dir, book, &image)
+}
+
+/// EPUB files of a book
+fn epub_files(conn: &Connection, library_dir: &Path, book: i64) -> Result<Vec<PathBuf>> {
+    let dir = library_dir.join(book_path(conn, book)?);
+    let mut stmt = conn.prepare("SELECT name FROM data WHERE book = ?1 AND format = 'EPUB'")?;
+    let names = stmt.query_map([book], |row| row.get::<_, String>(0))?;
+    let mut files = Vec::new();
+    for name in names {
+        files.push(dir.join(format!("{}.epub", name?)));
+    }
+    Ok(files)
+}
+
+/// Image data of a cover source
+pub fn source_image(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    source: &CoverSource,
+) -> Result<Vec<u8>> {
+    match source {
+        CoverSource::Current => {
+            let path = library_dir.join(book_path(conn, book)?).join("cover.jpg");
+            std::fs::read(&path).with_context(|| format!("Reading {path:?}"))
+        }
+        CoverSource::BookFile { format, path } if format == "EPUB" => {
+            for file in epub_files(conn, library_dir, book)? {
+                let mut epub = Epub::open(&file)?;
+                if epub.manifest.iter().any(|item| &item.path == path) {
+                    return epub.read(path);
+                }
+            }
+            Err(anyhow!("No file of book {book} contains {path:?}"))
+        }
+        CoverSource::BookFile { format, .. } => Err(anyhow!(
+            "Reading images from {format} files is not supported"
+        )),
+        CoverSource::Provider { url, .. } | CoverSource::Url(url) => fetch(url),
+        CoverSource::Upload(data) => Ok(data.clone()),
+    }
+}
+
+fn option(
+    source: CoverSource,
+    label: String,
+    image: &[u8],
+    embedded_cover: bool,
+) -> Result<CoverOption> {
+    let (width, height, preview) = preview(image)?;
+    Ok(CoverOption {
+        source,
+        label,
+        width,
+        height,
+        preview,
+        embedded_cover,
+    })
+}
+
+/// Cover options from the library itself: the current cover and the images inside the book's
+/// EPUB files, the declared cover image first. Images that can't be decoded are skipped.
+pub fn local_options(conn: &Connection, library_dir: &Path, book: i64) -> Result<Vec<CoverOption>> {
+    let mut options = Vec::new();
+    if let Ok(image) = source_image(conn, library_dir, book, &CoverSource::Current) {
+        match option(
+            CoverSource::Current,
+            "Current cover".to_string(),
+            &image,
+            false,
+        ) {
+            Ok(current) => options.push(current),
+            Err(e) => tracing::warn!("Cover of book {book} is unreadable: {e:#}"),
+        }
+    }
+    for file in epub_files(conn, library_dir, book)? {
+        let mut epub = match Epub::open(&file) {
+            Ok(epub) => epub,
+            Err(e) => {
+                tracing::warn!("Skipping images of {file:?}: {e:#}");
+                continue;
+            }
+        };
+        let cover = epub.cover().map(|item| item.path.clone());
+        let mut images: Vec<String> = epub.images().map(|item| item.path.clone()).collect();
+        images.sort_by_key(|path| Some(path) != cover.as_ref());
+        for path in images {
+            let embedded_cover = Some(&path) == cover.as_ref();
+            let Ok(image) = epub.read(&path) else {
+                continue;
+            };
+            let source = CoverSource::BookFile {
+                format: "EPUB".to_string(),
+                path: path.clone(),
+            };
+            match option(source, path, &image, embedded_cover) {
+                Ok(option)
+                    if embedded_cover
+                        || (option.width >= MIN_COVER_SIZE && option.height >= MIN_COVER_SIZE) =>
+                {
+                    options.push(option)
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Cover option for a cover offered by a metadata provider, downloading it
+pub fn provider_option(provider: &str, url: &str) -> Result<CoverOption> {
+    let image = fetch(url)?;
+    let source = CoverSource::Provider {
+        provider: provider.to_string(),
+        url: url.to_string(),
+    };
+    option(source, provider.to_string(), &image, false)
+}
+
+/// Cover option for an image given by URL or uploaded
+pub fn external_option(source: CoverSource) -> Result<CoverOption> {
+    let (image, label) = match &source {
+        CoverSource::Url(url) => (fetch(url)?, url.clone()),
+        CoverSource::Upload(data) => (data.clone(), "Uploaded image".to_string()),
+        _ => return Err(anyhow!("Not an external cover source")),
+    };
+    option(source, label, &image, false)
+}
+
+/// Covers of all books in the series of `book`, in series order. Thumbnails are used where
+/// they exist.
+pub fn series_covers(conn: &Connection, library_dir: &Path, book: i64) -> Result<Vec<SeriesCover>> {
+    let mut stmt = conn.prepare(
+        "SELECT b.id, b.title, bsl.entry, b.path, b.has_cover FROM books_series_link bsl
+         JOIN books b ON b.id = bsl.book
+         WHERE bsl.series = (SELECT series FROM books_series_link WHERE book = ?1)
+         ORDER BY bsl.entry",
+    )?;
+    let rows = stmt.query_map(params![book], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, f64>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, bool>(4)?,
+        ))
+    })?;
+    let mut covers = Vec::new();
+    for row in rows {
+        let (id, title, index, path, has_cover) = row?;
+        let preview = if has_cover {
+            let thumbnail = thumbnail_path(library_dir, id, THUMBNAIL_HEIGHTS[0]);
+            match std::fs::read(&thumbnail) {
+                Ok(jpeg) => Some(data_url(&jpeg)),
+                Err(_) => std::fs::read(library_dir.join(path).join("cover.jpg"))
+                    .ok()
+                    .and_then(|image| preview(&image).ok())
+                    .map(|(_, _, url)| url),
+            }
+        } else {
+            None
+        };
+        covers.push(SeriesCover {
+            book: id,
+            title,
+            index,
+            preview,
+        });
+    }
+    Ok(covers)
+}
@@ -1,21 +1,12 @@
 use crate::config::ConfigInterface;
+use crate::library;
 use anyhow::{anyhow, Context, Result};
-use once_cell::sync::OnceCell;
 use rusqlite::Connection;
 use shared::types::AppConfig;
-use std::cell::RefCell;
 use std::path::PathBuf;
 
-pub static DB_PATH: OnceCell<PathBuf> = OnceCell::new();
-
-thread_local! {
-    pub static DB: RefCell<Option<rusqlite::Connection>> = const { RefCell::new(None) };
-}
-
-// Provide a directory path; we ensure a file named "library.db" exists/initialized within it.
-pub fn set_db_path(input_path: PathBuf) -> Result<()> {
-    tracing::info!("set_db_path called with input: {:?}", input_path);
-
+/// Resolve user input to the directory a library lives in
+pub fn resolve_data_dir(input_path: PathBuf) -> Result<PathBuf> {
     // Error out if empty path provided
     if input_path.as_os_str().is_empty() {
         return Err(anyhow!("Provided path is empty"));
@@ -44,87 +35,76 @@ pub fn set_db_path(input_path: PathBuf) -> Result<()> {
     }
     std::fs::create_dir_all(&dir_path)
         .with_context(|| format!("Creating/ensuring data directory {dir_path:?}"))?;
+    Ok(dir_path)
+}
 
-    let db_file_path = dir_path.join("library.db");
-    let first_time = !db_file_path.exists();
-    tracing::debug!(
-        "Resolved data directory: {:?}; db file: {:?}",
-        dir_path,
-        db_file_path
-    );
-
-    // Handle already-initialized global path.
-    if let Some(existing) = DB_PATH.get() {
-        if *existing != db_file_path {
-            tracing::warn!(
-                "Attempt to change DB path at runtime from {:?} to {:?}. This is currently unsupported for safety; restart the application to switch.",
-                existing, db_file_path
-            );
-            return Err(anyhow!(
-                "Database path already set; restart application to change it."
-            ));
-        } else {
-            tracing::debug!(
-                "DB path already set to requested path; reusing connection/migrations."
-            );
-        }
-    }
+// Provide a directory path; we ensure a file named "library.db" exists/initialized within it.
+// The directory is registered as a library (named after the directory) if it isn't already and
+// becomes the active library, closing whichever library was open before.
+pub fn set_db_path(input_path: PathBuf) -> Result<()> {
+    tracing::info!("set_db_path called with input: {:?}", input_path);
 
-    let conn = Connection::open(&db_file_path)
-        .with_context(|| format!("Opening DB file at {db_file_path:?}"))?;
-    run_migrations(&conn)?; // idempotent
+    let dir_path = resolve_data_dir(input_path)?;
+    tracing::debug!("Resolved data directory: {:?}", dir_path);
 
-    // Only set OnceCell if not already set
-    if DB_PATH.get().is_none() {
-        DB_PATH
-            .set(db_file_path.clone())
-            .map_err(|_| anyhow!("DB path already set!"))?;
-    }
+    let mut cfg = AppConfig::read()?;
+    let name = match cfg.libraries.iter().find(|l| l.data_dir == dir_path) {
+        Some(entry) => entry.name.clone(),
+        None => {
+            let name = unique_library_name(&cfg, &dir_path);
+            cfg = library::register(&name, dir_path.clone())?;
+            name
+        }
+    };
 
-    // Store connection in thread-local for reuse in this thread
-    DB.with(|cell| {
-        *cell.borrow_mut() = Some(conn);
-    });
+    library::activate(&name, &dir_path)?;
 
     // Persist the directory (not the file path) in config (overwrite if legacy file path was there)
-    let mut cfg = AppConfig::read()?;
     if cfg.data_dir.as_ref() != Some(&dir_path) {
         cfg.data_dir = Some(dir_path.clone());
         cfg.write()?;
     }
+    Ok(())
+}
 
-    if first_time {
-        tracing::info!("Initialized new SQLite database at {:?}", db_file_path);
-    } else {
-        tracing::info!("Opened existing SQLite database at {:?}", db_file_path);
+/// Derive a library name from its directory that doesn't clash with registered libraries
+fn unique_library_name(cfg: &AppConfig, dir: &std::path::Path) -> String {
+    let base = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "Library".to_string());
+    let mut name = base.clone();
+    let mut counter = 2;
+    while cfg.find_library(&name).is_some() {
+        name = format!("{base} ({counter})");
+        counter += 1;
     }
-    Ok(())
+    name
 }
 
-pub fn run_migrations(conn: &Connection) -> Result<()> {
-    let sql = include_str!("./schema.sql");
-    conn.execute("PRAGMA foreign_keys = ON", [])?;
-    conn.execute_batch(sql)?;
-    Ok(())
+/// Run `f` with a read-only connection to the active library on the blocking thread pool, so
+/// SQLite calls never stall the async runtime.
+pub async fn with_conn<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce(&Connection) -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    let library = library::require_active()?;
+    tokio::task::spawn_blocking(move || library.pool().read(f))
+        .await
+        .context("Database task panicked")?
 }
 
-pub fn with_conn<F, R>(f: F) -> Result<R>
+/// Run `f` with the write connection of the active library on the blocking thread pool. Writes
+/// are serialized; use a transaction inside `f` for changes spanning several statements.
+pub async fn with_write_conn<F, R>(f: F) -> Result<R>
 where
-    F: FnOnce(&Connection) -> Result<R>,
+    F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+    R: Send + 'static,
 {
-    DB.with(|cell| -> Result<R> {
-        if cell.borrow().is_none() {
-            let path = DB_PATH
-                .get()
-                .ok_or_else(|| anyhow!("DB path not configured yet!"))?
-                .clone();
-            let conn = Connection::open(path)?;
-            conn.execute("PRAGMA foreign_keys = ON", [])?;
-            run_migrations(&conn)?;
-            *cell.borrow_mut() = Some(conn);
-        }
-        let conn_ref = cell.borrow();
-        let conn = conn_ref.as_ref().unwrap();
-        f(conn)
-    })
+    let library = library::require_active()?;
+    tokio::task::spawn_blocking(move || library.pool().write(f))
+        .await
+        .context("Database task panicked")?
 }
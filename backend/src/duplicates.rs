@@ -0,0 +1,569 @@
+use crate::books;
+use crate::covers;
+use crate::matching::{self, normalize, BookIndex, MATCH_THRESHOLD};
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use shared::types::{BookMetadata, DuplicateBook, DuplicateGroup, DuplicateReason};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// SHA-256 of the file at `path` as lower-case hex
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("Opening {path:?}"))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("Reading {path:?}"))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// What identifies the content of a file: its hash, which only needs to be computed again once
+/// its size or modification time changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub size: u64,
+    /// Modification time in seconds since the epoch
+    pub modified: i64,
+    pub sha256: Option<String>,
+}
+
+impl Fingerprint {
+    /// Size and modification time of the file at `path`, without its hash
+    pub fn of(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path).with_context(|| format!("Reading {path:?}"))?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+            sha256: None,
+        })
+    }
+
+    pub fn hashed(path: &Path) -> Result<Self> {
+        Ok(Self {
+            sha256: Some(hash_file(path)?),
+            ..Self::of(path)?
+        })
+    }
+
+    /// Whether the file may have changed since `self` was taken, going by size and modification
+    /// time
+    pub fn differs(&self, current: &Fingerprint) -> bool {
+        self.size != current.size || self.modified != current.modified
+    }
+}
+
+/// Remember the hash of the file of `book` in `format`, which has the `fingerprint` now
+pub fn record_hash(
+    conn: &Connection,
+    book: i64,
+    format: &str,
+    fingerprint: &Fingerprint,
+) -> Result<()> {
+    let sha256 = fingerprint.sha256.as_deref().ok_or_else(|| {
+        anyhow!("The fingerprint of the {format} file of book {book} has no hash")
+    })?;
+    conn.execute(
+        "INSERT OR REPLACE INTO file_hashes (data, size, modified, sha256)
+         SELECT id, ?3, ?4, ?5 FROM data WHERE book = ?1 AND format = ?2",
+        params![
+            book,
+            format.to_uppercase(),
+            fingerprint.size,
+            fingerprint.modified,
+            sha256
+        ],
+    )?;
+    Ok(())
+}
+
+/// The hash of the file of `book` in `format`, to be stored with [`record_hashes`]
+#[derive(Debug, Clone)]
+pub struct FileHash {
+    pub book: i64,
+    pub format: String,
+    pub fingerprint: Fingerprint,
+}
+
+/// Hash the book files that have no hash yet or a stale one. A hash is stale once the size or
+/// modification time of the file changed. Files missing on disk are left out. Only reads from
+/// `conn`, so hashing a large library can run on a read connection while writes go on; store the
+/// hashes with [`record_hashes`].
+pub fn hash_stale_files(conn: &Connection, library_dir: &Path) -> Result<Vec<FileHash>> {
+    let mut stmt = conn.prepare(
+        "SELECT d.book, d.format, b.path, d.name, h.size, h.modified FROM data d
+         JOIN books b ON b.id = d.book
+         LEFT JOIN file_hashes h ON h.data = d.id",
+    )?;
+    let files = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<u64>>(4)?,
+                row.get::<_, Option<i64>>(5)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut hashes = Vec::new();
+    for (book, format, dir, name, size, modified) in files {
+        let path = library_dir
+            .join(dir)
+            .join(format!("{name}.{}", format.to_lowercase()));
+        if !path.is_file() {
+            continue;
+        }
+        let current = Fingerprint::of(&path)?;
+        if let (Some(size), Some(modified)) = (size, modified) {
+            let recorded = Fingerprint {
+                size,
+                modified,
+                sha256: None,
+            };
+            if !recorded.differs(&current) {
+                continue;
+            }
+        }
+        hashes.push(FileHash {
+            book,
+            format,
+            fingerprint: Fingerprint::hashed(&path)?,
+        });
+    }
+    Ok(hashes)
+}
+
+/// Store the `hashes` computed by [`hash_stale_files`]. Hashes of files deleted since are
+/// dropped.
+pub fn record_hashes(conn: &mut Connection, hashes: &[FileHash]) -> Result<()> {
+    let tx = conn.transaction()?;
+    for hash in hashes {
+        record_hash(&tx, hash.book, &hash.format, &hash.fingerprint)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// A book of the library that a file about to be imported duplicates
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duplicate {
+    pub book: i64,
+    pub title: String,
+    pub reason: DuplicateReason,
+}
+
+/// The book that the file with hash `sha256` and `metadata` most certainly duplicates: one with
+/// the identical file, else one with the same ISBN, hyphens aside, else the one with the most
+/// similar title and author in `index`
+pub fn find_for(
+    conn: &Connection,
+    index: &BookIndex,
+    sha256: &str,
+    metadata: &BookMetadata,
+) -> Result<Option<Duplicate>> {
+    let by_hash = conn
+        .query_row(
+            "SELECT b.id, b.title FROM file_hashes h
+             JOIN data d ON d.id = h.data JOIN books b ON b.id = d.book
+             WHERE h.sha256 = ?1 LIMIT 1",
+            [sha256],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    if let Some((book, title)) = by_hash {
+        return Ok(Some(Duplicate {
+            book,
+            title,
+            reason: DuplicateReason::SameFile,
+        }));
+    }
+    if let Some(isbn) = &metadata.isbn {
+        let by_isbn = conn
+            .query_row(
+                "SELECT b.id, b.title FROM identifiers i JOIN books b ON b.id = i.book
+                 WHERE i.type = 'isbn' AND REPLACE(i.val, '-', '') = ?1 LIMIT 1",
+                [isbn.replace('-', "")],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if let Some((book, title)) = by_isbn {
+            return Ok(Some(Duplicate {
+                book,
+                title,
+                reason: DuplicateReason::SameIsbn,
+            }));
+        }
+    }
+    let Some(title) = &metadata.title else {
+        return Ok(None);
+    };
+    Ok(index
+        .matches(title, metadata.authors.first().map(String::as_str))
+        .into_iter()
+        .next()
+        .map(|m| Duplicate {
+            book: m.book,
+            title: m.title,
+            reason: DuplicateReason::SimilarTitle,
+        }))
+}
+
+/// Groups of books that are probably the same: sharing a file or an ISBN, hyphens aside, or with
+/// nearly the
+/// same title and author. Only books whose normalized titles start with the same word are
+/// compared for similarity, which keeps large libraries fast. File hashes must be up to date,
+/// see [`hash_stale_files`].
+pub fn find_all(conn: &Connection) -> Result<Vec<DuplicateGroup>> {
+    let mut stmt = conn.prepare(
+        "SELECT b.id, b.title,
+            (SELECT GROUP_CONCAT(a.name, '|') FROM books_authors_link bal
+             JOIN authors a ON a.id = bal.author WHERE bal.book = b.id),
+            (SELECT GROUP_CONCAT(format, '|') FROM data WHERE book = b.id)
+         FROM books b ORDER BY b.id",
+    )?;
+    let split = |value: Option<String>| -> Vec<String> {
+        value
+            .map(|v| v.split('|').map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let books: BTreeMap<i64, DuplicateBook> = stmt
+        .query_map([], |row| {
+            Ok(DuplicateBook {
+                id: row.get(0)?,
+                title: row.get(1)?,
+                authors: split(row.get(2)?),
+                formats: split(row.get(3)?),
+            })
+        })?
+        .map(|book| book.map(|book| (book.id, book)))
+        .collect::<Result<_, _>>()?;
+
+    let mut pairs: Vec<(i64, i64, DuplicateReason)> = Vec::new();
+    for (sql, reason) in [
+        (
+            "SELECT GROUP_CONCAT(DISTINCT d.book) FROM file_hashes h JOIN data d ON d.id = h.data
+             GROUP BY h.sha256 HAVING COUNT(DISTINCT d.book) > 1",
+            DuplicateReason::SameFile,
+        ),
+        (
+            "SELECT GROUP_CONCAT(DISTINCT book) FROM identifiers WHERE type = 'isbn'
+             GROUP BY REPLACE(val, '-', '') HAVING COUNT(DISTINCT book) > 1",
+            DuplicateReason::SameIsbn,
+        ),
+    ] {
+        let mut stmt = conn.prepare(sql)?;
+        for ids in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            let ids: Vec<i64> = ids?.split(',').filter_map(|id| id.parse().ok()).collect();
+            pairs.extend(ids.windows(2).map(|w| (w[0], w[1], reason)));
+        }
+    }
+    let mut blocks: HashMap<String, Vec<&DuplicateBook>> = HashMap::new();
+    for book in books.values() {
+        let key = normalize(&book.title)
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        blocks.entry(key).or_default().push(book);
+    }
+    for block in blocks.values() {
+        for (i, a) in block.iter().enumerate() {
+            for b in &block[i + 1..] {
+                let author = a.authors.first().map(String::as_str);
+                if matching::score(&a.title, author, &b.title, &b.authors) >= MATCH_THRESHOLD {
+                    pairs.push((a.id, b.id, DuplicateReason::SimilarTitle));
+                }
+            }
+        }
+    }
+
+    // Union the pairs into groups, named by their smallest book ID
+    let mut parent: HashMap<i64, i64> = HashMap::new();
+    fn root(parent: &mut HashMap<i64, i64>, id: i64) -> i64 {
+        let next = *parent.get(&id).unwrap_or(&id);
+        if next == id {
+            return id;
+        }
+        let root = root(parent, next);
+        parent.insert(id, root);
+        root
+    }
+    for (a, b, _) in &pairs {
+        let (a, b) = (root(&mut parent, *a), root(&mut parent, *b));
+        if a != b {
+            parent.insert(a.max(b), a.min(b));
+        }
+    }
+    let mut groups: BTreeMap<i64, (BTreeSet<i64>, BTreeSet<DuplicateReason>)> = BTreeMap::new();
+    for (a, b, reason) in pairs {
+        let group = groups.entry(root(&mut parent, a)).or_default();
+        group.0.extend([a, b]);
+        group.1.insert(reason);
+    }
+    Ok(groups
+        .into_values()
+        .map(|(ids, reasons)| DuplicateGroup {
+            books: ids.iter().filter_map(|id| books.get(id).cloned()).collect(),
+            reasons: reasons.into_iter().collect(),
+        })
+        .collect())
+}
+
+/// What is left to do on disk after the database part of a [`merge`] was committed
+#[derive(Debug, Default)]
+pub struct MergedFiles {
+    /// Files of the merged books for the kept book, as pairs of source and target
+    moves: Vec<(PathBuf, PathBuf)>,
+    /// Directories of the merged books and their thumbnails
+    removals: Vec<PathBuf>,
+}
+
+impl MergedFiles {
+    /// Move the files of the merged books to the kept book, then delete what is left of the
+    /// merged books. If a file cannot be moved, nothing is deleted, so no file is lost.
+    pub fn apply(self, library_dir: &Path) -> Result<()> {
+        for (from, to) in &self.moves {
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(from, to).with_context(|| {
+                format!("Moving {from:?} to {to:?}, the files of the merged books were kept")
+            })?;
+        }
+        for path in &self.removals {
+            if path.is_dir() {
+                std::fs::remove_dir_all(path).with_context(|| format!("Removing {path:?}"))?;
+                // Remove the author directory if this was the author's last book
+                if let Some(parent) = path.parent() {
+                    if parent != library_dir {
+                        let _ = std::fs::remove_dir(parent);
+                    }
+                }
+            } else {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Merge the books `others` into `keep`. The metadata of `keep` wins; what it lacks is taken from
+/// the others: files of formats it has none of, identifiers, tags, collections, readings, the
+/// description, series, cover and so on. The others are deleted with their remaining files.
+///
+/// Run inside a transaction and only [`MergedFiles::apply`] the returned files once it is
+/// committed: the database is changed here, files are only moved and deleted then.
+pub fn merge(
+    conn: &Connection,
+    library_dir: &Path,
+    keep: i64,
+    others: &[i64],
+) -> Result<MergedFiles> {
+    let (dir, title): (String, String) = conn
+        .query_row(
+            "SELECT path, title FROM books WHERE id = ?1",
+            [keep],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .ok_or_else(|| anyhow!("There is no book with ID {keep}"))?;
+    if dir.is_empty() {
+        return Err(anyhow!("Book {keep} has no directory for the merged files"));
+    }
+    let keep_path = library_dir.join(&dir);
+    let mut merged = Vec::new();
+    for &other in others.iter().filter(|other| **other != keep) {
+        let (other_dir, has_cover): (String, bool) = conn
+            .query_row(
+                "SELECT path, has_cover FROM books WHERE id = ?1",
+                [other],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("There is no book with ID {other}"))?;
+        merged.push((other, other_dir, has_cover));
+    }
+
+    let mut files = MergedFiles::default();
+    for (other, other_dir, has_cover) in merged {
+        let other_path = library_dir.join(&other_dir);
+
+        // Files of formats the kept book lacks, named like its other files
+        let author: Option<String> = conn
+            .query_row(
+                "SELECT a.name FROM books_authors_link bal JOIN authors a ON a.id = bal.author
+                 WHERE bal.book = ?1 ORDER BY bal.rowid LIMIT 1",
+                [keep],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let name = books::book_file_name(&title, author.as_deref());
+        let mut stmt = conn.prepare(
+            "SELECT format, name, uncompressed_size FROM data
+             WHERE book = ?1 AND format NOT IN (SELECT format FROM data WHERE book = ?2)",
+        )?;
+        let other_files = stmt
+            .query_map(params![other, keep], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u64>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (format, other_name, size) in other_files {
+            let extension = format.to_lowercase();
+            let from = other_path.join(format!("{other_name}.{extension}"));
+            if !from.is_file() {
+                continue;
+            }
+            let to = keep_path.join(format!("{name}.{extension}"));
+            files.moves.push((from, to));
+            books::add_format(conn, keep, &format, &name, size)?;
+        }
+
+        let cover = other_path.join("cover.jpg");
+        let keep_has_cover: bool =
+            conn.query_row("SELECT has_cover FROM books WHERE id = ?1", [keep], |row| {
+                row.get(0)
+            })?;
+        if has_cover && !keep_has_cover && cover.is_file() {
+            files.moves.push((cover, keep_path.join("cover.jpg")));
+            conn.execute("UPDATE books SET has_cover = 1 WHERE id = ?1", [keep])?;
+        }
+
+        conn.execute(
+            "UPDATE books SET
+                date_published = COALESCE(date_published, (SELECT date_published FROM books WHERE id = ?2)),
+                number_of_pages = CASE WHEN number_of_pages = 0
+                    THEN (SELECT number_of_pages FROM books WHERE id = ?2) ELSE number_of_pages END,
+                goodreads_id = COALESCE(goodreads_id, (SELECT goodreads_id FROM books WHERE id = ?2)),
+                last_modified = CURRENT_TIMESTAMP
+             WHERE id = ?1",
+            params![keep, other],
+        )?;
+        // Authors and series only if the kept book has none, the rest is combined
+        conn.execute(
+            "UPDATE books_authors_link SET book = ?1 WHERE book = ?2
+             AND NOT EXISTS (SELECT 1 FROM books_authors_link WHERE book = ?1)",
+            params![keep, other],
+        )?;
+        conn.execute(
+            "UPDATE books_series_link SET book = ?1 WHERE book = ?2
+             AND NOT EXISTS (SELECT 1 FROM books_series_link WHERE book = ?1)",
+            params![keep, other],
+        )?;
+        for table in [
+            "books_tags_link",
+            "identifiers",
+            "comments",
+            "books_collections_link",
+            "books_custom_column_values",
+            "calibre_book_ids",
+            "read_books",
+        ] {
+            conn.execute(
+                &format!("UPDATE OR IGNORE {table} SET book = ?1 WHERE book = ?2"),
+                params![keep, other],
+            )?;
+        }
+        conn.execute("DELETE FROM books WHERE id = ?1", [other])?;
+        for height in covers::THUMBNAIL_HEIGHTS {
+            files
+                .removals
+                .push(covers::thumbnail_path(library_dir, other, height));
+        }
+        if !other_dir.is_empty() {
+            files.removals.push(other_path);
+        }
+    }
+    conn.execute_batch(
+        "DELETE FROM authors WHERE id NOT IN (SELECT author FROM books_authors_link);
+         DELETE FROM series WHERE id NOT IN (SELECT series FROM books_series_link);
+         DELETE FROM tags WHERE id NOT IN (SELECT tag FROM books_tags_link);",
+    )?;
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::books::NewBook;
+    use crate::migrations;
+
+    /// A library with the books of `titles`, all by Brandon Sanderson, numbered from 1
+    fn library(titles: &[&str]) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        for title in titles {
+            let book = books::insert_book(
+                &conn,
+                &NewBook {
+                    title: title.to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let metadata = BookMetadata {
+                title: Some(title.to_string()),
+                authors: vec!["Brandon Sanderson".to_string()],
+                ..Default::default()
+            };
+            books::update_book(&conn, book, &metadata).unwrap();
+        }
+        conn
+    }
+
+    fn metadata(title: &str, isbn: Option<&str>) -> BookMetadata {
+        BookMetadata {
+            title: Some(title.to_string()),
+            authors: vec!["Sanderson, Brandon".to_string()],
+            isbn: isbn.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn volumes_of_a_series_are_no_duplicates() {
+        let conn = library(&[
+            "Mistborn: The Final Empire",
+            "Mistborn: The Well of Ascension",
+        ]);
+        assert!(find_all(&conn).unwrap().is_empty());
+        let index = BookIndex::load(&conn).unwrap();
+        let found = find_for(
+            &conn,
+            &index,
+            "no such hash",
+            &metadata("Mistborn: The Hero of Ages", None),
+        )
+        .unwrap();
+        assert_eq!(found, None);
+        let found = find_for(&conn, &index, "no such hash", &metadata("Mistborn", None)).unwrap();
+        assert_eq!(found.map(|d| d.reason), Some(DuplicateReason::SimilarTitle));
+    }
+
+    #[test]
+    fn isbns_match_without_hyphens() {
+        let conn = library(&["The Way of Kings", "Warbreaker"]);
+        books::set_identifier(&conn, 1, "isbn", "978-0-7653-2635-5").unwrap();
+        books::set_identifier(&conn, 2, "isbn", "9780765326355").unwrap();
+        let groups = find_all(&conn).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].reasons, vec![DuplicateReason::SameIsbn]);
+        let index = BookIndex::load(&conn).unwrap();
+        let found = find_for(
+            &conn,
+            &index,
+            "no such hash",
+            &metadata("Something Else", Some("978-0765326355")),
+        )
+        .unwrap();
+        assert_eq!(found.map(|d| d.book), Some(1));
+    }
+}
@@ -0,0 +1,144 @@
+use crate::epub::Epub;
+use crate::normalize::{self, Changes};
+use anyhow::{anyhow, Context, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+
+/// Path of the EPUB file of `book`
+pub(crate) fn epub_path(conn: &Connection, library_dir: &Path, book: i64) -> Result<PathBuf> {
+    let (dir, _, name) =
+        normalize::epub_file(conn, book)?.ok_or_else(|| anyhow!("Book {book} has no EPUB file"))?;
+    Ok(library_dir.join(dir).join(format!("{name}.epub")))
+}
+
+/// Archive entries of the content documents in the reading order of the EPUB of `book`
+pub fn documents(conn: &Connection, library_dir: &Path, book: i64) -> Result<Vec<String>> {
+    let epub = Epub::open(&epub_path(conn, library_dir, book)?)?;
+    Ok(epub
+        .spine_items()
+        .filter(|item| item.is_content_document())
+        .map(|item| item.path.clone())
+        .collect())
+}
+
+/// The content document `path` of the EPUB of `book`
+pub fn read_document(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    path: &str,
+) -> Result<String> {
+    let mut epub = Epub::open(&epub_path(conn, library_dir, book)?)?;
+    if !epub
+        .manifest
+        .iter()
+        .any(|item| item.path == path && item.is_content_document())
+    {
+        return Err(anyhow!("{path:?} is not a content document of book {book}"));
+    }
+    String::from_utf8(epub.read(path)?).with_context(|| format!("{path:?} is not UTF-8"))
+}
+
+/// Replace the content document `path` of the EPUB of `book` with `content`, which must be
+/// well-formed. The EPUB as it was before the first change is kept, see [`normalize::apply`].
+pub fn save_document(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    path: &str,
+    content: &str,
+) -> Result<()> {
+    check_well_formed(content)?;
+    // Only existing documents can be saved, so nothing is added by accident
+    read_document(conn, library_dir, book, path)?;
+    let mut changes = Changes::default();
+    changes
+        .replaced
+        .insert(path.to_string(), content.as_bytes().to_vec());
+    normalize::apply(conn, library_dir, book, &changes)
+}
+
+/// Line and column, both starting at 1, of the byte `position` in `text`
+fn line_column(text: &str, position: usize) -> (usize, usize) {
+    let before = &text.as_bytes()[..position.min(text.len())];
+    let line = before.iter().filter(|b| **b == b'\n').count() + 1;
+    let line_start = before
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    let column = String::from_utf8_lossy(&before[line_start..])
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+/// Check that `xhtml` is a well-formed XML document: elements nested and closed properly, one
+/// root element, valid attributes and only entities that are defined. Named entities such as
+/// `&nbsp;` are accepted if a DTD is referenced that may define them.
+pub fn check_well_formed(xhtml: &str) -> Result<()> {
+    let mut reader = Reader::from_str(xhtml);
+    let mut open = Vec::new();
+    let mut roots = 0;
+    let mut has_dtd = false;
+    loop {
+        let position = reader.buffer_position() as usize;
+        let at = |position: usize| {
+            let (line, column) = line_column(xhtml, position);
+            format!("Line {line}, column {column}")
+        };
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => return Err(anyhow!("{}: {e}", at(reader.error_position() as usize))),
+        };
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                for attribute in e.attributes() {
+                    attribute.map_err(|e| anyhow!("{}: {e}", at(position)))?;
+                }
+                if open.is_empty() {
+                    roots += 1;
+                    if roots > 1 {
+                        return Err(anyhow!("{}: a second root element", at(position)));
+                    }
+                }
+                if matches!(event, Event::Start(_)) {
+                    open.push(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                }
+            }
+            Event::End(_) => {
+                open.pop();
+            }
+            Event::DocType(e) => has_dtd = e.decode()?.contains("DTD"),
+            Event::Text(e) if open.is_empty() && !e.decode()?.trim().is_empty() => {
+                return Err(anyhow!(
+                    "{}: text outside of the root element",
+                    at(position)
+                ));
+            }
+            Event::GeneralRef(e) => {
+                let name = e.decode()?;
+                let defined = e
+                    .resolve_char_ref()
+                    .map_err(|e| anyhow!("{}: {e}", at(position)))?
+                    .is_some()
+                    || quick_xml::escape::resolve_predefined_entity(&name).is_some()
+                    || has_dtd;
+                if !defined {
+                    return Err(anyhow!("{}: undefined entity &{name};", at(position)));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if let Some(name) = open.last() {
+        return Err(anyhow!("<{name}> is not closed"));
+    }
+    if roots == 0 {
+        return Err(anyhow!("No root element"));
+    }
+    Ok(())
+}
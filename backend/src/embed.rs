@@ -0,0 +1,505 @@
+use crate::epub::{self, attribute, element_text, identifier_type, Epub};
+use anyhow::{anyhow, Result};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Manifest ID and file name of a cover image added by us, when the book had no JPEG cover
+const COVER_ID: &str = "ironscribe-cover";
+const COVER_HREF: &str = "ironscribe-cover.jpg";
+
+/// `<meta name="...">` entries written by Calibre that are replaced when embedding
+const REPLACED_NAMED_META: &[&str] = &[
+    "calibre:series",
+    "calibre:series_index",
+    "calibre:title_sort",
+];
+
+/// Metadata of a book as it is written into the package document of its EPUB files
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EmbeddedMetadata {
+    pub title: String,
+    pub title_sort: String,
+    /// Names with their sort form, e.g., ("Brandon Sanderson", "Sanderson, Brandon")
+    pub authors: Vec<(String, String)>,
+    pub series: Option<(String, f64)>,
+    /// Type and value, e.g., ("isbn", "9780765326355")
+    pub identifiers: Vec<(String, String)>,
+    pub description: Option<String>,
+}
+
+/// The metadata of a book to embed into its files
+pub fn load(conn: &Connection, book: i64) -> Result<EmbeddedMetadata> {
+    let (title, title_sort) = conn
+        .query_row(
+            "SELECT title, sort FROM books WHERE id = ?1",
+            [book],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .ok_or_else(|| anyhow!("There is no book with ID {book}"))?;
+    let pairs = |sql: &str| -> Result<Vec<(String, String)>> {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([book], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    };
+    let series = conn
+        .query_row(
+            "SELECT s.name, bsl.entry FROM books_series_link bsl JOIN series s ON s.id = bsl.series WHERE bsl.book = ?1",
+            [book],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let description = conn
+        .query_row("SELECT text FROM comments WHERE book = ?1", [book], |row| {
+            row.get::<_, String>(0)
+        })
+        .optional()?
+        .filter(|text| !text.trim().is_empty());
+    Ok(EmbeddedMetadata {
+        title,
+        title_sort,
+        authors: pairs(
+            "SELECT a.name, a.sort FROM books_authors_link bal JOIN authors a ON a.id = bal.author WHERE bal.book = ?1 ORDER BY bal.rowid",
+        )?,
+        series,
+        identifiers: pairs("SELECT type, val FROM identifiers WHERE book = ?1 ORDER BY type")?,
+        description,
+    })
+}
+
+/// Where the cover image goes in the EPUB
+struct CoverTarget {
+    id: String,
+    /// Name of the entry in the archive
+    path: String,
+    /// Set if the image is added to the manifest rather than replacing an existing JPEG
+    new_href: Option<String>,
+}
+
+/// Rewrite the package document of the EPUB at `path` with `metadata` and, if given, replace
+/// its cover with the JPEG `cover`. Only the package document and the cover image change, all
+/// other entries of the archive stay byte-for-byte intact. Returns the new size of the file.
+pub fn embed_into_epub(
+    path: &Path,
+    metadata: &EmbeddedMetadata,
+    cover: Option<&[u8]>,
+) -> Result<u64> {
+    let mut epub = Epub::open(path)?;
+    let opf = epub.read_opf()?;
+    let target = cover.map(|_| match epub.cover() {
+        Some(item) if item.media_type == "image/jpeg" => CoverTarget {
+            id: item.id.clone(),
+            path: item.path.clone(),
+            new_href: None,
+        },
+        _ => CoverTarget {
+            id: COVER_ID.to_string(),
+            path: epub::resolve_href(&epub.opf_path, COVER_HREF),
+            new_href: Some(COVER_HREF.to_string()),
+        },
+    });
+    let mut replacements = BTreeMap::new();
+    replacements.insert(
+        epub.opf_path.clone(),
+        rewrite_opf(&opf, metadata, target.as_ref())?.into_bytes(),
+    );
+    if let (Some(target), Some(cover)) = (target, cover) {
+        replacements.insert(target.path, cover.to_vec());
+    }
+    epub::replace_entries(path, &replacements)
+}
+
+/// Embed the metadata and cover of a book into all of its EPUB files, updating their recorded
+/// sizes. Returns the number of files written.
+pub fn embed(conn: &Connection, library_dir: &Path, book: i64) -> Result<usize> {
+    let metadata = load(conn, book)?;
+    let (dir, has_cover): (String, bool) = conn.query_row(
+        "SELECT path, has_cover FROM books WHERE id = ?1",
+        [book],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let dir = library_dir.join(dir);
+    let cover = if has_cover {
+        std::fs::read(dir.join("cover.jpg")).ok()
+    } else {
+        None
+    };
+    let mut stmt = conn.prepare("SELECT id, name FROM data WHERE book = ?1 AND format = 'EPUB'")?;
+    let files = stmt
+        .query_map([book], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (id, name) in &files {
+        let size = embed_into_epub(
+            &dir.join(format!("{name}.epub")),
+            &metadata,
+            cover.as_deref(),
+        )?;
+        conn.execute(
+            "UPDATE data SET uncompressed_size = ?1 WHERE id = ?2",
+            rusqlite::params![size as i64, id],
+        )?;
+    }
+    Ok(files.len())
+}
+
+fn is_replaced_element(
+    element: &BytesStart,
+    text: &str,
+    unique_identifier: Option<&str>,
+    identifier_types: &HashSet<String>,
+    replacing_cover: bool,
+) -> bool {
+    match element.local_name().as_ref() {
+        b"title" | b"creator" | b"description" => true,
+        b"identifier" => {
+            attribute(element, "id").as_deref() != unique_identifier
+                && identifier_type(element, text).is_some_and(|t| identifier_types.contains(&t))
+        }
+        b"meta" => match (attribute(element, "name"), attribute(element, "property")) {
+            (Some(name), _) => {
+                REPLACED_NAMED_META.contains(&name.as_str()) || (replacing_cover && name == "cover")
+            }
+            (None, Some(property)) => property == "belongs-to-collection",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// IDs of the metadata elements that are replaced, so refinements of them can be dropped too
+fn replaced_ids(
+    opf: &str,
+    unique_identifier: Option<&str>,
+    identifier_types: &HashSet<String>,
+    replacing_cover: bool,
+) -> Result<HashSet<String>> {
+    let mut reader = Reader::from_str(opf);
+    let mut ids = HashSet::new();
+    let mut in_metadata = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"metadata" => in_metadata = true,
+            Event::End(e) if e.local_name().as_ref() == b"metadata" => in_metadata = false,
+            Event::Start(e) if in_metadata => {
+                let text = element_text(&mut reader)?;
+                if is_replaced_element(
+                    &e,
+                    &text,
+                    unique_identifier,
+                    identifier_types,
+                    replacing_cover,
+                ) {
+                    ids.extend(attribute(&e, "id"));
+                }
+            }
+            Event::Empty(e) if in_metadata => {
+                if is_replaced_element(&e, "", unique_identifier, identifier_types, replacing_cover)
+                {
+                    ids.extend(attribute(&e, "id"));
+                }
+            }
+            Event::Eof => return Ok(ids),
+            _ => {}
+        }
+    }
+}
+
+fn format_index(index: f64) -> String {
+    if index.fract() == 0.0 {
+        format!("{index:.0}")
+    } else {
+        index.to_string()
+    }
+}
+
+/// The metadata elements written by us, indented like `indent` and in the syntax of EPUB 3
+/// (`refines`) or EPUB 2 (`opf:` attributes)
+fn metadata_elements(
+    metadata: &EmbeddedMetadata,
+    epub3: bool,
+    cover_id: Option<&str>,
+    existing_identifiers: &HashSet<String>,
+    indent: &str,
+) -> String {
+    let mut lines = Vec::new();
+    let title = escape(&metadata.title);
+    let title_sort = escape(&metadata.title_sort);
+    if epub3 {
+        lines.push(format!(
+            r#"<dc:title id="ironscribe-title">{title}</dc:title>"#
+        ));
+        lines.push(format!(
+            r##"<meta refines="#ironscribe-title" property="file-as">{title_sort}</meta>"##
+        ));
+    } else {
+        lines.push(format!("<dc:title>{title}</dc:title>"));
+    }
+    lines.push(format!(
+        r#"<meta name="calibre:title_sort" content="{title_sort}"/>"#
+    ));
+    for (index, (name, sort)) in metadata.authors.iter().enumerate() {
+        let (name, sort) = (escape(name), escape(sort));
+        if epub3 {
+            let id = format!("ironscribe-creator{}", index + 1);
+            lines.push(format!(r#"<dc:creator id="{id}">{name}</dc:creator>"#));
+            lines.push(format!(
+                r##"<meta refines="#{id}" property="role" scheme="marc:relators">aut</meta>"##
+            ));
+            lines.push(format!(
+                r##"<meta refines="#{id}" property="file-as">{sort}</meta>"##
+            ));
+        } else {
+            lines.push(format!(
+                r#"<dc:creator opf:role="aut" opf:file-as="{sort}">{name}</dc:creator>"#
+            ));
+        }
+    }
+    if let Some(description) = &metadata.description {
+        lines.push(format!(
+            "<dc:description>{}</dc:description>",
+            escape(description)
+        ));
+    }
+    for (kind, value) in &metadata.identifiers {
+        if existing_identifiers.contains(value) {
+            continue;
+        }
+        let value = escape(value);
+        if !epub3 {
+            lines.push(format!(
+                r#"<dc:identifier opf:scheme="{}">{value}</dc:identifier>"#,
+                escape(kind.to_uppercase())
+            ));
+        } else if kind == "isbn" {
+            lines.push(format!("<dc:identifier>urn:isbn:{value}</dc:identifier>"));
+        } else {
+            lines.push(format!(
+                "<dc:identifier>{}:{value}</dc:identifier>",
+                escape(kind)
+            ));
+        }
+    }
+    if let Some((name, index)) = &metadata.series {
+        let (name, index) = (escape(name), format_index(*index));
+        if epub3 {
+            lines.push(format!(
+                r#"<meta property="belongs-to-collection" id="ironscribe-series">{name}</meta>"#
+            ));
+            lines.push(
+                r##"<meta refines="#ironscribe-series" property="collection-type">series</meta>"##
+                    .to_string(),
+            );
+            lines.push(format!(
+                r##"<meta refines="#ironscribe-series" property="group-position">{index}</meta>"##
+            ));
+        }
+        // Understood by Calibre and many readers, also in EPUB 3
+        lines.push(format!(r#"<meta name="calibre:series" content="{name}"/>"#));
+        lines.push(format!(
+            r#"<meta name="calibre:series_index" content="{index}"/>"#
+        ));
+    }
+    if let Some(id) = cover_id {
+        lines.push(format!(r#"<meta name="cover" content="{}"/>"#, escape(id)));
+    }
+    lines
+        .into_iter()
+        .map(|line| format!("{indent}{line}"))
+        .collect()
+}
+
+/// Copy of `element` with the `cover-image` property removed
+fn without_cover_property(element: &BytesStart) -> BytesStart<'static> {
+    let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+    let mut copy = BytesStart::new(name);
+    for a in element.attributes().flatten() {
+        let key = String::from_utf8_lossy(a.key.as_ref()).into_owned();
+        let value = String::from_utf8_lossy(&a.value).into_owned();
+        if key == "properties" {
+            let rest: Vec<&str> = value
+                .split_whitespace()
+                .filter(|p| *p != "cover-image")
+                .collect();
+            if !rest.is_empty() {
+                copy.push_attribute((key.as_str(), rest.join(" ").as_str()));
+            }
+        } else {
+            copy.push_attribute((key.as_str(), value.as_str()));
+        }
+    }
+    copy
+}
+
+/// The package document with the title, creators, description, identifiers, series and cover
+/// reference replaced by `metadata`. Everything else, including other metadata such as
+/// languages, subjects and the unique identifier, is kept as written.
+fn rewrite_opf(
+    opf: &str,
+    metadata: &EmbeddedMetadata,
+    cover: Option<&CoverTarget>,
+) -> Result<String> {
+    let mut reader = Reader::from_str(opf);
+    let mut epub3 = false;
+    let mut unique_identifier = None;
+    let identifier_types: HashSet<String> = metadata
+        .identifiers
+        .iter()
+        .map(|(t, _)| t.clone())
+        .collect();
+    // The unique identifier is kept, so don't write its value a second time
+    let mut existing_identifiers = HashSet::new();
+    let mut writer = Writer::new(Vec::new());
+    let mut in_metadata = false;
+    let mut replaced = HashSet::new();
+    // Whitespace is held back until the next event, so it can be dropped with removed elements
+    // and new elements can be inserted before the whitespace preceding an end tag
+    let mut pending = String::new();
+    // Whitespace before the first metadata element and manifest item, to indent ours alike
+    let mut metadata_indent = None::<String>;
+    let mut item_indent = None::<String>;
+    loop {
+        let event = reader.read_event()?;
+        if let Event::Text(e) = &event {
+            let text = e.decode()?;
+            if text.trim().is_empty() {
+                pending.push_str(&text);
+                continue;
+            }
+        }
+        match &event {
+            Event::Start(e) if e.local_name().as_ref() == b"package" => {
+                epub3 = attribute(e, "version").is_some_and(|v| v.starts_with('3'));
+                unique_identifier = attribute(e, "unique-identifier");
+                replaced = replaced_ids(
+                    opf,
+                    unique_identifier.as_deref(),
+                    &identifier_types,
+                    cover.is_some(),
+                )?;
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"metadata" => {
+                in_metadata = true;
+                let mut start = e.clone().into_owned();
+                // EPUB 2 attributes need the OPF namespace, which is often declared on the
+                // package element only
+                if !epub3 && attribute(e, "opf").is_none() {
+                    start.push_attribute(("xmlns:opf", "http://www.idpf.org/2007/opf"));
+                }
+                if attribute(e, "dc").is_none() {
+                    start.push_attribute(("xmlns:dc", "http://purl.org/dc/elements/1.1/"));
+                }
+                writer
+                    .get_mut()
+                    .extend_from_slice(std::mem::take(&mut pending).as_bytes());
+                writer.write_event(Event::Start(start))?;
+                continue;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"metadata" => {
+                in_metadata = false;
+                let elements = metadata_elements(
+                    metadata,
+                    epub3,
+                    cover.map(|c| c.id.as_str()),
+                    &existing_identifiers,
+                    metadata_indent.as_deref().unwrap_or("\n    "),
+                );
+                writer.get_mut().extend_from_slice(elements.as_bytes());
+            }
+            Event::Start(e) | Event::Empty(e) if in_metadata => {
+                let whitespace = std::mem::take(&mut pending);
+                if metadata_indent.is_none() && whitespace.contains('\n') {
+                    metadata_indent =
+                        Some(whitespace[whitespace.rfind('\n').unwrap_or(0)..].to_string());
+                }
+                let (text, content) = if matches!(event, Event::Start(_)) {
+                    let start = reader.buffer_position() as usize;
+                    let text = element_text(&mut reader)?;
+                    let end = reader.buffer_position() as usize;
+                    // The content and end tag, written as they are
+                    (text, Some(&opf[start..end]))
+                } else {
+                    (String::new(), None)
+                };
+                let refined = attribute(e, "refines")
+                    .is_some_and(|r| replaced.contains(r.trim_start_matches('#')));
+                let dropped = refined
+                    || is_replaced_element(
+                        e,
+                        &text,
+                        unique_identifier.as_deref(),
+                        &identifier_types,
+                        cover.is_some(),
+                    );
+                if !dropped {
+                    if e.local_name().as_ref() == b"identifier" {
+                        let value = text.trim();
+                        existing_identifiers.insert(value.to_string());
+                        if let Some((_, rest)) = value.rsplit_once(':') {
+                            existing_identifiers.insert(rest.to_string());
+                        }
+                    }
+                    writer.get_mut().extend_from_slice(whitespace.as_bytes());
+                    writer.write_event(event.clone())?;
+                    if let Some(content) = content {
+                        writer.get_mut().extend_from_slice(content.as_bytes());
+                    }
+                }
+                continue;
+            }
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"item" => {
+                if item_indent.is_none() && pending.contains('\n') {
+                    item_indent = Some(pending[pending.rfind('\n').unwrap_or(0)..].to_string());
+                }
+                let uncovered = cover.is_some_and(|c| c.new_href.is_some())
+                    && attribute(e, "properties")
+                        .is_some_and(|p| p.split_whitespace().any(|p| p == "cover-image"));
+                if uncovered {
+                    let copy = without_cover_property(e);
+                    writer
+                        .get_mut()
+                        .extend_from_slice(std::mem::take(&mut pending).as_bytes());
+                    writer.write_event(match event {
+                        Event::Start(_) => Event::Start(copy),
+                        _ => Event::Empty(copy),
+                    })?;
+                    continue;
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"manifest" => {
+                if let Some(CoverTarget {
+                    id,
+                    new_href: Some(href),
+                    ..
+                }) = cover
+                {
+                    let properties = if epub3 {
+                        r#" properties="cover-image""#
+                    } else {
+                        ""
+                    };
+                    let item = format!(
+                        r#"{}<item id="{}" href="{}" media-type="image/jpeg"{properties}/>"#,
+                        item_indent.as_deref().unwrap_or("\n    "),
+                        escape(id),
+                        escape(href)
+                    );
+                    writer.get_mut().extend_from_slice(item.as_bytes());
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        writer
+            .get_mut()
+            .extend_from_slice(std::mem::take(&mut pending).as_bytes());
+        writer.write_event(event)?;
+    }
+    writer.get_mut().extend_from_slice(pending.as_bytes());
+    Ok(String::from_utf8(writer.into_inner())?)
+}
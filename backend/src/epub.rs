@@ -0,0 +1,450 @@
+use anyhow::{anyhow, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+/// Content of the `mimetype` entry that starts every EPUB archive
+pub(crate) const MIMETYPE: &str = "application/epub+zip";
+
+/// A resource listed in the manifest of an EPUB's package document
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestItem {
+    pub id: String,
+    /// As written in the package document, relative to it
+    pub href: String,
+    /// Name of the entry in the archive
+    pub path: String,
+    pub media_type: String,
+    pub properties: Vec<String>,
+}
+
+impl ManifestItem {
+    /// Whether the item is an XHTML content document, as opposed to images, style sheets or
+    /// fonts
+    pub fn is_content_document(&self) -> bool {
+        matches!(
+            self.media_type.as_str(),
+            "application/xhtml+xml" | "text/html"
+        )
+    }
+}
+
+/// An EPUB file, opened for reading its package document and resources
+pub struct Epub {
+    archive: ZipArchive<File>,
+    /// Name of the package document (OPF) in the archive
+    pub opf_path: String,
+    pub manifest: Vec<ManifestItem>,
+    /// Manifest IDs of the reading order
+    pub spine: Vec<String>,
+    /// Manifest ID of the cover image as declared by EPUB 2 `<meta name="cover">`
+    cover_id: Option<String>,
+}
+
+/// Value of the attribute `name` of an element, unescaped
+pub(crate) fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == name.as_bytes())
+        .map(|a| {
+            let value = String::from_utf8_lossy(&a.value);
+            quick_xml::escape::unescape(&value)
+                .map(|v| v.into_owned())
+                .unwrap_or_else(|_| value.into_owned())
+        })
+}
+
+/// Text content of the element that `reader` just read the start of, consuming its end
+pub(crate) fn element_text(reader: &mut Reader<&[u8]>) -> Result<String> {
+    let mut text = String::new();
+    let mut depth = 0usize;
+    loop {
+        match reader.read_event()? {
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => return Ok(text),
+            Event::End(_) => depth -= 1,
+            Event::Text(e) => text.push_str(&e.decode()?),
+            Event::CData(e) => text.push_str(&e.decode()?),
+            Event::GeneralRef(e) => {
+                let name = e.decode()?;
+                text.push_str(&quick_xml::escape::unescape(&format!("&{name};"))?);
+            }
+            Event::Eof => return Err(anyhow!("Unexpected end of the package document")),
+            _ => {}
+        }
+    }
+}
+
+/// Type of an identifier in a package document, from its `opf:scheme` or a prefix such as
+/// `urn:isbn:`
+pub(crate) fn identifier_type(element: &BytesStart, value: &str) -> Option<String> {
+    attribute(element, "scheme")
+        .or_else(|| {
+            let value = value.trim();
+            let value = value.strip_prefix("urn:").unwrap_or(value);
+            value.split_once(':').map(|(scheme, _)| scheme.to_string())
+        })
+        .map(|scheme| scheme.to_lowercase())
+}
+
+/// Decode `%XX` escapes of a URL path
+fn percent_decode(value: &str) -> String {
+    percent_encoding::percent_decode_str(value)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Characters escaped in hrefs written into EPUB documents
+const HREF_ESCAPED: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`');
+
+/// The href that leads from the archive entry `base` to the entry `path`, the reverse of
+/// [`resolve_href`], e.g., `../Images/cover.jpg` from `OEBPS/Text/title.xhtml` to
+/// `OEBPS/Images/cover.jpg`
+pub fn relative_href(base: &str, path: &str) -> String {
+    let mut from: Vec<&str> = base.split('/').collect();
+    from.pop();
+    let to: Vec<&str> = path.split('/').collect();
+    let common = from
+        .iter()
+        .zip(&to)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(to.len() - 1);
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(&to[common..]);
+    utf8_percent_encode(&parts.join("/"), HREF_ESCAPED).to_string()
+}
+
+/// Resolve `href` relative to the archive entry `base`, e.g., `../Images/cover.jpg` relative to
+/// `OEBPS/Text/title.xhtml` becomes `OEBPS/Images/cover.jpg`
+pub fn resolve_href(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    let decoded = percent_decode(href);
+    for part in decoded.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+impl Epub {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Opening {path:?}"))?;
+        let mut archive =
+            ZipArchive::new(file).with_context(|| format!("{path:?} is not a valid EPUB"))?;
+        let container = read_entry(&mut archive, "META-INF/container.xml")?;
+        let opf_path = rootfile(&String::from_utf8_lossy(&container))
+            .ok_or_else(|| anyhow!("{path:?} has no package document"))?;
+        let opf = read_entry(&mut archive, &opf_path)?;
+        let mut epub = Self {
+            archive,
+            opf_path,
+            manifest: Vec::new(),
+            spine: Vec::new(),
+            cover_id: None,
+        };
+        epub.parse_package(&String::from_utf8_lossy(&opf))
+            .with_context(|| format!("Parsing the package document of {path:?}"))?;
+        Ok(epub)
+    }
+
+    fn parse_package(&mut self, opf: &str) -> Result<()> {
+        let mut reader = Reader::from_str(opf);
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                    b"item" => {
+                        let (Some(id), Some(href)) = (attribute(&e, "id"), attribute(&e, "href"))
+                        else {
+                            continue;
+                        };
+                        self.manifest.push(ManifestItem {
+                            path: resolve_href(&self.opf_path, &href),
+                            id,
+                            href,
+                            media_type: attribute(&e, "media-type").unwrap_or_default(),
+                            properties: attribute(&e, "properties")
+                                .map(|p| p.split_whitespace().map(str::to_string).collect())
+                                .unwrap_or_default(),
+                        });
+                    }
+                    b"itemref" => self.spine.extend(attribute(&e, "idref")),
+                    b"meta" if attribute(&e, "name").as_deref() == Some("cover") => {
+                        self.cover_id = attribute(&e, "content");
+                    }
+                    _ => {}
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of all entries of the archive
+    pub fn entry_names(&self) -> Vec<String> {
+        self.archive.file_names().map(str::to_string).collect()
+    }
+
+    /// Content of the archive entry `name`
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+        read_entry(&mut self.archive, name)
+    }
+
+    /// Content of the package document
+    pub fn read_opf(&mut self) -> Result<String> {
+        let opf_path = self.opf_path.clone();
+        Ok(String::from_utf8_lossy(&self.read(&opf_path)?).into_owned())
+    }
+
+    pub fn item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    /// The cover image, declared as EPUB 3 `cover-image` or EPUB 2 cover meta
+    pub fn cover(&self) -> Option<&ManifestItem> {
+        self.manifest
+            .iter()
+            .find(|item| item.properties.iter().any(|p| p == "cover-image"))
+            .or_else(|| self.cover_id.as_deref().and_then(|id| self.item(id)))
+            .filter(|item| item.media_type.starts_with("image/"))
+    }
+
+    /// Content documents in reading order
+    pub fn spine_items(&self) -> impl Iterator<Item = &ManifestItem> {
+        self.spine.iter().filter_map(|id| self.item(id))
+    }
+
+    /// The EPUB 3 navigation document
+    pub fn nav(&self) -> Option<&ManifestItem> {
+        self.manifest
+            .iter()
+            .find(|item| item.properties.iter().any(|p| p == "nav"))
+    }
+
+    /// The EPUB 2 table of contents (NCX)
+    pub fn ncx(&self) -> Option<&ManifestItem> {
+        self.manifest
+            .iter()
+            .find(|item| item.media_type == "application/x-dtbncx+xml")
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = &ManifestItem> {
+        self.manifest
+            .iter()
+            .filter(|item| item.media_type.starts_with("image/"))
+    }
+}
+
+/// Elements that start a new line of text, so words on both sides of them are separate
+pub(crate) const BLOCK_ELEMENTS: &[&[u8]] = &[
+    b"p",
+    b"div",
+    b"br",
+    b"li",
+    b"h1",
+    b"h2",
+    b"h3",
+    b"h4",
+    b"h5",
+    b"h6",
+    b"td",
+    b"th",
+    b"tr",
+    b"blockquote",
+    b"section",
+    b"article",
+    b"aside",
+    b"header",
+    b"footer",
+    b"pre",
+    b"dt",
+    b"dd",
+    b"hr",
+    b"figcaption",
+];
+
+/// Elements whose content is not text of the book
+const SKIPPED_ELEMENTS: &[&[u8]] = &[b"head", b"script", b"style"];
+
+/// The readable text of an (X)HTML content document, with block elements separated by newlines
+pub fn xhtml_text(xhtml: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xhtml);
+    // Tolerate sloppy markup, which is common in the wild
+    reader.config_mut().check_end_names = false;
+    let mut text = String::new();
+    let mut skipped = 0usize;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let name = e.local_name();
+                if SKIPPED_ELEMENTS.contains(&name.as_ref()) {
+                    skipped += 1;
+                } else if BLOCK_ELEMENTS.contains(&name.as_ref()) {
+                    text.push('\n');
+                }
+            }
+            Event::End(e) => {
+                let name = e.local_name();
+                if SKIPPED_ELEMENTS.contains(&name.as_ref()) {
+                    skipped = skipped.saturating_sub(1);
+                } else if BLOCK_ELEMENTS.contains(&name.as_ref()) {
+                    text.push('\n');
+                }
+            }
+            Event::Empty(e) if BLOCK_ELEMENTS.contains(&e.local_name().as_ref()) => {
+                text.push('\n');
+            }
+            Event::Text(e) if skipped == 0 => text.push_str(&e.decode()?),
+            Event::CData(e) if skipped == 0 => text.push_str(&e.decode()?),
+            Event::GeneralRef(e) if skipped == 0 => {
+                let name = e.decode()?;
+                match quick_xml::escape::unescape(&format!("&{name};")) {
+                    Ok(resolved) => text.push_str(&resolved),
+                    // HTML entities such as &nbsp; are not defined in XML
+                    Err(_) => text.push(' '),
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(text)
+}
+
+/// Replace the content of entries of the EPUB at `path`, adding those that don't exist yet.
+/// All other entries are copied without recompressing them, so they stay byte-for-byte intact.
+/// The `mimetype` entry is written first and uncompressed, as the EPUB container format
+/// requires, even if the old archive got it wrong. The new archive is written next to the old
+/// one and then moved over it, so a failure leaves the file untouched. Returns the new size of
+/// the file.
+pub fn replace_entries(path: &Path, replacements: &BTreeMap<String, Vec<u8>>) -> Result<u64> {
+    rewrite_entries(path, replacements, &BTreeSet::new())
+}
+
+/// Like [`replace_entries`], and leave out the entries named in `removed`
+pub fn rewrite_entries(
+    path: &Path,
+    replacements: &BTreeMap<String, Vec<u8>>,
+    removed: &BTreeSet<String>,
+) -> Result<u64> {
+    let mut archive =
+        ZipArchive::new(File::open(path).with_context(|| format!("Opening {path:?}"))?)
+            .with_context(|| format!("{path:?} is not a valid EPUB"))?;
+    let temp = path.with_extension("epub.tmp");
+    let result = (|| -> Result<()> {
+        let mut writer = ZipWriter::new(File::create(&temp)?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        writer.start_file(
+            "mimetype",
+            SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        writer.write_all(MIMETYPE.as_bytes())?;
+        let mut written = vec!["mimetype".to_string()];
+        for index in 0..archive.len() {
+            let entry = archive.by_index_raw(index)?;
+            if removed.contains(entry.name()) || entry.name() == "mimetype" {
+                continue;
+            }
+            match replacements.get(entry.name()) {
+                Some(content) => {
+                    let name = entry.name().to_string();
+                    drop(entry);
+                    writer.start_file(name.as_str(), options)?;
+                    writer.write_all(content)?;
+                    written.push(name);
+                }
+                None => writer.raw_copy_file(entry)?,
+            }
+        }
+        for (name, content) in replacements {
+            if !written.contains(name) {
+                writer.start_file(name.as_str(), options)?;
+                writer.write_all(content)?;
+            }
+        }
+        writer.finish()?.sync_all()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e.context(format!("Rewriting {path:?}")));
+    }
+    std::fs::rename(&temp, path).with_context(|| format!("Replacing {path:?}"))?;
+    Ok(std::fs::metadata(path)?.len())
+}
+
+/// Write a new EPUB to `path` with `entries` and a container pointing at the package document
+/// `opf_path`, which must be one of them. Returns the size of the file.
+pub fn create(path: &Path, opf_path: &str, entries: &BTreeMap<String, Vec<u8>>) -> Result<u64> {
+    let container = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="{}" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#,
+        quick_xml::escape::escape(opf_path)
+    );
+    let mut writer =
+        ZipWriter::new(File::create(path).with_context(|| format!("Creating {path:?}"))?);
+    writer.start_file(
+        "mimetype",
+        SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    writer.write_all(MIMETYPE.as_bytes())?;
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    writer.start_file("META-INF/container.xml", options)?;
+    writer.write_all(container.as_bytes())?;
+    for (name, content) in entries {
+        writer.start_file(name.as_str(), options)?;
+        writer.write_all(content)?;
+    }
+    writer.finish()?.sync_all()?;
+    Ok(std::fs::metadata(path)?.len())
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>> {
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("Missing {name:?}"))?;
+    let mut content = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Path of the package document declared by `META-INF/container.xml`
+fn rootfile(container: &str) -> Option<String> {
+    let mut reader = Reader::from_str(container);
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                return attribute(&e, "full-path");
+            }
+            Event::Eof => return None,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,563 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Utc};
+use rusqlite::Connection;
+use serde::Serialize;
+use shared::types::{ReadingEntry, ReadingExportFormat};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+/// Version of the JSON export format, increased whenever its shape changes
+pub const JSON_EXPORT_VERSION: u32 = 1;
+/// Date format of Goodreads and StoryGraph CSV files
+const CSV_DATE_FORMAT: &str = "%Y/%m/%d";
+
+const GOODREADS_HEADERS: &[&str] = &[
+    "Book Id",
+    "Title",
+    "Author",
+    "Author l-f",
+    "Additional Authors",
+    "ISBN",
+    "ISBN13",
+    "My Rating",
+    "Number of Pages",
+    "Year Published",
+    "Date Read",
+    "Date Added",
+    "Bookshelves",
+    "Exclusive Shelf",
+    "Read Count",
+];
+
+const STORYGRAPH_HEADERS: &[&str] = &[
+    "Title",
+    "Authors",
+    "ISBN/UID",
+    "Format",
+    "Read Status",
+    "Date Added",
+    "Last Date Read",
+    "Dates Read",
+    "Read Count",
+    "Star Rating",
+    "Tags",
+];
+
+const CATALOG_CSV_HEADERS: &[&str] = &[
+    "Id",
+    "Title",
+    "Sort",
+    "Authors",
+    "Series",
+    "Tags",
+    "Collections",
+    "ISBN",
+    "Goodreads Id",
+    "Pages",
+    "Date Added",
+    "Date Published",
+    "Last Modified",
+];
+
+/// Everything about a book that goes into an export
+struct ExportedBook {
+    id: i64,
+    title: String,
+    sort: String,
+    goodreads_id: Option<i64>,
+    number_of_pages: u32,
+    date_added: DateTime<Utc>,
+    date_published: Option<DateTime<Utc>>,
+    last_modified: DateTime<Utc>,
+    /// Directory of the book's files, relative to the library directory
+    path: String,
+    has_cover: bool,
+    /// (name, sort) in the order they were added
+    authors: Vec<(String, String)>,
+    /// (name, entry)
+    series: Vec<(String, f64)>,
+    tags: Vec<String>,
+    isbn: Option<String>,
+    collections: Vec<String>,
+    readings: Vec<ReadingEntry>,
+}
+
+impl ExportedBook {
+    fn author_names(&self) -> Vec<&str> {
+        self.authors.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    fn series_names(&self) -> Vec<String> {
+        self.series
+            .iter()
+            .map(|(name, entry)| format!("{name} #{entry}"))
+            .collect()
+    }
+
+    fn finished(&self) -> impl Iterator<Item = &ReadingEntry> {
+        self.readings.iter().filter(|r| r.end_date.is_some())
+    }
+
+    fn last_finished(&self) -> Option<&ReadingEntry> {
+        self.finished().max_by_key(|r| r.end_date)
+    }
+
+    /// Goodreads' exclusive shelf, which StoryGraph uses as read status as well
+    fn exclusive_shelf(&self) -> &'static str {
+        if self.readings.iter().any(|r| r.end_date.is_none()) {
+            "currently-reading"
+        } else if self.last_finished().is_some() {
+            "read"
+        } else {
+            "to-read"
+        }
+    }
+
+    fn rating(&self) -> Option<u8> {
+        self.last_finished().and_then(|r| r.rating)
+    }
+}
+
+/// Load the books with the given IDs, or all books if `ids` is `None`, in the order of `ids` or
+/// by sort title
+fn load_books(conn: &Connection, ids: Option<&[i64]>) -> Result<Vec<ExportedBook>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, title, sort, goodreads_id, number_of_pages, date_added, date_published, last_modified, path, has_cover
+         FROM books ORDER BY sort",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ExportedBook {
+            id: row.get("id")?,
+            title: row.get("title")?,
+            sort: row.get("sort")?,
+            goodreads_id: row.get("goodreads_id")?,
+            number_of_pages: row
+                .get::<_, Option<u32>>("number_of_pages")?
+                .unwrap_or_default(),
+            date_added: row.get("date_added")?,
+            date_published: row.get("date_published")?,
+            last_modified: row.get("last_modified")?,
+            path: row.get("path")?,
+            has_cover: row.get("has_cover")?,
+            authors: Vec::new(),
+            series: Vec::new(),
+            tags: Vec::new(),
+            isbn: None,
+            collections: Vec::new(),
+            readings: Vec::new(),
+        })
+    })?;
+    let mut order = Vec::new();
+    let mut books = HashMap::new();
+    for row in rows {
+        let book = row?;
+        order.push(book.id);
+        books.insert(book.id, book);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT bal.book, a.name, a.sort FROM books_authors_link bal JOIN authors a ON a.id = bal.author ORDER BY bal.rowid",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    for row in rows {
+        let (book, name, sort) = row?;
+        if let Some(book) = books.get_mut(&book) {
+            book.authors.push((name, sort));
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT bsl.book, s.name, bsl.entry FROM books_series_link bsl JOIN series s ON s.id = bsl.series",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    for row in rows {
+        let (book, name, entry) = row?;
+        if let Some(book) = books.get_mut(&book) {
+            book.series.push((name, entry));
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT btl.book, t.name FROM books_tags_link btl JOIN tags t ON t.id = btl.tag ORDER BY t.name",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (book, name) = row?;
+        if let Some(book) = books.get_mut(&book) {
+            book.tags.push(name);
+        }
+    }
+
+    let mut stmt = conn.prepare("SELECT book, val FROM identifiers WHERE type = 'isbn'")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (book, isbn) = row?;
+        if let Some(book) = books.get_mut(&book) {
+            book.isbn = Some(isbn);
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT bcl.book, c.name FROM books_collections_link bcl JOIN collections c ON c.id = bcl.collection ORDER BY c.name",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (book, name) = row?;
+        if let Some(book) = books.get_mut(&book) {
+            book.collections.push(name);
+        }
+    }
+
+    for (book, readings) in readings(conn)? {
+        if let Some(book) = books.get_mut(&book) {
+            book.readings = readings;
+        }
+    }
+
+    let order = ids.map(<[i64]>::to_vec).unwrap_or(order);
+    Ok(order
+        .into_iter()
+        .filter_map(|id| books.remove(&id))
+        .collect())
+}
+
+/// Reading history of every book, oldest reading first
+pub fn readings(conn: &Connection) -> Result<HashMap<i64, Vec<ReadingEntry>>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.book, r.start_date, r.end_date, r.rating, u.name
+         FROM read_books r LEFT JOIN users u ON u.id = r.user
+         ORDER BY r.start_date",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            ReadingEntry {
+                start_date: row.get(1)?,
+                end_date: row.get(2)?,
+                rating: row.get(3)?,
+                user: row.get(4)?,
+            },
+        ))
+    })?;
+    let mut readings: HashMap<i64, Vec<ReadingEntry>> = HashMap::new();
+    for row in rows {
+        let (book, reading) = row?;
+        readings.entry(book).or_default().push(reading);
+    }
+    Ok(readings)
+}
+
+/// `dest` itself, or a timestamped file name inside it if it is a directory
+fn export_path(dest: &Path, name: &str, extension: &str) -> PathBuf {
+    if dest.is_dir() {
+        let timestamp = Utc::now().format(TIMESTAMP_FORMAT);
+        dest.join(format!("ironscribe-{name}-{timestamp}{extension}"))
+    } else {
+        dest.to_path_buf()
+    }
+}
+
+fn write_csv(dest: &Path, headers: &[&str], records: Vec<Vec<String>>) -> Result<()> {
+    let mut writer = csv::Writer::from_path(dest).with_context(|| format!("Creating {dest:?}"))?;
+    writer.write_record(headers)?;
+    for record in records {
+        writer.write_record(record)?;
+    }
+    writer
+        .flush()
+        .with_context(|| format!("Writing {dest:?}"))?;
+    Ok(())
+}
+
+fn date(value: Option<DateTime<Utc>>) -> String {
+    value
+        .map(|d| d.format(CSV_DATE_FORMAT).to_string())
+        .unwrap_or_default()
+}
+
+fn goodreads_record(book: &ExportedBook) -> Vec<String> {
+    let (author, author_sort) = book.authors.first().cloned().unwrap_or_default();
+    let additional_authors: Vec<&str> = book
+        .authors
+        .iter()
+        .skip(1)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let isbn = book.isbn.clone().unwrap_or_default();
+    let (isbn10, isbn13) = if isbn.replace('-', "").len() == 13 {
+        (String::new(), isbn)
+    } else {
+        (isbn, String::new())
+    };
+    let shelf = book.exclusive_shelf();
+    let bookshelves: Vec<&str> = std::iter::once(shelf)
+        .chain(
+            book.collections
+                .iter()
+                .map(String::as_str)
+                .filter(|c| *c != shelf),
+        )
+        .collect();
+    vec![
+        book.goodreads_id
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+        book.title.clone(),
+        author,
+        author_sort,
+        additional_authors.join(", "),
+        isbn10,
+        isbn13,
+        book.rating().unwrap_or(0).to_string(),
+        book.number_of_pages.to_string(),
+        book.date_published
+            .map(|d| d.year().to_string())
+            .unwrap_or_default(),
+        date(book.last_finished().and_then(|r| r.end_date)),
+        date(Some(book.date_added)),
+        bookshelves.join(", "),
+        shelf.to_string(),
+        book.finished().count().to_string(),
+    ]
+}
+
+fn storygraph_record(book: &ExportedBook) -> Vec<String> {
+    let authors = book.author_names();
+    let dates_read: Vec<String> = book
+        .finished()
+        .map(|r| format!("{}-{}", date(Some(r.start_date)), date(r.end_date)))
+        .collect();
+    vec![
+        book.title.clone(),
+        authors.join(", "),
+        book.isbn.clone().unwrap_or_default(),
+        String::new(),
+        book.exclusive_shelf().to_string(),
+        date(Some(book.date_added)),
+        date(book.last_finished().and_then(|r| r.end_date)),
+        dates_read.join(", "),
+        book.finished().count().to_string(),
+        book.rating().map(|r| r.to_string()).unwrap_or_default(),
+        book.collections.join(", "),
+    ]
+}
+
+/// Write the library with its reading history as a CSV file that Goodreads or StoryGraph can
+/// import. If `dest` is a directory, a timestamped file is created in it. Returns the path of the
+/// written file.
+pub fn export_readings(
+    conn: &Connection,
+    format: ReadingExportFormat,
+    dest: &Path,
+) -> Result<PathBuf> {
+    let (name, headers, record): (_, _, fn(&ExportedBook) -> Vec<String>) = match format {
+        ReadingExportFormat::Goodreads => ("goodreads", GOODREADS_HEADERS, goodreads_record),
+        ReadingExportFormat::StoryGraph => ("storygraph", STORYGRAPH_HEADERS, storygraph_record),
+    };
+    let dest = export_path(dest, name, ".csv");
+    let records = load_books(conn, None)?.iter().map(record).collect();
+    write_csv(&dest, headers, records)?;
+    tracing::info!("Exported reading history to {:?}", dest);
+    Ok(dest)
+}
+
+fn reading_text(reading: &ReadingEntry) -> String {
+    let mut text = match reading.end_date {
+        Some(end) => format!("{} to {}", date(Some(reading.start_date)), date(Some(end))),
+        None => format!("since {}", date(Some(reading.start_date))),
+    };
+    if let Some(rating) = reading.rating {
+        let _ = write!(text, " ({rating}/5)");
+    }
+    if let Some(user) = &reading.user {
+        let _ = write!(text, " by {user}");
+    }
+    text
+}
+
+/// Write the books with the given IDs as a CSV file, one row per book. With `include_readings`,
+/// the reading history is added as the last column.
+pub fn export_csv(
+    conn: &Connection,
+    ids: &[i64],
+    include_readings: bool,
+    dest: &Path,
+) -> Result<PathBuf> {
+    let dest = export_path(dest, "library", ".csv");
+    let mut headers = CATALOG_CSV_HEADERS.to_vec();
+    if include_readings {
+        headers.push("Readings");
+    }
+    let records = load_books(conn, Some(ids))?
+        .iter()
+        .map(|book| {
+            let mut record = vec![
+                book.id.to_string(),
+                book.title.clone(),
+                book.sort.clone(),
+                book.author_names().join(" & "),
+                book.series_names().join(" & "),
+                book.tags.join(", "),
+                book.collections.join(", "),
+                book.isbn.clone().unwrap_or_default(),
+                book.goodreads_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+                book.number_of_pages.to_string(),
+                book.date_added.to_rfc3339(),
+                book.date_published
+                    .map(|d| d.to_rfc3339())
+                    .unwrap_or_default(),
+                book.last_modified.to_rfc3339(),
+            ];
+            if include_readings {
+                let readings: Vec<String> = book.readings.iter().map(reading_text).collect();
+                record.push(readings.join("; "));
+            }
+            record
+        })
+        .collect();
+    write_csv(&dest, &headers, records)?;
+    tracing::info!("Exported library as CSV to {:?}", dest);
+    Ok(dest)
+}
+
+/// The JSON export: the records in the `BookRecords` shape plus a format version
+#[derive(Serialize)]
+struct JsonExport<'a, T: Serialize> {
+    version: u32,
+    exported: DateTime<Utc>,
+    records: &'a T,
+}
+
+/// Write `records` as versioned JSON
+pub fn export_json<T: Serialize>(records: &T, dest: &Path) -> Result<PathBuf> {
+    let dest = export_path(dest, "library", ".json");
+    let export = JsonExport {
+        version: JSON_EXPORT_VERSION,
+        exported: Utc::now(),
+        records,
+    };
+    let file = std::fs::File::create(&dest).with_context(|| format!("Creating {dest:?}"))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), &export)
+        .with_context(|| format!("Writing {dest:?}"))?;
+    tracing::info!("Exported library as JSON to {:?}", dest);
+    Ok(dest)
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const CATALOG_STYLE: &str = "body{font-family:sans-serif;margin:2em;background:#fafafa}\
+#search{width:100%;max-width:30em;padding:.5em;margin-bottom:1em}\
+.books{display:grid;grid-template-columns:repeat(auto-fill,minmax(14em,1fr));gap:1em}\
+.book{background:#fff;border:1px solid #ddd;border-radius:4px;padding:.75em}\
+.book img{width:100%;aspect-ratio:2/3;object-fit:cover;background:#eee}\
+.book h2{font-size:1.05em;margin:.5em 0 .25em}\
+.book p{margin:.2em 0;font-size:.9em;color:#444}";
+
+const CATALOG_SCRIPT: &str = "document.getElementById('search').addEventListener('input',e=>{\
+const q=e.target.value.toLowerCase();\
+document.querySelectorAll('.book').forEach(b=>{b.hidden=!b.textContent.toLowerCase().includes(q)})})";
+
+/// Write a static HTML catalog of the books with the given IDs into a new directory inside
+/// `dest`: an `index.html` without external dependencies and a `covers` directory. The catalog
+/// can be hosted anywhere.
+pub fn export_html(
+    conn: &Connection,
+    library_dir: &Path,
+    ids: &[i64],
+    include_readings: bool,
+    dest: &Path,
+) -> Result<PathBuf> {
+    let timestamp = Utc::now().format(TIMESTAMP_FORMAT);
+    let dir = dest.join(format!("ironscribe-catalog-{timestamp}"));
+    let covers_dir = dir.join("covers");
+    std::fs::create_dir_all(&covers_dir).with_context(|| format!("Creating {covers_dir:?}"))?;
+
+    let books = load_books(conn, Some(ids))?;
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>Library catalog</title>\n<style>{CATALOG_STYLE}</style>\n</head>\n<body>\n\
+         <h1>Library catalog</h1>\n<p>{} books, exported {}</p>\n\
+         <input id=\"search\" type=\"search\" placeholder=\"Filter\">\n<div class=\"books\">\n",
+        books.len(),
+        Utc::now().format("%Y-%m-%d")
+    );
+    for book in &books {
+        let cover = library_dir.join(&book.path).join("cover.jpg");
+        let cover_name = format!("{}.jpg", book.id);
+        let has_cover = book.has_cover
+            && cover.is_file()
+            && std::fs::copy(&cover, covers_dir.join(&cover_name)).is_ok();
+
+        html.push_str("<article class=\"book\">\n");
+        if has_cover {
+            let _ = writeln!(
+                html,
+                "<img src=\"covers/{cover_name}\" alt=\"Cover of {}\" loading=\"lazy\">",
+                escape_html(&book.title)
+            );
+        }
+        let _ = writeln!(html, "<h2>{}</h2>", escape_html(&book.title));
+        let _ = writeln!(
+            html,
+            "<p>{}</p>",
+            escape_html(&book.author_names().join(", "))
+        );
+        for series in book.series_names() {
+            let _ = writeln!(html, "<p>{}</p>", escape_html(&series));
+        }
+        let mut details = Vec::new();
+        if let Some(published) = book.date_published {
+            details.push(published.year().to_string());
+        }
+        if book.number_of_pages > 0 {
+            details.push(format!("{} pages", book.number_of_pages));
+        }
+        if !details.is_empty() {
+            let _ = writeln!(html, "<p>{}</p>", details.join(" · "));
+        }
+        if !book.tags.is_empty() {
+            let _ = writeln!(html, "<p>{}</p>", escape_html(&book.tags.join(", ")));
+        }
+        if include_readings && !book.readings.is_empty() {
+            html.push_str("<ul>\n");
+            for reading in &book.readings {
+                let _ = writeln!(html, "<li>{}</li>", escape_html(&reading_text(reading)));
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str("</article>\n");
+    }
+    let _ = write!(
+        html,
+        "</div>\n<script>{CATALOG_SCRIPT}</script>\n</body>\n</html>\n"
+    );
+
+    let index = dir.join("index.html");
+    std::fs::write(&index, html).with_context(|| format!("Writing {index:?}"))?;
+    tracing::info!("Exported HTML catalog to {:?}", dir);
+    Ok(dir)
+}
@@ -0,0 +1,123 @@
+use crate::formats::{self, date, isbn, non_empty, split_authors};
+use regex::Regex;
+use shared::types::{BookMetadata, PatternTest};
+
+/// Named groups of file name patterns that become metadata fields
+pub const GROUPS: &[&str] = &["title", "author", "series", "series_index", "isbn", "year"];
+
+/// Titles and author names that files carry in place of none, e.g., set by conversion tools
+const JUNK: &[&str] = &[
+    "unknown",
+    "untitled",
+    "no title",
+    "unknown title",
+    "unknown author",
+    "anonymous",
+];
+
+/// Whether a title or author name read from a file is a placeholder rather than metadata
+pub fn is_junk(value: &str) -> bool {
+    let value = value.trim().to_lowercase();
+    value.is_empty() || JUNK.contains(&value.as_str())
+}
+
+/// The name of a book file as patterns see it: without the extension of a supported format,
+/// with underscores as spaces
+pub fn file_name(name: &str) -> String {
+    let stem = match name.rsplit_once('.') {
+        Some((stem, extension))
+            if formats::reader(&extension.to_uppercase()).is_some() && !stem.is_empty() =>
+        {
+            stem
+        }
+        _ => name,
+    };
+    stem.replace('_', " ").trim().to_string()
+}
+
+/// Compiled file name patterns, tried in order
+pub struct FilenamePatterns(Vec<Regex>);
+
+impl FilenamePatterns {
+    /// Compile `patterns`, skipping invalid ones, which are returned as error messages
+    pub fn compile(patterns: &[String]) -> (Self, Vec<String>) {
+        let mut errors = Vec::new();
+        let regexes = patterns
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    errors.push(format!("Invalid file name pattern {pattern:?}: {e}"));
+                    None
+                }
+            })
+            .collect();
+        (Self(regexes), errors)
+    }
+
+    /// Metadata the first matching pattern extracts from the file name `name`
+    pub fn parse(&self, name: &str) -> Option<BookMetadata> {
+        let name = file_name(name);
+        self.0
+            .iter()
+            .find_map(|regex| captures(regex, &name))
+            .map(|fields| metadata(&fields))
+    }
+}
+
+/// Non-empty known groups of `regex` matched against `name`, in the order of the pattern
+fn captures(regex: &Regex, name: &str) -> Option<Vec<(String, String)>> {
+    let captures = regex.captures(name)?;
+    Some(
+        regex
+            .capture_names()
+            .flatten()
+            .filter(|group| GROUPS.contains(group))
+            .filter_map(|group| {
+                let value = non_empty(captures.name(group)?.as_str())?;
+                Some((group.to_string(), value))
+            })
+            .collect(),
+    )
+}
+
+fn metadata(fields: &[(String, String)]) -> BookMetadata {
+    let mut metadata = BookMetadata::default();
+    for (group, value) in fields {
+        match group.as_str() {
+            "title" => metadata.title = Some(value.clone()),
+            "author" => metadata.authors = split_authors(value),
+            "series" => metadata.series = Some(value.clone()),
+            "series_index" => metadata.series_index = value.parse().ok(),
+            "isbn" => metadata.isbn = isbn(value),
+            "year" => metadata.date_published = date(value),
+            _ => {}
+        }
+    }
+    metadata
+}
+
+/// What each of `patterns` extracts from the file name `sample`, for trying out patterns
+pub fn test(patterns: &[String], sample: &str) -> Vec<PatternTest> {
+    let name = file_name(sample);
+    patterns
+        .iter()
+        .map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => {
+                let fields = captures(&regex, &name);
+                PatternTest {
+                    pattern: pattern.clone(),
+                    error: None,
+                    matched: fields.is_some(),
+                    fields: fields.unwrap_or_default(),
+                }
+            }
+            Err(e) => PatternTest {
+                pattern: pattern.clone(),
+                error: Some(e.to_string()),
+                matched: false,
+                fields: Vec::new(),
+            },
+        })
+        .collect()
+}
@@ -0,0 +1,121 @@
+pub mod comic;
+pub mod epub;
+pub mod fb2;
+pub mod mobi;
+pub mod pdf;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use shared::types::BookMetadata;
+use std::path::Path;
+
+/// Metadata read from a book file. Fields the file doesn't carry are left empty, the import
+/// fills them in from the file name where possible.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileMetadata {
+    pub metadata: BookMetadata,
+    /// Identifiers besides ISBN and Goodreads ID, e.g., ("amazon", "B003P2WO5E")
+    pub identifiers: Vec<(String, String)>,
+    /// Image data of the cover
+    pub cover: Option<Vec<u8>>,
+}
+
+/// Reads the metadata embedded in book files of some formats
+pub trait FormatReader: Send + Sync {
+    /// Formats this reader handles, as upper-case file extensions, e.g., `["CBZ", "CBR"]`
+    fn formats(&self) -> &'static [&'static str];
+
+    fn read(&self, path: &Path) -> Result<FileMetadata>;
+}
+
+static READERS: &[&dyn FormatReader] = &[
+    &epub::EpubReader,
+    &pdf::PdfReader,
+    &mobi::MobiReader,
+    &fb2::Fb2Reader,
+    &comic::ComicReader,
+];
+
+/// Format of a file as upper-case extension, e.g., "EPUB"
+pub fn format_of(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_uppercase())
+}
+
+/// The reader for files of `format`
+pub fn reader(format: &str) -> Option<&'static dyn FormatReader> {
+    READERS
+        .iter()
+        .copied()
+        .find(|reader| reader.formats().contains(&format))
+}
+
+/// All formats a reader exists for
+pub fn supported_formats() -> Vec<&'static str> {
+    READERS
+        .iter()
+        .flat_map(|reader| reader.formats())
+        .copied()
+        .collect()
+}
+
+/// Read the metadata of the book file at `path` with the reader for its extension
+pub fn read(path: &Path) -> Result<FileMetadata> {
+    let format = format_of(path).unwrap_or_default();
+    reader(&format)
+        .ok_or_else(|| anyhow!("Reading metadata from {format:?} files is not supported"))?
+        .read(path)
+}
+
+/// `value` trimmed, `None` if that leaves nothing
+pub(crate) fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// An ISBN with separators removed, if `value` looks like one
+pub(crate) fn isbn(value: &str) -> Option<String> {
+    let value = value.trim();
+    let value = value.strip_prefix("urn:isbn:").unwrap_or(value);
+    let digits: String = value.chars().filter(|c| !matches!(c, '-' | ' ')).collect();
+    let valid = match digits.len() {
+        13 => digits.chars().all(|c| c.is_ascii_digit()),
+        10 => {
+            digits[..9].chars().all(|c| c.is_ascii_digit())
+                && digits[9..]
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == 'X' || c == 'x')
+        }
+        _ => false,
+    };
+    valid.then(|| digits.to_uppercase())
+}
+
+/// A publication date, which files often give as year or year and month only
+pub(crate) fn date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    crate::import::parse_timestamp(value).or_else(|| {
+        let mut parts = value.splitn(3, '-');
+        let year = parts.next()?.get(..4)?.parse().ok()?;
+        let month = parts.next().and_then(|m| m.parse().ok()).unwrap_or(1);
+        NaiveDate::from_ymd_opt(year, month, 1)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|dt| dt.and_utc())
+            .filter(|_| year > 101)
+    })
+}
+
+/// Tags given as one separated list, e.g., `"Fantasy, Epic; Adventure"`
+pub(crate) fn split_list(value: &str) -> Vec<String> {
+    value.split([',', ';']).filter_map(non_empty).collect()
+}
+
+/// Authors given as one string, e.g., "Terry Pratchett & Neil Gaiman". Commas are not split
+/// on, as they often separate last and first name.
+pub(crate) fn split_authors(value: &str) -> Vec<String> {
+    value
+        .split([';', '&'])
+        .flat_map(|part| part.split(" and "))
+        .filter_map(non_empty)
+        .collect()
+}
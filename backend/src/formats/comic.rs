@@ -0,0 +1,136 @@
+use super::{date, isbn, non_empty, split_list, FileMetadata, FormatReader};
+use crate::epub::{attribute, element_text};
+use anyhow::{anyhow, Context, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+const IMAGE_EXTENSIONS: &[&str] = &[".jpg", ".jpeg", ".png", ".gif", ".webp"];
+
+/// Reads the `ComicInfo.xml` of comic book archives, taking the cover from the page marked as
+/// front cover or the first image. Only ZIP archives can be read: CBR files that are RAR
+/// archives are rejected, as no RAR decoder is available, but CBR files that are actually ZIP
+/// archives (which is common) are read like CBZ files.
+pub struct ComicReader;
+
+impl FormatReader for ComicReader {
+    fn formats(&self) -> &'static [&'static str] {
+        &["CBZ", "CBR"]
+    }
+
+    fn read(&self, path: &Path) -> Result<FileMetadata> {
+        let mut file = File::open(path).with_context(|| format!("Opening {path:?}"))?;
+        let mut signature = [0u8; 4];
+        let read = file.read(&mut signature)?;
+        if signature[..read].starts_with(b"Rar!") {
+            return Err(anyhow!(
+                "{path:?} is a RAR archive, which can't be read; convert it to CBZ"
+            ));
+        }
+        let file = File::open(path)?;
+        let mut archive = ZipArchive::new(file)
+            .with_context(|| format!("{path:?} is not a valid comic archive"))?;
+        let mut images: Vec<String> = archive
+            .file_names()
+            .filter(|name| {
+                let lower = name.to_lowercase();
+                IMAGE_EXTENSIONS
+                    .iter()
+                    .any(|extension| lower.ends_with(extension))
+                    && !lower.starts_with("__macosx/")
+            })
+            .map(str::to_string)
+            .collect();
+        images.sort_by_key(|name| name.to_lowercase());
+        let info_name = archive
+            .file_names()
+            .find(|name| name.eq_ignore_ascii_case("ComicInfo.xml"))
+            .map(str::to_string);
+
+        let mut result = FileMetadata::default();
+        let mut front_cover = None;
+        if let Some(name) = info_name {
+            let mut xml = String::new();
+            archive.by_name(&name)?.read_to_string(&mut xml)?;
+            let (fields, cover_page) = parse_comic_info(&xml)?;
+            apply_fields(&mut result, &fields);
+            front_cover = cover_page;
+        }
+        if result.metadata.number_of_pages.is_none() {
+            result.metadata.number_of_pages = u32::try_from(images.len()).ok().filter(|n| *n > 0);
+        }
+        let cover = front_cover
+            .and_then(|page| images.get(page))
+            .or(images.first());
+        if let Some(cover) = cover {
+            let mut image = Vec::new();
+            archive.by_name(cover)?.read_to_end(&mut image)?;
+            result.cover = Some(image);
+        }
+        Ok(result)
+    }
+}
+
+/// Text of the top-level elements of a `ComicInfo.xml` and the index of the front cover page
+fn parse_comic_info(xml: &str) -> Result<(HashMap<String, String>, Option<usize>)> {
+    let mut reader = Reader::from_str(xml);
+    let mut fields = HashMap::new();
+    let mut cover = None;
+    let mut depth = 0usize;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if depth == 1 && e.local_name().as_ref() != b"Pages" => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                let text = element_text(&mut reader)?;
+                fields.extend(non_empty(&text).map(|text| (name, text)));
+            }
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth = depth.saturating_sub(1),
+            Event::Empty(e) if e.local_name().as_ref() == b"Page" && cover.is_none() => {
+                if attribute(&e, "Type").as_deref() == Some("FrontCover") {
+                    cover = attribute(&e, "Image").and_then(|i| i.parse().ok());
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok((fields, cover))
+}
+
+fn apply_fields(file: &mut FileMetadata, fields: &HashMap<String, String>) {
+    let get = |name: &str| fields.get(name).cloned();
+    let metadata = &mut file.metadata;
+    metadata.series = get("Series");
+    metadata.series_index = get("Number").and_then(|n| n.parse().ok());
+    // Issues often have no title of their own
+    metadata.title = get("Title").or_else(|| match (&metadata.series, get("Number")) {
+        (Some(series), Some(number)) => Some(format!("{series} #{number}")),
+        (Some(series), None) => Some(series.clone()),
+        _ => None,
+    });
+    metadata.description = get("Summary");
+    metadata.authors = ["Writer", "Penciller", "Artist"]
+        .iter()
+        .find_map(|role| get(role))
+        .map(|names| split_list(&names))
+        .unwrap_or_default();
+    metadata.tags = ["Genre", "Tags"]
+        .iter()
+        .filter_map(|field| get(field))
+        .flat_map(|list| split_list(&list))
+        .collect();
+    metadata.date_published = get("Year").and_then(|year| {
+        let month = get("Month").unwrap_or_else(|| "1".to_string());
+        let day = get("Day").unwrap_or_else(|| "1".to_string());
+        date(&format!("{year}-{month:0>2}-{day:0>2}"))
+    });
+    metadata.isbn = get("GTIN").and_then(|gtin| isbn(&gtin));
+    metadata.number_of_pages = get("PageCount")
+        .and_then(|n| n.parse().ok())
+        .filter(|n| *n > 0);
+}
@@ -0,0 +1,144 @@
+use super::{date, isbn, non_empty, FileMetadata, FormatReader};
+use crate::epub::{attribute, element_text, identifier_type, Epub};
+use anyhow::Result;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Reads the package document of EPUB 2 and 3 files, including series given as EPUB 3
+/// collection or Calibre meta
+pub struct EpubReader;
+
+impl FormatReader for EpubReader {
+    fn formats(&self) -> &'static [&'static str] {
+        &["EPUB"]
+    }
+
+    fn read(&self, path: &Path) -> Result<FileMetadata> {
+        let mut epub = Epub::open(path)?;
+        let mut file = parse_opf(&epub.read_opf()?)?;
+        if let Some(cover) = epub.cover().map(|item| item.path.clone()) {
+            file.cover = epub.read(&cover).ok();
+        }
+        Ok(file)
+    }
+}
+
+/// A `<meta refines="#id" property="...">` refinement
+struct Refinement {
+    target: String,
+    property: String,
+    value: String,
+}
+
+fn parse_opf(opf: &str) -> Result<FileMetadata> {
+    let mut reader = Reader::from_str(opf);
+    let mut file = FileMetadata::default();
+    let metadata = &mut file.metadata;
+    let mut in_metadata = false;
+    // Creators with their ID and `opf:role`, kept until the refinements are known
+    let mut creators = Vec::new();
+    let mut refinements = Vec::new();
+    let mut collections = Vec::new();
+    let mut named = HashMap::new();
+    loop {
+        let (element, text) = match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"metadata" => {
+                in_metadata = true;
+                continue;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"metadata" => break,
+            Event::Start(e) if in_metadata => {
+                let text = element_text(&mut reader)?;
+                (e.into_owned(), text)
+            }
+            Event::Empty(e) if in_metadata => (e.into_owned(), String::new()),
+            Event::Eof => break,
+            _ => continue,
+        };
+        let id = attribute(&element, "id");
+        match element.local_name().as_ref() {
+            b"title" if metadata.title.is_none() => metadata.title = non_empty(&text),
+            b"creator" => {
+                if let Some(name) = non_empty(&text) {
+                    creators.push((id, attribute(&element, "role"), name));
+                }
+            }
+            b"description" => metadata.description = non_empty(&text),
+            b"date" if metadata.date_published.is_none() => metadata.date_published = date(&text),
+            b"subject" => metadata.tags.extend(non_empty(&text)),
+            b"identifier" => match identifier_type(&element, &text).as_deref() {
+                Some("isbn") => metadata.isbn = metadata.isbn.take().or_else(|| isbn(&text)),
+                Some("goodreads") => {
+                    metadata.goodreads_id =
+                        text.rsplit(':').next().and_then(|v| v.trim().parse().ok())
+                }
+                // Identifiers of the file itself rather than the book
+                Some("uuid" | "calibre") | None => {}
+                Some(kind) => {
+                    let value = text.trim();
+                    let value = value
+                        .strip_prefix("urn:")
+                        .unwrap_or(value)
+                        .strip_prefix(&format!("{kind}:"))
+                        .unwrap_or(value);
+                    file.identifiers
+                        .extend(non_empty(value).map(|v| (kind.to_string(), v)));
+                }
+            },
+            b"meta" => {
+                if let (Some(name), Some(content)) =
+                    (attribute(&element, "name"), attribute(&element, "content"))
+                {
+                    named.insert(name, content);
+                } else if let Some(property) = attribute(&element, "property") {
+                    match attribute(&element, "refines") {
+                        Some(target) => refinements.push(Refinement {
+                            target: target.trim_start_matches('#').to_string(),
+                            property,
+                            value: text.trim().to_string(),
+                        }),
+                        None if property == "belongs-to-collection" => {
+                            collections.push((id, text.trim().to_string()))
+                        }
+                        None => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let refinement = |id: &Option<String>, property: &str| {
+        let id = id.as_deref()?;
+        refinements
+            .iter()
+            .find(|r| r.target == id && r.property == property)
+            .map(|r| r.value.clone())
+    };
+    // Creators without a role are authors, other roles such as illustrators are not
+    metadata.authors = creators
+        .iter()
+        .filter(|(id, role, _)| {
+            role.clone()
+                .or_else(|| refinement(id, "role"))
+                .is_none_or(|role| role == "aut")
+        })
+        .map(|(_, _, name)| name.clone())
+        .collect();
+    let series = collections
+        .iter()
+        .find(|(id, _)| refinement(id, "collection-type").is_none_or(|t| t == "series"))
+        .map(|(id, name)| (name.clone(), refinement(id, "group-position")))
+        .or_else(|| {
+            named
+                .get("calibre:series")
+                .map(|name| (name.clone(), named.get("calibre:series_index").cloned()))
+        });
+    if let Some((name, index)) = series {
+        metadata.series = non_empty(&name);
+        metadata.series_index = index.and_then(|index| index.trim().parse().ok());
+    }
+    Ok(file)
+}
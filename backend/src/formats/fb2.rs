@@ -0,0 +1,182 @@
+use super::{date, isbn, non_empty, FileMetadata, FormatReader};
+use crate::epub::{attribute, element_text, xhtml_text};
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::Read;
+use std::path::Path;
+
+/// Reads the `title-info` and `publish-info` of FictionBook files, plain (FB2) or zipped (FBZ),
+/// and the cover from the binaries
+pub struct Fb2Reader;
+
+impl FormatReader for Fb2Reader {
+    fn formats(&self) -> &'static [&'static str] {
+        &["FB2", "FBZ"]
+    }
+
+    fn read(&self, path: &Path) -> Result<FileMetadata> {
+        parse(&load(path)?).with_context(|| format!("{path:?} is not a valid FB2 file"))
+    }
+}
+
+/// The FB2 document of the FictionBook at `path`, unpacked and decoded
+pub(crate) fn load(path: &Path) -> Result<String> {
+    let data = std::fs::read(path).with_context(|| format!("Reading {path:?}"))?;
+    let data = if data.starts_with(b"PK") {
+        unzip(&data).with_context(|| format!("Unpacking {path:?}"))?
+    } else {
+        data
+    };
+    Ok(decode(&data))
+}
+
+/// The FB2 document inside a zipped FictionBook
+fn unzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    let name = archive
+        .file_names()
+        .find(|name| name.to_lowercase().ends_with(".fb2"))
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("The archive contains no FB2 file"))?;
+    let mut content = Vec::new();
+    archive.by_name(&name)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// The document as text in the encoding declared by it; many FB2 files use legacy encodings
+/// such as windows-1251
+fn decode(data: &[u8]) -> String {
+    let declaration = &data[..data.len().min(200)];
+    let declaration = String::from_utf8_lossy(declaration);
+    let label = declaration
+        .split_once("encoding=")
+        .and_then(|(_, rest)| {
+            let quote = rest.chars().next()?;
+            rest[quote.len_utf8()..].split(quote).next()
+        })
+        .unwrap_or("utf-8");
+    let encoding = encoding_rs::Encoding::for_label(label.as_bytes()).unwrap_or(encoding_rs::UTF_8);
+    encoding.decode(data).0.into_owned()
+}
+
+fn parse(document: &str) -> Result<FileMetadata> {
+    let mut reader = Reader::from_str(document);
+    let mut file = FileMetadata::default();
+    let metadata = &mut file.metadata;
+    // Names of the open elements below <description>
+    let mut path: Vec<String> = Vec::new();
+    let mut cover_id = None;
+    // Parts of the name of the author being read
+    let mut name: Vec<String> = Vec::new();
+    let mut nickname = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let local = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                let section = path.get(1).map(String::as_str);
+                match (section, local.as_str()) {
+                    (_, "binary") => {
+                        let id = attribute(&e, "id");
+                        let content = element_text(&mut reader)?;
+                        if id.is_some() && id == cover_id {
+                            let content: String =
+                                content.chars().filter(|c| !c.is_whitespace()).collect();
+                            file.cover = base64::engine::general_purpose::STANDARD
+                                .decode(content)
+                                .ok();
+                        }
+                        continue;
+                    }
+                    (Some("title-info"), "book-title") => {
+                        metadata.title = non_empty(&element_text(&mut reader)?);
+                        continue;
+                    }
+                    (Some("title-info"), "annotation") => {
+                        // The annotation is FB2 markup, similar enough to XHTML for its text
+                        let start = reader.buffer_position() as usize;
+                        element_text(&mut reader)?;
+                        let end = reader.buffer_position() as usize;
+                        // Without the end tag, which was consumed as well
+                        let content = &document[start..end];
+                        let content = &content[..content.rfind('<').unwrap_or(content.len())];
+                        let text = xhtml_text(content).unwrap_or_default();
+                        let paragraphs: Vec<&str> = text
+                            .lines()
+                            .map(str::trim)
+                            .filter(|l| !l.is_empty())
+                            .collect();
+                        metadata.description = non_empty(&paragraphs.join("\n\n"));
+                        continue;
+                    }
+                    (Some("title-info"), "genre") => {
+                        metadata.tags.extend(non_empty(&element_text(&mut reader)?));
+                        continue;
+                    }
+                    (Some("title-info"), "date") => {
+                        let text = element_text(&mut reader)?;
+                        metadata.date_published = attribute(&e, "value")
+                            .and_then(|value| date(&value))
+                            .or_else(|| date(&text));
+                        continue;
+                    }
+                    (Some("title-info"), "first-name" | "middle-name" | "last-name")
+                        if path.last().is_some_and(|p| p == "author") =>
+                    {
+                        name.extend(non_empty(&element_text(&mut reader)?));
+                        continue;
+                    }
+                    (Some("title-info"), "nickname")
+                        if path.last().is_some_and(|p| p == "author") =>
+                    {
+                        nickname = non_empty(&element_text(&mut reader)?);
+                        continue;
+                    }
+                    (Some("publish-info"), "isbn") => {
+                        metadata.isbn = isbn(&element_text(&mut reader)?);
+                        continue;
+                    }
+                    _ => {}
+                }
+                if !path.is_empty() || local == "description" {
+                    path.push(local);
+                }
+            }
+            Event::Empty(e) => {
+                let in_title_info = path.get(1).is_some_and(|p| p == "title-info");
+                match e.local_name().as_ref() {
+                    b"sequence" if in_title_info && metadata.series.is_none() => {
+                        metadata.series = attribute(&e, "name").and_then(|n| non_empty(&n));
+                        metadata.series_index =
+                            attribute(&e, "number").and_then(|n| n.trim().parse().ok());
+                    }
+                    b"image" if in_title_info && path.last().is_some_and(|p| p == "coverpage") => {
+                        cover_id = attribute(&e, "href")
+                            .map(|href| href.trim_start_matches('#').to_string());
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(e) => {
+                if path
+                    .last()
+                    .is_some_and(|p| p.as_bytes() == e.local_name().as_ref())
+                {
+                    let closed = path.pop();
+                    if closed.as_deref() == Some("author")
+                        && path.get(1).is_some_and(|p| p == "title-info")
+                    {
+                        let full_name = non_empty(&name.join(" ")).or(nickname.take());
+                        metadata.authors.extend(full_name);
+                        name.clear();
+                        nickname = None;
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(file)
+}
@@ -0,0 +1,135 @@
+use super::{date, isbn, non_empty, split_list, FileMetadata, FormatReader};
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+/// EXTH record types that are read
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_DESCRIPTION: u32 = 103;
+const EXTH_ISBN: u32 = 104;
+const EXTH_SUBJECT: u32 = 105;
+const EXTH_PUBLISHED: u32 = 106;
+const EXTH_ASIN: u32 = 113;
+const EXTH_COVER_OFFSET: u32 = 201;
+const EXTH_THUMBNAIL_OFFSET: u32 = 202;
+const EXTH_UPDATED_TITLE: u32 = 503;
+
+/// Text encoding of the MOBI header for UTF-8; anything else is CP1252
+const UTF8: u32 = 65001;
+
+/// Reads the EXTH records of MOBI files and of AZW3 (KF8) files, which share the header format.
+/// MOBI has no notion of series.
+pub struct MobiReader;
+
+impl FormatReader for MobiReader {
+    fn formats(&self) -> &'static [&'static str] {
+        &["MOBI", "AZW", "AZW3", "PRC"]
+    }
+
+    fn read(&self, path: &Path) -> Result<FileMetadata> {
+        let data = std::fs::read(path).with_context(|| format!("Reading {path:?}"))?;
+        parse(&data).with_context(|| format!("{path:?} is not a valid MOBI file"))
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Offsets of the records of the Palm database the file is made of
+fn record_offsets(data: &[u8]) -> Option<Vec<usize>> {
+    let count = u16_at(data, 76)?;
+    (0..count)
+        .map(|i| u32_at(data, 78 + i * 8).map(|offset| offset as usize))
+        .collect()
+}
+
+fn record<'a>(data: &'a [u8], offsets: &[usize], index: usize) -> Option<&'a [u8]> {
+    let start = *offsets.get(index)?;
+    let end = offsets.get(index + 1).copied().unwrap_or(data.len());
+    data.get(start..end)
+}
+
+fn parse(data: &[u8]) -> Result<FileMetadata> {
+    if data.get(60..68) != Some(b"BOOKMOBI") {
+        return Err(anyhow!("Missing BOOKMOBI signature"));
+    }
+    let offsets = record_offsets(data).ok_or_else(|| anyhow!("Truncated record list"))?;
+    let header = record(data, &offsets, 0).ok_or_else(|| anyhow!("Missing header record"))?;
+    if header.get(16..20) != Some(b"MOBI") {
+        return Err(anyhow!("Missing MOBI header"));
+    }
+    let header_length = u32_at(header, 20).unwrap_or(0) as usize;
+    let utf8 = u32_at(header, 28) == Some(UTF8);
+    let decode = |bytes: &[u8]| -> String {
+        if utf8 {
+            String::from_utf8_lossy(bytes).into_owned()
+        } else {
+            encoding_rs::WINDOWS_1252.decode(bytes).0.into_owned()
+        }
+    };
+
+    let mut file = FileMetadata::default();
+    let full_name = match (u32_at(header, 84), u32_at(header, 88)) {
+        (Some(offset), Some(length)) => header
+            .get(offset as usize..offset as usize + length as usize)
+            .map(decode),
+        _ => None,
+    };
+    // The name of the Palm database is a truncated title, the last resort
+    let database_name = decode(data[..32].split(|b| *b == 0).next().unwrap_or_default());
+    let first_image = u32_at(header, 108).map(|index| index as usize);
+    let has_exth = u32_at(header, 128).is_some_and(|flags| flags & 0x40 != 0);
+
+    let mut title = None;
+    let mut cover = None;
+    let mut thumbnail = None;
+    if has_exth {
+        let exth = header
+            .get(16 + header_length..)
+            .filter(|exth| exth.starts_with(b"EXTH"))
+            .ok_or_else(|| anyhow!("Missing EXTH header"))?;
+        let count = u32_at(exth, 8).unwrap_or(0);
+        let mut position = 12;
+        for _ in 0..count {
+            let (Some(kind), Some(length)) = (u32_at(exth, position), u32_at(exth, position + 4))
+            else {
+                break;
+            };
+            let Some(value) = exth.get(position + 8..position + length as usize) else {
+                break;
+            };
+            position += (length as usize).max(8);
+            let text = || decode(value);
+            let metadata = &mut file.metadata;
+            match kind {
+                EXTH_AUTHOR => metadata.authors.extend(non_empty(&text())),
+                EXTH_DESCRIPTION => metadata.description = non_empty(&text()),
+                EXTH_ISBN => metadata.isbn = isbn(&text()),
+                EXTH_SUBJECT => metadata.tags.extend(split_list(&text())),
+                EXTH_PUBLISHED => metadata.date_published = date(&text()),
+                EXTH_ASIN => file
+                    .identifiers
+                    .extend(non_empty(&text()).map(|asin| ("amazon".to_string(), asin))),
+                EXTH_COVER_OFFSET => cover = u32_at(value, 0),
+                EXTH_THUMBNAIL_OFFSET => thumbnail = u32_at(value, 0),
+                EXTH_UPDATED_TITLE => title = non_empty(&text()),
+                _ => {}
+            }
+        }
+    }
+    file.metadata.title = title
+        .or_else(|| full_name.as_deref().and_then(non_empty))
+        .or_else(|| non_empty(&database_name));
+    // Cover and thumbnail are given relative to the first image record; 0xFFFFFFFF means none
+    file.cover = first_image
+        .zip(cover.or(thumbnail).filter(|offset| *offset != u32::MAX))
+        .and_then(|(first, offset)| record(data, &offsets, first + offset as usize))
+        .map(<[u8]>::to_vec);
+    Ok(file)
+}
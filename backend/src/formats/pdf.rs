@@ -0,0 +1,196 @@
+use super::{date, isbn, non_empty, split_authors, split_list, FileMetadata, FormatReader};
+use anyhow::{Context, Result};
+use lopdf::{Dictionary, Document, Object};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// XMP properties that are read, by their usual prefixed name
+const XMP_PROPERTIES: &[&str] = &[
+    "dc:title",
+    "dc:creator",
+    "dc:description",
+    "dc:subject",
+    "dc:date",
+    "dc:identifier",
+    "pdf:Keywords",
+    "prism:isbn",
+    "calibre:series",
+    "calibreSI:series_index",
+];
+
+/// Reads the Info dictionary and XMP metadata stream of PDF files. XMP is preferred where both
+/// have a value, as it is the newer and more expressive of the two. PDFs carry no cover image,
+/// rendering the first page is left to the user.
+pub struct PdfReader;
+
+impl FormatReader for PdfReader {
+    fn formats(&self) -> &'static [&'static str] {
+        &["PDF"]
+    }
+
+    fn read(&self, path: &Path) -> Result<FileMetadata> {
+        let document = Document::load(path).with_context(|| format!("Reading PDF {path:?}"))?;
+        let info = info(&document);
+        let xmp = document
+            .catalog()
+            .ok()
+            .and_then(|catalog| xmp_packet(&document, catalog))
+            .map(|packet| parse_xmp(&packet))
+            .unwrap_or_default();
+        let xmp_first = |key: &str| xmp.get(key).and_then(|values| values.first().cloned());
+
+        let mut file = FileMetadata::default();
+        let metadata = &mut file.metadata;
+        metadata.title = xmp_first("dc:title")
+            .or_else(|| info.get("Title").cloned())
+            .filter(|title| plausible_title(title));
+        metadata.authors = match xmp.get("dc:creator") {
+            Some(creators) => creators.clone(),
+            None => info
+                .get("Author")
+                .map(|a| split_authors(a))
+                .unwrap_or_default(),
+        };
+        metadata.description = xmp_first("dc:description").or_else(|| info.get("Subject").cloned());
+        metadata.tags = match xmp.get("dc:subject") {
+            Some(subjects) => subjects.clone(),
+            None => xmp_first("pdf:Keywords")
+                .or_else(|| info.get("Keywords").cloned())
+                .map(|keywords| split_list(&keywords))
+                .unwrap_or_default(),
+        };
+        // The creation date of the file is not the publication date of the book, so only an
+        // explicit date is used
+        metadata.date_published = xmp_first("dc:date").and_then(|d| date(&d));
+        metadata.isbn = xmp_first("prism:isbn")
+            .into_iter()
+            .chain(xmp.get("dc:identifier").into_iter().flatten().cloned())
+            .find_map(|value| isbn(&value));
+        metadata.series = xmp_first("calibre:series");
+        metadata.series_index = xmp_first("calibreSI:series_index").and_then(|i| i.parse().ok());
+        metadata.number_of_pages = u32::try_from(document.get_pages().len())
+            .ok()
+            .filter(|pages| *pages > 0);
+        Ok(file)
+    }
+}
+
+/// Entries of the Info dictionary that are text
+fn info(document: &Document) -> HashMap<String, String> {
+    let Some(dictionary) = document
+        .trailer
+        .get(b"Info")
+        .ok()
+        .and_then(|info| document.dereference(info).ok())
+        .and_then(|(_, info)| info.as_dict().ok())
+    else {
+        return HashMap::new();
+    };
+    dictionary
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = lopdf::decode_text_string(value).ok()?;
+            Some((
+                String::from_utf8_lossy(key).into_owned(),
+                non_empty(&value)?,
+            ))
+        })
+        .collect()
+}
+
+/// The XMP packet referenced by the document catalog
+fn xmp_packet(document: &Document, catalog: &Dictionary) -> Option<String> {
+    let (_, object) = document.dereference(catalog.get(b"Metadata").ok()?).ok()?;
+    let Object::Stream(stream) = object else {
+        return None;
+    };
+    let content = stream
+        .decompressed_content()
+        .unwrap_or_else(|_| stream.content.clone());
+    Some(String::from_utf8_lossy(&content).into_owned())
+}
+
+/// Values of the known XMP properties, with one value per list item for lists such as
+/// `dc:creator`
+fn parse_xmp(packet: &str) -> HashMap<String, Vec<String>> {
+    let mut reader = Reader::from_str(packet);
+    let mut values: HashMap<String, Vec<String>> = HashMap::new();
+    // Names of the open elements
+    let mut open: Vec<String> = Vec::new();
+    // Text since the last tag, which is split into several events around entity references
+    let mut text = String::new();
+    loop {
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!("Stopped reading damaged XMP metadata: {e}");
+                break;
+            }
+        };
+        match &event {
+            Event::Text(e) => {
+                text.push_str(&e.decode().unwrap_or_default());
+                continue;
+            }
+            Event::GeneralRef(e) => {
+                let name = e.decode().unwrap_or_default();
+                if let Ok(resolved) = quick_xml::escape::unescape(&format!("&{name};")) {
+                    text.push_str(&resolved);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        // The innermost known property gets the text, e.g., dc:creator that of its rdf:li items
+        if let Some(value) = non_empty(&std::mem::take(&mut text)) {
+            if let Some(property) = open
+                .iter()
+                .rev()
+                .find(|name| XMP_PROPERTIES.contains(&name.as_str()))
+            {
+                values.entry(property.clone()).or_default().push(value);
+            }
+        }
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e)
+                if e.local_name().as_ref() == b"Description" =>
+            {
+                // Simple properties may be written as attributes of rdf:Description
+                for a in e.attributes().flatten() {
+                    let key = String::from_utf8_lossy(a.key.as_ref()).into_owned();
+                    if XMP_PROPERTIES.contains(&key.as_str()) {
+                        let value = String::from_utf8_lossy(&a.value);
+                        let value = quick_xml::escape::unescape(&value)
+                            .map(|v| v.into_owned())
+                            .unwrap_or_else(|_| value.into_owned());
+                        values.entry(key).or_default().extend(non_empty(&value));
+                    }
+                }
+                if matches!(event, Event::Start(_)) {
+                    open.push(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                }
+            }
+            Event::Start(e) => open.push(String::from_utf8_lossy(e.name().as_ref()).into_owned()),
+            Event::End(_) => {
+                open.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    values
+}
+
+/// Whether a title from a PDF is a title of the book rather than a leftover of the program
+/// that produced it, such as "Microsoft Word - draft.docx"
+fn plausible_title(title: &str) -> bool {
+    let lower = title.to_lowercase();
+    !lower.starts_with("microsoft word - ")
+        && ![
+            ".doc", ".docx", ".pdf", ".indd", ".qxd", ".tex", ".dvi", ".odt",
+        ]
+        .iter()
+        .any(|extension| lower.ends_with(extension))
+}
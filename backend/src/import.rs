@@ -0,0 +1,83 @@
+pub mod calibre;
+pub mod calibre_web;
+pub mod files;
+pub mod goodreads;
+pub mod reading_log;
+
+use crate::jobs::JobContext;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, Utc};
+use shared::types::ImportReport;
+use std::path::Path;
+
+/// How book files of an imported library end up in ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileTransfer {
+    Copy,
+    /// Hard link the files, falling back to copying where that's impossible (e.g., across
+    /// file systems)
+    HardLink,
+}
+
+impl FileTransfer {
+    pub fn apply(self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent).with_context(|| format!("Creating {parent:?}"))?;
+        }
+        if self == FileTransfer::HardLink && std::fs::hard_link(from, to).is_ok() {
+            return Ok(());
+        }
+        std::fs::copy(from, to).with_context(|| format!("Copying {from:?} to {to:?}"))?;
+        Ok(())
+    }
+}
+
+/// Save the report as JSON into `<library>/reports` and remember where it went
+pub fn write_report(library_dir: &Path, kind: &str, report: &mut ImportReport) -> Result<()> {
+    let dir = library_dir.join("reports");
+    std::fs::create_dir_all(&dir).with_context(|| format!("Creating {dir:?}"))?;
+    let path = dir.join(format!(
+        "{kind}-import-{}.json",
+        Utc::now().format("%Y%m%d-%H%M%S")
+    ));
+    report.report_path = Some(path.clone());
+    std::fs::write(&path, serde_json::to_string_pretty(report)?)
+        .with_context(|| format!("Writing import report {path:?}"))?;
+    Ok(())
+}
+
+/// Copy what the import of a background job did into the log of the job
+pub(crate) fn log_report(ctx: &JobContext, report: &ImportReport) {
+    for (kind, count) in &report.imported {
+        ctx.log(format!("Imported {count} {kind}"));
+    }
+    for warning in &report.warnings {
+        ctx.log(warning.as_str());
+    }
+    if let Some(path) = &report.report_path {
+        ctx.log(format!("Saved the report to {path:?}"));
+    }
+}
+
+/// Parse the timestamp formats found in Calibre and export files. Calibre marks unknown
+/// publication dates with the year 101, which is treated as missing.
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    let parsed = DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f%:z"))
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+                .ok()
+                .map(|dt| dt.and_utc())
+        })
+        .or_else(|| {
+            ["%Y-%m-%d", "%Y/%m/%d", "%d.%m.%Y", "%m/%d/%Y"]
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|dt| dt.and_utc())
+        })?;
+    (parsed.year() > 101).then_some(parsed)
+}
@@ -0,0 +1,505 @@
+use crate::automation;
+use crate::books::{self, NewBook};
+use crate::import::{self, parse_timestamp, FileTransfer};
+use crate::jobs::JobContext;
+use crate::pool::Pool;
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use shared::types::{ImportReport, RuleTrigger};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Calibre tables that have no counterpart in IronScribe; their contents are only reported
+const UNMAPPED_LINK_TABLES: &[(&str, &str)] = &[
+    ("books_publishers_link", "publisher"),
+    ("books_ratings_link", "rating"),
+    ("books_languages_link", "language"),
+];
+
+struct CalibreBook {
+    id: i64,
+    title: String,
+    sort: Option<String>,
+    timestamp: Option<String>,
+    pubdate: Option<String>,
+    last_modified: Option<String>,
+    series_index: f64,
+    path: String,
+    has_cover: bool,
+    uuid: Option<String>,
+}
+
+/// A book that was written to the database and whose files still need to be transferred
+struct ImportedBook {
+    /// Directory of the book in the Calibre library
+    path: String,
+    /// Directory of the book in ours, the same unless that was taken
+    target: String,
+    has_cover: bool,
+    files: Vec<String>,
+}
+
+/// What tells the Calibre library with the database `calibre` in `calibre_dir` apart from
+/// others: the UUID Calibre gives each library, or the path of libraries without one
+pub(crate) fn library_source(calibre: &Connection, calibre_dir: &Path) -> Result<String> {
+    let uuid: Option<String> = calibre
+        .query_row("SELECT uuid FROM library_id LIMIT 1", [], |row| row.get(0))
+        .optional()
+        .unwrap_or_default();
+    match uuid.filter(|uuid| !uuid.is_empty()) {
+        Some(uuid) => Ok(uuid),
+        None => Ok(calibre_dir
+            .canonicalize()
+            .with_context(|| format!("Reading {calibre_dir:?}"))?
+            .display()
+            .to_string()),
+    }
+}
+
+/// Import the Calibre library in `calibre_dir` (the directory containing `metadata.db`).
+///
+/// All metadata is written in a single transaction; the book files and covers are transferred
+/// afterwards, without holding the write connection of `pool`, keeping Calibre's directory layout
+/// where no other book has the directory yet, as happens with a second Calibre library. Books
+/// that were imported from the same Calibre library before are skipped, as are those whose
+/// mapping was imported before it was known which library they came from. The rules for imported
+/// books run once the files are transferred.
+pub fn import(
+    pool: &Pool,
+    calibre_dir: &Path,
+    library_dir: &Path,
+    transfer: FileTransfer,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::new(calibre_dir.to_path_buf());
+    import_library(
+        pool,
+        calibre_dir,
+        library_dir,
+        transfer,
+        &mut report,
+        &mut |_, _| {},
+    )?;
+    Ok(report)
+}
+
+/// See [`import`]. `progress` is told how many of how many books had their files transferred.
+fn import_library(
+    pool: &Pool,
+    calibre_dir: &Path,
+    library_dir: &Path,
+    transfer: FileTransfer,
+    report: &mut ImportReport,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<()> {
+    let (imported, ours) =
+        pool.write(|conn| write_books(conn, calibre_dir, library_dir, report))?;
+    transfer_files(
+        &imported,
+        calibre_dir,
+        library_dir,
+        transfer,
+        report,
+        progress,
+    );
+    pool.read(|conn| {
+        for book in ours {
+            automation::emit(conn, RuleTrigger::BookImported, book, None);
+        }
+        Ok(())
+    })
+}
+
+/// Run the import of the Calibre library in `calibre_dir` as a background job, see [`import`].
+/// The job can only be cancelled before the books are written, as their files would be missing
+/// after. The report is saved and copied into the log of the job.
+pub(crate) fn run_job(ctx: &JobContext, calibre_dir: &Path, link_files: bool) -> Result<()> {
+    ctx.check_cancelled()?;
+    let library_dir = ctx.library().dir();
+    let transfer = if link_files {
+        FileTransfer::HardLink
+    } else {
+        FileTransfer::Copy
+    };
+    let mut report = ImportReport::new(calibre_dir.to_path_buf());
+    let result = import_library(
+        ctx.library().pool(),
+        calibre_dir,
+        library_dir,
+        transfer,
+        &mut report,
+        &mut |done, total| ctx.progress(done, total),
+    );
+    import::write_report(library_dir, "calibre", &mut report)?;
+    import::log_report(ctx, &report);
+    result
+}
+
+/// Write the books of the Calibre library in `calibre_dir` to the database, see [`import`],
+/// returning the books whose files are to be transferred and the IDs of all new books
+fn write_books(
+    conn: &mut Connection,
+    calibre_dir: &Path,
+    library_dir: &Path,
+    report: &mut ImportReport,
+) -> Result<(Vec<ImportedBook>, Vec<i64>)> {
+    let metadata_path = calibre_dir.join("metadata.db");
+    if !metadata_path.is_file() {
+        return Err(anyhow!("No Calibre library found at {calibre_dir:?}"));
+    }
+    let calibre = Connection::open_with_flags(&metadata_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Opening Calibre database {metadata_path:?}"))?;
+    let source = library_source(&calibre, calibre_dir)?;
+
+    let tx = conn.transaction()?;
+    let authors = import_names(&calibre, &tx, "authors", report, |tx, name, sort| {
+        // Calibre stores commas in author names as '|'
+        books::author_id(tx, &name.replace('|', ","), sort)
+    })?;
+    let series = import_names(&calibre, &tx, "series", report, |tx, name, sort| {
+        books::series_id(tx, name, sort)
+    })?;
+    let tags = import_names(&calibre, &tx, "tags", report, |tx, name, _| {
+        books::tag_id(tx, name)
+    })?;
+
+    let mut book_ids = HashMap::new();
+    // Books whose Calibre directory is taken get one after their title and author
+    let mut relocated = Vec::new();
+    for book in read_books(&calibre)? {
+        let already_imported: Option<i64> = tx
+            .query_row(
+                "SELECT book FROM calibre_book_ids WHERE source IN (?1, '') AND calibre_id = ?2",
+                params![source, book.id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(existing) = already_imported {
+            report.warn(format!(
+                "Calibre book {} ({:?}) was already imported as book {existing}; skipped",
+                book.id, book.title
+            ));
+            continue;
+        }
+
+        let taken = library_dir.join(&book.path).exists()
+            || tx
+                .query_row("SELECT 1 FROM books WHERE path = ?1", [&book.path], |_| {
+                    Ok(())
+                })
+                .optional()?
+                .is_some();
+        let id = books::insert_book(
+            &tx,
+            &NewBook {
+                title: book.title.clone(),
+                sort: book.sort.clone(),
+                date_added: book.timestamp.as_deref().and_then(parse_timestamp),
+                date_published: book.pubdate.as_deref().and_then(parse_timestamp),
+                last_modified: book.last_modified.as_deref().and_then(parse_timestamp),
+                path: if taken {
+                    String::new()
+                } else {
+                    book.path.clone()
+                },
+                has_cover: book.has_cover,
+                uuid: book.uuid.clone(),
+                ..Default::default()
+            },
+        )?;
+        tx.execute(
+            "INSERT INTO calibre_book_ids (source, calibre_id, book) VALUES (?1, ?2, ?3)",
+            params![source, book.id, id],
+        )?;
+        if taken {
+            relocated.push(id);
+        }
+        book_ids.insert(book.id, (id, book));
+    }
+    report.count("books", book_ids.len());
+
+    for (calibre_book, author) in read_pairs(&calibre, "books_authors_link", "author")? {
+        if let (Some((book, _)), Some(author)) = (book_ids.get(&calibre_book), authors.get(&author))
+        {
+            books::link_author(&tx, *book, *author)?;
+        }
+    }
+    for (calibre_book, calibre_series) in read_pairs(&calibre, "books_series_link", "series")? {
+        let (Some((book, source)), Some(series_id)) =
+            (book_ids.get(&calibre_book), series.get(&calibre_series))
+        else {
+            continue;
+        };
+        if !books::link_series(&tx, *book, *series_id, source.series_index)? {
+            report.warn(format!(
+                "{:?} could not be added to its series as #{}, another book already has that number",
+                source.title, source.series_index
+            ));
+        }
+    }
+    for (calibre_book, tag) in read_pairs(&calibre, "books_tags_link", "tag")? {
+        if let (Some((book, _)), Some(tag)) = (book_ids.get(&calibre_book), tags.get(&tag)) {
+            books::link_tag(&tx, *book, *tag)?;
+        }
+    }
+
+    let mut stmt = calibre.prepare("SELECT book, type, val FROM identifiers")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (calibre_book, kind, value) = row?;
+        if let Some((book, _)) = book_ids.get(&calibre_book) {
+            books::set_identifier(&tx, *book, &kind, &value)?;
+            report.count("identifiers", 1);
+        }
+    }
+
+    let mut stmt = calibre.prepare("SELECT book, text FROM comments")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (calibre_book, text) = row?;
+        if let Some((book, _)) = book_ids.get(&calibre_book) {
+            books::set_comment(&tx, *book, &text)?;
+            report.count("comments", 1);
+        }
+    }
+
+    let mut files: HashMap<i64, Vec<String>> = HashMap::new();
+    let mut stmt = calibre.prepare("SELECT book, format, name, uncompressed_size FROM data")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)?,
+        ))
+    })?;
+    for row in rows {
+        let (calibre_book, format, name, size) = row?;
+        if let Some((book, _)) = book_ids.get(&calibre_book) {
+            books::add_format(&tx, *book, &format, &name, size.max(0) as u64)?;
+            files
+                .entry(calibre_book)
+                .or_default()
+                .push(format!("{name}.{}", format.to_lowercase()));
+        }
+    }
+
+    let calibre_to_ours: HashMap<i64, i64> = book_ids
+        .iter()
+        .map(|(calibre, (ours, _))| (*calibre, *ours))
+        .collect();
+    import_custom_columns(&calibre, &tx, &calibre_to_ours, report)?;
+    report_unmapped(&calibre, report)?;
+    for book in relocated {
+        // Only assigns the directory, the files are transferred below
+        books::rename_files(&tx, library_dir, book)?;
+    }
+    let mut imported = Vec::new();
+    for (calibre_book, (ours, book)) in &book_ids {
+        imported.push(ImportedBook {
+            path: book.path.clone(),
+            target: tx.query_row("SELECT path FROM books WHERE id = ?1", [ours], |row| {
+                row.get(0)
+            })?,
+            has_cover: book.has_cover,
+            files: files.remove(calibre_book).unwrap_or_default(),
+        });
+    }
+    tx.commit()?;
+
+    let ours = book_ids.values().map(|(ours, _)| *ours).collect();
+    Ok((imported, ours))
+}
+
+fn read_books(calibre: &Connection) -> Result<Vec<CalibreBook>> {
+    let mut stmt = calibre.prepare(
+        "SELECT id, title, sort, timestamp, pubdate, last_modified, series_index, path, has_cover, uuid FROM books ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(CalibreBook {
+            id: row.get("id")?,
+            title: row.get("title")?,
+            sort: row.get("sort")?,
+            timestamp: row.get("timestamp")?,
+            pubdate: row.get("pubdate")?,
+            last_modified: row.get("last_modified")?,
+            series_index: row.get::<_, Option<f64>>("series_index")?.unwrap_or(1.0),
+            path: row.get("path")?,
+            has_cover: row.get::<_, Option<bool>>("has_cover")?.unwrap_or(false),
+            uuid: row.get("uuid")?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Import a Calibre name table (authors, series, tags), returning a map from Calibre IDs to ours
+fn import_names<F>(
+    calibre: &Connection,
+    conn: &Connection,
+    table: &str,
+    report: &mut ImportReport,
+    mut get_or_create: F,
+) -> Result<HashMap<i64, i64>>
+where
+    F: FnMut(&Connection, &str, Option<&str>) -> Result<i64>,
+{
+    let has_sort = table != "tags";
+    let query = if has_sort {
+        format!("SELECT id, name, sort FROM {table}")
+    } else {
+        format!("SELECT id, name, NULL FROM {table}")
+    };
+    let mut stmt = calibre.prepare(&query)?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+        ))
+    })?;
+    let mut ids = HashMap::new();
+    for row in rows {
+        let (calibre_id, name, sort) = row?;
+        ids.insert(calibre_id, get_or_create(conn, &name, sort.as_deref())?);
+    }
+    report.count(table, ids.len());
+    Ok(ids)
+}
+
+/// Pairs of (book, other) from one of Calibre's `books_*_link` tables
+fn read_pairs(calibre: &Connection, table: &str, column: &str) -> Result<Vec<(i64, i64)>> {
+    let mut stmt = calibre.prepare(&format!("SELECT book, {column} FROM {table} ORDER BY id"))?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Copy Calibre's custom column definitions and their values. Values are stored as text, which
+/// covers every data type except the computed "composite" columns, which are skipped.
+fn import_custom_columns(
+    calibre: &Connection,
+    conn: &Connection,
+    book_ids: &HashMap<i64, i64>,
+    report: &mut ImportReport,
+) -> Result<()> {
+    let mut stmt = calibre.prepare(
+        "SELECT id, label, name, datatype, is_multiple, normalized FROM custom_columns WHERE mark_for_delete = 0",
+    )?;
+    let columns = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, bool>(4)?,
+                row.get::<_, bool>(5)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (calibre_id, label, name, datatype, is_multiple, normalized) in columns {
+        if datatype == "composite" {
+            report.warn(format!(
+                "Custom column {label:?} is computed from other columns and was not imported"
+            ));
+            continue;
+        }
+        conn.execute(
+            "INSERT OR IGNORE INTO custom_columns (label, name, datatype, is_multiple) VALUES (?1, ?2, ?3, ?4)",
+            params![label, name, datatype, is_multiple],
+        )?;
+        let column_id: i64 = conn.query_row(
+            "SELECT id FROM custom_columns WHERE label = ?1",
+            [&label],
+            |row| row.get(0),
+        )?;
+
+        let query = if normalized {
+            format!(
+                "SELECT l.book, CAST(v.value AS TEXT) FROM books_custom_column_{calibre_id}_link l JOIN custom_column_{calibre_id} v ON v.id = l.value"
+            )
+        } else {
+            format!("SELECT book, CAST(value AS TEXT) FROM custom_column_{calibre_id}")
+        };
+        let mut stmt = match calibre.prepare(&query) {
+            Ok(stmt) => stmt,
+            Err(e) => {
+                report.warn(format!(
+                    "Values of custom column {label:?} could not be read: {e}"
+                ));
+                continue;
+            }
+        };
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?))
+        })?;
+        let mut count = 0;
+        for row in rows {
+            let (calibre_book, value) = row?;
+            if let (Some(book), Some(value)) = (book_ids.get(&calibre_book), value) {
+                conn.execute(
+                    "INSERT OR IGNORE INTO books_custom_column_values (book, custom_column, value) VALUES (?1, ?2, ?3)",
+                    params![book, column_id, value],
+                )?;
+                count += 1;
+            }
+        }
+        report.count("custom column values", count);
+    }
+    Ok(())
+}
+
+fn report_unmapped(calibre: &Connection, report: &mut ImportReport) -> Result<()> {
+    for (table, what) in UNMAPPED_LINK_TABLES {
+        let count: Option<i64> = calibre
+            .query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .unwrap_or(None);
+        if let Some(count) = count.filter(|c| *c > 0) {
+            report.warn(format!(
+                "{count} {what} assignments were not imported, IronScribe doesn't track the {what} of a book"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn transfer_files(
+    books: &[ImportedBook],
+    calibre_dir: &Path,
+    library_dir: &Path,
+    transfer: FileTransfer,
+    report: &mut ImportReport,
+    progress: &mut dyn FnMut(usize, usize),
+) {
+    for (done, book) in books.iter().enumerate() {
+        progress(done, books.len());
+        let source_dir = calibre_dir.join(&book.path);
+        let target_dir = library_dir.join(&book.target);
+        let mut names = book.files.clone();
+        if book.has_cover {
+            names.push("cover.jpg".to_string());
+        }
+        for name in names {
+            let (from, to) = (source_dir.join(&name), target_dir.join(&name));
+            if to.exists() {
+                report.warn(format!("{to:?} already exists and was not overwritten"));
+                continue;
+            }
+            match transfer.apply(&from, &to) {
+                Ok(()) if name == "cover.jpg" => report.count("covers", 1),
+                Ok(()) => report.count("files", 1),
+                Err(e) => report.warn(format!("{e:#}")),
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::books;
+use crate::import::calibre;
+use crate::import::parse_timestamp;
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use shared::types::ImportReport;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// Values of `book_read_link.read_status`
+const STATUS_FINISHED: i64 = 1;
+const STATUS_IN_PROGRESS: i64 = 2;
+
+/// Import the per-user read states and shelves of a Calibre-Web `app.db` (or the directory
+/// containing it).
+///
+/// Calibre-Web refers to books by their Calibre IDs, so the Calibre library it belongs to has to
+/// be imported first. If several were, the one Calibre-Web is configured with is used. Entries
+/// for books that weren't imported are reported and skipped.
+pub fn import(conn: &mut Connection, path: &Path) -> Result<ImportReport> {
+    let app_db = if path.is_dir() {
+        path.join("app.db")
+    } else {
+        path.to_path_buf()
+    };
+    if !app_db.is_file() {
+        return Err(anyhow!("No Calibre-Web database found at {path:?}"));
+    }
+    let web = Connection::open_with_flags(&app_db, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Opening Calibre-Web database {app_db:?}"))?;
+    let mut report = ImportReport::new(app_db.clone());
+
+    let tx = conn.transaction()?;
+    let sources: Vec<String> = {
+        let mut stmt = tx.prepare("SELECT DISTINCT source FROM calibre_book_ids")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<Result<_, _>>()?
+    };
+    let source = match sources.as_slice() {
+        [] => {
+            return Err(anyhow!(
+                "No Calibre books have been imported into this library yet, import the Calibre library first"
+            ))
+        }
+        [only] => only.clone(),
+        _ => calibre_source(&web)?,
+    };
+    let book_ids: HashMap<i64, i64> = {
+        let mut stmt =
+            tx.prepare("SELECT calibre_id, book FROM calibre_book_ids WHERE source IN (?1, '')")?;
+        let rows = stmt.query_map([&source], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let users = import_users(&web, &tx, &mut report)?;
+    let mut missing_books = BTreeSet::new();
+    import_read_states(
+        &web,
+        &tx,
+        &book_ids,
+        &users,
+        &mut missing_books,
+        &mut report,
+    )?;
+    import_shelves(
+        &web,
+        &tx,
+        &book_ids,
+        &users,
+        &mut missing_books,
+        &mut report,
+    )?;
+    if !missing_books.is_empty() {
+        let ids: Vec<String> = missing_books.iter().map(i64::to_string).collect();
+        report.warn(format!(
+            "{} Calibre books referenced by Calibre-Web were not found in this library and were skipped (Calibre IDs {})",
+            ids.len(),
+            ids.join(", ")
+        ));
+    }
+    tx.commit()?;
+    Ok(report)
+}
+
+/// The source of the Calibre library Calibre-Web is configured with, see
+/// [`calibre::library_source`]
+fn calibre_source(web: &Connection) -> Result<String> {
+    let dir: Option<String> = web
+        .query_row(
+            "SELECT config_calibre_dir FROM settings LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Reading the Calibre library of Calibre-Web")?
+        .flatten();
+    let dir = dir.ok_or_else(|| {
+        anyhow!("Several Calibre libraries were imported, and Calibre-Web doesn't tell which one it uses")
+    })?;
+    let dir = Path::new(&dir);
+    let calibre = Connection::open_with_flags(
+        dir.join("metadata.db"),
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )
+    .with_context(|| {
+        format!("Several Calibre libraries were imported, and the one Calibre-Web uses at {dir:?} can't be opened to tell which")
+    })?;
+    calibre::library_source(&calibre, dir)
+}
+
+/// Map Calibre-Web user IDs to ours, creating users by name
+fn import_users(
+    web: &Connection,
+    conn: &Connection,
+    report: &mut ImportReport,
+) -> Result<HashMap<i64, i64>> {
+    let mut stmt = web.prepare("SELECT id, name FROM user")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    let mut users = HashMap::new();
+    for row in rows {
+        let (web_id, name) = row?;
+        users.insert(web_id, books::user_id(conn, &name)?);
+    }
+    report.count("users", users.len());
+    Ok(users)
+}
+
+fn import_read_states(
+    web: &Connection,
+    conn: &Connection,
+    book_ids: &HashMap<i64, i64>,
+    users: &HashMap<i64, i64>,
+    missing_books: &mut BTreeSet<i64>,
+    report: &mut ImportReport,
+) -> Result<()> {
+    // Older Calibre-Web versions don't record when reading started
+    let started = if has_column(web, "book_read_link", "last_time_started_reading")? {
+        "last_time_started_reading"
+    } else {
+        "NULL"
+    };
+    let mut stmt = web.prepare(&format!(
+        "SELECT book_id, user_id, read_status, CAST({started} AS TEXT), CAST(last_modified AS TEXT) FROM book_read_link"
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, Option<String>>(4)?,
+        ))
+    })?;
+    for row in rows {
+        let (calibre_book, web_user, status, started, modified) = row?;
+        if status != STATUS_FINISHED && status != STATUS_IN_PROGRESS {
+            continue;
+        }
+        let Some(book) = book_ids.get(&calibre_book) else {
+            missing_books.insert(calibre_book);
+            continue;
+        };
+        let Some(user) = users.get(&web_user) else {
+            report.warn(format!(
+                "Read state of Calibre book {calibre_book} belongs to unknown Calibre-Web user {web_user}; skipped"
+            ));
+            continue;
+        };
+        let modified = modified.as_deref().and_then(parse_timestamp);
+        let end_date = (status == STATUS_FINISHED).then_some(modified).flatten();
+        let start_date = started.as_deref().and_then(parse_timestamp).or(modified);
+
+        // Importing the same app.db twice must not duplicate readings
+        let exists = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM read_books WHERE book = ?1 AND user = ?2 AND end_date IS ?3)",
+            params![book, user, end_date],
+            |row| row.get::<_, bool>(0),
+        )?;
+        if exists {
+            continue;
+        }
+        books::add_reading(conn, *book, Some(*user), start_date, end_date, None)?;
+        if status == STATUS_FINISHED {
+            report.count("finished books", 1);
+        } else {
+            report.count("books in progress", 1);
+        }
+    }
+    Ok(())
+}
+
+/// Shelves become collections of the user that owns them
+fn import_shelves(
+    web: &Connection,
+    conn: &Connection,
+    book_ids: &HashMap<i64, i64>,
+    users: &HashMap<i64, i64>,
+    missing_books: &mut BTreeSet<i64>,
+    report: &mut ImportReport,
+) -> Result<()> {
+    let mut stmt = web.prepare("SELECT id, name, is_public, user_id FROM shelf")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<bool>>(2)?.unwrap_or(false),
+            row.get::<_, Option<i64>>(3)?,
+        ))
+    })?;
+    let mut collections = HashMap::new();
+    for row in rows {
+        let (shelf, name, is_public, web_user) = row?;
+        let user = web_user.and_then(|u| users.get(&u).copied());
+        collections.insert(shelf, books::collection_id(conn, &name, user, is_public)?);
+    }
+    report.count("collections", collections.len());
+
+    let mut stmt = web.prepare(
+        r#"SELECT book_id, shelf, "order", CAST(date_added AS TEXT) FROM book_shelf_link"#,
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+            row.get::<_, Option<String>>(3)?,
+        ))
+    })?;
+    for row in rows {
+        let (calibre_book, shelf, position, date_added) = row?;
+        let Some(book) = book_ids.get(&calibre_book) else {
+            missing_books.insert(calibre_book);
+            continue;
+        };
+        let Some(collection) = collections.get(&shelf) else {
+            report.warn(format!(
+                "Calibre book {calibre_book} is on unknown shelf {shelf}; skipped"
+            ));
+            continue;
+        };
+        let date_added = date_added.as_deref().and_then(parse_timestamp);
+        if books::add_to_collection(conn, *book, *collection, position, date_added)? {
+            report.count("collection entries", 1);
+        }
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>("name"))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,446 @@
+use crate::automation;
+use crate::books::{self, NewBook};
+use crate::config::ConfigInterface;
+use crate::covers;
+use crate::duplicates::{self, Duplicate, Fingerprint};
+use crate::filenames::{self, FilenamePatterns};
+use crate::formats::{self, FileMetadata};
+use crate::import::{self, FileTransfer};
+use crate::jobs::JobContext;
+use crate::matching::BookIndex;
+use crate::normalize;
+use crate::pool::Pool;
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::{AppConfig, DuplicateAction, FormattingConfig, ImportReport, RuleTrigger};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Book files below `dir` in a format a reader exists for, in name order
+fn book_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Reading {dir:?}"))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            book_files(&path, files)?;
+        } else if formats::format_of(&path).is_some_and(|format| formats::reader(&format).is_some())
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Fill in what the file didn't tell, or told only placeholders for: the fields the first
+/// matching file name pattern extracts, and the title from the file name as last resort. Books
+/// without any author get "Unknown", like their file names, since books are listed by author.
+fn fill_fallbacks(file: &mut FileMetadata, path: &Path, patterns: &FilenamePatterns) {
+    let metadata = &mut file.metadata;
+    if metadata.title.as_deref().is_some_and(filenames::is_junk) {
+        metadata.title = None;
+    }
+    metadata
+        .authors
+        .retain(|author| !filenames::is_junk(author));
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if let Some(parsed) = patterns.parse(&name) {
+        if metadata.title.is_none() {
+            metadata.title = parsed.title;
+        }
+        if metadata.authors.is_empty() {
+            metadata.authors = parsed.authors;
+        }
+        if metadata.series.is_none() {
+            metadata.series = parsed.series;
+            metadata.series_index = parsed.series_index;
+        }
+        metadata.isbn = metadata.isbn.take().or(parsed.isbn);
+        metadata.date_published = metadata.date_published.or(parsed.date_published);
+    }
+    if metadata.title.is_none() {
+        let name = filenames::file_name(&name);
+        metadata.title = Some(name).filter(|t| !t.is_empty());
+    }
+    if metadata.title.is_none() {
+        metadata.title = Some("Unknown".to_string());
+    }
+    if metadata.authors.is_empty() {
+        metadata.authors.push("Unknown".to_string());
+    }
+    let mut seen = HashSet::new();
+    metadata
+        .authors
+        .retain(|author| seen.insert(author.clone()));
+    metadata.tags.sort();
+    metadata.tags.dedup();
+}
+
+/// Whether another book already has the entry of the series that `file` claims
+fn series_entry_taken(conn: &Connection, file: &FileMetadata) -> Result<bool> {
+    let Some(series) = &file.metadata.series else {
+        return Ok(false);
+    };
+    let entry = file.metadata.series_index.unwrap_or(1.0);
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM books_series_link bsl JOIN series s ON s.id = bsl.series
+             WHERE s.name = ?1 AND bsl.entry = ?2",
+            params![series, entry],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// How the files of an import are imported
+struct Options<'a> {
+    library_dir: &'a Path,
+    transfer: FileTransfer,
+    patterns: FilenamePatterns,
+    on_duplicate: DuplicateAction,
+    formatting: Option<&'a FormattingConfig>,
+}
+
+/// Normalize the formatting of the EPUB just imported for `book` if the options ask for it. The
+/// file is rewritten into a new one, so a hard linked source stays as it was.
+fn format_epub(
+    conn: &Connection,
+    path: &Path,
+    options: &Options,
+    book: i64,
+    format: &str,
+    report: &mut ImportReport,
+) {
+    let Some(formatting) = options.formatting.filter(|_| format == "EPUB") else {
+        return;
+    };
+    match normalize::normalize(conn, options.library_dir, book, formatting) {
+        Ok(true) => report.count("formatted", 1),
+        Ok(false) => {}
+        Err(e) => report.warn(format!(
+            "{path:?}: could not normalize the formatting: {e:#}"
+        )),
+    }
+}
+
+/// Transfer the book file at `path` to `target`, the file of `book` in `format` whose row was
+/// committed before, and record its hash. The transferred file is removed again if recording
+/// fails.
+fn transfer_file(
+    conn: &Connection,
+    path: &Path,
+    target: &Path,
+    options: &Options,
+    book: i64,
+    format: &str,
+    sha256: &str,
+) -> Result<()> {
+    options.transfer.apply(path, target)?;
+    let recorded = Fingerprint::of(target).and_then(|fingerprint| {
+        let fingerprint = Fingerprint {
+            sha256: Some(sha256.to_string()),
+            ..fingerprint
+        };
+        duplicates::record_hash(conn, book, format, &fingerprint)
+    });
+    if recorded.is_err() {
+        let _ = std::fs::remove_file(target);
+    }
+    recorded
+}
+
+/// Add the book file at `path` to the existing book `duplicate` as another format, returning
+/// what was imported as kind for the report
+fn merge_file(
+    conn: &mut Connection,
+    path: &Path,
+    options: &Options,
+    file: &FileMetadata,
+    sha256: &str,
+    duplicate: &Duplicate,
+    report: &mut ImportReport,
+) -> Result<&'static str> {
+    let format = formats::format_of(path).unwrap_or_default();
+    let book = duplicate.book;
+    let has_format = conn
+        .query_row(
+            "SELECT 1 FROM data WHERE book = ?1 AND format = ?2",
+            params![book, format],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if has_format {
+        report.warn(format!(
+            "{path:?}: skipped, book {book} {:?} ({}) has a {format} file already",
+            duplicate.title, duplicate.reason
+        ));
+        return Ok("duplicates");
+    }
+
+    let tx = conn.transaction()?;
+    let (dir, has_cover): (String, bool) = tx.query_row(
+        "SELECT path, has_cover FROM books WHERE id = ?1",
+        [book],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let author: Option<String> = tx
+        .query_row(
+            "SELECT a.name FROM books_authors_link bal JOIN authors a ON a.id = bal.author
+             WHERE bal.book = ?1 ORDER BY bal.rowid LIMIT 1",
+            [book],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let name = books::book_file_name(&duplicate.title, author.as_deref());
+    let target = options
+        .library_dir
+        .join(&dir)
+        .join(format!("{name}.{}", format.to_lowercase()));
+    let size = std::fs::metadata(path)
+        .with_context(|| format!("Reading {path:?}"))?
+        .len();
+    books::add_format(&tx, book, &format, &name, size)?;
+    tx.commit()?;
+
+    // Like for new books, the file is only transferred once its row is committed
+    if let Err(e) = transfer_file(conn, path, &target, options, book, &format, sha256) {
+        conn.execute(
+            "DELETE FROM data WHERE book = ?1 AND format = ?2",
+            params![book, format],
+        )?;
+        return Err(e);
+    }
+    if let (Some(cover), false) = (&file.cover, has_cover) {
+        match covers::save(conn, options.library_dir, book, cover, None) {
+            Ok(()) => report.count("covers", 1),
+            Err(e) => report.warn(format!("{path:?}: the embedded cover is unusable: {e:#}")),
+        }
+    }
+    format_epub(conn, path, options, book, &format, report);
+    Ok("formats")
+}
+
+/// Add the book file at `path` as a new book, or handle it as the options say if it duplicates
+/// a book of `index`, returning what was imported as kind for the report
+fn import_file(
+    conn: &mut Connection,
+    path: &Path,
+    options: &Options,
+    index: &mut BookIndex,
+    report: &mut ImportReport,
+) -> Result<&'static str> {
+    let format = formats::format_of(path).unwrap_or_default();
+    let mut file = match formats::read(path) {
+        Ok(file) => file,
+        Err(e) => {
+            report.warn(format!(
+                "{path:?}: could not read metadata, using the file name: {e:#}"
+            ));
+            FileMetadata::default()
+        }
+    };
+    fill_fallbacks(&mut file, path, &options.patterns);
+    let sha256 = duplicates::hash_file(path)?;
+    if options.on_duplicate != DuplicateAction::ImportAnyway {
+        if let Some(duplicate) = duplicates::find_for(conn, index, &sha256, &file.metadata)? {
+            if options.on_duplicate == DuplicateAction::Merge {
+                return merge_file(conn, path, options, &file, &sha256, &duplicate, report);
+            }
+            report.warn(format!(
+                "{path:?}: skipped, duplicates book {} {:?} ({})",
+                duplicate.book, duplicate.title, duplicate.reason
+            ));
+            return Ok("duplicates");
+        }
+    }
+    if series_entry_taken(conn, &file)? {
+        report.warn(format!(
+            "{path:?}: another book is number {} of {:?}, importing it without series",
+            file.metadata.series_index.unwrap_or(1.0),
+            file.metadata.series.as_deref().unwrap_or_default()
+        ));
+        file.metadata.series = None;
+        file.metadata.series_index = None;
+    }
+
+    let tx = conn.transaction()?;
+    let title = file.metadata.title.clone().unwrap_or_default();
+    let book = books::insert_book(
+        &tx,
+        &NewBook {
+            title: title.clone(),
+            ..Default::default()
+        },
+    )?;
+    // Creates authors, series, tags, description and identifiers
+    books::update_book(&tx, book, &file.metadata)?;
+    // Only assigns the directory, a new book has no files to move yet
+    books::rename_files(&tx, options.library_dir, book)?;
+    for (kind, value) in &file.identifiers {
+        books::set_identifier(&tx, book, kind, value)?;
+    }
+    let dir: String = tx.query_row("SELECT path FROM books WHERE id = ?1", [book], |row| {
+        row.get(0)
+    })?;
+    let name = books::book_file_name(&title, file.metadata.authors.first().map(String::as_str));
+    let target = options
+        .library_dir
+        .join(&dir)
+        .join(format!("{name}.{}", format.to_lowercase()));
+    let size = std::fs::metadata(path)
+        .with_context(|| format!("Reading {path:?}"))?
+        .len();
+    books::add_format(&tx, book, &format, &name, size)?;
+    tx.commit()?;
+
+    // The file is only transferred once the book is committed, so that a failed import leaves no
+    // file behind. A book whose file can't be transferred is removed again.
+    if let Err(e) = transfer_file(conn, path, &target, options, book, &format, &sha256) {
+        conn.execute("DELETE FROM books WHERE id = ?1", [book])?;
+        let book_dir = options.library_dir.join(&dir);
+        if std::fs::remove_dir(&book_dir).is_ok() {
+            if let Some(parent) = book_dir.parent().filter(|p| *p != options.library_dir) {
+                let _ = std::fs::remove_dir(parent);
+            }
+        }
+        return Err(e);
+    }
+    if let Some(cover) = &file.cover {
+        match covers::save(conn, options.library_dir, book, cover, None) {
+            Ok(()) => report.count("covers", 1),
+            Err(e) => report.warn(format!("{path:?}: the embedded cover is unusable: {e:#}")),
+        }
+    }
+    automation::emit(conn, RuleTrigger::BookImported, book, None);
+    index.add(book, &title, &file.metadata.authors);
+    format_epub(conn, path, options, book, &format, report);
+    Ok("books")
+}
+
+/// Import the book file `source`, or all book files below the directory `source`, as new
+/// books. Metadata is read from the files by the reader for their format; fields they don't
+/// carry, or carry placeholders such as "Unknown" for, are taken from the file name by the
+/// first of `filename_patterns` that matches; see [`FilenamePatterns`]. Invalid patterns are
+/// reported and skipped. Files that duplicate a book of the library, or one imported before
+/// them, by content, ISBN or title and author are handled as `on_duplicate` says. Imported
+/// EPUB files are normalized with the `formatting` steps, if given.
+///
+/// Each file is imported in its own transaction, files that fail are reported and skipped. The
+/// write connection of `pool` is taken for one file at a time, so other writes can go on between
+/// files.
+pub fn import(
+    pool: &Pool,
+    source: &Path,
+    library_dir: &Path,
+    transfer: FileTransfer,
+    filename_patterns: &[String],
+    on_duplicate: DuplicateAction,
+    formatting: Option<&FormattingConfig>,
+) -> Result<ImportReport> {
+    let (patterns, errors) = FilenamePatterns::compile(filename_patterns);
+    let options = Options {
+        library_dir,
+        transfer,
+        patterns,
+        on_duplicate,
+        formatting,
+    };
+    let mut report = ImportReport::new(source.to_path_buf());
+    import_files(pool, source, &options, errors, &mut report, &mut |_, _| {
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+/// See [`import`]. `progress` is told how many of how many files are done before each file, an
+/// error it returns stops the import. What was imported until then is in `report`.
+fn import_files(
+    pool: &Pool,
+    source: &Path,
+    options: &Options,
+    pattern_errors: Vec<String>,
+    report: &mut ImportReport,
+    progress: &mut dyn FnMut(usize, usize) -> Result<()>,
+) -> Result<()> {
+    let mut files = Vec::new();
+    if source.is_dir() {
+        book_files(source, &mut files)?;
+    } else if source.is_file() {
+        files.push(source.to_path_buf());
+    } else {
+        return Err(anyhow!("{source:?} does not exist"));
+    }
+    for error in pattern_errors {
+        report.warn(error);
+    }
+    // Hashing takes a while, so only the storing holds the write connection
+    let hashes = pool.read(|conn| duplicates::hash_stale_files(conn, options.library_dir))?;
+    let mut index = pool.write(|conn| {
+        duplicates::record_hashes(conn, &hashes)?;
+        BookIndex::load(conn)
+    })?;
+    let total = files.len();
+    for (done, path) in files.into_iter().enumerate() {
+        if let Err(e) = progress(done, total) {
+            report.warn(format!("Stopped after {done} of {total} files: {e:#}"));
+            return Err(e);
+        }
+        match pool.write(|conn| import_file(conn, &path, options, &mut index, report)) {
+            Ok(kind) => report.count(kind, 1),
+            Err(e) => report.warn(format!("{path:?}: not imported: {e:#}")),
+        }
+    }
+    Ok(())
+}
+
+/// Run the import of the book files at `source` as a background job, with the configured file
+/// name patterns and formatting, see [`import`]. The write connection is taken for one file at a
+/// time. The report is saved and copied into the log of the job, also when the job is cancelled
+/// or fails part way.
+pub(crate) fn run_job(
+    ctx: &JobContext,
+    source: &Path,
+    link_files: bool,
+    on_duplicate: DuplicateAction,
+) -> Result<()> {
+    let config = AppConfig::read()?;
+    let (patterns, errors) = FilenamePatterns::compile(&config.import.filename_patterns);
+    let formatting = config.formatting.on_import.then_some(config.formatting);
+    let library_dir = ctx.library().dir();
+    let options = Options {
+        library_dir,
+        transfer: if link_files {
+            FileTransfer::HardLink
+        } else {
+            FileTransfer::Copy
+        },
+        patterns,
+        on_duplicate,
+        formatting: formatting.as_ref(),
+    };
+    let mut report = ImportReport::new(source.to_path_buf());
+    let result = import_files(
+        ctx.library().pool(),
+        source,
+        &options,
+        errors,
+        &mut report,
+        &mut |done, total| {
+            ctx.progress(done, total);
+            ctx.check_cancelled()
+        },
+    );
+    import::write_report(library_dir, "files", &mut report)?;
+    import::log_report(ctx, &report);
+    result
+}
@@ -0,0 +1,198 @@
+use crate::books;
+use crate::import::parse_timestamp;
+use crate::import::reading_log::{parse_rating, read_table};
+use crate::matching::BookIndex;
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::ImportReport;
+use std::path::Path;
+
+/// Goodreads' exclusive shelves; every book is on exactly one of them
+const SHELF_READ: &str = "read";
+const SHELF_CURRENTLY_READING: &str = "currently-reading";
+const SHELF_TO_READ: &str = "to-read";
+
+/// Columns of the Goodreads library export that are used
+struct Columns {
+    book_id: usize,
+    title: usize,
+    author: Option<usize>,
+    isbn: Option<usize>,
+    isbn13: Option<usize>,
+    rating: Option<usize>,
+    date_read: Option<usize>,
+    date_added: Option<usize>,
+    exclusive_shelf: Option<usize>,
+    bookshelves: Option<usize>,
+    read_count: Option<usize>,
+}
+
+impl Columns {
+    fn find(headers: &[String]) -> Result<Self> {
+        let find = |name: &str| headers.iter().position(|h| h.trim() == name);
+        Ok(Self {
+            book_id: find("Book Id")
+                .ok_or_else(|| anyhow!("Not a Goodreads library export: no \"Book Id\" column"))?,
+            title: find("Title")
+                .ok_or_else(|| anyhow!("Not a Goodreads library export: no \"Title\" column"))?,
+            author: find("Author"),
+            isbn: find("ISBN"),
+            isbn13: find("ISBN13"),
+            rating: find("My Rating"),
+            date_read: find("Date Read"),
+            date_added: find("Date Added"),
+            exclusive_shelf: find("Exclusive Shelf"),
+            bookshelves: find("Bookshelves"),
+            read_count: find("Read Count"),
+        })
+    }
+}
+
+/// Cell of a row, with Goodreads' `="0765326353"` quoting of ISBNs removed
+fn cell(row: &[String], column: Option<usize>) -> Option<String> {
+    let value = row.get(column?)?.trim();
+    let value = value
+        .strip_prefix("=\"")
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Import a Goodreads library export (`goodreads_library_export.csv`).
+///
+/// Rows are matched to books of the library by their Goodreads ID, then by ISBN and finally by
+/// title and author. Matched books get their `goodreads_id`, the "read" and "currently-reading"
+/// shelves become readings and all other shelves collections. Books that aren't in the library
+/// are reported, not created. The export contains no IDs for authors or series, and only the
+/// date of the last reading of books read more than once ("Read Count"); their earlier readings
+/// are reported, not recorded.
+pub fn import(conn: &mut Connection, path: &Path) -> Result<ImportReport> {
+    let table = read_table(path)?;
+    let columns = Columns::find(&table.headers)?;
+    let mut report = ImportReport::new(path.to_path_buf());
+
+    let tx = conn.transaction()?;
+    let index = BookIndex::load(&tx)?;
+    for (i, row) in table.rows.iter().enumerate() {
+        let Some(title) = cell(row, Some(columns.title)) else {
+            continue;
+        };
+        let row_number = table.header_row + 1 + i;
+        let goodreads_id: Option<i64> =
+            cell(row, Some(columns.book_id)).and_then(|id| id.parse().ok());
+        let isbns: Vec<String> = [columns.isbn13, columns.isbn]
+            .into_iter()
+            .filter_map(|c| cell(row, c))
+            .collect();
+        let author = cell(row, columns.author);
+
+        let book = match find_book(&tx, goodreads_id, &isbns)? {
+            Some(book) => book,
+            None => match index.find(&title, author.as_deref()) {
+                Ok(book) => book.book,
+                Err(e) => {
+                    report.warn(format!("Row {row_number} ({title:?}) was skipped: {e}"));
+                    continue;
+                }
+            },
+        };
+        report.count("matched books", 1);
+
+        if let Some(goodreads_id) = goodreads_id {
+            tx.execute(
+                "UPDATE books SET goodreads_id = ?1 WHERE id = ?2",
+                params![goodreads_id, book],
+            )?;
+            books::set_identifier(&tx, book, "goodreads", &goodreads_id.to_string())?;
+        }
+
+        let shelf = cell(row, columns.exclusive_shelf).unwrap_or_else(|| SHELF_TO_READ.to_string());
+        let date_read = cell(row, columns.date_read)
+            .as_deref()
+            .and_then(parse_timestamp);
+        let date_added = cell(row, columns.date_added)
+            .as_deref()
+            .and_then(parse_timestamp);
+        let rating = cell(row, columns.rating).as_deref().and_then(parse_rating);
+        let read_count: u32 = cell(row, columns.read_count)
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(1);
+        match shelf.as_str() {
+            SHELF_READ => match date_read {
+                // Goodreads only exports when the last reading ended
+                Some(end) => {
+                    if !books::reading_exists(&tx, book, Some(end))? {
+                        books::add_reading(&tx, book, None, Some(end), Some(end), rating)?;
+                        report.count("finished books", 1);
+                    }
+                    if read_count > 1 {
+                        report.warn(format!(
+                            "Row {row_number} ({title:?}) was read {read_count} times, but Goodreads only exports the date of the last reading, so only that one was recorded"
+                        ));
+                    }
+                }
+                None => report.warn(format!(
+                    "Row {row_number} ({title:?}) is marked as read without a date and was not recorded as a reading"
+                )),
+            },
+            SHELF_CURRENTLY_READING => {
+                if !books::reading_exists(&tx, book, None)? {
+                    books::add_reading(&tx, book, None, date_added, None, None)?;
+                    report.count("books in progress", 1);
+                }
+            }
+            _ => {}
+        }
+
+        let mut shelves = vec![shelf.clone()];
+        if let Some(bookshelves) = cell(row, columns.bookshelves) {
+            shelves.extend(bookshelves.split(',').map(|s| s.trim().to_string()));
+        }
+        shelves.retain(|s| !s.is_empty() && s != SHELF_READ && s != SHELF_CURRENTLY_READING);
+        shelves.sort();
+        shelves.dedup();
+        for shelf in shelves {
+            let collection = books::collection_id(&tx, &shelf, None, false)?;
+            if books::add_to_collection(&tx, book, collection, 0, date_added)? {
+                report.count("collection entries", 1);
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(report)
+}
+
+/// Book with the Goodreads ID or one of the ISBNs
+fn find_book(
+    conn: &Connection,
+    goodreads_id: Option<i64>,
+    isbns: &[String],
+) -> Result<Option<i64>> {
+    if let Some(id) = goodreads_id {
+        let book = conn
+            .query_row(
+                "SELECT id FROM books WHERE goodreads_id = ?1
+                 UNION SELECT book FROM identifiers WHERE type = 'goodreads' AND val = ?2",
+                params![id, id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if book.is_some() {
+            return Ok(book);
+        }
+    }
+    for isbn in isbns {
+        let book = conn
+            .query_row(
+                "SELECT book FROM identifiers WHERE type = 'isbn' AND REPLACE(val, '-', '') = ?1",
+                [isbn.replace('-', "")],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if book.is_some() {
+            return Ok(book);
+        }
+    }
+    Ok(None)
+}
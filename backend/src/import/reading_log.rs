@@ -0,0 +1,331 @@
+use crate::books;
+use crate::import::parse_timestamp;
+use crate::matching::BookIndex;
+use anyhow::{anyhow, Context, Result};
+use calamine::{open_workbook_auto, Data, Reader};
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use shared::types::{
+    ImportReport, ReadingLogMapping, ReadingLogRow, ReadingLogSheet, ReadingStatus,
+};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Number of rows shown next to the headers while mapping columns
+const SAMPLE_ROWS: usize = 5;
+
+/// Header names (lower case) recognized when guessing the column mapping
+const TITLE_HEADERS: &[&str] = &["title", "book", "book title", "titel"];
+const AUTHOR_HEADERS: &[&str] = &["author", "authors", "author name", "autor"];
+const START_HEADERS: &[&str] = &["start", "start date", "started", "date started", "begonnen"];
+const END_HEADERS: &[&str] = &[
+    "end",
+    "end date",
+    "finished",
+    "date finished",
+    "date read",
+    "read",
+    "beendet",
+];
+const STATUS_HEADERS: &[&str] = &["status", "state", "shelf", "exclusive shelf"];
+const RATING_HEADERS: &[&str] = &["rating", "my rating", "stars", "bewertung"];
+
+/// Cells of a CSV file or the first sheet of a workbook, as text
+pub struct Table {
+    pub headers: Vec<String>,
+    /// Rows below the headers, empty ones included to keep row numbers intact
+    pub rows: Vec<Vec<String>>,
+    /// Row number of the headers in the file, counting from 1
+    pub header_row: usize,
+}
+
+impl Table {
+    fn cell(&self, row: &[String], column: Option<usize>) -> Option<String> {
+        let value = row.get(column?)?.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    }
+}
+
+/// Read a reading log from a CSV file or a spreadsheet (XLSX, XLS, ODS)
+pub fn read_table(path: &Path) -> Result<Table> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    let mut rows = match extension.as_str() {
+        "csv" | "tsv" | "txt" => read_csv(path)?,
+        "xlsx" | "xlsm" | "xls" | "ods" => read_workbook(path)?,
+        _ => return Err(anyhow!("{path:?} is neither a CSV file nor a spreadsheet")),
+    };
+    let header_index = rows
+        .iter()
+        .position(|row| !is_empty(row))
+        .ok_or_else(|| anyhow!("{path:?} is empty"))?;
+    let rows = rows.split_off(header_index);
+    let mut rows = rows.into_iter();
+    let mut headers = rows.next().unwrap_or_default();
+    if let Some(first) = headers.first_mut() {
+        *first = first.trim_start_matches('\u{feff}').to_string();
+    }
+    Ok(Table {
+        headers,
+        rows: rows.collect(),
+        header_row: header_index + 1,
+    })
+}
+
+fn is_empty(row: &[String]) -> bool {
+    row.iter().all(|cell| cell.trim().is_empty())
+}
+
+fn read_csv(path: &Path) -> Result<Vec<Vec<String>>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Reading {path:?}"))?;
+    // Spreadsheet applications in many locales export with ';' or tabs instead of ','
+    let first_line = content.lines().next().unwrap_or_default();
+    let delimiter = [b',', b';', b'\t']
+        .into_iter()
+        .max_by_key(|d| first_line.matches(*d as char).count())
+        .unwrap_or(b',');
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.with_context(|| format!("Parsing {path:?}"))?;
+        rows.push(record.iter().map(str::to_string).collect());
+    }
+    Ok(rows)
+}
+
+fn read_workbook(path: &Path) -> Result<Vec<Vec<String>>> {
+    let mut workbook =
+        open_workbook_auto(path).with_context(|| format!("Opening spreadsheet {path:?}"))?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| anyhow!("{path:?} contains no sheets"))?
+        .with_context(|| format!("Reading the first sheet of {path:?}"))?;
+    Ok(range
+        .rows()
+        .map(|row| row.iter().map(cell_text).collect())
+        .collect())
+}
+
+fn cell_text(cell: &Data) -> String {
+    match cell {
+        Data::Empty => String::new(),
+        Data::DateTime(dt) => dt
+            .as_datetime()
+            .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default(),
+        Data::Float(f) if f.fract() == 0.0 => format!("{f:.0}"),
+        other => other.to_string(),
+    }
+}
+
+/// Guess which column holds which field from the header names
+pub fn guess_mapping(headers: &[String]) -> ReadingLogMapping {
+    let find = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|h| names.contains(&h.trim().to_lowercase().as_str()))
+    };
+    ReadingLogMapping {
+        title: find(TITLE_HEADERS),
+        author: find(AUTHOR_HEADERS),
+        start_date: find(START_HEADERS),
+        end_date: find(END_HEADERS),
+        status: find(STATUS_HEADERS),
+        rating: find(RATING_HEADERS),
+    }
+}
+
+/// Headers, first rows and a guessed mapping of the reading log at `path`
+pub fn inspect(path: &Path) -> Result<ReadingLogSheet> {
+    let table = read_table(path)?;
+    Ok(ReadingLogSheet {
+        mapping: guess_mapping(&table.headers),
+        sample: table
+            .rows
+            .iter()
+            .filter(|row| !is_empty(row))
+            .take(SAMPLE_ROWS)
+            .cloned()
+            .collect(),
+        headers: table.headers,
+    })
+}
+
+pub fn parse_status(value: &str) -> Option<ReadingStatus> {
+    let value = value.trim().to_lowercase().replace(['-', '_'], " ");
+    match value.as_str() {
+        "read" | "finished" | "done" | "completed" | "gelesen" => Some(ReadingStatus::Finished),
+        "reading" | "currently reading" | "in progress" | "started" => Some(ReadingStatus::Reading),
+        "to read" | "want to read" | "tbr" | "planned" => Some(ReadingStatus::ToRead),
+        "dnf" | "did not finish" | "abandoned" | "dropped" => Some(ReadingStatus::Abandoned),
+        _ => None,
+    }
+}
+
+/// Parse ratings such as "4", "4.5", "4/5" or "★★★★". 0 means unrated.
+pub fn parse_rating(value: &str) -> Option<u8> {
+    let value = value.trim();
+    let stars = value.chars().filter(|c| *c == '★').count();
+    if stars > 0 {
+        return Some(stars.min(5) as u8);
+    }
+    let number: f64 = value
+        .split('/')
+        .next()?
+        .trim()
+        .replace(',', ".")
+        .parse()
+        .ok()?;
+    let rating = number.round();
+    (1.0..=5.0).contains(&rating).then_some(rating as u8)
+}
+
+/// Match every row of the reading log to a book of the library and check whether it can be
+/// imported. Nothing is written.
+pub fn preview(
+    conn: &Connection,
+    path: &Path,
+    mapping: &ReadingLogMapping,
+) -> Result<Vec<ReadingLogRow>> {
+    if mapping.title.is_none() {
+        return Err(anyhow!("The title column has to be mapped"));
+    }
+    let table = read_table(path)?;
+    let index = BookIndex::load(conn)?;
+    // Row each (book, end date) was first seen in, to spot duplicates within the log
+    let mut seen: HashMap<(i64, Option<DateTime<Utc>>), usize> = HashMap::new();
+
+    let mut rows = Vec::new();
+    for (i, cells) in table.rows.iter().enumerate() {
+        if is_empty(cells) {
+            continue;
+        }
+        let row_number = table.header_row + 1 + i;
+        let mut problems = Vec::new();
+        let mut date = |column: Option<usize>| {
+            let value = table.cell(cells, column)?;
+            let parsed = parse_timestamp(&value);
+            if parsed.is_none() {
+                problems.push(format!("Unrecognized date {value:?}"));
+            }
+            parsed
+        };
+        let start_date = date(mapping.start_date);
+        let end_date = date(mapping.end_date);
+
+        let rating = table.cell(cells, mapping.rating).and_then(|value| {
+            let rating = parse_rating(&value);
+            if rating.is_none() && value != "0" {
+                problems.push(format!("Unrecognized rating {value:?}"));
+            }
+            rating
+        });
+        let status = match table.cell(cells, mapping.status) {
+            Some(value) => parse_status(&value).unwrap_or_else(|| {
+                problems.push(format!("Unrecognized status {value:?}"));
+                ReadingStatus::Finished
+            }),
+            None if end_date.is_none() && start_date.is_some() => ReadingStatus::Reading,
+            None => ReadingStatus::Finished,
+        };
+        match status {
+            ReadingStatus::ToRead | ReadingStatus::Abandoned => {
+                problems.push(format!(
+                    "Books marked as \"{status}\" aren't recorded as readings"
+                ));
+            }
+            _ if start_date.is_none() && end_date.is_none() => {
+                problems.push("Neither a start nor an end date".to_string());
+            }
+            _ => {}
+        }
+        if let (Some(start), Some(end)) = (start_date, end_date) {
+            if end < start {
+                problems.push("Ends before it starts".to_string());
+            }
+        }
+        let end_date = if status == ReadingStatus::Reading {
+            None
+        } else {
+            end_date
+        };
+
+        let title = table.cell(cells, mapping.title).unwrap_or_default();
+        let author = table.cell(cells, mapping.author);
+        let book = if title.is_empty() {
+            problems.push("No title".to_string());
+            None
+        } else {
+            match index.find(&title, author.as_deref()) {
+                Ok(book) => Some(book),
+                Err(e) => {
+                    problems.push(e);
+                    None
+                }
+            }
+        };
+        if let Some(book) = &book {
+            if let Some(first) = seen.insert((book.book, end_date), row_number) {
+                problems.push(format!("Same reading as row {first}"));
+            } else if books::reading_exists(conn, book.book, end_date)? {
+                problems.push("This reading is already recorded".to_string());
+            }
+        }
+
+        rows.push(ReadingLogRow {
+            row: row_number,
+            title,
+            author,
+            start_date,
+            end_date,
+            status,
+            rating,
+            book,
+            conflict: (!problems.is_empty()).then(|| problems.join("; ")),
+        });
+    }
+    Ok(rows)
+}
+
+/// Write every matched row without conflicts to `read_books`, all in one transaction. Skipped
+/// rows are listed in the report.
+pub fn import(
+    conn: &mut Connection,
+    path: &Path,
+    mapping: &ReadingLogMapping,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::new(path.to_path_buf());
+    let tx = conn.transaction()?;
+    for row in preview(&tx, path, mapping)? {
+        match (&row.book, &row.conflict) {
+            (Some(book), None) => {
+                books::add_reading(
+                    &tx,
+                    book.book,
+                    None,
+                    row.start_date.or(row.end_date),
+                    row.end_date,
+                    row.rating,
+                )?;
+                report.count("readings", 1);
+            }
+            (_, conflict) => report.warn(format!(
+                "Row {} ({:?}) was skipped: {}",
+                row.row,
+                row.title,
+                conflict
+                    .as_deref()
+                    .unwrap_or("No matching book in the library")
+            )),
+        }
+    }
+    tx.commit()?;
+    Ok(report)
+}
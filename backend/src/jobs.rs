@@ -0,0 +1,589 @@
+use crate::config::ConfigInterface;
+use crate::import::{calibre, files};
+use crate::library::{self, Library};
+use crate::{backup, convert};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use shared::types::{AppConfig, Job, JobLogEntry, JobState, JobTask};
+use std::collections::{HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
+
+/// How often the dispatcher looks for jobs to start if it isn't woken up before
+const DISPATCH_TICK: Duration = Duration::from_secs(5);
+/// Number of done jobs kept with their logs, older ones are deleted
+const KEPT_JOBS: usize = 200;
+/// Jobs of a kind that may run at once, for kinds that may not use all workers. Imports look for
+/// duplicates among the books of the library as it was when they started, so two at once could
+/// both import the same book.
+const KIND_LIMITS: &[(&str, usize)] = &[("import", 1), ("backup", 1)];
+
+const JOB_COLUMNS: &str = "id, task, title, state, progress, attempts, max_attempts, error, created_at, started_at, finished_at, retry_at";
+
+static EVENTS: Lazy<broadcast::Sender<Job>> = Lazy::new(|| broadcast::channel(256).0);
+static WAKEUP: Lazy<Notify> = Lazy::new(Notify::new);
+static DISPATCHER: Once = Once::new();
+/// Jobs running in this process, of any library
+static RUNNING: Lazy<Mutex<Vec<Arc<JobContext>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// Directories of the libraries whose jobs left running by a previous process were queued again
+static RECOVERED: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Returned by a job that stopped because it was cancelled
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The job was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+pub fn state_name(state: JobState) -> &'static str {
+    match state {
+        JobState::Queued => "queued",
+        JobState::Running => "running",
+        JobState::Finished => "finished",
+        JobState::Failed => "failed",
+        JobState::Cancelled => "cancelled",
+    }
+}
+
+fn parse_state(name: &str) -> Result<JobState> {
+    JobState::ALL
+        .into_iter()
+        .find(|state| state_name(*state) == name)
+        .ok_or_else(|| anyhow!("Unknown job state {name:?}"))
+}
+
+/// What a running job reports to: its progress, its log and whether it was cancelled
+pub struct JobContext {
+    library: Arc<Library>,
+    job: Mutex<Job>,
+    cancel: AtomicBool,
+    log: Mutex<Vec<JobLogEntry>>,
+}
+
+impl JobContext {
+    /// The library the job belongs to, which need not be the active one anymore
+    pub fn library(&self) -> &Library {
+        &self.library
+    }
+
+    fn job(&self) -> Job {
+        self.job.lock().expect("job poisoned").clone()
+    }
+
+    /// Report that `done` of `total` steps are done
+    pub fn progress(&self, done: usize, total: usize) {
+        let percent = (done * 100).checked_div(total).unwrap_or(0).min(100) as u8;
+        let mut job = self.job.lock().expect("job poisoned");
+        if job.progress != percent {
+            job.progress = percent;
+            publish(&job);
+        }
+    }
+
+    /// Add a message to the log kept with the job
+    pub fn log(&self, message: impl Into<String>) {
+        let entry = JobLogEntry {
+            at: Utc::now(),
+            message: message.into(),
+        };
+        tracing::debug!("Job {}: {}", self.job().id, entry.message);
+        self.log.lock().expect("job log poisoned").push(entry);
+    }
+
+    /// Fails with [`Cancelled`] if the job was cancelled. Jobs call this between their steps.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+/// Subscribe to changes of jobs: new jobs, changes of their state and progress
+pub fn subscribe() -> broadcast::Receiver<Job> {
+    EVENTS.subscribe()
+}
+
+fn publish(job: &Job) {
+    // Sending only fails if nobody is subscribed, which is fine
+    let _ = EVENTS.send(job.clone());
+}
+
+fn read_job(row: &Row) -> Result<Job> {
+    Ok(Job {
+        id: row.get(0)?,
+        task: serde_json::from_str(&row.get::<_, String>(1)?)?,
+        title: row.get(2)?,
+        state: parse_state(&row.get::<_, String>(3)?)?,
+        progress: row.get(4)?,
+        attempts: row.get(5)?,
+        max_attempts: row.get(6)?,
+        error: row.get(7)?,
+        created_at: row.get(8)?,
+        started_at: row.get(9)?,
+        finished_at: row.get(10)?,
+        retry_at: row.get(11)?,
+    })
+}
+
+/// The job as running in this process, with its current progress
+fn running(library: &Library, id: i64) -> Option<Arc<JobContext>> {
+    RUNNING
+        .lock()
+        .expect("running jobs poisoned")
+        .iter()
+        .find(|ctx| ctx.library.dir() == library.dir() && ctx.job().id == id)
+        .cloned()
+}
+
+fn query_jobs(conn: &Connection, library: &Library, filter: &str) -> Result<Vec<Job>> {
+    let mut stmt = conn.prepare(&format!("SELECT {JOB_COLUMNS} FROM jobs {filter}"))?;
+    let mut rows = stmt.query([])?;
+    let mut jobs = Vec::new();
+    while let Some(row) = rows.next()? {
+        let job = read_job(row)?;
+        jobs.push(match running(library, job.id) {
+            Some(ctx) if job.state == JobState::Running => ctx.job(),
+            _ => job,
+        });
+    }
+    Ok(jobs)
+}
+
+/// The job `id` of `library`
+pub fn get(conn: &Connection, library: &Library, id: i64) -> Result<Job> {
+    query_jobs(conn, library, &format!("WHERE id = {id}"))?
+        .pop()
+        .ok_or_else(|| anyhow!("There is no job with ID {id}"))
+}
+
+/// The jobs of `library`, newest first
+pub fn list(conn: &Connection, library: &Library) -> Result<Vec<Job>> {
+    query_jobs(
+        conn,
+        library,
+        &format!("ORDER BY id DESC LIMIT {KEPT_JOBS}"),
+    )
+}
+
+/// The log of job `id`, including what a running job logged so far
+pub fn log(conn: &Connection, library: &Library, id: i64) -> Result<Vec<JobLogEntry>> {
+    let mut stmt =
+        conn.prepare("SELECT at, message FROM job_logs WHERE job = ?1 ORDER BY rowid")?;
+    let mut entries = stmt
+        .query_map([id], |row| {
+            Ok(JobLogEntry {
+                at: row.get(0)?,
+                message: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(ctx) = running(library, id) {
+        entries.extend(ctx.log.lock().expect("job log poisoned").iter().cloned());
+    }
+    Ok(entries)
+}
+
+fn add_log(conn: &Connection, id: i64, entries: &[JobLogEntry]) -> Result<()> {
+    let mut stmt = conn.prepare("INSERT INTO job_logs (job, at, message) VALUES (?1, ?2, ?3)")?;
+    for entry in entries {
+        stmt.execute(params![id, entry.at, entry.message])?;
+    }
+    Ok(())
+}
+
+fn log_entry(message: impl Into<String>) -> JobLogEntry {
+    JobLogEntry {
+        at: Utc::now(),
+        message: message.into(),
+    }
+}
+
+/// Queue a job running `task`, described by `title`. Returns `None` if a job with the same task
+/// is queued or running already.
+pub fn enqueue(conn: &Connection, task: JobTask, title: &str) -> Result<Option<Job>> {
+    let json = serde_json::to_string(&task)?;
+    let pending = conn
+        .query_row(
+            "SELECT 1 FROM jobs WHERE task = ?1 AND state IN (?2, ?3)",
+            params![
+                json,
+                state_name(JobState::Queued),
+                state_name(JobState::Running)
+            ],
+            |_| Ok(()),
+        )
+        .optional()?;
+    if pending.is_some() {
+        return Ok(None);
+    }
+    let max_attempts = AppConfig::read()?.jobs.max_attempts.max(1);
+    let job = Job {
+        id: 0,
+        title: title.to_string(),
+        state: JobState::Queued,
+        progress: 0,
+        attempts: 0,
+        max_attempts,
+        error: None,
+        created_at: Utc::now(),
+        started_at: None,
+        finished_at: None,
+        retry_at: None,
+        task,
+    };
+    conn.execute(
+        "INSERT INTO jobs (kind, task, title, state, max_attempts, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            job.task.kind(),
+            json,
+            job.title,
+            state_name(job.state),
+            job.max_attempts,
+            job.created_at
+        ],
+    )?;
+    let job = Job {
+        id: conn.last_insert_rowid(),
+        ..job
+    };
+    publish(&job);
+    WAKEUP.notify_one();
+    Ok(Some(job))
+}
+
+/// Cancel job `id` of `library`. A queued job is cancelled right away, a running one stops at
+/// its next check.
+pub fn cancel(conn: &Connection, library: &Library, id: i64) -> Result<Job> {
+    let mut job = get(conn, library, id)?;
+    match job.state {
+        JobState::Queued => {
+            job.state = JobState::Cancelled;
+            job.finished_at = Some(Utc::now());
+            job.retry_at = None;
+            conn.execute(
+                "UPDATE jobs SET state = ?2, finished_at = ?3, retry_at = NULL WHERE id = ?1",
+                params![id, state_name(job.state), job.finished_at],
+            )?;
+            add_log(conn, id, &[log_entry("Cancelled before it ran")])?;
+            publish(&job);
+        }
+        JobState::Running => match running(library, id) {
+            Some(ctx) => {
+                ctx.cancel.store(true, Ordering::SeqCst);
+                ctx.log("Cancelling");
+            }
+            None => return Err(anyhow!("The job is running in another process")),
+        },
+        _ => return Err(anyhow!("The job is {} already", job.state)),
+    }
+    Ok(job)
+}
+
+/// Queue failed or cancelled job `id` again, with all its attempts
+pub fn retry(conn: &Connection, library: &Library, id: i64) -> Result<Job> {
+    let mut job = get(conn, library, id)?;
+    if !matches!(job.state, JobState::Failed | JobState::Cancelled) {
+        return Err(anyhow!("Only failed or cancelled jobs can be retried"));
+    }
+    job.state = JobState::Queued;
+    job.progress = 0;
+    job.attempts = 0;
+    job.error = None;
+    job.finished_at = None;
+    job.retry_at = None;
+    conn.execute(
+        "UPDATE jobs SET state = ?2, progress = 0, attempts = 0, error = NULL, finished_at = NULL, retry_at = NULL WHERE id = ?1",
+        params![id, state_name(job.state)],
+    )?;
+    add_log(conn, id, &[log_entry("Queued again")])?;
+    publish(&job);
+    WAKEUP.notify_one();
+    Ok(job)
+}
+
+/// Queue the jobs of `library` again that a previous process left running, once per library
+fn recover(library: &Library) -> Result<()> {
+    if !RECOVERED
+        .lock()
+        .expect("recovered libraries poisoned")
+        .insert(library.dir().to_path_buf())
+    {
+        return Ok(());
+    }
+    library.pool().write(|conn| {
+        let mut stmt = conn.prepare("SELECT id FROM jobs WHERE state = ?1")?;
+        let ids = stmt
+            .query_map([state_name(JobState::Running)], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for id in ids {
+            conn.execute(
+                "UPDATE jobs SET state = ?2 WHERE id = ?1",
+                params![id, state_name(JobState::Queued)],
+            )?;
+            add_log(conn, id, &[log_entry("Interrupted, queued again")])?;
+        }
+        Ok(())
+    })
+}
+
+/// Mark the queued jobs of the active library as running that may start now, within the limits
+/// of workers and kinds
+fn claim() -> Result<Vec<Arc<JobContext>>> {
+    let Some(library) = library::active() else {
+        return Ok(Vec::new());
+    };
+    recover(&library)?;
+    let workers = AppConfig::read()?.jobs.workers.max(1);
+    let mut kinds: HashMap<&str, usize> = HashMap::new();
+    let running = RUNNING.lock().expect("running jobs poisoned").clone();
+    for ctx in &running {
+        *kinds.entry(ctx.job().task.kind()).or_default() += 1;
+    }
+    let mut free = workers.saturating_sub(running.len());
+    if free == 0 {
+        return Ok(Vec::new());
+    }
+    let now = Utc::now();
+    let queued = library.pool().read(|conn| {
+        query_jobs(
+            conn,
+            &library,
+            &format!(
+                "WHERE state = '{}' ORDER BY id",
+                state_name(JobState::Queued)
+            ),
+        )
+    })?;
+    let mut startable = Vec::new();
+    for job in queued {
+        if free == 0 {
+            break;
+        }
+        if job.retry_at.is_some_and(|at| at > now) {
+            continue;
+        }
+        let kind = job.task.kind();
+        let limit = KIND_LIMITS
+            .iter()
+            .find(|(name, _)| *name == kind)
+            .map_or(workers, |(_, limit)| *limit);
+        let count = kinds.entry(kind).or_default();
+        if *count >= limit {
+            continue;
+        }
+        *count += 1;
+        free -= 1;
+        startable.push(job);
+    }
+    if startable.is_empty() {
+        return Ok(Vec::new());
+    }
+    let claimed = library.pool().write(|conn| {
+        let mut claimed = Vec::new();
+        for mut job in startable {
+            job.state = JobState::Running;
+            job.attempts += 1;
+            job.progress = 0;
+            job.started_at = Some(now);
+            job.retry_at = None;
+            let changed = conn.execute(
+                "UPDATE jobs SET state = ?2, attempts = ?3, progress = 0, started_at = ?4, retry_at = NULL WHERE id = ?1 AND state = ?5",
+                params![
+                    job.id,
+                    state_name(job.state),
+                    job.attempts,
+                    job.started_at,
+                    state_name(JobState::Queued)
+                ],
+            )?;
+            if changed == 1 {
+                claimed.push(job);
+            }
+        }
+        Ok(claimed)
+    })?;
+    let contexts: Vec<Arc<JobContext>> = claimed
+        .into_iter()
+        .map(|job| {
+            publish(&job);
+            let ctx = Arc::new(JobContext {
+                library: library.clone(),
+                cancel: AtomicBool::new(false),
+                log: Mutex::new(Vec::new()),
+                job: Mutex::new(job),
+            });
+            ctx.log(format!("Attempt {} started", ctx.job().attempts));
+            ctx
+        })
+        .collect();
+    RUNNING
+        .lock()
+        .expect("running jobs poisoned")
+        .extend(contexts.iter().cloned());
+    Ok(contexts)
+}
+
+fn run_task(ctx: &JobContext, task: &JobTask) -> Result<()> {
+    match task {
+        JobTask::Convert { book, target } => convert::run_job(ctx, *book, target),
+        JobTask::ImportFiles {
+            path,
+            link_files,
+            on_duplicate,
+        } => files::run_job(ctx, path, *link_files, *on_duplicate),
+        JobTask::Backup { label } => backup::run_job(ctx, label),
+        JobTask::ImportCalibre { path, link_files } => calibre::run_job(ctx, path, *link_files),
+    }
+}
+
+/// When a job that failed `attempts` times is tried again
+fn retry_at(attempts: u32, delay_seconds: u64, now: DateTime<Utc>) -> DateTime<Utc> {
+    let factor = 1u64 << attempts.saturating_sub(1).min(16);
+    // A delay too long to be represented means the job practically never runs again
+    i64::try_from(delay_seconds.saturating_mul(factor))
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|delay| now.checked_add_signed(delay))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// Store the outcome of a job's attempt with its log: finished, cancelled, queued for a retry
+/// or failed for good
+fn complete(ctx: &JobContext, outcome: Result<()>) -> Result<Job> {
+    let delay = AppConfig::read()
+        .map(|config| config.jobs.retry_delay_seconds)
+        .unwrap_or_default();
+    let mut job = ctx.job();
+    let now = Utc::now();
+    match outcome {
+        Ok(()) => {
+            job.state = JobState::Finished;
+            job.progress = 100;
+            job.error = None;
+            job.finished_at = Some(now);
+            ctx.log("Finished");
+        }
+        Err(e) if e.is::<Cancelled>() || ctx.cancel.load(Ordering::SeqCst) => {
+            job.state = JobState::Cancelled;
+            job.finished_at = Some(now);
+            ctx.log("Cancelled");
+        }
+        Err(e) => {
+            let message = format!("{e:#}");
+            if job.attempts < job.max_attempts {
+                job.state = JobState::Queued;
+                job.retry_at = Some(retry_at(job.attempts, delay, now));
+                ctx.log(format!(
+                    "Attempt {} failed: {message}; retrying at {}",
+                    job.attempts,
+                    job.retry_at.unwrap_or(now).format("%Y-%m-%d %H:%M:%S UTC")
+                ));
+            } else {
+                job.state = JobState::Failed;
+                job.finished_at = Some(now);
+                ctx.log(format!("Attempt {} failed: {message}", job.attempts));
+            }
+            job.error = Some(message);
+        }
+    }
+    let entries = std::mem::take(&mut *ctx.log.lock().expect("job log poisoned"));
+    ctx.library.pool().write(|conn| {
+        conn.execute(
+            "UPDATE jobs SET state = ?2, progress = ?3, error = ?4, finished_at = ?5, retry_at = ?6 WHERE id = ?1",
+            params![
+                job.id,
+                state_name(job.state),
+                job.progress,
+                job.error,
+                job.finished_at,
+                job.retry_at
+            ],
+        )?;
+        add_log(conn, job.id, &entries)?;
+        if job.state.is_done() {
+            conn.execute(
+                "DELETE FROM jobs WHERE state IN (?1, ?2, ?3) AND id NOT IN (SELECT id FROM jobs ORDER BY id DESC LIMIT ?4)",
+                params![
+                    state_name(JobState::Finished),
+                    state_name(JobState::Failed),
+                    state_name(JobState::Cancelled),
+                    KEPT_JOBS
+                ],
+            )?;
+        }
+        Ok(())
+    })?;
+    Ok(job)
+}
+
+/// Run a claimed job and store its outcome
+fn execute(ctx: Arc<JobContext>) {
+    let job = ctx.job();
+    let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| run_task(&ctx, &job.task)))
+        .unwrap_or_else(|_| Err(anyhow!("The job panicked")));
+    if let Err(e) = &outcome {
+        tracing::warn!("Job {} ({}) failed: {e:#}", job.id, job.title);
+    }
+    match complete(&ctx, outcome) {
+        Ok(job) => publish(&job),
+        Err(e) => tracing::error!("Storing the outcome of job {} failed: {e:#}", job.id),
+    }
+    RUNNING
+        .lock()
+        .expect("running jobs poisoned")
+        .retain(|running| !Arc::ptr_eq(running, &ctx));
+    WAKEUP.notify_one();
+}
+
+/// Start the background task that runs the queued jobs of the active library, as many at once
+/// as configured. Must be called from within the tokio runtime; calling it more than once has
+/// no effect.
+pub fn start() {
+    DISPATCHER.call_once(|| {
+        tokio::spawn(async {
+            loop {
+                match tokio::task::spawn_blocking(claim).await {
+                    Ok(Ok(claimed)) => {
+                        for ctx in claimed {
+                            tokio::task::spawn_blocking(move || execute(ctx));
+                        }
+                    }
+                    Ok(Err(e)) => tracing::error!("Starting jobs failed: {e:#}"),
+                    Err(e) => tracing::error!("Starting jobs panicked: {e}"),
+                }
+                let _ = tokio::time::timeout(DISPATCH_TICK, WAKEUP.notified()).await;
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        let now = Utc::now();
+        let after = |attempts| (retry_at(attempts, 30, now) - now).num_seconds();
+        assert_eq!(after(1), 30);
+        assert_eq!(after(2), 60);
+        assert_eq!(after(3), 120);
+        // The delay stops growing after 16 doublings
+        assert_eq!(after(17), 30 << 16);
+        assert_eq!(after(40), 30 << 16);
+        assert_eq!(retry_at(1, 0, now), now);
+        assert_eq!(retry_at(20, u64::MAX, now), DateTime::<Utc>::MAX_UTC);
+    }
+}
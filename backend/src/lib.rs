@@ -1,2 +1,27 @@
+pub mod automation;
+pub mod backup;
+pub mod books;
+pub mod checker;
 pub mod config;
+pub mod convert;
+pub mod covers;
 pub mod database;
+pub mod duplicates;
+pub mod editor;
+pub mod embed;
+pub mod epub;
+pub mod export;
+pub mod filenames;
+pub mod formats;
+pub mod import;
+pub mod jobs;
+pub mod library;
+pub mod matching;
+pub mod metadata;
+pub mod migrations;
+pub mod normalize;
+pub mod pool;
+pub mod replace;
+pub mod search;
+pub mod statistics;
+pub mod watcher;
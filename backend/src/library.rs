@@ -0,0 +1,172 @@
+use crate::config::ConfigInterface;
+use crate::pool::{Pool, DEFAULT_MAX_READERS};
+use crate::watcher::LibraryWatcher;
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::Lazy;
+use shared::types::{AppConfig, LibraryEntry};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// The library that is currently opened. Swapping it out does not interrupt queries that are
+/// already running: they hold their own `Arc` and the old connections are closed once the last
+/// of them finishes.
+static ACTIVE: Lazy<RwLock<Option<Arc<Library>>>> = Lazy::new(|| RwLock::new(None));
+
+/// An opened library: its directory, the database file inside it and the connections to it
+pub struct Library {
+    name: String,
+    dir: PathBuf,
+    pool: Pool,
+    _watcher: Option<LibraryWatcher>,
+}
+
+impl Library {
+    /// Open (and migrate) the `library.db` inside `dir`, creating it if necessary
+    pub fn open(name: &str, dir: &Path) -> Result<Self> {
+        let db_path = dir.join("library.db");
+        let first_time = !db_path.exists();
+        let pool = Pool::open(&db_path, DEFAULT_MAX_READERS)?;
+        let watcher = LibraryWatcher::start(name, &db_path)
+            .inspect_err(|e| tracing::warn!("Not watching {:?} for changes: {e:#}", db_path))
+            .ok();
+
+        if first_time {
+            tracing::info!("Initialized new SQLite database at {:?}", db_path);
+        } else {
+            tracing::info!("Opened existing SQLite database at {:?}", db_path);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            dir: dir.to_path_buf(),
+            pool,
+            _watcher: watcher,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn db_path(&self) -> &Path {
+        self.pool.db_path()
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+}
+
+/// Returns the currently opened library, if any
+pub fn active() -> Option<Arc<Library>> {
+    ACTIVE.read().ok().and_then(|guard| guard.clone())
+}
+
+/// Returns the currently opened library or an error if none has been opened yet
+pub fn require_active() -> Result<Arc<Library>> {
+    active().ok_or_else(|| anyhow!("DB path not configured yet!"))
+}
+
+/// Open the library in `dir` and make it the active one. The previously active library is
+/// closed as soon as no query uses it anymore.
+pub fn activate(name: &str, dir: &Path) -> Result<Arc<Library>> {
+    if let Some(current) = active() {
+        if current.dir() == dir {
+            tracing::debug!("Library {:?} is already active; reusing connection", name);
+            return Ok(current);
+        }
+    }
+
+    let library = Arc::new(Library::open(name, dir)?);
+    let previous = ACTIVE
+        .write()
+        .map_err(|_| anyhow!("Library registry is poisoned"))?
+        .replace(library.clone());
+    if let Some(previous) = previous {
+        tracing::info!(
+            "Switched library from {:?} ({:?}) to {:?} ({:?})",
+            previous.name(),
+            previous.dir(),
+            library.name(),
+            library.dir()
+        );
+    }
+    Ok(library)
+}
+
+/// Close the active library, e.g., before its files are moved or deleted
+pub fn close() -> Result<()> {
+    ACTIVE
+        .write()
+        .map_err(|_| anyhow!("Library registry is poisoned"))?
+        .take();
+    Ok(())
+}
+
+/// Add a named library to the config. Re-registering an existing name with the same directory
+/// is a no-op.
+pub fn register(name: &str, dir: PathBuf) -> Result<AppConfig> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(anyhow!("Library name must not be empty"));
+    }
+    let mut cfg = AppConfig::read()?;
+    if let Some(existing) = cfg.find_library(name) {
+        if existing.data_dir != dir {
+            return Err(anyhow!(
+                "A library named {name:?} already exists at {:?}",
+                existing.data_dir
+            ));
+        }
+        return Ok(cfg);
+    }
+    if let Some(existing) = cfg.libraries.iter().find(|l| l.data_dir == dir) {
+        return Err(anyhow!(
+            "Directory {dir:?} is already registered as library {:?}",
+            existing.name
+        ));
+    }
+    cfg.libraries.push(LibraryEntry {
+        name: name.to_string(),
+        data_dir: dir,
+    });
+    cfg.write()?;
+    Ok(cfg)
+}
+
+/// Remove a library from the config. The files on disk are left untouched.
+pub fn unregister(name: &str) -> Result<AppConfig> {
+    let mut cfg = AppConfig::read()?;
+    let entry = cfg
+        .find_library(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("No library named {name:?}"))?;
+    if cfg.data_dir.as_ref() == Some(&entry.data_dir) {
+        return Err(anyhow!(
+            "Library {name:?} is currently active; switch to another library first"
+        ));
+    }
+    cfg.libraries.retain(|l| l.name != name);
+    cfg.write()?;
+    Ok(cfg)
+}
+
+/// Open a registered library by name and persist it as the active one
+pub fn switch(name: &str) -> Result<Arc<Library>> {
+    let mut cfg = AppConfig::read()?;
+    let entry = cfg
+        .find_library(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("No library named {name:?}"))?;
+    std::fs::create_dir_all(&entry.data_dir)
+        .with_context(|| format!("Creating/ensuring data directory {:?}", entry.data_dir))?;
+    let library = activate(&entry.name, &entry.data_dir)?;
+    if cfg.data_dir.as_ref() != Some(&entry.data_dir) {
+        cfg.data_dir = Some(entry.data_dir);
+        cfg.write()?;
+    }
+    Ok(library)
+}
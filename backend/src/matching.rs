@@ -0,0 +1,247 @@
+use anyhow::Result;
+use rusqlite::Connection;
+use shared::types::BookMatch;
+use strsim::normalized_levenshtein;
+
+/// Minimum score for a book to count as a match
+pub const MATCH_THRESHOLD: f64 = 0.85;
+/// Matches whose scores are closer than this to the best one make a match ambiguous
+pub const AMBIGUITY_MARGIN: f64 = 0.02;
+
+/// Normalize a title or name for comparison: lower case, punctuation removed, whitespace
+/// collapsed and a leading article dropped
+pub fn normalize(value: &str) -> String {
+    let cleaned: String = value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_lowercase().next().unwrap_or(c)
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    let words: Vec<&str> = cleaned.split_whitespace().collect();
+    match words.split_first() {
+        Some((first, rest)) if !rest.is_empty() && ["the", "a", "an"].contains(first) => {
+            rest.join(" ")
+        }
+        _ => words.join(" "),
+    }
+}
+
+/// Normalized name with its words sorted, so "Sanderson, Brandon" equals "Brandon Sanderson"
+fn name_key(name: &str) -> String {
+    let normalized = normalize(name);
+    let mut words: Vec<&str> = normalized.split_whitespace().collect();
+    words.sort_unstable();
+    words.join(" ")
+}
+
+/// Similarity of two titles between 0 and 1. A subtitle on only one side doesn't count against
+/// the match. Subtitles on both sides do, they tell apart the volumes of a series such as
+/// "Mistborn: The Final Empire" and "Mistborn: The Hero of Ages".
+pub fn title_similarity(a: &str, b: &str) -> f64 {
+    const SUBTITLE_START: [char; 2] = [':', '('];
+    let main = |t: &str| t.split(SUBTITLE_START).next().unwrap_or(t).to_string();
+    let full = normalized_levenshtein(&normalize(a), &normalize(b));
+    if a.contains(SUBTITLE_START) == b.contains(SUBTITLE_START) {
+        return full;
+    }
+    full.max(normalized_levenshtein(
+        &normalize(&main(a)),
+        &normalize(&main(b)),
+    ))
+}
+
+/// Similarity of an author name to the closest of `authors`, between 0 and 1
+pub fn author_similarity(name: &str, authors: &[String]) -> f64 {
+    let key = name_key(name);
+    authors
+        .iter()
+        .map(|a| normalized_levenshtein(&key, &name_key(a)))
+        .fold(0.0, f64::max)
+}
+
+/// How well a book with `title` and `author` matches one with `other_title` and
+/// `other_authors`, between 0 and 1. The author counts only if both sides have one.
+pub fn score(
+    title: &str,
+    author: Option<&str>,
+    other_title: &str,
+    other_authors: &[String],
+) -> f64 {
+    let title_score = title_similarity(title, other_title);
+    match author {
+        Some(author) if !other_authors.is_empty() => {
+            0.75 * title_score + 0.25 * author_similarity(author, other_authors)
+        }
+        _ => title_score,
+    }
+}
+
+struct Candidate {
+    id: i64,
+    title: String,
+    authors: Vec<String>,
+}
+
+/// All books of a library with their authors, loaded once to match many rows of an import
+pub struct BookIndex {
+    books: Vec<Candidate>,
+}
+
+impl BookIndex {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut stmt = conn.prepare(
+            "SELECT b.id, b.title, GROUP_CONCAT(a.name, '|')
+             FROM books b
+             LEFT JOIN books_authors_link bal ON bal.book = b.id
+             LEFT JOIN authors a ON a.id = bal.author
+             GROUP BY b.id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let authors: Option<String> = row.get(2)?;
+            Ok(Candidate {
+                id: row.get(0)?,
+                title: row.get(1)?,
+                authors: authors
+                    .map(|a| a.split('|').map(str::to_string).collect())
+                    .unwrap_or_default(),
+            })
+        })?;
+        Ok(Self {
+            books: rows.collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Add a book created after loading, e.g., by the import that matches against the index
+    pub fn add(&mut self, id: i64, title: &str, authors: &[String]) {
+        self.books.push(Candidate {
+            id,
+            title: title.to_string(),
+            authors: authors.to_vec(),
+        });
+    }
+
+    /// Books scoring at least [`MATCH_THRESHOLD`] for the title and, if given, the author, best
+    /// match first
+    pub fn matches(&self, title: &str, author: Option<&str>) -> Vec<BookMatch> {
+        let mut matches: Vec<BookMatch> = self
+            .books
+            .iter()
+            .filter_map(|book| {
+                let score = score(title, author, &book.title, &book.authors);
+                (score >= MATCH_THRESHOLD).then(|| BookMatch {
+                    book: book.id,
+                    title: book.title.clone(),
+                    authors: book.authors.clone(),
+                    score,
+                })
+            })
+            .collect();
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        matches
+    }
+
+    /// The single best match, or an error message if there is none or it is ambiguous
+    pub fn find(&self, title: &str, author: Option<&str>) -> Result<BookMatch, String> {
+        let mut matches = self.matches(title, author).into_iter();
+        let best = matches
+            .next()
+            .ok_or_else(|| "No matching book in the library".to_string())?;
+        let close: Vec<String> = matches
+            .take_while(|m| best.score - m.score < AMBIGUITY_MARGIN)
+            .map(|m| format!("{:?} (book {})", m.title, m.book))
+            .collect();
+        if !close.is_empty() {
+            return Err(format!(
+                "Matches several books: {:?} (book {}), {}",
+                best.title,
+                best.book,
+                close.join(", ")
+            ));
+        }
+        Ok(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authors(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn volumes_of_a_series_do_not_match() {
+        let sanderson = authors(&["Brandon Sanderson"]);
+        assert!(
+            title_similarity("Mistborn: The Final Empire", "Mistborn: The Hero of Ages") < 0.85
+        );
+        assert!(
+            score(
+                "Mistborn: The Final Empire",
+                Some("Brandon Sanderson"),
+                "Mistborn: The Hero of Ages",
+                &sanderson
+            ) < MATCH_THRESHOLD
+        );
+        assert!(
+            score(
+                "The Way of Kings (The Stormlight Archive, #1)",
+                Some("Brandon Sanderson"),
+                "Words of Radiance (The Stormlight Archive, #2)",
+                &sanderson
+            ) < MATCH_THRESHOLD
+        );
+    }
+
+    #[test]
+    fn subtitle_on_one_side_matches() {
+        assert_eq!(
+            title_similarity("Mistborn", "Mistborn: The Final Empire"),
+            1.0
+        );
+        assert_eq!(
+            title_similarity(
+                "The Way of Kings (The Stormlight Archive, #1)",
+                "Way of Kings"
+            ),
+            1.0
+        );
+        assert_eq!(
+            title_similarity("Mistborn: The Final Empire", "mistborn - the final empire"),
+            1.0
+        );
+    }
+
+    #[test]
+    fn last_first_authors_match() {
+        assert_eq!(
+            author_similarity(
+                "Sanderson, Brandon",
+                &authors(&["Terry Pratchett", "Brandon Sanderson"])
+            ),
+            1.0
+        );
+        assert_eq!(
+            score(
+                "Mistborn",
+                Some("Sanderson, Brandon"),
+                "Mistborn: The Final Empire",
+                &authors(&["Brandon Sanderson"])
+            ),
+            1.0
+        );
+        assert!(
+            score(
+                "Mistborn",
+                Some("Pratchett, Terry"),
+                "Mistborn",
+                &authors(&["Brandon Sanderson"])
+            ) < 1.0
+        );
+    }
+}
@@ -0,0 +1,562 @@
+pub mod fixture;
+
+use crate::{automation, books, covers};
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::{
+    BookMetadata, CoverCandidate, MergeStrategy, MetadataChange, MetadataConfig, MetadataField,
+    MetadataProposal, MetadataQuery, MetadataSearchResult, RuleTrigger,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// A source of book metadata, e.g., a web service or recorded responses of one.
+///
+/// Calls may block (e.g., on HTTP requests), so they must not be made on the async runtime
+/// directly.
+pub trait MetadataProvider: Send + Sync {
+    /// Unique name of the provider, used in merge strategies and shown to users
+    fn name(&self) -> &str;
+
+    /// Books matching the query, best match first
+    fn search(&self, query: &MetadataQuery) -> Result<Vec<MetadataSearchResult>>;
+
+    /// Full metadata of the book with the provider's `id` from a search result
+    fn details(&self, id: &str) -> Result<BookMetadata>;
+
+    /// Cover images available for the book with the provider's `id`
+    fn covers(&self, id: &str) -> Result<Vec<CoverCandidate>>;
+}
+
+/// How providers for web services fetch their responses. Providers take a client instead of
+/// making requests themselves, so they can be tested against recorded responses
+/// ([`fixture::RecordedHttp`]).
+pub trait HttpClient: Send + Sync {
+    fn get(&self, url: &str) -> Result<Vec<u8>>;
+}
+
+/// Makes real HTTP requests
+pub struct WebClient {
+    client: reqwest::blocking::Client,
+}
+
+impl WebClient {
+    pub fn new() -> Result<Self> {
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(concat!("IronScribe/", env!("CARGO_PKG_VERSION")))
+            .timeout(std::time::Duration::from_secs(30))
+            .build()?;
+        Ok(Self { client })
+    }
+}
+
+impl HttpClient for WebClient {
+    fn get(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .with_context(|| format!("Requesting {url}"))?
+            .error_for_status()?;
+        Ok(response.bytes()?.to_vec())
+    }
+}
+
+static HTTP: Lazy<RwLock<Option<Arc<dyn HttpClient>>>> = Lazy::new(|| RwLock::new(None));
+
+/// The client for HTTP requests, a [`WebClient`] unless replaced with [`set_http`]
+pub fn http() -> Arc<dyn HttpClient> {
+    if let Some(client) = HTTP.read().unwrap().as_ref() {
+        return client.clone();
+    }
+    let mut http = HTTP.write().unwrap();
+    http.get_or_insert_with(|| match WebClient::new() {
+        Ok(client) => Arc::new(client),
+        Err(e) => {
+            tracing::error!("Failed to set up HTTP client: {e:#}");
+            Arc::new(NoHttp)
+        }
+    })
+    .clone()
+}
+
+/// Use `client` for all HTTP requests, e.g., [`fixture::RecordedHttp`] to work offline
+pub fn set_http(client: Arc<dyn HttpClient>) {
+    *HTTP.write().unwrap() = Some(client);
+}
+
+/// Stands in if no HTTP client could be set up
+struct NoHttp;
+
+impl HttpClient for NoHttp {
+    fn get(&self, url: &str) -> Result<Vec<u8>> {
+        Err(anyhow!("HTTP requests are unavailable, cannot fetch {url}"))
+    }
+}
+
+static PROVIDERS: Lazy<RwLock<Vec<Arc<dyn MetadataProvider>>>> =
+    Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Make a provider available, replacing a registered one of the same name
+pub fn register(provider: Arc<dyn MetadataProvider>) {
+    let mut providers = PROVIDERS.write().unwrap();
+    providers.retain(|p| p.name() != provider.name());
+    tracing::info!("Registered metadata provider {:?}", provider.name());
+    providers.push(provider);
+}
+
+pub fn providers() -> Vec<Arc<dyn MetadataProvider>> {
+    PROVIDERS.read().unwrap().clone()
+}
+
+pub fn provider(name: &str) -> Option<Arc<dyn MetadataProvider>> {
+    providers().into_iter().find(|p| p.name() == name)
+}
+
+/// Register the providers set up in the config
+pub fn configure(config: &MetadataConfig) {
+    for dir in &config.fixture_dirs {
+        match fixture::FixtureProvider::open(dir) {
+            Ok(provider) => register(Arc::new(provider)),
+            Err(e) => tracing::error!("Failed to load metadata fixtures from {dir:?}: {e:#}"),
+        }
+    }
+}
+
+/// Search a provider and fetch the details of its best match, with the provider's ID of it
+fn fetch_best(
+    provider: &dyn MetadataProvider,
+    query: &MetadataQuery,
+) -> Result<Option<(String, BookMetadata)>> {
+    match provider.search(query)?.into_iter().next() {
+        Some(best) => Ok(Some((best.id.clone(), provider.details(&best.id)?))),
+        None => Ok(None),
+    }
+}
+
+/// Search every registered provider and fetch the details of its best match. Providers that
+/// fail or find nothing are skipped.
+pub fn fetch_all(query: &MetadataQuery) -> Vec<(String, BookMetadata)> {
+    let mut results = Vec::new();
+    for provider in providers() {
+        match fetch_best(provider.as_ref(), query) {
+            Ok(Some((_, metadata))) => results.push((provider.name().to_string(), metadata)),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Metadata provider {:?} failed: {e:#}", provider.name()),
+        }
+    }
+    results
+}
+
+/// Covers offered by every registered provider for its best match of the query
+pub fn fetch_covers(query: &MetadataQuery) -> Vec<CoverCandidate> {
+    let mut covers = Vec::new();
+    for provider in providers() {
+        let found = provider
+            .search(query)
+            .and_then(|results| match results.first() {
+                Some(best) => provider.covers(&best.id),
+                None => Ok(Vec::new()),
+            });
+        match found {
+            Ok(found) => covers.extend(found),
+            Err(e) => tracing::warn!("Metadata provider {:?} failed: {e:#}", provider.name()),
+        }
+    }
+    covers
+}
+
+/// Name of a field as stored in the `locked_fields` table
+pub fn field_name(field: MetadataField) -> &'static str {
+    match field {
+        MetadataField::Title => "title",
+        MetadataField::Authors => "authors",
+        MetadataField::Series => "series",
+        MetadataField::Description => "description",
+        MetadataField::DatePublished => "date_published",
+        MetadataField::NumberOfPages => "number_of_pages",
+        MetadataField::Isbn => "isbn",
+        MetadataField::GoodreadsId => "goodreads_id",
+        MetadataField::Tags => "tags",
+    }
+}
+
+pub fn locked_fields(conn: &Connection, book: i64) -> Result<BTreeSet<MetadataField>> {
+    let mut stmt = conn.prepare("SELECT field FROM locked_fields WHERE book = ?1")?;
+    let names = stmt.query_map([book], |row| row.get::<_, String>(0))?;
+    let mut locked = BTreeSet::new();
+    for name in names {
+        let name = name?;
+        if let Some(field) = MetadataField::ALL
+            .into_iter()
+            .find(|f| field_name(*f) == name)
+        {
+            locked.insert(field);
+        }
+    }
+    Ok(locked)
+}
+
+pub fn set_locked(conn: &Connection, book: i64, field: MetadataField, locked: bool) -> Result<()> {
+    if locked {
+        conn.execute(
+            "INSERT OR IGNORE INTO locked_fields (book, field) VALUES (?1, ?2)",
+            params![book, field_name(field)],
+        )?;
+    } else {
+        conn.execute(
+            "DELETE FROM locked_fields WHERE book = ?1 AND field = ?2",
+            params![book, field_name(field)],
+        )?;
+    }
+    Ok(())
+}
+
+/// Metadata of a book as stored in the library
+pub fn current_metadata(conn: &Connection, book: i64) -> Result<BookMetadata> {
+    let (title, date_published, number_of_pages, goodreads_id) = conn
+        .query_row(
+            "SELECT title, date_published, number_of_pages, goodreads_id FROM books WHERE id = ?1",
+            [book],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get(1)?,
+                    row.get::<_, Option<u32>>(2)?,
+                    row.get(3)?,
+                ))
+            },
+        )
+        .optional()?
+        .ok_or_else(|| anyhow!("There is no book with ID {book}"))?;
+    let strings = |sql: &str| -> Result<Vec<String>> {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([book], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    };
+    let series = conn
+        .query_row(
+            "SELECT s.name, bsl.entry FROM books_series_link bsl JOIN series s ON s.id = bsl.series WHERE bsl.book = ?1",
+            [book],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?)),
+        )
+        .optional()?;
+    Ok(BookMetadata {
+        title: Some(title),
+        authors: strings(
+            "SELECT a.name FROM books_authors_link bal JOIN authors a ON a.id = bal.author WHERE bal.book = ?1 ORDER BY bal.rowid",
+        )?,
+        series_index: series.as_ref().map(|(_, entry)| *entry),
+        series: series.map(|(name, _)| name),
+        description: strings("SELECT text FROM comments WHERE book = ?1")?
+            .into_iter()
+            .next(),
+        date_published,
+        number_of_pages: number_of_pages.filter(|n| *n > 0),
+        isbn: strings("SELECT val FROM identifiers WHERE book = ?1 AND type = 'isbn'")?
+            .into_iter()
+            .next(),
+        goodreads_id,
+        tags: strings(
+            "SELECT t.name FROM books_tags_link btl JOIN tags t ON t.id = btl.tag WHERE btl.book = ?1 ORDER BY t.name",
+        )?,
+    })
+}
+
+fn has_value(metadata: &BookMetadata, field: MetadataField) -> bool {
+    match field {
+        MetadataField::Title => metadata.title.is_some(),
+        MetadataField::Authors => !metadata.authors.is_empty(),
+        MetadataField::Series => metadata.series.is_some(),
+        MetadataField::Description => metadata.description.is_some(),
+        MetadataField::DatePublished => metadata.date_published.is_some(),
+        MetadataField::NumberOfPages => metadata.number_of_pages.is_some(),
+        MetadataField::Isbn => metadata.isbn.is_some(),
+        MetadataField::GoodreadsId => metadata.goodreads_id.is_some(),
+        MetadataField::Tags => !metadata.tags.is_empty(),
+    }
+}
+
+fn copy_field(from: &BookMetadata, to: &mut BookMetadata, field: MetadataField) {
+    match field {
+        MetadataField::Title => to.title = from.title.clone(),
+        MetadataField::Authors => to.authors = from.authors.clone(),
+        MetadataField::Series => {
+            to.series = from.series.clone();
+            to.series_index = from.series_index;
+        }
+        MetadataField::Description => to.description = from.description.clone(),
+        MetadataField::DatePublished => to.date_published = from.date_published,
+        MetadataField::NumberOfPages => to.number_of_pages = from.number_of_pages,
+        MetadataField::Isbn => to.isbn = from.isbn.clone(),
+        MetadataField::GoodreadsId => to.goodreads_id = from.goodreads_id,
+        MetadataField::Tags => to.tags = from.tags.clone(),
+    }
+}
+
+/// The value of a single field of `metadata`, with all other fields empty
+fn field_value(metadata: &BookMetadata, field: MetadataField) -> BookMetadata {
+    let mut value = BookMetadata::default();
+    copy_field(metadata, &mut value, field);
+    value
+}
+
+/// Combine the metadata fetched from providers (pairs of provider name and metadata) with the
+/// book's current metadata.
+///
+/// Every field is taken from the first provider in the field's precedence order that has a
+/// value for it. Providers missing from the order come last, in the order they were given.
+/// Fields without any fetched value, locked fields (if the strategy keeps them) and, with
+/// `only_fill_empty`, fields that already have a value keep the current value.
+pub fn merge(
+    current: &BookMetadata,
+    fetched: &[(String, BookMetadata)],
+    locked: &BTreeSet<MetadataField>,
+    strategy: &MergeStrategy,
+) -> BookMetadata {
+    merge_with_sources(current, fetched, locked, strategy).0
+}
+
+/// [`merge`], also returning the provider each field that was taken from one came from
+fn merge_with_sources(
+    current: &BookMetadata,
+    fetched: &[(String, BookMetadata)],
+    locked: &BTreeSet<MetadataField>,
+    strategy: &MergeStrategy,
+) -> (BookMetadata, BTreeMap<MetadataField, String>) {
+    let mut merged = current.clone();
+    let mut sources = BTreeMap::new();
+    for field in MetadataField::ALL {
+        if strategy.keep_locked && locked.contains(&field) {
+            continue;
+        }
+        if strategy.only_fill_empty && has_value(current, field) {
+            continue;
+        }
+        let order = strategy
+            .field_order
+            .get(&field)
+            .unwrap_or(&strategy.provider_order);
+        let rank = |name: &str| order.iter().position(|p| p == name).unwrap_or(order.len());
+        let mut candidates: Vec<&(String, BookMetadata)> = fetched
+            .iter()
+            .filter(|(_, metadata)| has_value(metadata, field))
+            .collect();
+        // Stable sort keeps the given order among providers of equal rank
+        candidates.sort_by_key(|(name, _)| rank(name));
+        if let Some((name, metadata)) = candidates.first() {
+            copy_field(metadata, &mut merged, field);
+            sources.insert(field, name.clone());
+        }
+    }
+    (merged, sources)
+}
+
+/// Fetch metadata and covers for a book from every registered provider and merge them into a
+/// proposal to review. Providers are queried by the current title, first author and ISBN.
+///
+/// This makes the providers' (possibly blocking) requests, so `current` and `locked` are
+/// passed in rather than read here, to not hold a database connection meanwhile.
+pub fn propose(
+    book: i64,
+    current: BookMetadata,
+    locked: BTreeSet<MetadataField>,
+    has_cover: bool,
+    strategy: &MergeStrategy,
+) -> MetadataProposal {
+    let query = MetadataQuery {
+        title: current.title.clone(),
+        author: current.authors.first().cloned(),
+        isbn: current.isbn.clone(),
+    };
+    let mut fetched = Vec::new();
+    let mut covers = Vec::new();
+    for provider in providers() {
+        let found = fetch_best(provider.as_ref(), &query).and_then(|found| match found {
+            Some((id, metadata)) => Ok(Some((metadata, provider.covers(&id)?))),
+            None => Ok(None),
+        });
+        match found {
+            Ok(Some((metadata, provider_covers))) => {
+                fetched.push((provider.name().to_string(), metadata));
+                covers.extend(provider_covers);
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Metadata provider {:?} failed: {e:#}", provider.name()),
+        }
+    }
+    let (proposed, mut changes) = merge_with_sources(&current, &fetched, &locked, strategy);
+    changes.retain(|field, _| field_value(&current, *field) != field_value(&proposed, *field));
+    MetadataProposal {
+        book,
+        current,
+        proposed,
+        changes,
+        locked,
+        has_cover,
+        covers,
+    }
+}
+
+/// Apply the accepted fields and `cover` of a reviewed change to the book, going through
+/// [`books::update_book`] like any other edit, and move its files after its new title and first
+/// author once that is committed. Fails without changing anything if an accepted field is
+/// locked.
+///
+/// `cover` is the image of the accepted cover candidate, fetched with [`covers::fetch`] before
+/// taking the write connection, which downloading must not hold.
+pub fn apply(
+    conn: &mut Connection,
+    library_dir: &Path,
+    change: &MetadataChange,
+    cover: Option<&[u8]>,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    let locked = locked_fields(&tx, change.book)?;
+    if let Some(field) = change.accepted.iter().find(|field| locked.contains(field)) {
+        return Err(anyhow!(
+            "The {} of book {} is locked, unlock it to change it",
+            field_name(*field),
+            change.book
+        ));
+    }
+    let mut metadata = current_metadata(&tx, change.book)?;
+    let old_tags = metadata.tags.clone();
+    for field in &change.accepted {
+        copy_field(&change.metadata, &mut metadata, *field);
+    }
+    books::update_book(&tx, change.book, &metadata)?;
+    if let Some(image) = cover {
+        covers::save(&tx, library_dir, change.book, image, None)?;
+    }
+    tx.commit()?;
+    automation::emit(conn, RuleTrigger::MetadataChanged, change.book, None);
+    for tag in metadata.tags.iter().filter(|tag| !old_tags.contains(tag)) {
+        automation::emit(conn, RuleTrigger::TagAdded, change.book, Some(tag));
+    }
+    books::rename_files(conn, library_dir, change.book).context(
+        "The metadata was saved, but moving the files of the book after its title and author failed",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::books::{self, NewBook};
+    use crate::migrations;
+    use fixture::FixtureProvider;
+
+    fn fixture(name: &str) -> FixtureProvider {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/metadata");
+        FixtureProvider::open(&dir.join(name)).unwrap()
+    }
+
+    fn current() -> BookMetadata {
+        BookMetadata {
+            title: Some("Way of Kings".to_string()),
+            authors: vec!["Brandon Sanderson".to_string()],
+            description: Some("My own summary".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The best matches of the sample and the other fixture provider for [`current`]
+    fn fetched() -> Vec<(String, BookMetadata)> {
+        let query = MetadataQuery {
+            title: current().title,
+            author: current().authors.first().cloned(),
+            isbn: None,
+        };
+        ["sample", "other"]
+            .into_iter()
+            .map(|name| {
+                let (_, metadata) = fetch_best(&fixture(name), &query).unwrap().unwrap();
+                (name.to_string(), metadata)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge_follows_provider_and_field_order() {
+        let strategy = MergeStrategy {
+            provider_order: vec!["other".to_string(), "sample".to_string()],
+            field_order: BTreeMap::from([(
+                MetadataField::NumberOfPages,
+                vec!["sample".to_string()],
+            )]),
+            ..Default::default()
+        };
+        let merged = merge(&current(), &fetched(), &BTreeSet::new(), &strategy);
+        assert!(merged
+            .description
+            .as_deref()
+            .is_some_and(|d| d.contains("incredible power")));
+        assert_eq!(merged.number_of_pages, Some(1007));
+        // Only the sample provider has them, so they come from it despite its rank
+        assert_eq!(merged.series.as_deref(), Some("The Stormlight Archive"));
+        assert_eq!(merged.tags, vec!["Fantasy", "Epic Fantasy"]);
+
+        let strategy = MergeStrategy {
+            provider_order: vec!["sample".to_string()],
+            ..Default::default()
+        };
+        let merged = merge(&current(), &fetched(), &BTreeSet::new(), &strategy);
+        assert_eq!(merged.number_of_pages, Some(1007));
+        assert_eq!(
+            merged.description.as_deref(),
+            Some("Roshar is a world of stone and storms.")
+        );
+    }
+
+    #[test]
+    fn merge_keeps_locked_fields() {
+        let locked = BTreeSet::from([MetadataField::Description, MetadataField::Title]);
+        let merged = merge(&current(), &fetched(), &locked, &MergeStrategy::default());
+        assert_eq!(merged.description, current().description);
+        assert_eq!(merged.title, current().title);
+        assert_eq!(merged.number_of_pages, Some(1007));
+
+        let strategy = MergeStrategy {
+            keep_locked: false,
+            ..Default::default()
+        };
+        let merged = merge(&current(), &fetched(), &locked, &strategy);
+        assert_eq!(merged.title.as_deref(), Some("The Way of Kings"));
+    }
+
+    #[test]
+    fn apply_refuses_locked_fields() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        let book = NewBook {
+            title: "Way of Kings".to_string(),
+            ..Default::default()
+        };
+        let book = books::insert_book(&conn, &book).unwrap();
+        set_locked(&conn, book, MetadataField::Title, true).unwrap();
+        let library_dir = std::env::temp_dir();
+        let (_, proposed) = fetched().remove(0);
+
+        let change = MetadataChange {
+            book,
+            metadata: proposed.clone(),
+            accepted: BTreeSet::from([MetadataField::Title, MetadataField::NumberOfPages]),
+            cover: None,
+        };
+        assert!(apply(&mut conn, &library_dir, &change, None).is_err());
+        let metadata = current_metadata(&conn, book).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Way of Kings"));
+        assert_eq!(metadata.number_of_pages, None);
+
+        let change = MetadataChange {
+            accepted: BTreeSet::from([MetadataField::NumberOfPages]),
+            ..change
+        };
+        apply(&mut conn, &library_dir, &change, None).unwrap();
+        let metadata = current_metadata(&conn, book).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Way of Kings"));
+        assert_eq!(metadata.number_of_pages, Some(1007));
+    }
+}
@@ -0,0 +1,165 @@
+use super::{HttpClient, MetadataProvider};
+use crate::matching::{self, MATCH_THRESHOLD};
+use anyhow::{anyhow, Context, Result};
+use chrono::Datelike;
+use serde::{Deserialize, Serialize};
+use shared::types::{BookMetadata, CoverCandidate, MetadataQuery, MetadataSearchResult};
+use std::path::{Path, PathBuf};
+
+/// A book as recorded in a fixture file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FixtureBook {
+    pub id: String,
+    pub metadata: BookMetadata,
+    /// Cover images, relative to the fixture directory or absolute URLs
+    #[serde(default)]
+    pub covers: Vec<FixtureCover>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FixtureCover {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// A metadata provider answering from JSON files instead of a web service, for tests and for
+/// working offline.
+///
+/// Every `*.json` file in the directory holds one [`FixtureBook`]. The provider is named after the
+/// directory, so recordings of a real provider can stand in for it under the same name.
+pub struct FixtureProvider {
+    name: String,
+    dir: PathBuf,
+    books: Vec<FixtureBook>,
+}
+
+impl FixtureProvider {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let name = dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Invalid fixture directory {dir:?}"))?
+            .to_string();
+        let mut books = Vec::new();
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Reading {dir:?}"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                let content =
+                    std::fs::read_to_string(&path).with_context(|| format!("Reading {path:?}"))?;
+                let book: FixtureBook = serde_json::from_str(&content)
+                    .with_context(|| format!("Parsing fixture {path:?}"))?;
+                books.push(book);
+            }
+        }
+        books.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(Self {
+            name,
+            dir: dir.to_path_buf(),
+            books,
+        })
+    }
+
+    fn book(&self, id: &str) -> Result<&FixtureBook> {
+        self.books
+            .iter()
+            .find(|b| b.id == id)
+            .ok_or_else(|| anyhow!("{} has no book with ID {id:?}", self.name))
+    }
+
+    fn score(book: &FixtureBook, query: &MetadataQuery) -> f64 {
+        let isbn = |value: &str| value.replace('-', "");
+        if let (Some(wanted), Some(isbn_value)) = (&query.isbn, &book.metadata.isbn) {
+            if isbn(wanted) == isbn(isbn_value) {
+                return 1.0;
+            }
+        }
+        let Some(title) = &query.title else {
+            return 0.0;
+        };
+        matching::score(
+            title,
+            query.author.as_deref(),
+            book.metadata.title.as_deref().unwrap_or(""),
+            &book.metadata.authors,
+        )
+    }
+}
+
+impl MetadataProvider for FixtureProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn search(&self, query: &MetadataQuery) -> Result<Vec<MetadataSearchResult>> {
+        let mut scored: Vec<(f64, &FixtureBook)> = self
+            .books
+            .iter()
+            .map(|book| (Self::score(book, query), book))
+            .filter(|(score, _)| *score >= MATCH_THRESHOLD)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored
+            .into_iter()
+            .map(|(_, book)| MetadataSearchResult {
+                provider: self.name.clone(),
+                id: book.id.clone(),
+                title: book.metadata.title.clone().unwrap_or_default(),
+                authors: book.metadata.authors.clone(),
+                year: book.metadata.date_published.map(|d| d.year()),
+            })
+            .collect())
+    }
+
+    fn details(&self, id: &str) -> Result<BookMetadata> {
+        Ok(self.book(id)?.metadata.clone())
+    }
+
+    fn covers(&self, id: &str) -> Result<Vec<CoverCandidate>> {
+        Ok(self
+            .book(id)?
+            .covers
+            .iter()
+            .map(|cover| CoverCandidate {
+                provider: self.name.clone(),
+                url: if cover.url.contains("://") {
+                    cover.url.clone()
+                } else {
+                    self.dir.join(&cover.url).to_string_lossy().into_owned()
+                },
+                width: cover.width,
+                height: cover.height,
+            })
+            .collect())
+    }
+}
+
+/// Serves recorded responses instead of making HTTP requests, so providers for web services
+/// can be exercised offline. The response to a URL is read from a file in the directory named
+/// after the URL with every character other than ASCII letters and digits replaced by `_`.
+pub struct RecordedHttp {
+    dir: PathBuf,
+}
+
+impl RecordedHttp {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn recording_path(&self, url: &str) -> PathBuf {
+        let name: String = url
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        self.dir.join(name)
+    }
+}
+
+impl HttpClient for RecordedHttp {
+    fn get(&self, url: &str) -> Result<Vec<u8>> {
+        let path = self.recording_path(url);
+        std::fs::read(&path).with_context(|| format!("No recorded response for {url} at {path:?}"))
+    }
+}
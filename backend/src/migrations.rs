@@ -0,0 +1,160 @@
+use crate::backup;
+use anyhow::{anyhow, Context, Result};
+use rusqlite::Connection;
+use std::path::PathBuf;
+
+/// A single schema change. Migrations are applied in order of their version, each one in its own
+/// transaction, and the version of the last applied migration is stored in `PRAGMA user_version`.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// All migrations, ordered by version. Never edit a migration that has been released; add a new
+/// one instead so existing databases receive the change too.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("./migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "calibre_tables",
+        sql: include_str!("./migrations/0002_calibre_tables.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "users_and_collections",
+        sql: include_str!("./migrations/0003_users_and_collections.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "reading_ratings",
+        sql: include_str!("./migrations/0004_reading_ratings.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "locked_fields",
+        sql: include_str!("./migrations/0005_locked_fields.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "book_statistics",
+        sql: include_str!("./migrations/0006_book_statistics.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "file_hashes",
+        sql: include_str!("./migrations/0007_file_hashes.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "epub_checks",
+        sql: include_str!("./migrations/0008_epub_checks.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "conversions",
+        sql: include_str!("./migrations/0009_conversions.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "jobs",
+        sql: include_str!("./migrations/0010_jobs.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "rules",
+        sql: include_str!("./migrations/0011_rules.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "file_fingerprints",
+        sql: include_str!("./migrations/0012_file_fingerprints.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "epub_check_fingerprints",
+        sql: include_str!("./migrations/0013_epub_check_fingerprints.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "calibre_sources",
+        sql: include_str!("./migrations/0014_calibre_sources.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "conversion_requeues",
+        sql: include_str!("./migrations/0015_conversion_requeues.sql"),
+    },
+];
+
+/// Schema version this build of the application expects
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Bring the database up to the latest schema version.
+///
+/// Before touching a database that already contains data, a snapshot is written to the
+/// configured backup directory. Databases created by a newer version of the application are
+/// rejected rather than risking damage to data this version doesn't know about.
+pub fn run(conn: &mut Connection) -> Result<()> {
+    conn.execute("PRAGMA foreign_keys = ON", [])?;
+
+    let current = current_version(conn)?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(anyhow!(
+            "The library database has schema version {current}, but this version of IronScribe only supports up to version {latest}. Please update IronScribe to open this library."
+        ));
+    }
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    if has_user_tables(conn)? {
+        if let Some(db_path) = conn.path().filter(|p| !p.is_empty()).map(PathBuf::from) {
+            let dir = backup::backup_dir_for_db(&db_path)?;
+            let backup_path = backup::snapshot(conn, &dir, &format!("pre-v{current}"))?;
+            tracing::info!(
+                "Backed up library database to {:?} before migrating from version {current}",
+                backup_path
+            );
+        }
+    }
+
+    for migration in pending {
+        tracing::info!(
+            "Applying migration {:04}_{}",
+            migration.version,
+            migration.name
+        );
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql).with_context(|| {
+            format!(
+                "Applying migration {:04}_{}",
+                migration.version, migration.name
+            )
+        })?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn has_user_tables(conn: &Connection) -> Result<bool> {
+    let count: u32 = conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
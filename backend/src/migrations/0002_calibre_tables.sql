@@ -0,0 +1,62 @@
+ALTER TABLE books ADD COLUMN path TEXT NOT NULL DEFAULT '';
+ALTER TABLE books ADD COLUMN has_cover INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE books ADD COLUMN uuid TEXT;
+CREATE TABLE tags (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL COLLATE NOCASE,
+    UNIQUE(name)
+);
+CREATE TABLE books_tags_link (
+    book INTEGER NOT NULL,
+    tag INTEGER NOT NULL,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    FOREIGN KEY(tag) REFERENCES tags(id) ON DELETE CASCADE,
+    UNIQUE(book, tag)
+);
+CREATE TABLE identifiers (
+    id INTEGER PRIMARY KEY,
+    book INTEGER NOT NULL,
+    type TEXT NOT NULL DEFAULT 'isbn' COLLATE NOCASE,
+    val TEXT NOT NULL COLLATE NOCASE,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    UNIQUE(book, type)
+);
+CREATE TABLE comments (
+    id INTEGER PRIMARY KEY,
+    book INTEGER NOT NULL,
+    text TEXT NOT NULL COLLATE NOCASE,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    UNIQUE(book)
+);
+CREATE TABLE data (
+    id INTEGER PRIMARY KEY,
+    book INTEGER NOT NULL,
+    format TEXT NOT NULL COLLATE NOCASE,
+    uncompressed_size INTEGER NOT NULL DEFAULT 0,
+    name TEXT NOT NULL,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    UNIQUE(book, format)
+);
+CREATE TABLE custom_columns (
+    id INTEGER PRIMARY KEY,
+    label TEXT NOT NULL,
+    name TEXT NOT NULL,
+    datatype TEXT NOT NULL,
+    is_multiple INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(label)
+);
+CREATE TABLE books_custom_column_values (
+    book INTEGER NOT NULL,
+    custom_column INTEGER NOT NULL,
+    value TEXT NOT NULL,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    FOREIGN KEY(custom_column) REFERENCES custom_columns(id) ON DELETE CASCADE,
+    UNIQUE(book, custom_column, value)
+);
+-- Maps book IDs of an imported Calibre library to ours, e.g., to import Calibre-Web data later
+CREATE TABLE calibre_book_ids (
+    calibre_id INTEGER PRIMARY KEY,
+    book INTEGER NOT NULL,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    UNIQUE(book)
+);
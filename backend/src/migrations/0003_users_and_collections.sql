@@ -0,0 +1,24 @@
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL COLLATE NOCASE,
+    UNIQUE(name)
+);
+-- NULL for readings that aren't attributed to a user
+ALTER TABLE read_books ADD COLUMN user INTEGER REFERENCES users(id) ON DELETE CASCADE;
+CREATE TABLE collections (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL COLLATE NOCASE,
+    user INTEGER,
+    is_public INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY(user) REFERENCES users(id) ON DELETE CASCADE,
+    UNIQUE(name, user)
+);
+CREATE TABLE books_collections_link (
+    book INTEGER NOT NULL,
+    collection INTEGER NOT NULL,
+    position INTEGER NOT NULL DEFAULT 0,
+    date_added TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    FOREIGN KEY(collection) REFERENCES collections(id) ON DELETE CASCADE,
+    UNIQUE(book, collection)
+);
@@ -0,0 +1,2 @@
+-- 1 to 5 stars given after the reading
+ALTER TABLE read_books ADD COLUMN rating INTEGER;
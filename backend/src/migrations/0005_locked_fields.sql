@@ -0,0 +1,7 @@
+-- Fields of a book that were edited by hand and must not be replaced by fetched metadata
+CREATE TABLE locked_fields (
+    book INTEGER NOT NULL,
+    field TEXT NOT NULL,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    UNIQUE(book, field)
+);
@@ -0,0 +1,15 @@
+-- Counts computed from a book's content. The manually entered page count stays in
+-- books.number_of_pages so either one can be used.
+CREATE TABLE book_statistics (
+    book INTEGER PRIMARY KEY,
+    -- Format of the file the counts were computed from, e.g., EPUB or PDF
+    format TEXT NOT NULL,
+    word_count INTEGER,
+    char_count INTEGER,
+    -- Derived from word_count and the configured words per page
+    estimated_pages INTEGER,
+    -- From the EPUB page-list or the pages of a PDF
+    real_pages INTEGER,
+    computed TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE
+);
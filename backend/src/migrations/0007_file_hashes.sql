@@ -0,0 +1,9 @@
+-- SHA-256 of the book files, to recognize the same file imported twice. A hash is stale once the
+-- file's size in `data` no longer matches, e.g., after metadata was embedded.
+CREATE TABLE file_hashes (
+    data INTEGER PRIMARY KEY,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    FOREIGN KEY(data) REFERENCES data(id) ON DELETE CASCADE
+);
+CREATE INDEX file_hashes_sha256 ON file_hashes(sha256);
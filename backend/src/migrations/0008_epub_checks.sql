@@ -0,0 +1,15 @@
+-- Results of checking EPUB files. Like a hash, a result is stale once the file's size in `data`
+-- no longer matches.
+CREATE TABLE epub_checks (
+    data INTEGER PRIMARY KEY,
+    size INTEGER NOT NULL,
+    FOREIGN KEY(data) REFERENCES data(id) ON DELETE CASCADE
+);
+CREATE TABLE epub_problems (
+    data INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    location TEXT NOT NULL,
+    message TEXT NOT NULL,
+    FOREIGN KEY(data) REFERENCES epub_checks(data) ON DELETE CASCADE
+);
+CREATE INDEX epub_problems_data ON epub_problems(data);
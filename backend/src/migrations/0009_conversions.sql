@@ -0,0 +1,13 @@
+-- Where converted files came from, so they are converted again when their source changes. The
+-- source is recognized by its size and modification time, or by its SHA-256 if only those
+-- changed.
+CREATE TABLE conversions (
+    data INTEGER PRIMARY KEY,
+    source INTEGER NOT NULL,
+    source_size INTEGER NOT NULL,
+    source_modified INTEGER NOT NULL,
+    source_sha256 TEXT NOT NULL,
+    converted_at TEXT NOT NULL,
+    FOREIGN KEY(data) REFERENCES data(id) ON DELETE CASCADE,
+    FOREIGN KEY(source) REFERENCES data(id) ON DELETE CASCADE
+);
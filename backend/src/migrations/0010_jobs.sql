@@ -0,0 +1,29 @@
+-- Background jobs of the library. `task` is the JSON of `shared::types::JobTask`, `kind` its
+-- kind for the concurrency limits. Jobs left running by a previous process are queued again.
+CREATE TABLE jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    task TEXT NOT NULL,
+    title TEXT NOT NULL,
+    state TEXT NOT NULL,
+    progress INTEGER NOT NULL DEFAULT 0,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL,
+    error TEXT,
+    created_at TEXT NOT NULL,
+    started_at TEXT,
+    finished_at TEXT,
+    retry_at TEXT
+);
+
+CREATE INDEX jobs_state ON jobs(state);
+
+-- Messages of jobs, kept with finished jobs for inspection
+CREATE TABLE job_logs (
+    job INTEGER NOT NULL,
+    at TEXT NOT NULL,
+    message TEXT NOT NULL,
+    FOREIGN KEY(job) REFERENCES jobs(id) ON DELETE CASCADE
+);
+
+CREATE INDEX job_logs_job ON job_logs(job);
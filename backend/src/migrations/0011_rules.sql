@@ -0,0 +1,27 @@
+-- Automation rules: `action` is the JSON of `shared::types::RuleAction`
+CREATE TABLE rules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    enabled INTEGER NOT NULL DEFAULT 1,
+    trigger TEXT NOT NULL,
+    tag TEXT,
+    condition TEXT NOT NULL DEFAULT '',
+    action TEXT NOT NULL
+);
+
+CREATE INDEX rules_trigger ON rules(trigger);
+
+-- Log of rule runs. The book is not a foreign key, runs stay logged after the book was deleted.
+CREATE TABLE rule_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    rule INTEGER NOT NULL,
+    book INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    trigger TEXT NOT NULL,
+    at TEXT NOT NULL,
+    success INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    FOREIGN KEY(rule) REFERENCES rules(id) ON DELETE CASCADE
+);
+
+CREATE INDEX rule_runs_rule ON rule_runs(rule);
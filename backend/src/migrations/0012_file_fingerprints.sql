@@ -0,0 +1,4 @@
+-- Modification time of hashed files in seconds since the epoch. Together with the size it tells
+-- whether a hash is stale, so that changes keeping the size are noticed. Hashes without one are
+-- computed again.
+ALTER TABLE file_hashes ADD COLUMN modified INTEGER;
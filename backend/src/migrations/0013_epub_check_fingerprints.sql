@@ -0,0 +1,4 @@
+-- Modification time of checked files in seconds since the epoch. Together with the size of the
+-- file on disk it tells whether a check result is stale, like for hashes. Results without one are
+-- stale.
+ALTER TABLE epub_checks ADD COLUMN modified INTEGER;
@@ -0,0 +1,16 @@
+-- Calibre book IDs are only unique within one Calibre library, so the mapping to our books is
+-- keyed by the library they come from as well: the UUID Calibre gives each library, or its
+-- path for libraries without one. Mappings imported before have no known source and an empty
+-- one.
+CREATE TABLE calibre_book_ids_keyed (
+    source TEXT NOT NULL,
+    calibre_id INTEGER NOT NULL,
+    book INTEGER NOT NULL,
+    FOREIGN KEY(book) REFERENCES books(id) ON DELETE CASCADE,
+    PRIMARY KEY(source, calibre_id),
+    UNIQUE(book)
+);
+INSERT INTO calibre_book_ids_keyed (source, calibre_id, book)
+    SELECT '', calibre_id, book FROM calibre_book_ids;
+DROP TABLE calibre_book_ids;
+ALTER TABLE calibre_book_ids_keyed RENAME TO calibre_book_ids;
@@ -0,0 +1,5 @@
+-- Size and modification time of the changed source a conversion was last queued again for. The
+-- conversion isn't queued again until the source changes once more, so that one that keeps
+-- failing isn't retried forever. A successful conversion replaces the row, clearing them.
+ALTER TABLE conversions ADD COLUMN requeued_size INTEGER;
+ALTER TABLE conversions ADD COLUMN requeued_modified INTEGER;
@@ -0,0 +1,326 @@
+pub mod css;
+pub mod toc;
+pub mod xhtml;
+
+use crate::books;
+use crate::epub::{self, attribute, Epub, ManifestItem};
+use anyhow::{anyhow, Context, Result};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::FormattingConfig;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+use toc::NewItem;
+
+/// Format the unchanged EPUB is kept as
+pub const ORIGINAL_FORMAT: &str = "ORIGINAL_EPUB";
+
+/// Files that other applications leave in archives, compared in lower case
+const VENDOR_FILES: &[&str] = &[
+    "itunesmetadata.plist",
+    "itunesmetadata-original.plist",
+    "itunesartwork",
+    ".ds_store",
+    "thumbs.db",
+    "desktop.ini",
+    "calibre_bookmarks.txt",
+];
+
+const FONT_EXTENSIONS: &[&str] = &[".ttf", ".otf", ".woff", ".woff2", ".eot"];
+
+fn is_vendor_file(name: &str) -> bool {
+    let file_name = name.rsplit('/').next().unwrap_or(name).to_lowercase();
+    name.starts_with("__MACOSX/") || VENDOR_FILES.contains(&file_name.as_str())
+}
+
+fn is_font(item: &ManifestItem) -> bool {
+    let media_type = item.media_type.to_lowercase();
+    let path = item.path.to_lowercase();
+    media_type.starts_with("font/")
+        || media_type.contains("font-")
+        || media_type == "application/vnd.ms-opentype"
+        || FONT_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+/// What normalizing an EPUB changes in its archive
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// New content of archive entries
+    pub replaced: BTreeMap<String, Vec<u8>>,
+    /// Archive entries to leave out
+    pub removed: BTreeSet<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.replaced.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The changes that the steps `options` enable make to the EPUB at `path`
+pub fn changes(path: &Path, options: &FormattingConfig) -> Result<Changes> {
+    let mut epub = Epub::open(path)?;
+    let mut changes = Changes::default();
+    let mut removed_ids = HashSet::new();
+
+    if options.remove_vendor_files {
+        for name in epub.entry_names() {
+            if is_vendor_file(&name) {
+                changes.removed.insert(name);
+            }
+        }
+    }
+    if options.strip_fonts {
+        for item in epub.manifest.iter().filter(|item| is_font(item)) {
+            changes.removed.insert(item.path.clone());
+        }
+    }
+    for item in &epub.manifest {
+        if changes.removed.contains(&item.path) {
+            removed_ids.insert(item.id.clone());
+        }
+    }
+
+    let rewrite_content = options.strip_fonts
+        || options.remove_empty_paragraphs
+        || options.smart_quotes
+        || options.normalize_margins;
+    if rewrite_content {
+        let items: Vec<ManifestItem> = epub
+            .manifest
+            .iter()
+            .filter(|item| !removed_ids.contains(&item.id))
+            .cloned()
+            .collect();
+        for item in items {
+            let updated = match item.media_type.as_str() {
+                "application/xhtml+xml" | "text/html" => {
+                    let content = String::from_utf8_lossy(&epub.read(&item.path)?).into_owned();
+                    xhtml::normalize_document(&content, options)
+                        .with_context(|| format!("Normalizing {}", item.path))?
+                }
+                "text/css" if options.strip_fonts || options.normalize_margins => {
+                    let content = String::from_utf8_lossy(&epub.read(&item.path)?).into_owned();
+                    let normalized = css::normalize_stylesheet(&content, options);
+                    (normalized != content).then_some(normalized)
+                }
+                _ => None,
+            };
+            if let Some(updated) = updated {
+                changes.replaced.insert(item.path, updated.into_bytes());
+            }
+        }
+    }
+
+    let mut toc_changes = toc::TocChanges::default();
+    if options.regenerate_toc {
+        let info = toc::package_info(&epub.read_opf()?)?;
+        toc_changes = toc::regenerate(&mut epub, &changes.replaced, &info)?;
+        changes.replaced.append(&mut toc_changes.replaced);
+    }
+
+    if !removed_ids.is_empty() || !toc_changes.added.is_empty() || toc_changes.spine_toc.is_some() {
+        let opf = epub.read_opf()?;
+        let opf = rewrite_package(
+            &opf,
+            &removed_ids,
+            &toc_changes.added,
+            toc_changes.spine_toc.as_deref(),
+        )?;
+        changes
+            .replaced
+            .insert(epub.opf_path.clone(), opf.into_bytes());
+    }
+    Ok(changes)
+}
+
+/// The package document without the manifest items `removed` and what refers to them, with the
+/// items `added` and the NCX `spine_toc` declared on the spine
+pub(crate) fn rewrite_package(
+    opf: &str,
+    removed: &HashSet<String>,
+    added: &[NewItem],
+    spine_toc: Option<&str>,
+) -> Result<String> {
+    let mut reader = Reader::from_str(opf);
+    let mut writer = Writer::new(Vec::new());
+    // Whitespace is held back until the next event, so it can be dropped with removed elements
+    let mut pending = String::new();
+    let mut item_indent = None::<String>;
+    loop {
+        let event = reader.read_event()?;
+        if let Event::Text(e) = &event {
+            let text = e.decode()?;
+            if text.trim().is_empty() {
+                pending.push_str(&text);
+                continue;
+            }
+        }
+        match &event {
+            Event::Start(e) | Event::Empty(e)
+                if matches!(e.local_name().as_ref(), b"item" | b"itemref") =>
+            {
+                if e.local_name().as_ref() == b"item"
+                    && item_indent.is_none()
+                    && pending.contains('\n')
+                {
+                    item_indent = Some(pending[pending.rfind('\n').unwrap_or(0)..].to_string());
+                }
+                let id = match e.local_name().as_ref() {
+                    b"item" => attribute(e, "id"),
+                    _ => attribute(e, "idref"),
+                };
+                if id.is_some_and(|id| removed.contains(&id)) {
+                    pending.clear();
+                    if matches!(event, Event::Start(_)) {
+                        reader.read_to_end(e.name())?;
+                    }
+                    continue;
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"manifest" => {
+                for item in added {
+                    let properties = match &item.properties {
+                        Some(properties) => format!(r#" properties="{}""#, escape(properties)),
+                        None => String::new(),
+                    };
+                    let element = format!(
+                        r#"{}<item id="{}" href="{}" media-type="{}"{properties}/>"#,
+                        item_indent.as_deref().unwrap_or("\n    "),
+                        escape(item.id.as_str()),
+                        escape(item.href.as_str()),
+                        escape(item.media_type.as_str())
+                    );
+                    writer.get_mut().extend_from_slice(element.as_bytes());
+                }
+            }
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"spine" => {
+                if let Some(toc) = spine_toc {
+                    let mut copy =
+                        BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                    for attr in e.attributes().flatten() {
+                        if attr.key.as_ref() != b"toc" {
+                            copy.push_attribute(attr);
+                        }
+                    }
+                    copy.push_attribute(("toc", toc));
+                    writer
+                        .get_mut()
+                        .extend_from_slice(std::mem::take(&mut pending).as_bytes());
+                    writer.write_event(match event {
+                        Event::Start(_) => Event::Start(copy),
+                        _ => Event::Empty(copy),
+                    })?;
+                    continue;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        writer
+            .get_mut()
+            .extend_from_slice(std::mem::take(&mut pending).as_bytes());
+        writer.write_event(event)?;
+    }
+    writer.get_mut().extend_from_slice(pending.as_bytes());
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Directory of `book` and the data ID and name of its EPUB, if it has one
+pub(crate) fn epub_file(conn: &Connection, book: i64) -> Result<Option<(String, i64, String)>> {
+    Ok(conn
+        .query_row(
+            "SELECT books.path, data.id, data.name FROM data
+             JOIN books ON books.id = data.book
+             WHERE data.book = ?1 AND data.format = 'EPUB'",
+            [book],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?)
+}
+
+fn has_original(conn: &Connection, book: i64) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM data WHERE book = ?1 AND format = ?2",
+            params![book, ORIGINAL_FORMAT],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Apply the steps `options` enable to the EPUB of `book`, see [`apply`]. Returns whether
+/// anything changed.
+pub fn normalize(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    options: &FormattingConfig,
+) -> Result<bool> {
+    let Some((dir, _, name)) = epub_file(conn, book)? else {
+        return Ok(false);
+    };
+    let path = library_dir.join(dir).join(format!("{name}.epub"));
+    let changes = changes(&path, options)?;
+    if changes.is_empty() {
+        return Ok(false);
+    }
+    apply(conn, library_dir, book, &changes)?;
+    Ok(true)
+}
+
+/// Make `changes` to the EPUB of `book`. The EPUB as it was before the first change is kept as
+/// the `ORIGINAL_EPUB` format, so [`revert`] can put it back.
+pub fn apply(conn: &Connection, library_dir: &Path, book: i64, changes: &Changes) -> Result<()> {
+    let (dir, data, name) =
+        epub_file(conn, book)?.ok_or_else(|| anyhow!("Book {book} has no EPUB file"))?;
+    let dir = library_dir.join(dir);
+    let path = dir.join(format!("{name}.epub"));
+    if !has_original(conn, book)? {
+        let original = dir.join(format!("{name}.{}", ORIGINAL_FORMAT.to_lowercase()));
+        let size = std::fs::copy(&path, &original)
+            .with_context(|| format!("Keeping the original of {path:?}"))?;
+        books::add_format(conn, book, ORIGINAL_FORMAT, &name, size)?;
+    }
+    let size = epub::rewrite_entries(&path, &changes.replaced, &changes.removed)?;
+    conn.execute(
+        "UPDATE data SET uncompressed_size = ?1 WHERE id = ?2",
+        params![size as i64, data],
+    )?;
+    Ok(())
+}
+
+/// Put the original EPUB of `book` kept by [`apply`] back. Returns whether it had one.
+pub fn revert(conn: &Connection, library_dir: &Path, book: i64) -> Result<bool> {
+    let original: Option<(String, String)> = conn
+        .query_row(
+            "SELECT books.path, data.name FROM data
+             JOIN books ON books.id = data.book
+             WHERE data.book = ?1 AND data.format = ?2",
+            params![book, ORIGINAL_FORMAT],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let Some((dir, original_name)) = original else {
+        return Ok(false);
+    };
+    let dir = library_dir.join(dir);
+    let original = dir.join(format!(
+        "{original_name}.{}",
+        ORIGINAL_FORMAT.to_lowercase()
+    ));
+    // The EPUB may have been renamed since, along with the book
+    let name = epub_file(conn, book)?.map_or(original_name, |(_, _, name)| name);
+    let path = dir.join(format!("{name}.epub"));
+    std::fs::rename(&original, &path).with_context(|| format!("Restoring {path:?}"))?;
+    let size = std::fs::metadata(&path)?.len();
+    books::add_format(conn, book, "EPUB", &name, size)?;
+    conn.execute(
+        "DELETE FROM data WHERE book = ?1 AND format = ?2",
+        params![book, ORIGINAL_FORMAT],
+    )?;
+    Ok(true)
+}
@@ -0,0 +1,262 @@
+use shared::types::FormattingConfig;
+
+/// Absolute length units with their size in em, taking 16px as the usual default font size
+const ABSOLUTE_UNITS: &[(&str, f64)] = &[
+    ("px", 1.0 / 16.0),
+    ("pt", 1.0 / 12.0),
+    ("pc", 1.0),
+    ("in", 6.0),
+    ("cm", 2.3622),
+    ("mm", 0.23622),
+];
+
+/// Selectors of rules that style the page rather than the text
+const PAGE_SELECTORS: &[&str] = &["html", "body", ":root", "@page"];
+
+/// Positions of the top-level occurrences of `separator` in `text`, outside strings,
+/// parentheses and comments
+fn top_level_positions(text: &str, separator: char) -> Vec<usize> {
+    let mut positions = Vec::new();
+    scan(text, separator, |i| {
+        positions.push(i);
+        true
+    });
+    positions
+}
+
+/// Call `found` with the top-level occurrences of `separator` in `text` until it returns false
+fn scan(text: &str, separator: char, mut found: impl FnMut(usize) -> bool) {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, '/') if chars.peek().is_some_and(|(_, c)| *c == '*') => {
+                let end = text[i + 2..]
+                    .find("*/")
+                    .map_or(text.len(), |e| i + 2 + e + 2);
+                while chars.peek().is_some_and(|(j, _)| *j < end) {
+                    chars.next();
+                }
+            }
+            (None, c) if c == separator && depth == 0 => {
+                if !found(i) {
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `text` split at the top-level occurrences of `separator`
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for position in top_level_positions(text, separator) {
+        parts.push(&text[start..position]);
+        start = position + separator.len_utf8();
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// A length such as `12pt` converted to em, `None` if it isn't in an absolute unit
+fn to_em(token: &str) -> Option<String> {
+    let (unit, factor) = ABSOLUTE_UNITS
+        .iter()
+        .find(|(unit, _)| token.to_ascii_lowercase().ends_with(unit))?;
+    let number: f64 = token[..token.len() - unit.len()].parse().ok()?;
+    let em = (number * factor * 100.0).round() / 100.0;
+    Some(if em == 0.0 {
+        "0".to_string()
+    } else {
+        format!("{em}em")
+    })
+}
+
+fn has_absolute_unit(value: &str) -> bool {
+    value.split_whitespace().any(|token| to_em(token).is_some())
+}
+
+/// The declarations of a rule or `style` attribute without what `options` remove, `None` if
+/// nothing changes. `page` tells whether they style the page, whose margins are left to the
+/// reading system.
+pub fn normalize_declarations(
+    declarations: &str,
+    page: bool,
+    options: &FormattingConfig,
+) -> Option<String> {
+    let mut changed = false;
+    let mut kept = Vec::new();
+    for piece in split_top_level(declarations, ';') {
+        let Some((property, value)) = piece.split_once(':') else {
+            kept.push(piece.to_string());
+            continue;
+        };
+        let name = property.trim().to_ascii_lowercase();
+        let spacing = &piece[..piece.len() - piece.trim_start().len()];
+        let trailing = &value[value.trim_end().len()..];
+        let value = value.trim();
+        let (value, important) = match value.strip_suffix("!important") {
+            Some(value) => (value.trim_end(), " !important"),
+            None => (value, ""),
+        };
+        let font = name == "font-family" || (name == "font-size" && has_absolute_unit(value));
+        let margin = name.starts_with("margin") || name.starts_with("padding");
+        if (options.strip_fonts && font) || (options.normalize_margins && page && margin) {
+            changed = true;
+            continue;
+        }
+        if options.normalize_margins && name.starts_with("margin") && has_absolute_unit(value) {
+            let converted: Vec<String> = value
+                .split_whitespace()
+                .map(|token| to_em(token).unwrap_or_else(|| token.to_string()))
+                .collect();
+            kept.push(format!(
+                "{spacing}{}: {}{important}{trailing}",
+                property.trim(),
+                converted.join(" ")
+            ));
+            changed = true;
+            continue;
+        }
+        kept.push(piece.to_string());
+    }
+    changed.then(|| kept.join(";"))
+}
+
+/// A style sheet without embedded fonts and the declarations that `options` remove. Rules left
+/// without declarations are removed.
+pub fn normalize_stylesheet(css: &str, options: &FormattingConfig) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    loop {
+        // Everything up to the next block is its prelude, possibly preceded by statements such
+        // as `@import`
+        let mut open = None;
+        scan(rest, '{', |i| {
+            open = Some(i);
+            false
+        });
+        let Some(open) = open else {
+            out.push_str(rest);
+            break;
+        };
+        let prelude_start = top_level_positions(&rest[..open], ';')
+            .last()
+            .map_or(0, |end| end + 1);
+        out.push_str(&rest[..prelude_start]);
+        let prelude = &rest[prelude_start..open];
+        let Some(close) = matching_brace(rest, open) else {
+            out.push_str(&rest[prelude_start..]);
+            break;
+        };
+        let block = &rest[open + 1..close];
+        let selector = prelude.trim().to_ascii_lowercase();
+        let selector = strip_comments(&selector);
+        if selector.starts_with("@font-face") {
+            // Removed with the whitespace before it, so no blank line is left behind
+            if !options.strip_fonts {
+                out.push_str(&rest[prelude_start..=close]);
+            }
+        } else if selector.starts_with("@media") || selector.starts_with("@supports") {
+            let normalized = normalize_stylesheet(block, options);
+            // Dropped if the normalization emptied it
+            if !normalized.trim().is_empty() || block.trim().is_empty() {
+                out.push_str(prelude);
+                out.push('{');
+                out.push_str(&normalized);
+                out.push('}');
+            }
+        } else if selector.starts_with('@') && !selector.starts_with("@page") {
+            out.push_str(&rest[prelude_start..=close]);
+        } else {
+            let page = selector
+                .split(',')
+                .all(|s| PAGE_SELECTORS.contains(&s.trim()));
+            match normalize_declarations(block, page, options) {
+                Some(declarations)
+                    if split_top_level(&declarations, ';')
+                        .iter()
+                        .all(|d| strip_comments(d).is_empty()) => {}
+                Some(declarations) => {
+                    out.push_str(prelude);
+                    out.push('{');
+                    out.push_str(&declarations);
+                    out.push('}');
+                }
+                None => out.push_str(&rest[prelude_start..=close]),
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+    // Rules removed from the start leave the whitespace after them behind
+    if out.len() != css.len() && !css.starts_with(char::is_whitespace) {
+        return out.trim_start().to_string();
+    }
+    out
+}
+
+/// Index of the `}` closing the block opened at `open`
+fn matching_brace(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut in_comment = false;
+    let bytes = text.as_bytes();
+    let mut i = open;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if in_comment {
+            if c == b'*' && bytes.get(i + 1) == Some(&b'/') {
+                in_comment = false;
+                i += 1;
+            }
+        } else if let Some(q) = quote {
+            if c == b'\\' {
+                i += 1;
+            } else if c == q {
+                quote = None;
+            }
+        } else {
+            match c {
+                b'"' | b'\'' => quote = Some(c),
+                b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                    in_comment = true;
+                    i += 1;
+                }
+                b'{' => depth += 1,
+                b'}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(i);
+                    }
+                }
+                _ => {}
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = rest[start + 2..]
+            .find("*/")
+            .map_or("", |end| &rest[start + 2 + end + 2..]);
+    }
+    out.push_str(rest);
+    out.trim().to_string()
+}
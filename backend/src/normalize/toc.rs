@@ -0,0 +1,614 @@
+use crate::epub::{attribute, element_text, relative_href, resolve_href, Epub};
+use anyhow::Result;
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::{BTreeMap, HashSet};
+
+const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+
+/// An entry of a table of contents
+#[derive(Debug, Clone, PartialEq)]
+pub struct TocEntry {
+    /// 1 for top-level entries
+    pub level: usize,
+    pub label: String,
+    /// Archive entry of the content document, with the fragment if any, e.g.,
+    /// `OEBPS/Text/ch01.xhtml#part1`
+    pub target: String,
+}
+
+/// A manifest item to be added to the package document
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewItem {
+    pub id: String,
+    pub href: String,
+    pub media_type: String,
+    pub properties: Option<String>,
+}
+
+/// What regenerating the table of contents changes
+#[derive(Debug, Default)]
+pub struct TocChanges {
+    /// New content of archive entries
+    pub replaced: BTreeMap<String, Vec<u8>>,
+    pub added: Vec<NewItem>,
+    /// ID of the NCX to declare on the spine, if it was added
+    pub spine_toc: Option<String>,
+}
+
+/// Version, unique identifier and title declared by a package document
+pub struct PackageInfo {
+    pub epub3: bool,
+    pub uid: String,
+    pub title: String,
+}
+
+pub fn package_info(opf: &str) -> Result<PackageInfo> {
+    let mut reader = Reader::from_str(opf);
+    let mut info = PackageInfo {
+        epub3: false,
+        uid: String::new(),
+        title: String::new(),
+    };
+    let mut unique_identifier = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"package" => {
+                info.epub3 = attribute(&e, "version").is_some_and(|v| v.starts_with('3'));
+                unique_identifier = attribute(&e, "unique-identifier");
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"title" && info.title.is_empty() => {
+                info.title = collapse(&element_text(&mut reader)?);
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"identifier" => {
+                let id = attribute(&e, "id");
+                let value = element_text(&mut reader)?;
+                if id.is_some() && id == unique_identifier || info.uid.is_empty() {
+                    info.uid = value.trim().to_string();
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"metadata" => break,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+/// Whitespace collapsed to single spaces
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The archive entry, with fragment, that `href` in the document `base` leads to
+fn resolve_target(base: &str, href: &str) -> String {
+    let (path, fragment) = match href.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (href, None),
+    };
+    let path = if path.is_empty() {
+        base.to_string()
+    } else {
+        resolve_href(base, path)
+    };
+    match fragment {
+        Some(fragment) => format!("{path}#{fragment}"),
+        None => path,
+    }
+}
+
+/// The href of `target` from the document `base`
+fn target_href(base: &str, target: &str) -> String {
+    match target.split_once('#') {
+        Some((path, fragment)) => format!("{}#{fragment}", relative_href(base, path)),
+        None => relative_href(base, target),
+    }
+}
+
+fn read_ncx(ncx: &str, ncx_path: &str) -> Result<Vec<TocEntry>> {
+    let mut reader = Reader::from_str(ncx);
+    let mut entries = Vec::new();
+    let mut depth = 0usize;
+    let mut label = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"navPoint" => {
+                depth += 1;
+                label = None;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"navPoint" => {
+                depth = depth.saturating_sub(1);
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"text" && depth > 0 => {
+                let text = collapse(&element_text(&mut reader)?);
+                label.get_or_insert(text);
+            }
+            Event::Start(e) | Event::Empty(e)
+                if e.local_name().as_ref() == b"content" && depth > 0 =>
+            {
+                if let Some(src) = attribute(&e, "src") {
+                    entries.push(TocEntry {
+                        level: depth,
+                        label: label.take().unwrap_or_default(),
+                        target: resolve_target(ncx_path, &src),
+                    });
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// The `toc` navigation element of a navigation document
+struct TocNav {
+    entries: Vec<TocEntry>,
+    /// Byte range of the element in the document
+    start: usize,
+    end: usize,
+    /// The start tag as written
+    start_tag: String,
+    heading: Option<String>,
+}
+
+fn read_nav(nav: &str, nav_path: &str) -> Result<Option<TocNav>> {
+    let mut reader = Reader::from_str(nav);
+    reader.config_mut().check_end_names = false;
+    let mut toc: Option<TocNav> = None;
+    let mut nav_depth = 0usize;
+    let mut list_depth = 0usize;
+    loop {
+        let position = reader.buffer_position() as usize;
+        let event = reader.read_event()?;
+        let Some(found) = toc.as_mut() else {
+            if let Event::Start(e) = &event {
+                let is_toc =
+                    attribute(e, "type").is_some_and(|t| t.split_whitespace().any(|t| t == "toc"));
+                if e.local_name().as_ref() == b"nav" && is_toc {
+                    let end = reader.buffer_position() as usize;
+                    toc = Some(TocNav {
+                        entries: Vec::new(),
+                        start: position,
+                        end,
+                        start_tag: nav[position..end].to_string(),
+                        heading: None,
+                    });
+                    nav_depth = 1;
+                }
+            }
+            if matches!(event, Event::Eof) {
+                return Ok(None);
+            }
+            continue;
+        };
+        match event {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"nav" => nav_depth += 1,
+                b"ol" | b"ul" => list_depth += 1,
+                b"a" => {
+                    let href = attribute(&e, "href");
+                    let label = collapse(&element_text(&mut reader)?);
+                    if let Some(href) = href {
+                        found.entries.push(TocEntry {
+                            level: list_depth.max(1),
+                            label,
+                            target: resolve_target(nav_path, &href),
+                        });
+                    }
+                }
+                b"h1" | b"h2" | b"h3" | b"h4" | b"h5" | b"h6" if list_depth == 0 => {
+                    found.heading = Some(collapse(&element_text(&mut reader)?));
+                }
+                _ => {}
+            },
+            Event::End(e) => match e.local_name().as_ref() {
+                b"ol" | b"ul" => list_depth = list_depth.saturating_sub(1),
+                b"nav" => {
+                    nav_depth -= 1;
+                    if nav_depth == 0 {
+                        found.end = reader.buffer_position() as usize;
+                        return Ok(toc);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Headings h1 to h3 of a content document, outside navigation elements
+fn headings(xhtml: &str, path: &str) -> Result<(Vec<TocEntry>, Option<String>)> {
+    let mut reader = Reader::from_str(xhtml);
+    reader.config_mut().check_end_names = false;
+    let mut entries = Vec::new();
+    let mut title = None;
+    let mut in_nav = 0usize;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let level = match e.local_name().as_ref() {
+                    b"nav" => {
+                        in_nav += 1;
+                        continue;
+                    }
+                    b"title" => {
+                        title = Some(collapse(&element_text(&mut reader)?));
+                        continue;
+                    }
+                    b"h1" => 1,
+                    b"h2" => 2,
+                    b"h3" => 3,
+                    _ => continue,
+                };
+                let id = attribute(&e, "id");
+                let label = collapse(&element_text(&mut reader)?);
+                if in_nav == 0 && !label.is_empty() {
+                    entries.push(TocEntry {
+                        level,
+                        label,
+                        target: match id {
+                            Some(id) => format!("{path}#{id}"),
+                            None => path.to_string(),
+                        },
+                    });
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"nav" => {
+                in_nav = in_nav.saturating_sub(1);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok((entries, title.filter(|t| !t.is_empty())))
+}
+
+/// Entries leading to content documents only, with levels starting at 1 and going down one
+/// level at a time
+fn sanitize(entries: Vec<TocEntry>, documents: &HashSet<String>) -> Vec<TocEntry> {
+    let mut entries: Vec<TocEntry> = entries
+        .into_iter()
+        .filter(|entry| {
+            let path = entry.target.split('#').next().unwrap_or_default();
+            documents.contains(path) && !entry.label.is_empty()
+        })
+        .collect();
+    let min = entries.iter().map(|e| e.level).min().unwrap_or(1);
+    let mut previous = 0;
+    for entry in &mut entries {
+        entry.level = (entry.level - min + 1).min(previous + 1);
+        previous = entry.level;
+    }
+    entries
+}
+
+fn ncx_document(
+    entries: &[TocEntry],
+    ncx_path: &str,
+    info: &PackageInfo,
+    extra_lists: &str,
+) -> String {
+    let depth = entries.iter().map(|e| e.level).max().unwrap_or(1);
+    let mut out = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head>
+    <meta name="dtb:uid" content="{}"/>
+    <meta name="dtb:depth" content="{depth}"/>
+    <meta name="dtb:totalPageCount" content="0"/>
+    <meta name="dtb:maxPageNumber" content="0"/>
+  </head>
+  <docTitle>
+    <text>{}</text>
+  </docTitle>
+  <navMap>"#,
+        escape(info.uid.as_str()),
+        escape(info.title.as_str())
+    );
+    let indent = |level: usize| "  ".repeat(level + 1);
+    let mut depth = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.level <= depth {
+            out.push_str(&format!("\n{}</navPoint>", indent(depth)));
+            while depth > entry.level {
+                depth -= 1;
+                out.push_str(&format!("\n{}</navPoint>", indent(depth)));
+            }
+        }
+        depth = entry.level;
+        let ind = indent(depth);
+        out.push_str(&format!(
+            "\n{ind}<navPoint id=\"navPoint-{n}\" playOrder=\"{n}\">\n{ind}  <navLabel>\n{ind}    <text>{}</text>\n{ind}  </navLabel>\n{ind}  <content src=\"{}\"/>",
+            escape(entry.label.as_str()),
+            escape(target_href(ncx_path, &entry.target).as_str()),
+            n = index + 1,
+        ));
+    }
+    while depth > 0 {
+        out.push_str(&format!("\n{}</navPoint>", indent(depth)));
+        depth -= 1;
+    }
+    out.push_str("\n  </navMap>");
+    out.push_str(extra_lists);
+    out.push_str("\n</ncx>\n");
+    out
+}
+
+/// The page and navigation lists of an NCX, as written, to be kept when it is regenerated
+fn ncx_extra_lists(ncx: &str) -> Result<String> {
+    let mut reader = Reader::from_str(ncx);
+    let mut lists = String::new();
+    loop {
+        let position = reader.buffer_position() as usize;
+        match reader.read_event()? {
+            Event::Start(e) if matches!(e.local_name().as_ref(), b"pageList" | b"navList") => {
+                reader.read_to_end(e.name())?;
+                let end = reader.buffer_position() as usize;
+                lists.push_str("\n  ");
+                lists.push_str(&ncx[position..end]);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(lists)
+}
+
+/// The `toc` navigation element, indented by `indent`
+fn nav_element(
+    entries: &[TocEntry],
+    nav_path: &str,
+    start_tag: &str,
+    heading: &str,
+    indent: &str,
+) -> String {
+    let list_indent = |level: usize| format!("{indent}{}", "  ".repeat(2 * level - 1));
+    let item_indent = |level: usize| format!("{indent}{}", "  ".repeat(2 * level));
+    let mut out = format!("{start_tag}\n{indent}  <h1>{}</h1>", escape(heading));
+    let mut depth = 0;
+    for entry in entries {
+        if entry.level > depth {
+            depth = entry.level;
+            out.push_str(&format!("\n{}<ol>", list_indent(depth)));
+        } else {
+            out.push_str("</li>");
+            while depth > entry.level {
+                out.push_str(&format!("\n{}</ol>", list_indent(depth)));
+                depth -= 1;
+                out.push_str(&format!("\n{}</li>", item_indent(depth)));
+            }
+        }
+        out.push_str(&format!(
+            "\n{}<li><a href=\"{}\">{}</a>",
+            item_indent(depth),
+            escape(target_href(nav_path, &entry.target).as_str()),
+            escape(entry.label.as_str())
+        ));
+    }
+    if depth > 0 {
+        out.push_str("</li>");
+    }
+    while depth > 0 {
+        out.push_str(&format!("\n{}</ol>", list_indent(depth)));
+        depth -= 1;
+        if depth > 0 {
+            out.push_str(&format!("\n{}</li>", item_indent(depth)));
+        }
+    }
+    out.push_str(&format!("\n{indent}</nav>"));
+    out
+}
+
+fn nav_document(nav: &str, title: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+  <head>
+    <title>{}</title>
+  </head>
+  <body>
+    {nav}
+  </body>
+</html>
+"#,
+        escape(title)
+    )
+}
+
+/// Indentation of the line that `position` is in
+fn indentation(text: &str, position: usize) -> &str {
+    let line = &text[text[..position].rfind('\n').map_or(0, |i| i + 1)..position];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// A file name next to the package document that is not taken yet
+fn free_path(epub: &Epub, names: &[String], file_name: &str) -> String {
+    let dir = match epub.opf_path.rsplit_once('/') {
+        Some((dir, _)) => format!("{dir}/"),
+        None => String::new(),
+    };
+    let path = format!("{dir}{file_name}");
+    if names.contains(&path) {
+        format!("{dir}ironscribe-{file_name}")
+    } else {
+        path
+    }
+}
+
+/// A manifest ID that is not taken yet
+fn free_id(epub: &Epub, id: &str) -> String {
+    if epub.item(id).is_some() {
+        format!("ironscribe-{id}")
+    } else {
+        id.to_string()
+    }
+}
+
+/// Rebuild the NCX and, for EPUB 3, the navigation document, from the entries of the existing
+/// navigation document or NCX that lead to content documents, or else from the headings of the
+/// content documents. `replaced` holds content documents already changed by other steps.
+pub fn regenerate(
+    epub: &mut Epub,
+    replaced: &BTreeMap<String, Vec<u8>>,
+    info: &PackageInfo,
+) -> Result<TocChanges> {
+    let read = |epub: &mut Epub, path: &str| -> Result<String> {
+        Ok(match replaced.get(path) {
+            Some(content) => String::from_utf8_lossy(content).into_owned(),
+            None => String::from_utf8_lossy(&epub.read(path)?).into_owned(),
+        })
+    };
+    let documents: HashSet<String> = epub
+        .manifest
+        .iter()
+        .filter(|item| {
+            matches!(
+                item.media_type.as_str(),
+                "application/xhtml+xml" | "text/html"
+            )
+        })
+        .map(|item| item.path.clone())
+        .collect();
+    let nav_item = epub.nav().cloned();
+    let ncx_item = epub.ncx().cloned();
+
+    let nav_content = match &nav_item {
+        Some(item) => Some(read(epub, &item.path)?),
+        None => None,
+    };
+    let existing_nav = match (&nav_item, &nav_content) {
+        (Some(item), Some(content)) => read_nav(content, &item.path)?,
+        _ => None,
+    };
+    let ncx_content = match &ncx_item {
+        Some(item) => Some(read(epub, &item.path)?),
+        None => None,
+    };
+
+    let mut entries = existing_nav
+        .as_ref()
+        .map(|nav| sanitize(nav.entries.clone(), &documents))
+        .unwrap_or_default();
+    if entries.is_empty() {
+        if let (Some(item), Some(content)) = (&ncx_item, &ncx_content) {
+            entries = sanitize(read_ncx(content, &item.path)?, &documents);
+        }
+    }
+    if entries.is_empty() {
+        let spine: Vec<String> = epub
+            .spine_items()
+            .filter(|item| Some(&item.id) != nav_item.as_ref().map(|n| &n.id))
+            .map(|item| item.path.clone())
+            .collect();
+        let mut titles = Vec::new();
+        for (index, path) in spine.iter().enumerate() {
+            let (found, title) = headings(&read(epub, path)?, path)?;
+            entries.extend(found);
+            titles.push(TocEntry {
+                level: 1,
+                label: title.unwrap_or_else(|| format!("Section {}", index + 1)),
+                target: path.clone(),
+            });
+        }
+        entries = sanitize(entries, &documents);
+        if entries.is_empty() {
+            entries = sanitize(titles, &documents);
+        }
+    }
+
+    let mut changes = TocChanges::default();
+    if entries.is_empty() {
+        return Ok(changes);
+    }
+    let names = epub.entry_names();
+
+    let (ncx_path, extra_lists) = match (&ncx_item, &ncx_content) {
+        (Some(item), Some(content)) => (item.path.clone(), ncx_extra_lists(content)?),
+        _ => (free_path(epub, &names, "toc.ncx"), String::new()),
+    };
+    let ncx = ncx_document(&entries, &ncx_path, info, &extra_lists);
+    if ncx_content.as_deref() != Some(ncx.as_str()) {
+        changes.replaced.insert(ncx_path.clone(), ncx.into_bytes());
+    }
+    if ncx_item.is_none() {
+        let id = free_id(epub, "ncx");
+        changes.added.push(NewItem {
+            id: id.clone(),
+            href: relative_href(&epub.opf_path, &ncx_path),
+            media_type: NCX_MEDIA_TYPE.to_string(),
+            properties: None,
+        });
+        changes.spine_toc = Some(id);
+    }
+
+    if !info.epub3 {
+        return Ok(changes);
+    }
+    let title = if info.title.is_empty() {
+        "Contents"
+    } else {
+        info.title.as_str()
+    };
+    match (&nav_item, &nav_content) {
+        (Some(item), Some(content)) => {
+            let updated = match &existing_nav {
+                Some(nav) => {
+                    let element = nav_element(
+                        &entries,
+                        &item.path,
+                        &nav.start_tag,
+                        nav.heading.as_deref().unwrap_or("Contents"),
+                        indentation(content, nav.start),
+                    );
+                    format!("{}{element}{}", &content[..nav.start], &content[nav.end..])
+                }
+                // A navigation document without table of contents gets one at the end
+                None => {
+                    let end = content.rfind("</body>").unwrap_or(content.len());
+                    let element = nav_element(
+                        &entries,
+                        &item.path,
+                        r#"<nav epub:type="toc" id="toc">"#,
+                        "Contents",
+                        "    ",
+                    );
+                    format!(
+                        "{}  {element}\n  {}",
+                        content[..end].trim_end_matches([' ', '\t']),
+                        &content[end..]
+                    )
+                }
+            };
+            if &updated != content {
+                changes
+                    .replaced
+                    .insert(item.path.clone(), updated.into_bytes());
+            }
+        }
+        _ => {
+            let path = free_path(epub, &names, "nav.xhtml");
+            let element = nav_element(
+                &entries,
+                &path,
+                r#"<nav epub:type="toc" id="toc">"#,
+                "Contents",
+                "    ",
+            );
+            changes
+                .replaced
+                .insert(path.clone(), nav_document(&element, title).into_bytes());
+            changes.added.push(NewItem {
+                id: free_id(epub, "nav"),
+                href: relative_href(&epub.opf_path, &path),
+                media_type: "application/xhtml+xml".to_string(),
+                properties: Some("nav".to_string()),
+            });
+        }
+    }
+    Ok(changes)
+}
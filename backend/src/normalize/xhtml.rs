@@ -0,0 +1,304 @@
+use super::css;
+use crate::epub::{attribute, BLOCK_ELEMENTS};
+use anyhow::Result;
+use quick_xml::events::{BytesRef, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use shared::types::FormattingConfig;
+
+/// Elements whose text is kept exactly as it is
+const VERBATIM_ELEMENTS: &[&[u8]] = &[b"pre", b"code", b"kbd", b"samp", b"script", b"style"];
+
+/// Elements that may appear in a paragraph that is still empty, e.g., `<p><br/></p>` or
+/// `<p><span> </span></p>`, unless they carry an ID that something may link to
+const EMPTY_INLINE_ELEMENTS: &[&[u8]] = &[
+    b"br", b"span", b"b", b"i", b"em", b"strong", b"font", b"small", b"big", b"u", b"s",
+];
+
+/// Characters after which a quote opens
+const OPENING: &[char] = &['(', '[', '{', '“', '‘', '—', '–'];
+
+const NBSP: char = '\u{a0}';
+
+/// `text` with runs of two or more non-breaking spaces, which publishers use for indentation
+/// and spacing, replaced by a single space
+fn collapse_nbsp_runs(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != NBSP {
+            out.push(c);
+            continue;
+        }
+        let mut run = String::from(c);
+        while let Some(&next) = chars.peek().filter(|c| **c == NBSP || **c == ' ') {
+            run.push(next);
+            chars.next();
+        }
+        if run.chars().filter(|c| *c == NBSP).count() > 1 {
+            out.push(' ');
+        } else {
+            out.push_str(&run);
+        }
+    }
+    out
+}
+
+/// `text` with straight quotes replaced by typographic ones, `prev` being the character before
+fn curl_quotes(text: &str, mut prev: char) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let after_space = prev.is_whitespace() || OPENING.contains(&prev);
+        let curled = match c {
+            '"' if after_space => '“',
+            '"' => '”',
+            // An apostrophe within or after a word, or for left out digits as in '90s
+            '\'' if prev.is_alphanumeric() => '’',
+            '\'' if chars.get(i + 1).is_some_and(char::is_ascii_digit) => '’',
+            '\'' if after_space => '‘',
+            '\'' => '’',
+            c => c,
+        };
+        out.push(curled);
+        prev = curled;
+    }
+    out
+}
+
+/// The text that a character or entity reference stands for, `None` for entities that only a
+/// DTD defines, except for `&nbsp;` which is common enough
+pub(crate) fn resolve_reference(reference: &BytesRef) -> Result<Option<String>> {
+    let name = reference.decode()?;
+    Ok(match reference.resolve_char_ref()? {
+        Some(c) => Some(c.to_string()),
+        None if name == "nbsp" => Some(NBSP.to_string()),
+        None => quick_xml::escape::resolve_predefined_entity(&name).map(str::to_string),
+    })
+}
+
+/// Text read since the last markup, written out at once so quotes and spaces can be fixed across
+/// character references
+#[derive(Default)]
+struct TextRun {
+    /// As written in the document
+    raw: String,
+    /// With character references resolved
+    text: String,
+}
+
+struct Normalizer<'a> {
+    options: &'a FormattingConfig,
+    writer: Writer<Vec<u8>>,
+    run: TextRun,
+    /// The character before the current text, for the direction of quotes
+    prev: char,
+    verbatim: usize,
+    in_style: bool,
+    /// Depth of open paragraphs, where the output was when the outermost one opened, and
+    /// whether it has been empty so far
+    paragraph: usize,
+    paragraph_start: usize,
+    paragraph_empty: bool,
+}
+
+impl Normalizer<'_> {
+    fn flush(&mut self) {
+        let run = std::mem::take(&mut self.run);
+        if run.raw.is_empty() {
+            return;
+        }
+        let mut text = run.text.clone();
+        if self.in_style {
+            text = css::normalize_stylesheet(&text, self.options);
+        } else if self.verbatim == 0 {
+            if self.options.remove_empty_paragraphs {
+                text = collapse_nbsp_runs(&text);
+            }
+            if self.options.smart_quotes {
+                text = curl_quotes(&text, self.prev);
+            }
+        }
+        if text.chars().any(|c| !c.is_whitespace()) {
+            self.paragraph_empty = false;
+        }
+        if let Some(last) = text.chars().last() {
+            self.prev = last;
+        }
+        let out = if text == run.text {
+            run.raw
+        } else {
+            quick_xml::escape::partial_escape(&text).into_owned()
+        };
+        self.writer.get_mut().extend_from_slice(out.as_bytes());
+    }
+
+    /// Where the output would end without the indentation of the line it ends in
+    fn line_start(&self) -> usize {
+        let out = self.writer.get_ref();
+        let indented = out.len()
+            - out
+                .iter()
+                .rev()
+                .take_while(|b| **b == b' ' || **b == b'\t')
+                .count();
+        match out[..indented].strip_suffix(b"\n") {
+            Some(rest) => rest.strip_suffix(b"\r").unwrap_or(rest).len(),
+            None => out.len(),
+        }
+    }
+
+    /// The element with its `style` attribute normalized, `None` if that changes nothing
+    fn restyle(&self, element: &BytesStart) -> Option<BytesStart<'static>> {
+        let style = attribute(element, "style")?;
+        let page = matches!(element.local_name().as_ref(), b"body" | b"html");
+        let style = css::normalize_declarations(&style, page, self.options)?;
+        let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+        let mut copy = BytesStart::new(name);
+        for attr in element.attributes().flatten() {
+            if attr.key.as_ref() != b"style" {
+                copy.push_attribute(attr);
+            } else if !style.trim().trim_matches(';').trim().is_empty() {
+                copy.push_attribute(("style", style.trim()));
+            }
+        }
+        Some(copy)
+    }
+
+    /// Track whether the open paragraph is still empty with `element` in it
+    fn enter(&mut self, element: &BytesStart) {
+        if self.paragraph > 0
+            && (!EMPTY_INLINE_ELEMENTS.contains(&element.local_name().as_ref())
+                || attribute(element, "id").is_some())
+        {
+            self.paragraph_empty = false;
+        }
+    }
+}
+
+/// A content document with the fixes that `options` enable applied, `None` if nothing changes.
+/// Applying it again to the result changes nothing.
+pub fn normalize_document(xhtml: &str, options: &FormattingConfig) -> Result<Option<String>> {
+    let mut reader = Reader::from_str(xhtml);
+    // Tolerate sloppy markup, which is common in the wild
+    reader.config_mut().check_end_names = false;
+    let mut n = Normalizer {
+        options,
+        writer: Writer::new(Vec::with_capacity(xhtml.len())),
+        run: TextRun::default(),
+        prev: ' ',
+        verbatim: 0,
+        in_style: false,
+        paragraph: 0,
+        paragraph_start: 0,
+        paragraph_empty: false,
+    };
+    let restyle = options.strip_fonts || options.normalize_margins;
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Text(e) => {
+                let text = e.decode()?;
+                n.run.raw.push_str(&text);
+                n.run.text.push_str(&text);
+                continue;
+            }
+            Event::GeneralRef(e) => {
+                let name = e.decode()?;
+                match resolve_reference(e)? {
+                    Some(resolved) => {
+                        n.run.raw.push_str(&format!("&{name};"));
+                        n.run.text.push_str(&resolved);
+                    }
+                    // Entities of a DTD are written as they are
+                    None => {
+                        n.flush();
+                        n.writer
+                            .get_mut()
+                            .extend_from_slice(format!("&{name};").as_bytes());
+                        n.prev = 'x';
+                        n.paragraph_empty = false;
+                    }
+                }
+                continue;
+            }
+            _ => n.flush(),
+        }
+        match event {
+            Event::Start(e) => {
+                let name = e.local_name();
+                let name = name.as_ref();
+                if BLOCK_ELEMENTS.contains(&name) {
+                    n.prev = ' ';
+                }
+                if VERBATIM_ELEMENTS.contains(&name) {
+                    n.verbatim += 1;
+                    n.in_style = name == b"style";
+                }
+                if name == b"p" && options.remove_empty_paragraphs {
+                    let linked = attribute(&e, "id").is_some();
+                    if n.paragraph == 0 {
+                        n.paragraph_start = n.line_start();
+                        n.paragraph_empty = !linked;
+                    } else if linked {
+                        n.paragraph_empty = false;
+                    }
+                    n.paragraph += 1;
+                } else {
+                    n.enter(&e);
+                }
+                match n.restyle(&e).filter(|_| restyle) {
+                    Some(copy) => n.writer.write_event(Event::Start(copy))?,
+                    None => n.writer.write_event(Event::Start(e))?,
+                }
+            }
+            Event::Empty(e) => {
+                let name = e.local_name();
+                if BLOCK_ELEMENTS.contains(&name.as_ref()) {
+                    n.prev = ' ';
+                }
+                let linked = attribute(&e, "id").is_some();
+                if name.as_ref() == b"p" && options.remove_empty_paragraphs && !linked {
+                    if n.paragraph == 0 {
+                        let start = n.line_start();
+                        n.writer.get_mut().truncate(start);
+                    }
+                    continue;
+                }
+                n.enter(&e);
+                match n.restyle(&e).filter(|_| restyle) {
+                    Some(copy) => n.writer.write_event(Event::Empty(copy))?,
+                    None => n.writer.write_event(Event::Empty(e))?,
+                }
+            }
+            Event::End(e) => {
+                let name = e.local_name();
+                let name = name.as_ref();
+                if BLOCK_ELEMENTS.contains(&name) {
+                    n.prev = ' ';
+                }
+                if VERBATIM_ELEMENTS.contains(&name) {
+                    n.verbatim = n.verbatim.saturating_sub(1);
+                    n.in_style = false;
+                }
+                let paragraph = name == b"p";
+                n.writer.write_event(Event::End(e))?;
+                if paragraph && n.paragraph > 0 {
+                    n.paragraph -= 1;
+                    if n.paragraph == 0 && n.paragraph_empty {
+                        let start = n.paragraph_start;
+                        n.writer.get_mut().truncate(start);
+                    }
+                }
+            }
+            Event::Eof => break,
+            event => {
+                if matches!(event, Event::CData(_)) {
+                    n.paragraph_empty = false;
+                }
+                n.writer.write_event(event)?;
+            }
+        }
+    }
+    let out = String::from_utf8(n.writer.into_inner())?;
+    Ok((out != xhtml).then_some(out))
+}
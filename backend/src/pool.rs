@@ -0,0 +1,158 @@
+use crate::migrations;
+use anyhow::{anyhow, Context, Result};
+use rusqlite::Connection;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Maximum number of read connections kept open per library
+pub const DEFAULT_MAX_READERS: usize = 4;
+/// How long SQLite waits on a locked database before giving up with `SQLITE_BUSY`
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a caller waits for a free read connection before giving up
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bounded set of connections to a single database file.
+///
+/// SQLite allows only one writer at a time, so all writes are serialized through a single
+/// connection, while up to `max_readers` read-only connections serve queries concurrently. The
+/// database runs in WAL mode so readers don't block the writer and vice versa.
+pub struct Pool {
+    db_path: PathBuf,
+    writer: Mutex<Connection>,
+    readers: Mutex<Readers>,
+    reader_returned: Condvar,
+    max_readers: usize,
+}
+
+struct Readers {
+    idle: Vec<Connection>,
+    open: usize,
+}
+
+impl Pool {
+    /// Open the writer connection (running migrations on it) and prepare the reader pool
+    pub fn open(db_path: &Path, max_readers: usize) -> Result<Self> {
+        let mut writer =
+            Connection::open(db_path).with_context(|| format!("Opening DB file at {db_path:?}"))?;
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        migrations::run(&mut writer)?; // idempotent
+
+        Ok(Self {
+            db_path: db_path.to_path_buf(),
+            writer: Mutex::new(writer),
+            readers: Mutex::new(Readers {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            reader_returned: Condvar::new(),
+            max_readers: max_readers.max(1),
+        })
+    }
+
+    pub fn db_path(&self) -> &Path {
+        &self.db_path
+    }
+
+    /// Run `f` with a read-only connection. Blocks until a connection is free.
+    pub fn read<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Connection) -> Result<R>,
+    {
+        let conn = self.checkout()?;
+        f(&conn)
+    }
+
+    /// Run `f` with the single write connection. Blocks until no other write is in progress.
+    pub fn write<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Connection) -> Result<R>,
+    {
+        let mut conn = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("Write connection to {:?} is poisoned", self.db_path))?;
+        f(&mut conn)
+    }
+
+    fn open_reader(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.db_path)
+            .with_context(|| format!("Opening read connection to {:?}", self.db_path))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.pragma_update(None, "query_only", "ON")?;
+        Ok(conn)
+    }
+
+    fn checkout(&self) -> Result<PooledConnection<'_>> {
+        let poisoned = || anyhow!("Read connections to {:?} are poisoned", self.db_path);
+        let mut readers = self.readers.lock().map_err(|_| poisoned())?;
+        loop {
+            if let Some(conn) = readers.idle.pop() {
+                return Ok(PooledConnection {
+                    pool: self,
+                    conn: Some(conn),
+                });
+            }
+            if readers.open < self.max_readers {
+                readers.open += 1;
+                drop(readers);
+                return match self.open_reader() {
+                    Ok(conn) => Ok(PooledConnection {
+                        pool: self,
+                        conn: Some(conn),
+                    }),
+                    Err(e) => {
+                        if let Ok(mut readers) = self.readers.lock() {
+                            readers.open -= 1;
+                        }
+                        self.reader_returned.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            let (guard, timeout) = self
+                .reader_returned
+                .wait_timeout(readers, CHECKOUT_TIMEOUT)
+                .map_err(|_| poisoned())?;
+            if timeout.timed_out() && guard.idle.is_empty() && guard.open >= self.max_readers {
+                return Err(anyhow!(
+                    "Timed out waiting for a free read connection to {:?}",
+                    self.db_path
+                ));
+            }
+            readers = guard;
+        }
+    }
+}
+
+/// A read connection borrowed from the pool, returned to it when dropped
+struct PooledConnection<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            match self.pool.readers.lock() {
+                Ok(mut readers) => readers.idle.push(conn),
+                Err(_) => tracing::warn!("Dropping read connection to a poisoned pool"),
+            }
+            self.pool.reader_returned.notify_one();
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use crate::editor;
+use crate::epub::Epub;
+use crate::normalize::{self, xhtml::resolve_reference, Changes};
+use anyhow::{anyhow, Result};
+use quick_xml::escape::partial_escape;
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use regex::{Regex, RegexBuilder};
+use rusqlite::Connection;
+use shared::types::{BookMatches, ReplacementMatch, TextReplacement};
+use std::path::Path;
+
+/// Elements whose text is not part of the content
+const SKIPPED_ELEMENTS: &[&[u8]] = &[b"head", b"script", b"style"];
+
+/// Matches listed per book, the rest are only counted
+const MAX_LISTED_MATCHES: usize = 500;
+
+/// Characters of context shown on each side of a match
+const CONTEXT_CHARS: usize = 40;
+
+/// The regex that finds the text of `replacement`, which is escaped unless in regex mode.
+/// Patterns that match empty text are refused, their replacement would be inserted everywhere.
+pub fn compile(replacement: &TextReplacement) -> Result<Regex> {
+    if replacement.find.is_empty() {
+        return Err(anyhow!("Nothing to find"));
+    }
+    let pattern = if replacement.regex {
+        replacement.find.clone()
+    } else {
+        regex::escape(&replacement.find)
+    };
+    let regex = RegexBuilder::new(&pattern)
+        .case_insensitive(!replacement.case_sensitive)
+        .build()?;
+    if regex.is_match("") {
+        return Err(anyhow!(
+            "{:?} matches empty text, make it match at least one character",
+            replacement.find
+        ));
+    }
+    Ok(regex)
+}
+
+/// The matches of `regex` in `text`, leaving out empty ones such as those of `\b` that
+/// [`compile`] cannot rule out
+fn matches<'r, 't>(
+    regex: &'r Regex,
+    text: &'t str,
+) -> impl Iterator<Item = regex::Captures<'t>> + 'r
+where
+    't: 'r,
+{
+    regex
+        .captures_iter(text)
+        .filter(|captures| !captures.get(0).expect("a match has group 0").is_empty())
+}
+
+/// The content document `document` of `epub` as text, `None` if it isn't UTF-8. Such documents
+/// are left alone rather than having their invalid bytes replaced when written back.
+fn read_text(epub: &mut Epub, document: &str) -> Result<Option<String>> {
+    Ok(String::from_utf8(epub.read(document)?).ok())
+}
+
+/// `xhtml` with each run of text between markup passed through `replace`, `None` if that
+/// changes nothing. Character references are resolved in the text `replace` gets, so matches
+/// may span them, but not markup.
+fn map_text(
+    xhtml: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> Result<Option<String>> {
+    let mut reader = Reader::from_str(xhtml);
+    reader.config_mut().check_end_names = false;
+    let mut writer = Writer::new(Vec::with_capacity(xhtml.len()));
+    // The text as written and with references resolved
+    let mut raw = String::new();
+    let mut text = String::new();
+    let mut skipped = 0usize;
+    let mut changed = false;
+    // Writes the text read so far, returning whether it was replaced
+    let mut flush = |writer: &mut Writer<Vec<u8>>, raw: &mut String, text: &mut String, skipped| {
+        let replaced = match skipped {
+            0 if !text.is_empty() => replace(text),
+            _ => None,
+        };
+        let was_replaced = replaced.is_some();
+        let out = match replaced {
+            Some(replaced) => partial_escape(&replaced).into_owned(),
+            None => std::mem::take(raw),
+        };
+        writer.get_mut().extend_from_slice(out.as_bytes());
+        raw.clear();
+        text.clear();
+        was_replaced
+    };
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Text(e) => {
+                let decoded = e.decode()?;
+                raw.push_str(&decoded);
+                text.push_str(&decoded);
+                continue;
+            }
+            Event::GeneralRef(e) => {
+                let name = e.decode()?;
+                match resolve_reference(e)? {
+                    Some(resolved) => {
+                        raw.push_str(&format!("&{name};"));
+                        text.push_str(&resolved);
+                    }
+                    None => {
+                        changed |= flush(&mut writer, &mut raw, &mut text, skipped);
+                        writer
+                            .get_mut()
+                            .extend_from_slice(format!("&{name};").as_bytes());
+                    }
+                }
+                continue;
+            }
+            _ => changed |= flush(&mut writer, &mut raw, &mut text, skipped),
+        }
+        match &event {
+            Event::Start(e) if SKIPPED_ELEMENTS.contains(&e.local_name().as_ref()) => {
+                skipped += 1;
+            }
+            Event::End(e) if SKIPPED_ELEMENTS.contains(&e.local_name().as_ref()) => {
+                skipped = skipped.saturating_sub(1);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        writer.write_event(event)?;
+    }
+    if !changed {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(writer.into_inner())?))
+}
+
+/// Paths of the content documents of `epub`, those in reading order first
+fn content_documents(epub: &Epub) -> Vec<String> {
+    let mut documents: Vec<String> = epub
+        .spine_items()
+        .filter(|item| item.is_content_document())
+        .map(|item| item.path.clone())
+        .collect();
+    for item in &epub.manifest {
+        if item.is_content_document() && !documents.contains(&item.path) {
+            documents.push(item.path.clone());
+        }
+    }
+    documents
+}
+
+/// What a match is replaced with
+fn replacement_text(captures: &regex::Captures, replacement: &TextReplacement) -> String {
+    if replacement.regex {
+        let mut text = String::new();
+        captures.expand(&replacement.replace, &mut text);
+        text
+    } else {
+        replacement.replace.clone()
+    }
+}
+
+/// Up to `count` characters at the end or start of `text`
+fn last_chars(text: &str, count: usize) -> String {
+    let skip = text.chars().count().saturating_sub(count);
+    text.chars().skip(skip).collect()
+}
+
+fn first_chars(text: &str, count: usize) -> String {
+    text.chars().take(count).collect()
+}
+
+/// The matches of `replacement` in the text of the EPUB of `book`, `None` if it has no EPUB
+pub fn find(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    regex: &Regex,
+    replacement: &TextReplacement,
+) -> Result<Option<BookMatches>> {
+    if normalize::epub_file(conn, book)?.is_none() {
+        return Ok(None);
+    }
+    let title: String = conn.query_row("SELECT title FROM books WHERE id = ?1", [book], |row| {
+        row.get(0)
+    })?;
+    let mut epub = Epub::open(&editor::epub_path(conn, library_dir, book)?)?;
+    let mut found = BookMatches {
+        book,
+        title,
+        matches: Vec::new(),
+        total: 0,
+        skipped: Vec::new(),
+    };
+    for document in content_documents(&epub) {
+        let Some(xhtml) = read_text(&mut epub, &document)? else {
+            found.skipped.push(document);
+            continue;
+        };
+        map_text(&xhtml, |text| {
+            for captures in matches(regex, text) {
+                found.total += 1;
+                if found.matches.len() == MAX_LISTED_MATCHES {
+                    continue;
+                }
+                let whole = captures.get(0).expect("a match has group 0");
+                found.matches.push(ReplacementMatch {
+                    document: document.clone(),
+                    before: last_chars(&text[..whole.start()], CONTEXT_CHARS),
+                    found: whole.as_str().to_string(),
+                    after: first_chars(&text[whole.end()..], CONTEXT_CHARS),
+                    replacement: replacement_text(&captures, replacement),
+                });
+            }
+            None
+        })?;
+    }
+    Ok(Some(found))
+}
+
+/// Replace the matches of `replacement` in the text of the EPUB of `book`. The EPUB is written
+/// anew and moved over the old one, and the EPUB as it was before the first change is kept, see
+/// [`normalize::apply`]. Content documents that aren't UTF-8 are left out, see [`find`]. Returns
+/// the number of replacements.
+pub fn replace(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    regex: &Regex,
+    replacement: &TextReplacement,
+) -> Result<usize> {
+    let mut epub = Epub::open(&editor::epub_path(conn, library_dir, book)?)?;
+    let mut changes = Changes::default();
+    let mut count = 0;
+    for document in content_documents(&epub) {
+        let Some(xhtml) = read_text(&mut epub, &document)? else {
+            continue;
+        };
+        let replaced = map_text(&xhtml, |text| {
+            let mut replaced = String::new();
+            let mut end = 0;
+            for captures in matches(regex, text) {
+                let whole = captures.get(0).expect("a match has group 0");
+                replaced.push_str(&text[end..whole.start()]);
+                replaced.push_str(&replacement_text(&captures, replacement));
+                end = whole.end();
+                count += 1;
+            }
+            if end == 0 {
+                return None;
+            }
+            replaced.push_str(&text[end..]);
+            Some(replaced)
+        })?;
+        if let Some(replaced) = replaced {
+            changes.replaced.insert(document, replaced.into_bytes());
+        }
+    }
+    if !changes.is_empty() {
+        normalize::apply(conn, library_dir, book, &changes)?;
+    }
+    Ok(count)
+}
@@ -0,0 +1,270 @@
+use crate::checker;
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use rusqlite::{params_from_iter, Connection};
+use std::path::{Path, PathBuf};
+
+/// A numbered placeholder in the SQL condition of a term
+static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\?(\d+)").expect("valid regex"));
+
+/// A single condition of a search query. Terms are combined with AND.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub field: Option<String>,
+    pub value: String,
+    pub negated: bool,
+}
+
+/// Fields that can be searched with `field:value`. Values of text fields match anywhere, e.g.,
+/// `author:sanders` finds Brandon Sanderson. `id:` takes one or more comma-separated book IDs.
+pub const FIELDS: &[&str] = &[
+    "id",
+    "title",
+    "author",
+    "series",
+    "tag",
+    "collection",
+    "format",
+    "isbn",
+    "goodreads",
+    "has",
+    "is",
+];
+
+/// Split a query such as `author:sanderson -is:read "way of kings"` into terms. Double quotes
+/// group words, a leading `-` negates a term.
+pub fn parse(query: &str) -> Result<Vec<Term>> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+        .into_iter()
+        .map(|token| {
+            let (negated, token) = match token.strip_prefix('-') {
+                Some(rest) if !rest.is_empty() => (true, rest.to_string()),
+                _ => (false, token),
+            };
+            let term = match token.split_once(':') {
+                Some((field, value)) if FIELDS.contains(&field.to_lowercase().as_str()) => Term {
+                    field: Some(field.to_lowercase()),
+                    value: value.to_string(),
+                    negated,
+                },
+                Some((field, _)) if !field.is_empty() && !field.contains(' ') => {
+                    return Err(anyhow!(
+                        "Unknown search field {field:?}, expected one of {}",
+                        FIELDS.join(", ")
+                    ))
+                }
+                _ => Term {
+                    field: None,
+                    value: token,
+                    negated,
+                },
+            };
+            Ok(term)
+        })
+        .collect()
+}
+
+/// The directory of the library whose database `conn` is connected to, empty for in-memory
+/// databases
+fn library_dir(conn: &Connection) -> PathBuf {
+    conn.path()
+        .and_then(|db| Path::new(db).parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+/// SQL condition on `books.id` for a term, with its parameters
+fn condition(conn: &Connection, term: &Term) -> Result<(String, Vec<String>)> {
+    let like = format!("%{}%", term.value);
+    let (sql, params) = match term.field.as_deref() {
+        None => (
+            "books.title LIKE ?1
+             OR books.id IN (SELECT bal.book FROM books_authors_link bal JOIN authors a ON a.id = bal.author WHERE a.name LIKE ?1)
+             OR books.id IN (SELECT bsl.book FROM books_series_link bsl JOIN series s ON s.id = bsl.series WHERE s.name LIKE ?1)"
+                .to_string(),
+            vec![like],
+        ),
+        Some("id") => {
+            let ids = term
+                .value
+                .split(',')
+                .map(|id| {
+                    id.trim()
+                        .parse::<i64>()
+                        .map(|id| id.to_string())
+                        .map_err(|_| anyhow!("Invalid book ID {id:?} for id:"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let placeholders = (1..=ids.len()).map(|i| format!("?{i}")).collect::<Vec<_>>();
+            (format!("books.id IN ({})", placeholders.join(", ")), ids)
+        }
+        Some("title") => ("books.title LIKE ?1".to_string(), vec![like]),
+        Some("author") => (
+            "books.id IN (SELECT bal.book FROM books_authors_link bal JOIN authors a ON a.id = bal.author WHERE a.name LIKE ?1)".to_string(),
+            vec![like],
+        ),
+        Some("series") => (
+            "books.id IN (SELECT bsl.book FROM books_series_link bsl JOIN series s ON s.id = bsl.series WHERE s.name LIKE ?1)".to_string(),
+            vec![like],
+        ),
+        Some("tag") => (
+            "books.id IN (SELECT btl.book FROM books_tags_link btl JOIN tags t ON t.id = btl.tag WHERE t.name LIKE ?1)".to_string(),
+            vec![like],
+        ),
+        Some("collection") => (
+            "books.id IN (SELECT bcl.book FROM books_collections_link bcl JOIN collections c ON c.id = bcl.collection WHERE c.name LIKE ?1)".to_string(),
+            vec![like],
+        ),
+        Some("format") => (
+            "books.id IN (SELECT book FROM data WHERE format = ?1)".to_string(),
+            vec![term.value.to_uppercase()],
+        ),
+        Some("isbn") => (
+            "books.id IN (SELECT book FROM identifiers WHERE type = 'isbn' AND REPLACE(val, '-', '') = ?1)".to_string(),
+            vec![term.value.replace('-', "")],
+        ),
+        Some("goodreads") => (
+            "books.goodreads_id = ?1".to_string(),
+            vec![term.value.clone()],
+        ),
+        Some("has") => match term.value.to_lowercase().as_str() {
+            "cover" => ("books.has_cover = 1".to_string(), vec![]),
+            "isbn" => (
+                "books.id IN (SELECT book FROM identifiers WHERE type = 'isbn')".to_string(),
+                vec![],
+            ),
+            "series" => (
+                "books.id IN (SELECT book FROM books_series_link)".to_string(),
+                vec![],
+            ),
+            "files" => ("books.id IN (SELECT book FROM data)".to_string(), vec![]),
+            // Problems found by the EPUB checker in files that haven't changed since. Whether
+            // they did is told by the files on disk, which SQL can't look at.
+            "problems" => {
+                let ids = checker::current_problems(conn, &library_dir(conn))?
+                    .iter()
+                    .map(i64::to_string)
+                    .collect::<Vec<_>>();
+                (
+                    format!("books.id IN (SELECT book FROM data WHERE id IN ({}))", ids.join(", ")),
+                    vec![],
+                )
+            }
+            other => return Err(anyhow!("Unknown value {other:?} for has:, expected cover, isbn, series, files or problems")),
+        },
+        Some("is") => match term.value.to_lowercase().as_str() {
+            "read" => (
+                "books.id IN (SELECT book FROM read_books WHERE end_date IS NOT NULL)".to_string(),
+                vec![],
+            ),
+            "reading" => (
+                "books.id IN (SELECT book FROM read_books WHERE end_date IS NULL)".to_string(),
+                vec![],
+            ),
+            "unread" => (
+                "books.id NOT IN (SELECT book FROM read_books)".to_string(),
+                vec![],
+            ),
+            other => return Err(anyhow!("Unknown value {other:?} for is:, expected read, reading or unread")),
+        },
+        Some(field) => return Err(anyhow!("Unknown search field {field:?}")),
+    };
+    Ok((sql, params))
+}
+
+/// IDs of the books matching `query`, ordered by their sort title. An empty query matches
+/// every book.
+pub fn matching_books(conn: &Connection, query: &str) -> Result<Vec<i64>> {
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    for term in parse(query)? {
+        let (sql, term_params) = condition(conn, &term)?;
+        // Renumber the term's placeholders so they follow the ones of previous terms. Done in
+        // one pass, replacing one number after the other would renumber numbers already replaced.
+        let offset = params.len();
+        let sql = PLACEHOLDER.replace_all(&sql, |caps: &Captures| {
+            format!("?{}", offset + caps[1].parse::<usize>().unwrap_or_default())
+        });
+        params.extend(term_params);
+        let negation = if term.negated { "NOT " } else { "" };
+        conditions.push(format!("{negation}({sql})"));
+    }
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT books.id FROM books {filter} ORDER BY books.sort"
+    ))?;
+    let ids = stmt.query_map(params_from_iter(params), |row| row.get(0))?;
+    Ok(ids.collect::<Result<_, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::books::{self, NewBook};
+    use crate::migrations;
+
+    fn library(titles: &[&str]) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        for title in titles {
+            let book = NewBook {
+                title: title.to_string(),
+                ..Default::default()
+            };
+            books::insert_book(&conn, &book).unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn many_ids_after_another_term() {
+        let titles: Vec<String> = (1..=12).map(|i| format!("Book {i:02}")).collect();
+        let conn = library(&titles.iter().map(String::as_str).collect::<Vec<_>>());
+        let tag = books::tag_id(&conn, "x").unwrap();
+        for book in 1..=12 {
+            books::link_tag(&conn, book, tag).unwrap();
+        }
+
+        let ids: Vec<String> = (1..=11).map(|id| id.to_string()).collect();
+        let query = format!("tag:x id:{}", ids.join(","));
+        assert_eq!(
+            matching_books(&conn, &query).unwrap(),
+            (1..=11).collect::<Vec<i64>>()
+        );
+        let query = format!("title:book -tag:y id:{} -id:3", ids.join(","));
+        let mut expected: Vec<i64> = (1..=11).collect();
+        expected.retain(|id| *id != 3);
+        assert_eq!(matching_books(&conn, &query).unwrap(), expected);
+    }
+
+    #[test]
+    fn terms_are_combined() {
+        let conn = library(&["The Way of Kings", "Words of Radiance", "Mistborn"]);
+        assert_eq!(matching_books(&conn, "of -kings").unwrap(), vec![2]);
+        assert_eq!(matching_books(&conn, "").unwrap(), vec![3, 1, 2]);
+        assert!(matching_books(&conn, "unknown:x").is_err());
+    }
+}
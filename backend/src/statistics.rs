@@ -0,0 +1,200 @@
+use crate::epub::{self, Epub};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::types::BookStatistics;
+use std::path::Path;
+
+/// Formats that statistics can be computed from, most useful first
+const SUPPORTED_FORMATS: &[&str] = &["EPUB", "PDF"];
+
+/// Word and character (other than whitespace) count of a text. Punctuation standing on its own,
+/// e.g., a dash between spaces, is not a word.
+pub fn count_text(text: &str) -> (u64, u64) {
+    let words = text
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .count() as u64;
+    let chars = text.chars().filter(|c| !c.is_whitespace()).count() as u64;
+    (words, chars)
+}
+
+/// Pages a text of `words` words fills, rounded up
+pub fn estimate_pages(words: u64, words_per_page: u32) -> u32 {
+    words.div_ceil(words_per_page.max(1) as u64) as u32
+}
+
+/// Number of entries of the EPUB's page-list, i.e., the pages of the print edition it was made
+/// from, if it has one. The EPUB 3 navigation document is preferred over the EPUB 2 NCX.
+pub fn epub_page_list(epub: &mut Epub) -> Result<Option<u32>> {
+    if let Some(nav) = epub.nav().map(|item| item.path.clone()) {
+        let content = String::from_utf8_lossy(&epub.read(&nav)?).into_owned();
+        let mut reader = Reader::from_str(&content);
+        reader.config_mut().check_end_names = false;
+        // Depth of nested <nav> elements inside the page-list, 0 when outside of it
+        let mut depth = 0usize;
+        let mut pages = 0u32;
+        let mut found = false;
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) if e.local_name().as_ref() == b"nav" => {
+                    if depth > 0 {
+                        depth += 1;
+                    } else if epub::attribute(&e, "type")
+                        .is_some_and(|t| t.split_whitespace().any(|t| t == "page-list"))
+                    {
+                        depth = 1;
+                        found = true;
+                    }
+                }
+                Event::End(e) if e.local_name().as_ref() == b"nav" && depth > 0 => depth -= 1,
+                Event::Start(e) if depth > 0 && e.local_name().as_ref() == b"a" => pages += 1,
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        if found && pages > 0 {
+            return Ok(Some(pages));
+        }
+    }
+    if let Some(ncx) = epub.ncx().map(|item| item.path.clone()) {
+        let content = String::from_utf8_lossy(&epub.read(&ncx)?).into_owned();
+        let mut reader = Reader::from_str(&content);
+        reader.config_mut().check_end_names = false;
+        let mut pages = 0u32;
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"pageTarget" => {
+                    pages += 1
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        if pages > 0 {
+            return Ok(Some(pages));
+        }
+    }
+    Ok(None)
+}
+
+/// Word and character count of the text of the EPUB's spine
+pub fn epub_counts(epub: &mut Epub) -> Result<(u64, u64)> {
+    let documents: Vec<String> = epub.spine_items().map(|item| item.path.clone()).collect();
+    let (mut words, mut chars) = (0, 0);
+    for path in documents {
+        let content = epub.read(&path)?;
+        let text = epub::xhtml_text(&String::from_utf8_lossy(&content))
+            .with_context(|| format!("Reading the text of {path:?}"))?;
+        let (document_words, document_chars) = count_text(&text);
+        words += document_words;
+        chars += document_chars;
+    }
+    Ok((words, chars))
+}
+
+pub fn pdf_pages(path: &Path) -> Result<u32> {
+    let document = lopdf::Document::load(path).with_context(|| format!("Reading PDF {path:?}"))?;
+    Ok(document.get_pages().len() as u32)
+}
+
+/// Compute the statistics of a book from the first of its files in a supported format
+pub fn compute(
+    conn: &Connection,
+    library_dir: &Path,
+    book: i64,
+    words_per_page: u32,
+) -> Result<BookStatistics> {
+    let (dir, manual_pages): (String, u32) = conn
+        .query_row(
+            "SELECT path, number_of_pages FROM books WHERE id = ?1",
+            [book],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .ok_or_else(|| anyhow!("There is no book with ID {book}"))?;
+    let mut stmt = conn.prepare("SELECT format, name FROM data WHERE book = ?1")?;
+    let mut files = stmt
+        .query_map([book], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|(format, _)| SUPPORTED_FORMATS.contains(&format.as_str()));
+    files.sort_by_key(|(format, _)| SUPPORTED_FORMATS.iter().position(|f| f == format));
+    let (format, name) = files.into_iter().next().ok_or_else(|| {
+        anyhow!(
+            "Book {book} has no file to count in ({})",
+            SUPPORTED_FORMATS.join(", ")
+        )
+    })?;
+    let path = library_dir
+        .join(dir)
+        .join(format!("{name}.{}", format.to_lowercase()));
+
+    let mut statistics = BookStatistics {
+        book,
+        format: format.clone(),
+        word_count: None,
+        char_count: None,
+        estimated_pages: None,
+        real_pages: None,
+        manual_pages: Some(manual_pages).filter(|pages| *pages > 0),
+        computed: Utc::now(),
+    };
+    match format.as_str() {
+        "EPUB" => {
+            let mut epub = Epub::open(&path)?;
+            let (words, chars) = epub_counts(&mut epub)?;
+            statistics.word_count = Some(words);
+            statistics.char_count = Some(chars);
+            statistics.estimated_pages = Some(estimate_pages(words, words_per_page));
+            statistics.real_pages = epub_page_list(&mut epub)?;
+        }
+        _ => statistics.real_pages = Some(pdf_pages(&path)?),
+    }
+    Ok(statistics)
+}
+
+pub fn store(conn: &Connection, statistics: &BookStatistics) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO book_statistics
+         (book, format, word_count, char_count, estimated_pages, real_pages, computed)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            statistics.book,
+            statistics.format,
+            statistics.word_count,
+            statistics.char_count,
+            statistics.estimated_pages,
+            statistics.real_pages,
+            statistics.computed,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Stored statistics of a book with its current manual page count
+pub fn load(conn: &Connection, book: i64) -> Result<Option<BookStatistics>> {
+    Ok(conn
+        .query_row(
+            "SELECT s.format, s.word_count, s.char_count, s.estimated_pages, s.real_pages,
+                    s.computed, b.number_of_pages
+             FROM book_statistics s JOIN books b ON b.id = s.book WHERE s.book = ?1",
+            [book],
+            |row| {
+                Ok(BookStatistics {
+                    book,
+                    format: row.get(0)?,
+                    word_count: row.get(1)?,
+                    char_count: row.get(2)?,
+                    estimated_pages: row.get(3)?,
+                    real_pages: row.get(4)?,
+                    computed: row.get(5)?,
+                    manual_pages: Some(row.get::<_, u32>(6)?).filter(|pages| *pages > 0),
+                })
+            },
+        )
+        .optional()?)
+}
@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use once_cell::sync::Lazy;
+use rusqlite::{Connection, OpenFlags};
+use shared::types::LibraryEvent;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How often `PRAGMA data_version` is checked even if no file notification arrived
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Revision of the library data, increased whenever a change is detected
+static REVISION: AtomicU64 = AtomicU64::new(0);
+static EVENTS: Lazy<broadcast::Sender<LibraryEvent>> = Lazy::new(|| broadcast::channel(64).0);
+
+/// Subscribe to change notifications of the active library
+pub fn subscribe() -> broadcast::Receiver<LibraryEvent> {
+    EVENTS.subscribe()
+}
+
+fn publish_change(library: &str) {
+    let revision = REVISION.fetch_add(1, Ordering::SeqCst) + 1;
+    tracing::debug!("Library {:?} changed, now at revision {revision}", library);
+    // Sending only fails if nobody is subscribed, which is fine
+    let _ = EVENTS.send(LibraryEvent {
+        library: library.to_string(),
+        revision,
+    });
+}
+
+/// Detects writes to a library database, including those made by other processes.
+///
+/// File notifications on `library.db` and its WAL file trigger an immediate check, while
+/// `PRAGMA data_version` is also polled regularly for file systems without notification support.
+/// The watcher stops when it is dropped.
+pub struct LibraryWatcher {
+    _notifier: Option<RecommendedWatcher>,
+    _wakeup: mpsc::Sender<()>,
+}
+
+impl LibraryWatcher {
+    pub fn start(library: &str, db_path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Opening watch connection to {db_path:?}"))?;
+        let (wakeup, signals) = mpsc::channel();
+
+        let notifier = match file_notifier(db_path, wakeup.clone()) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                tracing::warn!(
+                    "File notifications unavailable for {:?}, relying on polling: {e:#}",
+                    db_path
+                );
+                None
+            }
+        };
+
+        let library = library.to_string();
+        std::thread::Builder::new()
+            .name(format!("watch-{library}"))
+            .spawn(move || poll_data_version(&conn, &signals, &library))
+            .context("Spawning library watcher thread")?;
+
+        Ok(Self {
+            _notifier: notifier,
+            _wakeup: wakeup,
+        })
+    }
+}
+
+fn file_notifier(db_path: &Path, wakeup: mpsc::Sender<()>) -> Result<RecommendedWatcher> {
+    let dir = db_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let db_name = db_path.file_name().map(|n| n.to_os_string());
+
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let Ok(event) = res else { return };
+        if !matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_)) {
+            return;
+        }
+        // Matches library.db as well as library.db-wal and library.db-journal
+        let touches_db = event
+            .paths
+            .iter()
+            .any(|p| match (p.file_name(), db_name.as_ref()) {
+                (Some(name), Some(db_name)) => name
+                    .to_string_lossy()
+                    .starts_with(db_name.to_string_lossy().as_ref()),
+                _ => false,
+            });
+        if touches_db {
+            let _ = wakeup.send(());
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+fn poll_data_version(conn: &Connection, signals: &mpsc::Receiver<()>, library: &str) {
+    let data_version = |conn: &Connection| {
+        conn.pragma_query_value(None, "data_version", |row| row.get::<_, i64>(0))
+    };
+    let mut last = match data_version(conn) {
+        Ok(version) => version,
+        Err(e) => {
+            tracing::error!("Cannot watch library {:?}: {e}", library);
+            return;
+        }
+    };
+
+    // Wakes up on file notifications or after the poll interval; stops once the watcher is
+    // dropped and the channel disconnects
+    while let Ok(()) | Err(RecvTimeoutError::Timeout) = signals.recv_timeout(POLL_INTERVAL) {
+        // A single write produces a burst of notifications; handle them as one
+        while signals.try_recv().is_ok() {}
+
+        match data_version(conn) {
+            Ok(version) if version != last => {
+                last = version;
+                publish_change(library);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Checking data_version of {:?} failed: {e}", library),
+        }
+    }
+    tracing::debug!("Stopped watching library {:?}", library);
+}
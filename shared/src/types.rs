@@ -1,7 +1,1007 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct AppConfig {
+    /// Directory of the library that is currently active
     pub data_dir: Option<PathBuf>,
+    /// All libraries known to the application, the active one included
+    #[serde(default)]
+    pub libraries: Vec<LibraryEntry>,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+    #[serde(default)]
+    pub statistics: StatisticsConfig,
+    #[serde(default)]
+    pub import: ImportConfig,
+    #[serde(default)]
+    pub formatting: FormattingConfig,
+    #[serde(default)]
+    pub conversion: ConversionConfig,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    /// Search queries saved under a name, e.g., `series:discworld` as "Discworld"
+    #[serde(default)]
+    pub saved_searches: BTreeMap<String, String>,
+}
+
+/// A named library, i.e., a directory containing a `library.db` and the book files
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryEntry {
+    pub name: String,
+    pub data_dir: PathBuf,
+}
+
+impl AppConfig {
+    /// Returns the registered library pointing at the active data directory, if any
+    pub fn active_library(&self) -> Option<&LibraryEntry> {
+        let dir = self.data_dir.as_ref()?;
+        self.libraries.iter().find(|l| &l.data_dir == dir)
+    }
+
+    pub fn find_library(&self, name: &str) -> Option<&LibraryEntry> {
+        self.libraries.iter().find(|l| l.name == name)
+    }
+}
+
+/// When and where snapshots of `library.db` are written, and how many of them are kept
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Directory for snapshots; `<library>/backups` if not set
+    pub dir: Option<PathBuf>,
+    /// Hours between scheduled snapshots, 0 disables the schedule
+    pub interval_hours: u32,
+    /// Number of most recent days for which the newest snapshot is kept
+    pub keep_daily: usize,
+    /// Number of most recent weeks for which the newest snapshot is kept
+    pub keep_weekly: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            interval_hours: 24,
+            keep_daily: 7,
+            keep_weekly: 4,
+        }
+    }
+}
+
+/// A snapshot of a library database in the backup directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupSnapshot {
+    /// File name inside the backup directory, used to identify the snapshot
+    pub name: String,
+    /// Why the snapshot was taken, e.g., "scheduled", "manual" or "pre-v2"
+    pub label: String,
+    pub created: DateTime<Utc>,
+    pub size: u64,
+}
+
+/// Pushed to clients whenever the active library's database was modified, be it by IronScribe
+/// itself or by another process
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryEvent {
+    /// Name of the library that changed
+    pub library: String,
+    /// Increases with every detected change; cached data from an older revision is stale
+    pub revision: u64,
+}
+
+/// Outcome of importing data from another application, e.g., a Calibre library
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    /// File or directory that was imported
+    pub source: PathBuf,
+    /// Number of imported items per kind, e.g., "books" or "covers"
+    pub imported: BTreeMap<String, usize>,
+    /// Everything that could not be mapped onto the IronScribe library
+    pub warnings: Vec<String>,
+    /// Where the report was saved
+    pub report_path: Option<PathBuf>,
+}
+
+impl ImportReport {
+    pub fn new(source: PathBuf) -> Self {
+        Self {
+            source,
+            ..Default::default()
+        }
+    }
+
+    pub fn count(&mut self, kind: &str, n: usize) {
+        *self.imported.entry(kind.to_string()).or_default() += n;
+    }
+
+    pub fn warn(&mut self, warning: impl Into<String>) {
+        self.warnings.push(warning.into());
+    }
+}
+
+/// What the import of book files does with a file that duplicates a book of the library
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateAction {
+    /// Leave the file out
+    #[default]
+    Skip,
+    /// Add the file to the existing book as another format, or leave it out if the book has a
+    /// file of that format already
+    Merge,
+    /// Import the file as a new book regardless
+    ImportAnyway,
+}
+
+/// Why books are considered duplicates
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DuplicateReason {
+    /// Files with identical content
+    SameFile,
+    SameIsbn,
+    /// Titles and authors that are nearly the same after normalization
+    SimilarTitle,
+}
+
+impl std::fmt::Display for DuplicateReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DuplicateReason::SameFile => "same file",
+            DuplicateReason::SameIsbn => "same ISBN",
+            DuplicateReason::SimilarTitle => "similar title and author",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DuplicateBook {
+    pub id: i64,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub formats: Vec<String>,
+}
+
+/// Books that are probably the same, to be reviewed and merged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    /// Oldest book first
+    pub books: Vec<DuplicateBook>,
+    pub reasons: Vec<DuplicateReason>,
+}
+
+/// How book files are imported
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ImportConfig {
+    /// Regular expressions matched against file names without extension, in order, for
+    /// metadata that files don't carry. Named groups `title`, `author`, `series`,
+    /// `series_index`, `isbn` and `year` become the respective fields.
+    pub filename_patterns: Vec<String>,
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        Self {
+            filename_patterns: vec![
+                // Author - Series 03 - Title
+                r"^(?P<author>.+?) - (?P<series>.+?) (?P<series_index>\d+(?:\.\d+)?) - (?P<title>.+)$"
+                    .to_string(),
+                // Title (Series #2) - Author
+                r"^(?P<title>.+?) \((?P<series>.+?),? #(?P<series_index>\d+(?:\.\d+)?)\) - (?P<author>.+)$"
+                    .to_string(),
+                // Title - Author, as IronScribe and Calibre name files
+                r"^(?P<title>.+?) - (?P<author>.+)$".to_string(),
+            ],
+        }
+    }
+}
+
+/// Steps of the content formatting of EPUB files. The original file is kept as
+/// `ORIGINAL_EPUB` format, like Calibre does, so formatting can be reverted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FormattingConfig {
+    /// Format the EPUB files of books as they are imported
+    pub on_import: bool,
+    /// Remove embedded fonts, font families and absolute font sizes
+    pub strip_fonts: bool,
+    /// Remove paragraphs without text and runs of non-breaking spaces
+    pub remove_empty_paragraphs: bool,
+    /// Replace straight quotes and apostrophes with typographic ones
+    pub smart_quotes: bool,
+    /// Remove page margins and convert absolute margins to em
+    pub normalize_margins: bool,
+    /// Remove files that vendors and tools leave behind, e.g., `iTunesMetadata.plist`
+    pub remove_vendor_files: bool,
+    /// Rebuild the NCX and EPUB 3 navigation document from the valid entries of the existing
+    /// table of contents, or from the headings if there are none
+    pub regenerate_toc: bool,
+}
+
+impl Default for FormattingConfig {
+    fn default() -> Self {
+        Self {
+            on_import: false,
+            strip_fonts: true,
+            remove_empty_paragraphs: true,
+            smart_quotes: true,
+            normalize_margins: true,
+            remove_vendor_files: true,
+            regenerate_toc: true,
+        }
+    }
+}
+
+/// What a file name pattern extracts from a sample file name
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PatternTest {
+    pub pattern: String,
+    /// Set if the pattern is not a valid regular expression
+    pub error: Option<String>,
+    pub matched: bool,
+    /// Non-empty named groups with their values, in the order of the pattern
+    pub fields: Vec<(String, String)>,
+}
+
+/// Which column of an imported sheet holds which field of a reading log, as column indices
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReadingLogMapping {
+    pub title: Option<usize>,
+    pub author: Option<usize>,
+    pub start_date: Option<usize>,
+    pub end_date: Option<usize>,
+    pub status: Option<usize>,
+    pub rating: Option<usize>,
+}
+
+/// Headers and first rows of a reading log, shown while mapping its columns
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReadingLogSheet {
+    pub headers: Vec<String>,
+    pub sample: Vec<Vec<String>>,
+    /// Mapping guessed from the headers
+    pub mapping: ReadingLogMapping,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadingStatus {
+    Finished,
+    Reading,
+    ToRead,
+    Abandoned,
+}
+
+impl std::fmt::Display for ReadingStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReadingStatus::Finished => "finished",
+            ReadingStatus::Reading => "reading",
+            ReadingStatus::ToRead => "to read",
+            ReadingStatus::Abandoned => "abandoned",
+        })
+    }
+}
+
+/// A book of the library that a row of an import was matched to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookMatch {
+    pub book: i64,
+    pub title: String,
+    pub authors: Vec<String>,
+    /// Similarity between 0 and 1, 1 meaning title and author match exactly
+    pub score: f64,
+}
+
+/// A row of a reading log as it will be imported
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadingLogRow {
+    /// Row number in the sheet, counting the header as row 1
+    pub row: usize,
+    pub title: String,
+    pub author: Option<String>,
+    pub start_date: Option<DateTime<Utc>>,
+    pub end_date: Option<DateTime<Utc>>,
+    pub status: ReadingStatus,
+    /// 1 to 5 stars
+    pub rating: Option<u8>,
+    pub book: Option<BookMatch>,
+    /// Why the row can't be imported as is; such rows are skipped
+    pub conflict: Option<String>,
+}
+
+/// CSV formats the reading history can be exported in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadingExportFormat {
+    Goodreads,
+    StoryGraph,
+}
+
+/// A reading of a book as included in exports
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadingEntry {
+    pub start_date: DateTime<Utc>,
+    /// `None` while the book is still being read
+    pub end_date: Option<DateTime<Utc>>,
+    pub rating: Option<u8>,
+    /// Who read the book, if readings are tracked per user
+    pub user: Option<String>,
+}
+
+/// Formats the library can be exported in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryExportFormat {
+    Csv,
+    /// The `BookRecords` shape, versioned
+    Json,
+    /// A static catalog with covers that can be hosted without IronScribe
+    Html,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LibraryExportOptions {
+    /// Search query selecting the exported books, all books if empty
+    pub query: String,
+    pub include_readings: bool,
+}
+
+/// Fields of a book that metadata providers can fill in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MetadataField {
+    Title,
+    Authors,
+    Series,
+    Description,
+    DatePublished,
+    NumberOfPages,
+    Isbn,
+    GoodreadsId,
+    Tags,
+}
+
+impl MetadataField {
+    pub const ALL: [MetadataField; 9] = [
+        MetadataField::Title,
+        MetadataField::Authors,
+        MetadataField::Series,
+        MetadataField::Description,
+        MetadataField::DatePublished,
+        MetadataField::NumberOfPages,
+        MetadataField::Isbn,
+        MetadataField::GoodreadsId,
+        MetadataField::Tags,
+    ];
+}
+
+impl std::fmt::Display for MetadataField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MetadataField::Title => "Title",
+            MetadataField::Authors => "Authors",
+            MetadataField::Series => "Series",
+            MetadataField::Description => "Description",
+            MetadataField::DatePublished => "Date published",
+            MetadataField::NumberOfPages => "Pages",
+            MetadataField::Isbn => "ISBN",
+            MetadataField::GoodreadsId => "Goodreads ID",
+            MetadataField::Tags => "Tags",
+        })
+    }
+}
+
+/// Metadata of a book, either as stored in the library or as returned by a provider. Empty
+/// fields are unknown.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct BookMetadata {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub description: Option<String>,
+    pub date_published: Option<DateTime<Utc>>,
+    pub number_of_pages: Option<u32>,
+    pub isbn: Option<String>,
+    pub goodreads_id: Option<i64>,
+    pub tags: Vec<String>,
+}
+
+/// What to look up at a metadata provider; at least one field should be set
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MetadataQuery {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub isbn: Option<String>,
+}
+
+/// A book found by a metadata provider, to be fetched in detail by its `id`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetadataSearchResult {
+    /// Name of the provider that found the book
+    pub provider: String,
+    /// The provider's ID of the book
+    pub id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub year: Option<i32>,
+}
+
+/// A cover image offered by a metadata provider
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoverCandidate {
+    pub provider: String,
+    /// Where the image can be downloaded, a file path for local providers
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// How metadata from several providers is combined with what the library already has
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MergeStrategy {
+    /// Providers to take values from, most trusted first
+    pub provider_order: Vec<String>,
+    /// Per-field exceptions to `provider_order`, e.g., page counts from a specific provider
+    pub field_order: BTreeMap<MetadataField, Vec<String>>,
+    /// Keep the library's values of fields that were locked on a book
+    pub keep_locked: bool,
+    /// Only fill fields that are empty in the library instead of replacing existing values
+    pub only_fill_empty: bool,
+}
+
+impl Default for MergeStrategy {
+    fn default() -> Self {
+        Self {
+            provider_order: Vec::new(),
+            field_order: BTreeMap::new(),
+            keep_locked: true,
+            only_fill_empty: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct MetadataConfig {
+    /// Directories of recorded provider responses, each one served by a fixture provider
+    pub fixture_dirs: Vec<PathBuf>,
+    pub merge: MergeStrategy,
+    /// Write the metadata and cover into the EPUB files of a book whenever they are edited
+    pub embed_on_edit: bool,
+}
+
+/// Metadata proposed for a book by the providers, to be reviewed field by field
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetadataProposal {
+    pub book: i64,
+    pub current: BookMetadata,
+    /// The current metadata with the fields that providers offer different values for replaced
+    pub proposed: BookMetadata,
+    /// Fields whose proposed value differs from the current one, with the provider it came from
+    pub changes: BTreeMap<MetadataField, String>,
+    pub locked: BTreeSet<MetadataField>,
+    pub has_cover: bool,
+    pub covers: Vec<CoverCandidate>,
+}
+
+/// Reviewed changes of a book's metadata, to be applied
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetadataChange {
+    pub book: i64,
+    /// The values of the accepted fields; other fields are ignored
+    pub metadata: BookMetadata,
+    pub accepted: BTreeSet<MetadataField>,
+    /// URL of the accepted cover candidate
+    pub cover: Option<String>,
+}
+
+/// Outcome of writing the metadata of several books into their EPUB files
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EmbedReport {
+    /// Number of files written
+    pub files: usize,
+    /// Books whose files could not be written, with the reason
+    pub failed: Vec<String>,
+}
+
+/// Outcome of formatting the content of several books, or reverting it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FormattingReport {
+    /// Number of books whose EPUB file was changed
+    pub changed: usize,
+    /// Number of books that needed no change
+    pub unchanged: usize,
+    /// Books that could not be formatted, with the reason
+    pub failed: Vec<String>,
+}
+
+/// A find-and-replace operation over the text of EPUB content documents
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TextReplacement {
+    pub find: String,
+    /// In regex mode, `$1` or `$name` insert capture groups
+    pub replace: String,
+    pub regex: bool,
+    pub case_sensitive: bool,
+}
+
+/// A match of a [`TextReplacement`] with the text around it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplacementMatch {
+    /// Content document the match is in, as path inside the archive
+    pub document: String,
+    pub before: String,
+    pub found: String,
+    pub after: String,
+    /// What the match would be replaced with
+    pub replacement: String,
+}
+
+/// The matches of a [`TextReplacement`] in one book
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookMatches {
+    pub book: i64,
+    pub title: String,
+    /// The first matches, up to a limit
+    pub matches: Vec<ReplacementMatch>,
+    /// Number of all matches
+    pub total: usize,
+    /// Content documents that were left out because they aren't UTF-8
+    #[serde(default)]
+    pub skipped: Vec<String>,
+}
+
+/// Outcome of a find-and-replace over several books
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReplacementReport {
+    /// Number of books whose EPUB file was changed
+    pub books: usize,
+    pub replacements: usize,
+    /// Books that could not be changed, with the reason
+    pub failed: Vec<String>,
+}
+
+/// Kinds of problems that checking an EPUB file finds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EpubProblemKind {
+    /// Not a ZIP archive, or without a usable container or package document
+    Archive,
+    /// The `mimetype` entry is missing, not first, compressed or has the wrong content
+    Mimetype,
+    /// Manifest items whose file is missing
+    Manifest,
+    /// Items in the spine that the manifest doesn't declare
+    Spine,
+    /// Images, style sheets and other resources that are referenced but not in the archive
+    MissingResource,
+    /// Links to files or anchors that don't exist
+    BrokenLink,
+    MalformedXhtml,
+    MissingCover,
+    /// No navigation document (EPUB 3) or NCX (EPUB 2)
+    MissingNav,
+}
+
+impl EpubProblemKind {
+    pub const ALL: [EpubProblemKind; 9] = [
+        EpubProblemKind::Archive,
+        EpubProblemKind::Mimetype,
+        EpubProblemKind::Manifest,
+        EpubProblemKind::Spine,
+        EpubProblemKind::MissingResource,
+        EpubProblemKind::BrokenLink,
+        EpubProblemKind::MalformedXhtml,
+        EpubProblemKind::MissingCover,
+        EpubProblemKind::MissingNav,
+    ];
+
+    /// Whether repairing a file fixes problems of this kind without guessing at its content
+    pub fn repairable(self) -> bool {
+        matches!(
+            self,
+            EpubProblemKind::Mimetype
+                | EpubProblemKind::Manifest
+                | EpubProblemKind::Spine
+                | EpubProblemKind::MissingNav
+        )
+    }
+}
+
+impl std::fmt::Display for EpubProblemKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EpubProblemKind::Archive => "Archive",
+            EpubProblemKind::Mimetype => "Mimetype",
+            EpubProblemKind::Manifest => "Manifest",
+            EpubProblemKind::Spine => "Spine",
+            EpubProblemKind::MissingResource => "Missing resource",
+            EpubProblemKind::BrokenLink => "Broken link",
+            EpubProblemKind::MalformedXhtml => "Malformed XHTML",
+            EpubProblemKind::MissingCover => "Missing cover",
+            EpubProblemKind::MissingNav => "Missing table of contents",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EpubProblem {
+    pub kind: EpubProblemKind,
+    /// Archive entry the problem is in, empty if it concerns the whole file
+    pub location: String,
+    pub message: String,
+}
+
+/// The problems found in the EPUB file of a book
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EpubCheck {
+    pub book: i64,
+    pub title: String,
+    pub problems: Vec<EpubProblem>,
+}
+
+/// Outcome of checking the EPUB files of several books
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EpubCheckReport {
+    /// Number of EPUB files checked
+    pub checked: usize,
+    /// The books with problems
+    pub books: Vec<EpubCheck>,
+    /// Books that could not be checked, with the reason
+    pub failed: Vec<String>,
+}
+
+/// How book files are converted between formats
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ConversionConfig {
+    /// Calibre's `ebook-convert`, a path or a command on `PATH`, for the formats that are not
+    /// converted by IronScribe itself, e.g., MOBI and AZW3
+    pub ebook_convert: String,
+}
+
+impl Default for ConversionConfig {
+    fn default() -> Self {
+        Self {
+            ebook_convert: "ebook-convert".to_string(),
+        }
+    }
+}
+
+/// How background jobs are run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// Number of jobs running at the same time
+    pub workers: usize,
+    /// How often a failing job is tried before it is given up
+    pub max_attempts: u32,
+    /// Wait before the first retry of a failed job, doubled for every further attempt
+    pub retry_delay_seconds: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            max_attempts: 3,
+            retry_delay_seconds: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub const ALL: [JobState; 5] = [
+        JobState::Queued,
+        JobState::Running,
+        JobState::Finished,
+        JobState::Failed,
+        JobState::Cancelled,
+    ];
+
+    /// Whether the job is done and won't run again unless retried
+    pub fn is_done(self) -> bool {
+        matches!(
+            self,
+            JobState::Finished | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Finished => "finished",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        })
+    }
+}
+
+/// What a background job does. Imports that only write rows in one short transaction
+/// (Calibre-Web, reading logs, Goodreads) run right away instead, as do metadata proposals, which
+/// are reviewed in the dialog that asked for them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum JobTask {
+    /// Convert the file of a book into another format, e.g., "KEPUB"
+    Convert { book: i64, target: String },
+    /// Import the book file at `path`, or all book files in the directory `path`
+    ImportFiles {
+        path: PathBuf,
+        link_files: bool,
+        on_duplicate: DuplicateAction,
+    },
+    /// Take a snapshot of the library database
+    Backup { label: String },
+    /// Import the Calibre library in the directory `path`
+    ImportCalibre { path: PathBuf, link_files: bool },
+}
+
+impl JobTask {
+    /// Name of the kind of task, each kind has its own limit of jobs running at once
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JobTask::Convert { .. } => "convert",
+            JobTask::ImportFiles { .. } => "import",
+            JobTask::Backup { .. } => "backup",
+            JobTask::ImportCalibre { .. } => "import",
+        }
+    }
+}
+
+/// A long-running operation of a library, run in the background
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: i64,
+    pub task: JobTask,
+    /// Description of the task, e.g., "Convert The Way of Kings into KEPUB"
+    pub title: String,
+    pub state: JobState,
+    /// Percentage done
+    pub progress: u8,
+    /// Number of times the job was started
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Set while a failed job waits for its retry
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+/// A message a job logged while running
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobLogEntry {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// What makes an automation rule run
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleTrigger {
+    BookImported,
+    ReadingFinished,
+    MetadataChanged,
+    TagAdded,
+}
+
+impl RuleTrigger {
+    pub const ALL: [RuleTrigger; 4] = [
+        RuleTrigger::BookImported,
+        RuleTrigger::ReadingFinished,
+        RuleTrigger::MetadataChanged,
+        RuleTrigger::TagAdded,
+    ];
+}
+
+impl std::fmt::Display for RuleTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RuleTrigger::BookImported => "Book imported",
+            RuleTrigger::ReadingFinished => "Reading finished",
+            RuleTrigger::MetadataChanged => "Metadata changed",
+            RuleTrigger::TagAdded => "Tag added",
+        })
+    }
+}
+
+/// What an automation rule does with the book it ran for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RuleAction {
+    AddTag {
+        tag: String,
+    },
+    /// Put the book on a shelf of the library, taking it off the other shelves of the library.
+    /// Shelves of single users are left alone.
+    MoveToShelf {
+        shelf: String,
+    },
+    /// Queue a conversion job into KEPUB
+    ConvertToKepub,
+    /// Rename the book's directory and files after its title and first author
+    RenameFiles,
+    /// Copy the book's file to a device mounted at `path`, e.g., an e-reader connected by USB
+    SendToDevice {
+        path: PathBuf,
+    },
+    /// POST a JSON description of the book and the trigger to `url`
+    RunWebhook {
+        url: String,
+    },
+}
+
+impl std::fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleAction::AddTag { tag } => write!(f, "Add tag {tag:?}"),
+            RuleAction::MoveToShelf { shelf } => write!(f, "Move to shelf {shelf:?}"),
+            RuleAction::ConvertToKepub => f.write_str("Convert to KEPUB"),
+            RuleAction::RenameFiles => f.write_str("Rename files"),
+            RuleAction::SendToDevice { path } => write!(f, "Send to device at {}", path.display()),
+            RuleAction::RunWebhook { url } => write!(f, "Run webhook {url}"),
+        }
+    }
+}
+
+/// A user-defined automation: when `trigger` occurs for a book matching `condition`, `action`
+/// is run for it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// 0 for a rule that wasn't saved yet
+    pub id: i64,
+    pub name: String,
+    pub enabled: bool,
+    pub trigger: RuleTrigger,
+    /// For [`RuleTrigger::TagAdded`], the tag that must have been added; any tag if `None`
+    pub tag: Option<String>,
+    /// Search query the book must match, e.g., `format:epub -tag:kobo`; every book if empty
+    pub condition: String,
+    pub action: RuleAction,
+}
+
+/// A run of a rule for a book, logged whether it succeeded or not
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleRun {
+    pub rule: i64,
+    pub rule_name: String,
+    pub book: i64,
+    /// Title of the book at the time of the run
+    pub title: String,
+    pub trigger: RuleTrigger,
+    pub at: DateTime<Utc>,
+    pub success: bool,
+    /// What the action did or why it failed
+    pub message: String,
+}
+
+/// Where a cover image comes from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CoverSource {
+    /// The book's current cover
+    Current,
+    /// An image inside one of the book's files, e.g., `("EPUB", "OEBPS/Images/cover.jpg")`
+    BookFile { format: String, path: String },
+    /// A cover offered by a metadata provider
+    Provider { provider: String, url: String },
+    /// An image on the web or a local file
+    Url(String),
+    /// Image data uploaded by the user
+    Upload(Vec<u8>),
+}
+
+/// A possible cover of a book, with a small preview to show
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoverOption {
+    pub source: CoverSource,
+    pub label: String,
+    pub width: u32,
+    pub height: u32,
+    /// `data:` URL of a downscaled copy of the image
+    pub preview: String,
+    /// Whether the book file declares this image as its cover
+    pub embedded_cover: bool,
+}
+
+/// How to cut a cover down to the standard 2:3 aspect ratio. `position` is where the kept part
+/// lies along the axis that is too long: 0.0 at the start (left or top), 0.5 in the middle and
+/// 1.0 at the end.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CoverCrop {
+    pub position: f32,
+}
+
+/// The cover of a book in a series, to compare the covers of the whole series
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SeriesCover {
+    pub book: i64,
+    pub title: String,
+    pub index: f64,
+    /// `data:` URL of the cover's thumbnail, if the book has a cover
+    pub preview: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct StatisticsConfig {
+    /// Words on a printed page, used to estimate page counts of books without real pages
+    pub words_per_page: u32,
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            words_per_page: 250,
+        }
+    }
+}
+
+/// Where a page count comes from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageCountSource {
+    /// Entered by hand or imported (`books.number_of_pages`)
+    Manual,
+    /// The EPUB page-list, i.e., the pages of the print edition, or the pages of a PDF
+    Real,
+    /// Derived from the word count
+    Estimated,
+}
+
+/// Counts computed from the content of a book, next to its manually entered page count
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookStatistics {
+    pub book: i64,
+    /// Format of the file the counts were computed from
+    pub format: String,
+    pub word_count: Option<u64>,
+    /// Characters other than whitespace
+    pub char_count: Option<u64>,
+    pub estimated_pages: Option<u32>,
+    pub real_pages: Option<u32>,
+    pub manual_pages: Option<u32>,
+    pub computed: DateTime<Utc>,
+}
+
+impl BookStatistics {
+    pub fn pages(&self, source: PageCountSource) -> Option<u32> {
+        match source {
+            PageCountSource::Manual => self.manual_pages,
+            PageCountSource::Real => self.real_pages,
+            PageCountSource::Estimated => self.estimated_pages,
+        }
+    }
+
+    /// The page count from the first source in `preference` that has one
+    pub fn preferred_pages(
+        &self,
+        preference: &[PageCountSource],
+    ) -> Option<(u32, PageCountSource)> {
+        preference
+            .iter()
+            .find_map(|source| self.pages(*source).map(|pages| (pages, *source)))
+    }
+}
+
+/// Outcome of computing the statistics of several books
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StatisticsReport {
+    /// Titles of the books with their statistics
+    pub computed: Vec<(String, BookStatistics)>,
+    /// Books whose statistics could not be computed, with the reason
+    pub failed: Vec<String>,
 }
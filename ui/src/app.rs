@@ -1,7 +1,27 @@
-use crate::{books::Books, path_picker::Modal};
+use crate::{
+    backups::Backups,
+    books::Books,
+    checker::EpubChecker,
+    convert::Conversions,
+    duplicates::Duplicates,
+    export::Export,
+    formatting::ContentFormatting,
+    import::Import,
+    jobs::Jobs,
+    libraries::LibrarySwitcher,
+    metadata::{EmbedMetadata, MetadataReview},
+    path_picker::Modal,
+    replace::FindReplace,
+    rules::Rules,
+    settings::Settings,
+    statistics::Statistics,
+};
 // use api::config::{init_config_server, read_config, write_path};
-use api::config::{initialize_config, read_config, write_config, write_path};
+use api::config::{initialize_config, read_config, write_path};
+use api::events::library_events;
 use dioxus::prelude::*;
+use futures::StreamExt;
+use shared::types::LibraryEvent;
 use std::path::PathBuf;
 
 const FAVICON: Asset = asset!("/assets/favicon.ico");
@@ -38,6 +58,47 @@ pub fn App() -> Element {
         }
     });
 
+    // Refetch the books whenever the server reports a change to the library database, whether
+    // it came from this client, another client or another program. Effects only run on the
+    // client, so the server never subscribes to its own event stream while rendering.
+    use_effect(move || {
+        let mut books_reload_key = books_reload_key.to_owned();
+        spawn(async move {
+            let events = match library_events().await {
+                Ok(events) => events,
+                Err(e) => {
+                    tracing::warn!("Not receiving library changes: {}", e);
+                    return;
+                }
+            };
+            let mut events = events.into_inner();
+            let mut buffer = String::new();
+            while let Some(chunk) = events.next().await {
+                match chunk {
+                    Ok(text) => buffer.push_str(&text),
+                    Err(e) => {
+                        tracing::warn!("Library change stream failed: {}", e);
+                        break;
+                    }
+                }
+                while let Some(end) = buffer.find('\n') {
+                    let line: String = buffer.drain(..=end).collect();
+                    match serde_json::from_str::<LibraryEvent>(line.trim()) {
+                        Ok(event) => {
+                            tracing::debug!(
+                                "Library {} changed (revision {})",
+                                event.library,
+                                event.revision
+                            );
+                            books_reload_key.set(books_reload_key() + 1);
+                        }
+                        Err(e) => tracing::warn!("Malformed library event {:?}: {}", line, e),
+                    }
+                }
+            }
+        });
+    });
+
     let contents = match cfg() {
         None => rsx! {
             div { "Loading configuration..." }
@@ -48,6 +109,105 @@ pub fn App() -> Element {
         Some(Ok(c)) => {
             if c.data_dir.is_some() {
                 rsx! {
+                    LibrarySwitcher {
+                        active_dir: c.data_dir.clone(),
+                        on_switch: {
+                            let mut config_reload_key = config_reload_key.to_owned();
+                            let mut books_reload_key = books_reload_key.to_owned();
+                            move |_| {
+                                config_reload_key.set(config_reload_key() + 1);
+                                books_reload_key.set(books_reload_key() + 1);
+                            }
+                        },
+                    }
+                    details {
+                        summary { "Backups" }
+                        Backups {
+                            on_restore: {
+                                let mut books_reload_key = books_reload_key.to_owned();
+                                move |_| books_reload_key.set(books_reload_key() + 1)
+                            },
+                        }
+                    }
+                    details {
+                        summary { "Import" }
+                        Import {
+                            on_import: {
+                                let mut books_reload_key = books_reload_key.to_owned();
+                                move |_| books_reload_key.set(books_reload_key() + 1)
+                            },
+                        }
+                    }
+                    details {
+                        summary { "Duplicates" }
+                        Duplicates {
+                            on_merge: {
+                                let mut books_reload_key = books_reload_key.to_owned();
+                                move |_| books_reload_key.set(books_reload_key() + 1)
+                            },
+                        }
+                    }
+                    details {
+                        summary { "Export" }
+                        Export {}
+                    }
+                    details {
+                        summary { "Metadata" }
+                        MetadataReview {
+                            on_apply: {
+                                let mut books_reload_key = books_reload_key.to_owned();
+                                move |_| books_reload_key.set(books_reload_key() + 1)
+                            },
+                        }
+                        EmbedMetadata {}
+                    }
+                    details {
+                        summary { "Formatting" }
+                        ContentFormatting {
+                            on_change: {
+                                let mut books_reload_key = books_reload_key.to_owned();
+                                move |_| books_reload_key.set(books_reload_key() + 1)
+                            },
+                        }
+                    }
+                    details {
+                        summary { "Find and replace" }
+                        FindReplace {
+                            on_change: {
+                                let mut books_reload_key = books_reload_key.to_owned();
+                                move |_| books_reload_key.set(books_reload_key() + 1)
+                            },
+                        }
+                    }
+                    details {
+                        summary { "EPUB check" }
+                        EpubChecker {
+                            on_change: {
+                                let mut books_reload_key = books_reload_key.to_owned();
+                                move |_| books_reload_key.set(books_reload_key() + 1)
+                            },
+                        }
+                    }
+                    details {
+                        summary { "Conversion" }
+                        Conversions {}
+                    }
+                    details {
+                        summary { "Jobs" }
+                        Jobs { reload_key: books_reload_key() }
+                    }
+                    details {
+                        summary { "Automation" }
+                        Rules { reload_key: books_reload_key() }
+                    }
+                    details {
+                        summary { "Statistics" }
+                        Statistics {}
+                    }
+                    details {
+                        summary { "Settings" }
+                        Settings {}
+                    }
                     Books { reload_key: books_reload_key() }
                 }
             } else {
@@ -77,8 +237,7 @@ pub fn App() -> Element {
                         let mut config_reload_key = config_reload_key.to_owned();
                         let mut books_reload_key = books_reload_key.to_owned();
                         async move {
-                            let config = cfg().unwrap().unwrap();
-                            match write_config(config).await {
+                            match write_path(PathBuf::from(s)).await {
                                 Ok(_) => {
                                     config_reload_key.set(config_reload_key() + 1);
                                     books_reload_key.set(books_reload_key() + 1);
@@ -0,0 +1,117 @@
+use api::backup::{
+    create_backup, delete_backup, export_library_archive, list_backups, restore_backup,
+};
+use dioxus::prelude::*;
+use std::path::PathBuf;
+
+/// Lists the snapshots of the active library and allows creating, restoring and deleting them,
+/// as well as exporting the whole library as a zip archive
+#[component]
+pub fn Backups(on_restore: EventHandler<()>) -> Element {
+    let mut reload_key = use_signal(|| 0u64);
+    let snapshots = use_server_future(move || {
+        let _k = reload_key();
+        list_backups()
+    })?;
+    let mut export_dest = use_signal(String::new);
+    let mut status = use_signal(|| None::<String>);
+
+    rsx! {
+        div { id: "backups",
+            button {
+                onclick: move |_| async move {
+                    match create_backup().await {
+                        Ok(_) => reload_key.set(reload_key() + 1),
+                        Err(e) => status.set(Some(format!("Backup failed: {e}"))),
+                    }
+                },
+                "Back up now"
+            }
+            match snapshots() {
+                None => rsx! {
+                    div { "Loading..." }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Error: {e}" }
+                },
+                Some(Ok(snapshots)) => rsx! {
+                    table {
+                        thead {
+                            tr {
+                                th { "Created" }
+                                th { "Reason" }
+                                th { "Size" }
+                                th {}
+                            }
+                        }
+                        tbody {
+                            for snapshot in snapshots {
+                                tr { key: "{snapshot.name}",
+                                    td { "{snapshot.created}" }
+                                    td { "{snapshot.label}" }
+                                    td { "{snapshot.size / 1024} KiB" }
+                                    td {
+                                        button {
+                                            onclick: {
+                                                let name = snapshot.name.clone();
+                                                move |_| {
+                                                    let name = name.clone();
+                                                    async move {
+                                                        match restore_backup(name.clone()).await {
+                                                            Ok(_) => {
+                                                                status.set(Some(format!("Restored {name}")));
+                                                                reload_key.set(reload_key() + 1);
+                                                                on_restore.call(());
+                                                            }
+                                                            Err(e) => status.set(Some(format!("Restore failed: {e}"))),
+                                                        }
+                                                    }
+                                                }
+                                            },
+                                            "Restore"
+                                        }
+                                        button {
+                                            onclick: {
+                                                let name = snapshot.name.clone();
+                                                move |_| {
+                                                    let name = name.clone();
+                                                    async move {
+                                                        match delete_backup(name).await {
+                                                            Ok(_) => reload_key.set(reload_key() + 1),
+                                                            Err(e) => status.set(Some(format!("Delete failed: {e}"))),
+                                                        }
+                                                    }
+                                                }
+                                            },
+                                            "Delete"
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+            div {
+                input {
+                    r#type: "text",
+                    placeholder: "C:/path/to/export/folder",
+                    value: "{export_dest}",
+                    oninput: move |e| export_dest.set(e.value()),
+                }
+                button {
+                    onclick: move |_| async move {
+                        match export_library_archive(PathBuf::from(export_dest())).await {
+                            Ok(path) => status.set(Some(format!("Exported to {}", path.display()))),
+                            Err(e) => status.set(Some(format!("Export failed: {e}"))),
+                        }
+                    },
+                    "Export archive"
+                }
+            }
+            if let Some(s) = status() {
+                div { "{s}" }
+            }
+        }
+    }
+}
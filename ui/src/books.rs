@@ -1,7 +1,10 @@
 use dioxus::prelude::*;
 use itertools::Itertools;
 
+use crate::covers::CoverPicker;
+use crate::editor::TextEditor;
 use api::database::{list_books, SortKey};
+use api::readings::finish_reading;
 
 struct SortState {
     key: SortKey,
@@ -9,9 +12,9 @@ struct SortState {
 }
 
 #[component]
-pub fn Books(reload_key: u64) -> Element {
+pub fn Books(reload_key: ReadOnlySignal<u64>) -> Element {
     let books = use_server_future(move || {
-        let _k = reload_key; // re-run when key changes
+        let _k = reload_key(); // re-run when key changes
         list_books()
     })?;
 
@@ -19,9 +22,15 @@ pub fn Books(reload_key: u64) -> Element {
         key: SortKey::DateAdded,
         ascending: false,
     }); // Default sorting
+    let mut cover_book = use_signal(|| None::<i64>);
+    let mut edited_book = use_signal(|| None::<i64>);
+    let mut status = use_signal(|| None::<String>);
 
     rsx! {
         div { id: "books",
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
             div { id: "books-container",
                 match books() {
                     Some(Err(e)) => rsx! {
@@ -91,6 +100,7 @@ pub fn Books(reload_key: u64) -> Element {
                                             },
                                             "Date Published"
                                         }
+                                        th {}
                                     }
                                 }
                                 tbody {
@@ -101,7 +111,39 @@ pub fn Books(reload_key: u64) -> Element {
                                             td { "{book.get_series_and_volumes().iter().join(\", \")}" }
                                             td { "{book.get_pages()}" }
                                             td { "{book.get_date_added()}" }
-                                            td { "{book.get_date_published()}" }
+                                            td { "{book.get_date_published().map(|d| d.to_string()).unwrap_or_default()}" }
+                                            td {
+                                                button {
+                                                    onclick: {
+                                                        let id = book.get_id() as i64;
+                                                        move |_| cover_book.set(Some(id))
+                                                    },
+                                                    "Cover"
+                                                }
+                                                button {
+                                                    onclick: {
+                                                        let id = book.get_id() as i64;
+                                                        move |_| edited_book.set(Some(id))
+                                                    },
+                                                    "Edit text"
+                                                }
+                                                button {
+                                                    onclick: {
+                                                        let id = book.get_id() as i64;
+                                                        let title = book.get_title();
+                                                        move |_| {
+                                                            let title = title.clone();
+                                                            async move {
+                                                                match finish_reading(id).await {
+                                                                    Ok(()) => status.set(Some(format!("Marked {title:?} as read"))),
+                                                                    Err(e) => status.set(Some(format!("Marking as read failed: {e}"))),
+                                                                }
+                                                            }
+                                                        }
+                                                    },
+                                                    "Finished"
+                                                }
+                                            }
                                         }
                                     }
                                 }
@@ -110,6 +152,18 @@ pub fn Books(reload_key: u64) -> Element {
                     }
                 }
             }
+            if let Some(book) = cover_book() {
+                CoverPicker {
+                    book,
+                    on_close: move |_| cover_book.set(None),
+                }
+            }
+            if let Some(book) = edited_book() {
+                TextEditor {
+                    book,
+                    on_close: move |_| edited_book.set(None),
+                }
+            }
         }
     }
 }
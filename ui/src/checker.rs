@@ -0,0 +1,124 @@
+use api::checker::{check_epubs, repair_epub};
+use dioxus::prelude::*;
+use shared::types::{EpubCheck, EpubCheckReport};
+
+/// Checks the EPUB files of the books matching a search and lists their problems, with a repair
+/// for books whose problems are simple enough. The results are stored, so `has:problems` finds
+/// these books later.
+#[component]
+pub fn EpubChecker(on_change: EventHandler<()>) -> Element {
+    let mut query = use_signal(String::new);
+    let mut recheck = use_signal(|| false);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<EpubCheckReport, String>>);
+    let mut status = use_signal(|| None::<String>);
+
+    let check = move |_| async move {
+        running.set(true);
+        status.set(None);
+        let outcome = check_epubs(query(), recheck()).await;
+        running.set(false);
+        result.set(Some(outcome.map_err(|e| e.to_string())));
+    };
+
+    let repair = move |book: i64| async move {
+        running.set(true);
+        let outcome = repair_epub(book).await;
+        running.set(false);
+        match outcome {
+            Ok(check) => {
+                status.set(Some(format!(
+                    "Repaired {}, {} problems left",
+                    check.title,
+                    check.problems.len()
+                )));
+                if let Some(Ok(report)) = &mut *result.write() {
+                    report.books.retain(|found| found.book != book);
+                    if !check.problems.is_empty() {
+                        report.books.push(check);
+                    }
+                }
+                on_change.call(());
+            }
+            Err(e) => status.set(Some(format!("Repairing failed: {e}"))),
+        }
+    };
+
+    rsx! {
+        div { id: "epub-checker",
+            input {
+                r#type: "text",
+                size: 50,
+                placeholder: "Books to check, all books if empty",
+                value: "{query}",
+                oninput: move |e| query.set(e.value()),
+            }
+            label {
+                input {
+                    r#type: "checkbox",
+                    checked: recheck(),
+                    onchange: move |e| recheck.set(e.checked()),
+                }
+                "Check unchanged files again"
+            }
+            button { disabled: running(), onclick: check,
+                if running() {
+                    "Working..."
+                } else {
+                    "Check EPUB files"
+                }
+            }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    div {
+                        "Checked {report.checked} files, {report.books.len()} with problems, {report.failed.len()} failed. Search for has:problems to find them again."
+                    }
+                    ul {
+                        for failure in report.failed {
+                            li { "{failure}" }
+                        }
+                    }
+                    for found in report.books {
+                        BookProblems { key: "{found.book}", found, disabled: running(), on_repair: repair }
+                    }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Checking failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
+
+/// The problems of the EPUB of one book, with a repair if some of them are simple enough
+#[component]
+fn BookProblems(found: EpubCheck, disabled: bool, on_repair: EventHandler<i64>) -> Element {
+    let repairable = found.problems.iter().filter(|p| p.kind.repairable()).count();
+    rsx! {
+        fieldset {
+            legend { "{found.title}: {found.problems.len()} problems" }
+            table {
+                tbody {
+                    for (index , problem) in found.problems.iter().enumerate() {
+                        tr { key: "{index}",
+                            td { "{problem.kind}" }
+                            td { "{problem.location}" }
+                            td { "{problem.message}" }
+                        }
+                    }
+                }
+            }
+            if repairable > 0 {
+                button {
+                    disabled,
+                    onclick: move |_| on_repair.call(found.book),
+                    "Repair {repairable} problems"
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use api::convert::convert_books;
+use dioxus::prelude::*;
+
+/// Formats books can be converted into
+const TARGETS: &[&str] = &["KEPUB", "EPUB"];
+
+/// Queues jobs converting the books matching a search. Converted files are added to their books
+/// as another format and converted again when their source changes.
+#[component]
+pub fn Conversions() -> Element {
+    let mut query = use_signal(String::new);
+    let mut target = use_signal(|| TARGETS[0].to_string());
+    let mut status = use_signal(|| None::<String>);
+
+    let convert = move |_| async move {
+        status.set(Some(match convert_books(query(), target()).await {
+            Ok(0) => "No books to convert".to_string(),
+            Ok(queued) => format!("Queued {queued} conversions, see Jobs for their progress"),
+            Err(e) => format!("Queueing failed: {e}"),
+        }));
+    };
+
+    rsx! {
+        div { id: "conversions",
+            input {
+                r#type: "text",
+                size: 50,
+                placeholder: "Books to convert, all books if empty",
+                value: "{query}",
+                oninput: move |e| query.set(e.value()),
+            }
+            select {
+                value: "{target}",
+                onchange: move |e| target.set(e.value()),
+                for format in TARGETS {
+                    option { value: *format, "{format}" }
+                }
+            }
+            button { onclick: convert, "Convert" }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use api::covers::{cover_options, preview_cover, save_cover, series_covers};
+use dioxus::prelude::*;
+use shared::types::{CoverCrop, CoverOption, CoverSource};
+
+/// Dialog for replacing the cover of a book with one of its candidates, an image URL or an
+/// uploaded image, optionally cropped to the standard 2:3 aspect ratio. In series mode, the
+/// covers of all books in the series are shown together and can be switched between.
+#[component]
+pub fn CoverPicker(book: i64, on_close: EventHandler<()>) -> Element {
+    let mut current_book = use_signal(|| book);
+    let mut options = use_resource(move || cover_options(current_book()));
+    let mut series = use_resource(move || series_covers(current_book()));
+    // Images given by URL or uploaded, in addition to the fetched options
+    let mut added = use_signal(Vec::<CoverOption>::new);
+    let mut selected = use_signal(|| None::<CoverSource>);
+    let mut crop = use_signal(|| true);
+    let mut position = use_signal(|| 50u8);
+    let mut url = use_signal(String::new);
+    let mut series_mode = use_signal(|| false);
+    let mut status = use_signal(|| None::<String>);
+
+    let add = move |source: CoverSource| async move {
+        match preview_cover(source).await {
+            Ok(option) => {
+                selected.set(Some(option.source.clone()));
+                added.write().push(option);
+            }
+            Err(e) => status.set(Some(format!("Image unusable: {e}"))),
+        }
+    };
+
+    let save = move |_| async move {
+        let Some(source) = selected() else {
+            return;
+        };
+        let crop = crop().then(|| CoverCrop {
+            position: position() as f32 / 100.0,
+        });
+        match save_cover(current_book(), source, crop).await {
+            Ok(()) => {
+                status.set(Some("Cover saved".to_string()));
+                selected.set(None);
+                options.restart();
+                series.restart();
+            }
+            Err(e) => status.set(Some(format!("Saving the cover failed: {e}"))),
+        }
+    };
+
+    let all_options: Vec<CoverOption> = match &*options.read() {
+        Some(Ok(options)) => options.iter().chain(added.read().iter()).cloned().collect(),
+        _ => added(),
+    };
+    // Preview the crop like it will be saved: object-position places the kept part the same way
+    let fit = if crop() {
+        format!(
+            "aspect-ratio:2/3; object-fit:cover; object-position:{0}% {0}%;",
+            position()
+        )
+    } else {
+        "object-fit:contain;".to_string()
+    };
+
+    rsx! {
+        div { style: "position:fixed; inset:0; background:rgba(0,0,0,0.45); display:flex; align-items:center; justify-content:center; z-index:1000;",
+            div {
+                role: "dialog",
+                "aria-modal": "true",
+                style: "background:#1e1e1e; max-width:90vw; max-height:90vh; overflow:auto; border-radius:10px; padding:16px;",
+                h2 { style: "margin:0 0 10px 0; font-size:18px;", "Cover" }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: series_mode(),
+                        onchange: move |e| series_mode.set(e.checked()),
+                    }
+                    "Match series covers"
+                }
+                if series_mode() {
+                    match &*series.read() {
+                        Some(Ok(covers)) if covers.is_empty() => rsx! {
+                            div { "This book is not part of a series" }
+                        },
+                        Some(Ok(covers)) => rsx! {
+                            div { class: "series-covers", style: "display:flex; gap:8px; flex-wrap:wrap;",
+                                for cover in covers.clone() {
+                                    figure {
+                                        key: "{cover.book}",
+                                        style: if cover.book == current_book() { "outline:2px solid #2563eb; margin:0;" } else { "margin:0; cursor:pointer;" },
+                                        onclick: move |_| {
+                                            current_book.set(cover.book);
+                                            selected.set(None);
+                                            added.set(Vec::new());
+                                        },
+                                        if let Some(preview) = cover.preview.clone() {
+                                            img { src: "{preview}", height: "160" }
+                                        } else {
+                                            div { style: "width:107px; height:160px; border:1px dashed #888;" }
+                                        }
+                                        figcaption { "#{cover.index} {cover.title}" }
+                                    }
+                                }
+                            }
+                        },
+                        Some(Err(e)) => rsx! {
+                            div { "Error: {e}" }
+                        },
+                        None => rsx! {
+                            div { "Loading series..." }
+                        },
+                    }
+                }
+                match &*options.read() {
+                    Some(Err(e)) => rsx! {
+                        div { "Error: {e}" }
+                    },
+                    None => rsx! {
+                        div { "Looking for covers..." }
+                    },
+                    Some(Ok(_)) => rsx! {},
+                }
+                div { class: "cover-options", style: "display:flex; gap:8px; flex-wrap:wrap;",
+                    for (index, option) in all_options.into_iter().enumerate() {
+                        figure {
+                            key: "{index}",
+                            style: if selected.read().as_ref() == Some(&option.source) { "outline:2px solid #2563eb; margin:0;" } else { "margin:0; cursor:pointer;" },
+                            onclick: {
+                                let source = option.source.clone();
+                                move |_| selected.set(Some(source.clone()))
+                            },
+                            img { src: "{option.preview}", height: "200", style: "{fit}" }
+                            figcaption {
+                                if option.embedded_cover {
+                                    "Embedded cover · "
+                                }
+                                "{option.label} ({option.width}×{option.height})"
+                            }
+                        }
+                    }
+                }
+                div {
+                    input {
+                        r#type: "text",
+                        placeholder: "https://example.com/cover.jpg",
+                        value: "{url}",
+                        oninput: move |e| url.set(e.value()),
+                    }
+                    button { onclick: move |_| add(CoverSource::Url(url())), "Add URL" }
+                    input {
+                        r#type: "file",
+                        accept: "image/*",
+                        onchange: move |e| async move {
+                            let Some(files) = e.files() else {
+                                return;
+                            };
+                            for name in files.files() {
+                                match files.read_file(&name).await {
+                                    Some(data) => add(CoverSource::Upload(data)).await,
+                                    None => status.set(Some(format!("Could not read {name}"))),
+                                }
+                            }
+                        },
+                    }
+                }
+                div {
+                    label {
+                        input {
+                            r#type: "checkbox",
+                            checked: crop(),
+                            onchange: move |e| crop.set(e.checked()),
+                        }
+                        "Crop to 2:3"
+                    }
+                    if crop() {
+                        input {
+                            r#type: "range",
+                            min: "0",
+                            max: "100",
+                            value: "{position}",
+                            oninput: move |e| position.set(e.value().parse().unwrap_or(50)),
+                        }
+                    }
+                }
+                if let Some(s) = status() {
+                    div { "{s}" }
+                }
+                button { disabled: selected.read().is_none(), onclick: save, "Save cover" }
+                button { onclick: move |_| on_close.call(()), "Close" }
+            }
+        }
+    }
+}
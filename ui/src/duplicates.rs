@@ -0,0 +1,91 @@
+use api::duplicates::{find_duplicates, merge_books};
+use dioxus::prelude::*;
+use itertools::Itertools;
+use shared::types::DuplicateGroup;
+
+/// Groups of probable duplicates in the library, each merged into the book chosen to keep
+#[component]
+pub fn Duplicates(on_merge: EventHandler<()>) -> Element {
+    let mut running = use_signal(|| false);
+    let mut groups = use_signal(|| None::<Result<Vec<DuplicateGroup>, String>>);
+    let mut status = use_signal(|| None::<String>);
+
+    let find = move |_| async move {
+        running.set(true);
+        let outcome = find_duplicates().await;
+        running.set(false);
+        groups.set(Some(outcome.map_err(|e| e.to_string())));
+    };
+
+    rsx! {
+        div { id: "duplicates",
+            button { disabled: running(), onclick: find,
+                if running() {
+                    "Searching..."
+                } else {
+                    "Find duplicates"
+                }
+            }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+            match groups() {
+                Some(Ok(found)) if found.is_empty() => rsx! {
+                    div { "No duplicates found" }
+                },
+                Some(Ok(found)) => rsx! {
+                    for (index , group) in found.into_iter().enumerate() {
+                        fieldset { key: "{group.books[0].id}",
+                            legend { "{group.reasons.iter().join(\", \")}" }
+                            table {
+                                tbody {
+                                    for book in group.books.clone() {
+                                        tr { key: "{book.id}",
+                                            td { "{book.title}" }
+                                            td { "{book.authors.join(\", \")}" }
+                                            td { "{book.formats.join(\", \")}" }
+                                            td {
+                                                button {
+                                                    onclick: {
+                                                        let others: Vec<i64> = group
+                                                            .books
+                                                            .iter()
+                                                            .map(|b| b.id)
+                                                            .filter(|id| *id != book.id)
+                                                            .collect();
+                                                        let title = book.title.clone();
+                                                        move |_| {
+                                                            let others = others.clone();
+                                                            let title = title.clone();
+                                                            async move {
+                                                                match merge_books(book.id, others).await {
+                                                                    Ok(()) => {
+                                                                        status.set(Some(format!("Merged into {title:?}")));
+                                                                        if let Some(Ok(found)) = groups.write().as_mut() {
+                                                                            found.remove(index);
+                                                                        }
+                                                                        on_merge.call(());
+                                                                    }
+                                                                    Err(e) => status.set(Some(format!("Merge failed: {e}"))),
+                                                                }
+                                                            }
+                                                        }
+                                                    },
+                                                    "Keep this, merge the others into it"
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Searching failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
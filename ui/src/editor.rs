@@ -0,0 +1,124 @@
+use api::editor::{check_xhtml, epub_document, epub_documents, revert_epub, save_epub_document};
+use dioxus::prelude::*;
+
+/// Editor for the XHTML source of the content documents of a book's EPUB, with a live preview
+/// and a well-formedness check that has to pass before saving. The EPUB as it was before the
+/// first save is kept and can be restored.
+#[component]
+pub fn TextEditor(book: i64, on_close: EventHandler<()>) -> Element {
+    let mut documents = use_signal(Vec::<String>::new);
+    let mut current = use_signal(|| None::<String>);
+    let mut source = use_signal(String::new);
+    // The source as last loaded or saved, to tell whether there are unsaved changes
+    let mut saved = use_signal(String::new);
+    let mut status = use_signal(|| None::<String>);
+    let problem = use_resource(move || check_xhtml(source()));
+
+    let open = move |path: String| async move {
+        match epub_document(book, path.clone()).await {
+            Ok(content) => {
+                source.set(content.clone());
+                saved.set(content);
+                current.set(Some(path));
+                status.set(None);
+            }
+            Err(e) => status.set(Some(format!("Opening {path} failed: {e}"))),
+        }
+    };
+
+    use_future(move || async move {
+        match epub_documents(book).await {
+            Ok(found) => {
+                let first = found.first().cloned();
+                documents.set(found);
+                if let Some(first) = first {
+                    open(first).await;
+                }
+            }
+            Err(e) => status.set(Some(format!("Opening the EPUB failed: {e}"))),
+        }
+    });
+
+    let save = move |_| async move {
+        let Some(path) = current() else {
+            return;
+        };
+        let content = source();
+        match save_epub_document(book, path.clone(), content.clone()).await {
+            Ok(()) => {
+                saved.set(content);
+                status.set(Some(format!("Saved {path}")));
+            }
+            Err(e) => status.set(Some(format!("Saving failed: {e}"))),
+        }
+    };
+
+    let revert = move |_| async move {
+        match revert_epub(book).await {
+            Ok(true) => {
+                status.set(Some("Restored the original EPUB".to_string()));
+                if let Some(path) = current() {
+                    open(path).await;
+                }
+            }
+            Ok(false) => status.set(Some("The EPUB has not been changed".to_string())),
+            Err(e) => status.set(Some(format!("Restoring failed: {e}"))),
+        }
+    };
+
+    let dirty = source() != saved();
+    let valid = matches!(&*problem.read(), Some(Ok(None)));
+
+    rsx! {
+        div { style: "position:fixed; inset:0; background:rgba(0,0,0,0.45); display:flex; align-items:center; justify-content:center; z-index:1000;",
+            div {
+                role: "dialog",
+                "aria-modal": "true",
+                style: "background:#1e1e1e; width:95vw; height:95vh; display:flex; flex-direction:column; gap:8px; border-radius:10px; padding:16px; box-sizing:border-box;",
+                div { style: "display:flex; gap:8px; align-items:center;",
+                    h2 { style: "margin:0; font-size:18px;", "Edit text" }
+                    select {
+                        onchange: move |e| async move { open(e.value()).await },
+                        for path in documents() {
+                            option {
+                                key: "{path}",
+                                value: "{path}",
+                                selected: current().as_ref() == Some(&path),
+                                "{path}"
+                            }
+                        }
+                    }
+                    button { disabled: !dirty || !valid, onclick: save, "Save" }
+                    button { onclick: revert, "Revert to original" }
+                    button { onclick: move |_| on_close.call(()), "Close" }
+                }
+                div {
+                    match &*problem.read() {
+                        Some(Ok(None)) if dirty => rsx! { "Well-formed, unsaved changes" },
+                        Some(Ok(None)) => rsx! { "Well-formed" },
+                        Some(Ok(Some(problem))) => rsx! { "Not well-formed: {problem}" },
+                        Some(Err(e)) => rsx! { "Checking failed: {e}" },
+                        None => rsx! { "Checking..." },
+                    }
+                }
+                if let Some(status) = status() {
+                    div { "{status}" }
+                }
+                div { style: "display:flex; gap:8px; flex:1; min-height:0;",
+                    textarea {
+                        style: "flex:1; font-family:monospace; font-size:13px; resize:none;",
+                        spellcheck: false,
+                        value: "{source}",
+                        oninput: move |e| source.set(e.value()),
+                    }
+                    // Sandboxed, so scripts in the book don't run
+                    iframe {
+                        style: "flex:1; background:white; border:none;",
+                        "sandbox": "",
+                        srcdoc: "{source}",
+                    }
+                }
+            }
+        }
+    }
+}
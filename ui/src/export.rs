@@ -0,0 +1,75 @@
+use api::export::{export_library, export_reading_history};
+use dioxus::prelude::*;
+use shared::types::{LibraryExportFormat, LibraryExportOptions, ReadingExportFormat};
+use std::path::PathBuf;
+
+/// Exports of the library as files or a static catalog, and for other applications
+#[component]
+pub fn Export() -> Element {
+    let mut dest = use_signal(String::new);
+    let mut query = use_signal(String::new);
+    let mut include_readings = use_signal(|| true);
+    let mut status = use_signal(|| None::<String>);
+
+    let export = move |format: LibraryExportFormat| async move {
+        let options = LibraryExportOptions {
+            query: query(),
+            include_readings: include_readings(),
+        };
+        match export_library(format, options, PathBuf::from(dest())).await {
+            Ok(path) => status.set(Some(format!("Exported to {}", path.display()))),
+            Err(e) => status.set(Some(format!("Export failed: {e}"))),
+        }
+    };
+    let export_readings = move |format: ReadingExportFormat| async move {
+        match export_reading_history(format, PathBuf::from(dest())).await {
+            Ok(path) => status.set(Some(format!("Exported to {}", path.display()))),
+            Err(e) => status.set(Some(format!("Export failed: {e}"))),
+        }
+    };
+
+    rsx! {
+        div { id: "export",
+            input {
+                r#type: "text",
+                placeholder: "C:/path/to/export/folder",
+                value: "{dest}",
+                oninput: move |e| dest.set(e.value()),
+            }
+            fieldset {
+                legend { "Library" }
+                input {
+                    r#type: "text",
+                    placeholder: "Only books matching, e.g., author:sanderson -is:read",
+                    value: "{query}",
+                    oninput: move |e| query.set(e.value()),
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: include_readings(),
+                        onchange: move |e| include_readings.set(e.checked()),
+                    }
+                    "Include reading history"
+                }
+                button { onclick: move |_| export(LibraryExportFormat::Csv), "Export CSV" }
+                button { onclick: move |_| export(LibraryExportFormat::Json), "Export JSON" }
+                button { onclick: move |_| export(LibraryExportFormat::Html), "Export HTML catalog" }
+            }
+            fieldset {
+                legend { "Reading history for other applications" }
+                button {
+                    onclick: move |_| export_readings(ReadingExportFormat::Goodreads),
+                    "Export for Goodreads"
+                }
+                button {
+                    onclick: move |_| export_readings(ReadingExportFormat::StoryGraph),
+                    "Export for StoryGraph"
+                }
+            }
+            if let Some(s) = status() {
+                div { "{s}" }
+            }
+        }
+    }
+}
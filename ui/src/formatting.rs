@@ -0,0 +1,129 @@
+use api::config::{read_config, write_config};
+use api::formatting::{format_books, revert_formatting};
+use dioxus::prelude::*;
+use shared::types::{FormattingConfig, FormattingReport};
+
+/// Access to one of the switches of the formatting configuration
+type Toggle = fn(&mut FormattingConfig) -> &mut bool;
+
+/// The formatting options that can be switched on and off, with their labels
+const OPTIONS: &[(&str, Toggle)] = &[
+    ("Remove embedded fonts and font sizes", |c| {
+        &mut c.strip_fonts
+    }),
+    (
+        "Remove empty paragraphs and runs of non-breaking spaces",
+        |c| &mut c.remove_empty_paragraphs,
+    ),
+    ("Use typographic quotes", |c| &mut c.smart_quotes),
+    ("Normalize margins", |c| &mut c.normalize_margins),
+    ("Remove files left by other applications", |c| {
+        &mut c.remove_vendor_files
+    }),
+    ("Regenerate the table of contents", |c| {
+        &mut c.regenerate_toc
+    }),
+    ("Format EPUB files as they are imported", |c| {
+        &mut c.on_import
+    }),
+];
+
+/// Normalizes the formatting of the EPUB files of books with the chosen steps, or puts their
+/// originals back
+#[component]
+pub fn ContentFormatting(on_change: EventHandler<()>) -> Element {
+    let mut config = use_signal(FormattingConfig::default);
+    let mut query = use_signal(String::new);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<FormattingReport, String>>);
+    let mut status = use_signal(|| None::<String>);
+
+    use_future(move || async move {
+        match read_config().await {
+            Ok(app_config) => config.set(app_config.formatting),
+            Err(e) => status.set(Some(format!("Loading settings failed: {e}"))),
+        }
+    });
+
+    let set_option = move |field: Toggle, enabled: bool| async move {
+        let mut formatting = config();
+        *field(&mut formatting) = enabled;
+        let outcome = async {
+            let mut app_config = read_config().await?;
+            app_config.formatting = formatting.clone();
+            write_config(app_config).await
+        }
+        .await;
+        match outcome {
+            Ok(()) => {
+                config.set(formatting);
+                status.set(None);
+            }
+            Err(e) => status.set(Some(format!("Saving failed: {e}"))),
+        }
+    };
+
+    let format = move |_| async move {
+        running.set(true);
+        let outcome = format_books(query()).await;
+        running.set(false);
+        result.set(Some(outcome.map_err(|e| e.to_string())));
+        on_change.call(());
+    };
+
+    let revert = move |_| async move {
+        running.set(true);
+        let outcome = revert_formatting(query()).await;
+        running.set(false);
+        result.set(Some(outcome.map_err(|e| e.to_string())));
+        on_change.call(());
+    };
+
+    rsx! {
+        div { id: "content-formatting",
+            for (label , field) in OPTIONS.iter().copied() {
+                label { key: "{label}",
+                    input {
+                        r#type: "checkbox",
+                        checked: *field(&mut config()),
+                        onchange: move |e| set_option(field, e.checked()),
+                    }
+                    "{label}"
+                }
+            }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+            input {
+                r#type: "text",
+                placeholder: "Books to format, all books if empty",
+                value: "{query}",
+                oninput: move |e| query.set(e.value()),
+            }
+            button { disabled: running(), onclick: format,
+                if running() {
+                    "Working..."
+                } else {
+                    "Format content"
+                }
+            }
+            button { disabled: running(), onclick: revert, "Revert to original" }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    div {
+                        "Changed {report.changed} books, {report.unchanged} unchanged, {report.failed.len()} failed"
+                    }
+                    ul {
+                        for failure in report.failed {
+                            li { "{failure}" }
+                        }
+                    }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Formatting failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use api::import::{
+    import_book_files, import_calibre_library, import_calibre_web, import_goodreads,
+    import_reading_log, inspect_reading_log, preview_reading_log, queue_book_import,
+    queue_calibre_import,
+};
+use dioxus::prelude::*;
+use shared::types::{
+    DuplicateAction, ImportReport, ReadingLogMapping, ReadingLogRow, ReadingLogSheet,
+};
+use std::path::PathBuf;
+
+/// Importers for data from other applications
+#[component]
+pub fn Import(on_import: EventHandler<()>) -> Element {
+    rsx! {
+        div { id: "import",
+            BookFilesImport { on_import }
+            CalibreImport { on_import }
+            CalibreWebImport { on_import }
+            ReadingLogImport { on_import }
+            GoodreadsImport { on_import }
+        }
+    }
+}
+
+/// Imports single book files or all book files in a directory, reading their metadata
+#[component]
+fn BookFilesImport(on_import: EventHandler<()>) -> Element {
+    let mut path = use_signal(String::new);
+    let mut link_files = use_signal(|| false);
+    let mut on_duplicate = use_signal(DuplicateAction::default);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<ImportReport, String>>);
+    let mut queued = use_signal(|| None::<String>);
+
+    let queue = move |_| async move {
+        let outcome = queue_book_import(PathBuf::from(path()), link_files(), on_duplicate()).await;
+        queued.set(Some(match outcome {
+            Ok(Some(_)) => "Queued the import, see Jobs for its progress and report".to_string(),
+            Ok(None) => "This import is queued already".to_string(),
+            Err(e) => format!("Queueing the import failed: {e}"),
+        }));
+    };
+
+    rsx! {
+        fieldset {
+            legend { "Book files (EPUB, PDF, MOBI, AZW3, FB2, CBZ)" }
+            input {
+                r#type: "text",
+                placeholder: "C:/path/to/book.epub or C:/path/to/books",
+                value: "{path}",
+                oninput: move |e| path.set(e.value()),
+            }
+            label {
+                input {
+                    r#type: "checkbox",
+                    checked: link_files(),
+                    onchange: move |e| link_files.set(e.checked()),
+                }
+                "Hard link files instead of copying them"
+            }
+            label {
+                "Books that are already in the library: "
+                select {
+                    onchange: move |e| {
+                        on_duplicate
+                            .set(
+                                match e.value().as_str() {
+                                    "merge" => DuplicateAction::Merge,
+                                    "import" => DuplicateAction::ImportAnyway,
+                                    _ => DuplicateAction::Skip,
+                                },
+                            )
+                    },
+                    option {
+                        value: "skip",
+                        selected: on_duplicate() == DuplicateAction::Skip,
+                        "Skip"
+                    }
+                    option {
+                        value: "merge",
+                        selected: on_duplicate() == DuplicateAction::Merge,
+                        "Add as another format"
+                    }
+                    option {
+                        value: "import",
+                        selected: on_duplicate() == DuplicateAction::ImportAnyway,
+                        "Import anyway"
+                    }
+                }
+            }
+            button {
+                disabled: running(),
+                onclick: move |_| async move {
+                    running.set(true);
+                    let report = import_book_files(PathBuf::from(path()), link_files(), on_duplicate())
+                        .await;
+                    running.set(false);
+                    if report.is_ok() {
+                        on_import.call(());
+                    }
+                    result.set(Some(report.map_err(|e| e.to_string())));
+                },
+                if running() {
+                    "Importing..."
+                } else {
+                    "Import"
+                }
+            }
+            button { disabled: running(), onclick: queue, "Import in background" }
+            if let Some(queued) = queued() {
+                div { "{queued}" }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    Report { report }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Import failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
+
+#[component]
+fn CalibreImport(on_import: EventHandler<()>) -> Element {
+    let mut path = use_signal(String::new);
+    let mut link_files = use_signal(|| false);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<ImportReport, String>>);
+    let mut queued = use_signal(|| None::<String>);
+
+    let queue = move |_| async move {
+        let outcome = queue_calibre_import(PathBuf::from(path()), link_files()).await;
+        queued.set(Some(match outcome {
+            Ok(Some(_)) => "Queued the import, see Jobs for its progress and report".to_string(),
+            Ok(None) => "This import is queued already".to_string(),
+            Err(e) => format!("Queueing the import failed: {e}"),
+        }));
+    };
+
+    rsx! {
+        fieldset {
+            legend { "Calibre library" }
+            input {
+                r#type: "text",
+                placeholder: "C:/path/to/Calibre Library",
+                value: "{path}",
+                oninput: move |e| path.set(e.value()),
+            }
+            label {
+                input {
+                    r#type: "checkbox",
+                    checked: link_files(),
+                    onchange: move |e| link_files.set(e.checked()),
+                }
+                "Hard link files instead of copying them"
+            }
+            button {
+                disabled: running(),
+                onclick: move |_| async move {
+                    running.set(true);
+                    let report = import_calibre_library(PathBuf::from(path()), link_files()).await;
+                    running.set(false);
+                    if report.is_ok() {
+                        on_import.call(());
+                    }
+                    result.set(Some(report.map_err(|e| e.to_string())));
+                },
+                if running() {
+                    "Importing..."
+                } else {
+                    "Import"
+                }
+            }
+            button { disabled: running(), onclick: queue, "Import in background" }
+            if let Some(queued) = queued() {
+                div { "{queued}" }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    Report { report }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Import failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
+
+#[component]
+fn CalibreWebImport(on_import: EventHandler<()>) -> Element {
+    let mut path = use_signal(String::new);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<ImportReport, String>>);
+
+    rsx! {
+        fieldset {
+            legend { "Calibre-Web read states and shelves" }
+            input {
+                r#type: "text",
+                placeholder: "C:/path/to/calibre-web/app.db",
+                value: "{path}",
+                oninput: move |e| path.set(e.value()),
+            }
+            button {
+                disabled: running(),
+                onclick: move |_| async move {
+                    running.set(true);
+                    let report = import_calibre_web(PathBuf::from(path())).await;
+                    running.set(false);
+                    if report.is_ok() {
+                        on_import.call(());
+                    }
+                    result.set(Some(report.map_err(|e| e.to_string())));
+                },
+                if running() {
+                    "Importing..."
+                } else {
+                    "Import"
+                }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    Report { report }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Import failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
+
+/// Imports a CSV or spreadsheet reading log in three steps: load the file, map its columns and
+/// check the preview, then write the readings
+#[component]
+fn ReadingLogImport(on_import: EventHandler<()>) -> Element {
+    let mut path = use_signal(String::new);
+    let mut sheet = use_signal(|| None::<ReadingLogSheet>);
+    let mut mapping = use_signal(ReadingLogMapping::default);
+    let mut preview = use_signal(|| None::<Vec<ReadingLogRow>>);
+    let mut result = use_signal(|| None::<Result<ImportReport, String>>);
+    let mut error = use_signal(|| None::<String>);
+
+    rsx! {
+        fieldset {
+            legend { "Reading log (CSV or spreadsheet)" }
+            input {
+                r#type: "text",
+                placeholder: "C:/path/to/reading-log.xlsx",
+                value: "{path}",
+                oninput: move |e| path.set(e.value()),
+            }
+            button {
+                onclick: move |_| async move {
+                    preview.set(None);
+                    result.set(None);
+                    match inspect_reading_log(PathBuf::from(path())).await {
+                        Ok(s) => {
+                            mapping.set(s.mapping.clone());
+                            sheet.set(Some(s));
+                            error.set(None);
+                        }
+                        Err(e) => error.set(Some(e.to_string())),
+                    }
+                },
+                "Load"
+            }
+            if let Some(sheet) = sheet() {
+                ColumnMapping { sheet, mapping }
+                button {
+                    onclick: move |_| async move {
+                        match preview_reading_log(PathBuf::from(path()), mapping()).await {
+                            Ok(rows) => {
+                                preview.set(Some(rows));
+                                error.set(None);
+                            }
+                            Err(e) => error.set(Some(e.to_string())),
+                        }
+                    },
+                    "Preview"
+                }
+            }
+            if let Some(rows) = preview() {
+                ReadingLogPreview { rows: rows.clone() }
+                button {
+                    disabled: !rows.iter().any(|r| r.book.is_some() && r.conflict.is_none()),
+                    onclick: move |_| async move {
+                        let report = import_reading_log(PathBuf::from(path()), mapping()).await;
+                        if report.is_ok() {
+                            preview.set(None);
+                            on_import.call(());
+                        }
+                        result.set(Some(report.map_err(|e| e.to_string())));
+                    },
+                    "Import readings"
+                }
+            }
+            if let Some(e) = error() {
+                div { "Error: {e}" }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    Report { report }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Import failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
+
+/// One select per reading log field to choose the column it is read from
+#[component]
+fn ColumnMapping(sheet: ReadingLogSheet, mapping: Signal<ReadingLogMapping>) -> Element {
+    type Field = fn(&mut ReadingLogMapping) -> &mut Option<usize>;
+    let fields: [(&str, Field); 6] = [
+        ("Title", |m| &mut m.title),
+        ("Author", |m| &mut m.author),
+        ("Start date", |m| &mut m.start_date),
+        ("End date", |m| &mut m.end_date),
+        ("Status", |m| &mut m.status),
+        ("Rating", |m| &mut m.rating),
+    ];
+
+    rsx! {
+        table { class: "column-mapping",
+            thead {
+                tr {
+                    for header in sheet.headers.iter() {
+                        th { "{header}" }
+                    }
+                }
+            }
+            tbody {
+                for row in sheet.sample.iter() {
+                    tr {
+                        for cell in row.iter() {
+                            td { "{cell}" }
+                        }
+                    }
+                }
+            }
+        }
+        for (label , field) in fields {
+            label {
+                "{label}: "
+                select {
+                    onchange: move |e| {
+                        *field(&mut mapping.write()) = e.value().parse().ok();
+                    },
+                    option {
+                        value: "",
+                        selected: field(&mut mapping()).is_none(),
+                        "(not in the file)"
+                    }
+                    for (i , header) in sheet.headers.iter().enumerate() {
+                        option {
+                            value: "{i}",
+                            selected: *field(&mut mapping()) == Some(i),
+                            "{header}"
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rows of a reading log with the book each was matched to and what keeps it from being
+/// imported
+#[component]
+fn ReadingLogPreview(rows: Vec<ReadingLogRow>) -> Element {
+    let importable = rows
+        .iter()
+        .filter(|r| r.book.is_some() && r.conflict.is_none())
+        .count();
+    let conflicts = rows.len() - importable;
+
+    rsx! {
+        div { "{importable} readings will be imported, {conflicts} rows have conflicts" }
+        table { class: "reading-log-preview",
+            thead {
+                tr {
+                    th { "Row" }
+                    th { "Title" }
+                    th { "Author" }
+                    th { "Started" }
+                    th { "Finished" }
+                    th { "Status" }
+                    th { "Rating" }
+                    th { "Matched book" }
+                    th { "Conflict" }
+                }
+            }
+            tbody {
+                for row in rows {
+                    tr { key: "{row.row}", class: if row.conflict.is_some() { "conflict" },
+                        td { "{row.row}" }
+                        td { "{row.title}" }
+                        td { {row.author.clone().unwrap_or_default()} }
+                        td { {row.start_date.map(|d| d.date_naive().to_string()).unwrap_or_default()} }
+                        td { {row.end_date.map(|d| d.date_naive().to_string()).unwrap_or_default()} }
+                        td { "{row.status}" }
+                        td { {row.rating.map(|r| "★".repeat(r as usize)).unwrap_or_default()} }
+                        td {
+                            if let Some(book) = row.book.as_ref() {
+                                {format!("{} by {} ({}%)", book.title, book.authors.join(", "), (book.score * 100.0).round())}
+                            }
+                        }
+                        td { {row.conflict.clone().unwrap_or_default()} }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[component]
+fn GoodreadsImport(on_import: EventHandler<()>) -> Element {
+    let mut path = use_signal(String::new);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<ImportReport, String>>);
+
+    rsx! {
+        fieldset {
+            legend { "Goodreads library export" }
+            input {
+                r#type: "text",
+                placeholder: "C:/path/to/goodreads_library_export.csv",
+                value: "{path}",
+                oninput: move |e| path.set(e.value()),
+            }
+            button {
+                disabled: running(),
+                onclick: move |_| async move {
+                    running.set(true);
+                    let report = import_goodreads(PathBuf::from(path())).await;
+                    running.set(false);
+                    if report.is_ok() {
+                        on_import.call(());
+                    }
+                    result.set(Some(report.map_err(|e| e.to_string())));
+                },
+                if running() {
+                    "Importing..."
+                } else {
+                    "Import"
+                }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    Report { report }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Import failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
+
+/// Summary of an import: what was imported and what could not be mapped
+#[component]
+pub fn Report(report: ImportReport) -> Element {
+    rsx! {
+        div { class: "import-report",
+            ul {
+                for (kind, count) in report.imported.iter() {
+                    li { "{count} {kind}" }
+                }
+            }
+            if !report.warnings.is_empty() {
+                details {
+                    summary { "{report.warnings.len()} warnings" }
+                    ul {
+                        for warning in report.warnings.iter() {
+                            li { "{warning}" }
+                        }
+                    }
+                }
+            }
+            if let Some(path) = report.report_path.as_ref() {
+                div { "Report saved to {path.display()}" }
+            }
+        }
+    }
+}
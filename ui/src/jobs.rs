@@ -0,0 +1,171 @@
+use api::jobs::{cancel_job, job_events, job_log, list_jobs, retry_job};
+use dioxus::prelude::*;
+use futures::StreamExt;
+use shared::types::{Job, JobLogEntry, JobState};
+
+/// The background jobs of the library with their live progress, reported by the server as it
+/// happens. Queued and running jobs can be cancelled, failed and cancelled ones retried, and the
+/// log of every job is kept for inspection.
+#[component]
+pub fn Jobs(reload_key: ReadOnlySignal<u64>) -> Element {
+    let mut jobs = use_signal(Vec::<Job>::new);
+    let mut status = use_signal(|| None::<String>);
+
+    // Reload the list whenever the library changes, e.g., when it was switched
+    use_effect(move || {
+        reload_key();
+        spawn(async move {
+            match list_jobs().await {
+                Ok(list) => jobs.set(list),
+                Err(e) => status.set(Some(format!("Loading jobs failed: {e}"))),
+            }
+        });
+    });
+
+    // Like the library changes in the app, job updates are only received on the client
+    use_effect(move || {
+        spawn(async move {
+            let events = match job_events().await {
+                Ok(events) => events,
+                Err(e) => {
+                    tracing::warn!("Not receiving job updates: {}", e);
+                    return;
+                }
+            };
+            let mut events = events.into_inner();
+            let mut buffer = String::new();
+            while let Some(chunk) = events.next().await {
+                match chunk {
+                    Ok(text) => buffer.push_str(&text),
+                    Err(e) => {
+                        tracing::warn!("Job update stream failed: {}", e);
+                        break;
+                    }
+                }
+                while let Some(end) = buffer.find('\n') {
+                    let line: String = buffer.drain(..=end).collect();
+                    match serde_json::from_str::<Job>(line.trim()) {
+                        Ok(job) => {
+                            let mut jobs = jobs.write();
+                            match jobs.iter_mut().find(|known| known.id == job.id) {
+                                Some(known) => *known = job,
+                                None => jobs.insert(0, job),
+                            }
+                        }
+                        Err(e) => tracing::warn!("Malformed job update {:?}: {}", line, e),
+                    }
+                }
+            }
+        });
+    });
+
+    let mut replace = move |outcome: Result<Job, ServerFnError>| match outcome {
+        Ok(job) => {
+            if let Some(known) = jobs.write().iter_mut().find(|known| known.id == job.id) {
+                *known = job;
+            }
+        }
+        Err(e) => status.set(Some(e.to_string())),
+    };
+
+    rsx! {
+        div { id: "jobs",
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+            if jobs.read().is_empty() {
+                div { "No jobs yet" }
+            }
+            table {
+                tbody {
+                    for job in jobs() {
+                        JobRow {
+                            key: "{job.id}",
+                            job,
+                            on_cancel: move |id| async move { replace(cancel_job(id).await) },
+                            on_retry: move |id| async move { replace(retry_job(id).await) },
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// One job with its progress and, on demand, its log
+#[component]
+fn JobRow(job: Job, on_cancel: EventHandler<i64>, on_retry: EventHandler<i64>) -> Element {
+    let mut log = use_signal(|| None::<Result<Vec<JobLogEntry>, String>>);
+    let id = job.id;
+    let state = match (job.state, &job.retry_at) {
+        (JobState::Queued, Some(at)) => format!(
+            "retrying at {}, attempt {} failed",
+            at.format("%H:%M:%S"),
+            job.attempts
+        ),
+        (JobState::Running, _) if job.attempts > 1 => {
+            format!("running, attempt {} of {}", job.attempts, job.max_attempts)
+        }
+        (state, _) => state.to_string(),
+    };
+
+    let toggle_log = move |_| async move {
+        if log.read().is_some() {
+            log.set(None);
+        } else {
+            log.set(Some(job_log(id).await.map_err(|e| e.to_string())));
+        }
+    };
+
+    rsx! {
+        tr {
+            td { "{job.title}" }
+            td {
+                progress { max: 100, value: "{job.progress}" }
+            }
+            td { "{state}" }
+            td {
+                if let Some(error) = &job.error {
+                    "{error}"
+                }
+            }
+            td {
+                if matches!(job.state, JobState::Queued | JobState::Running) {
+                    button { onclick: move |_| on_cancel.call(id), "Cancel" }
+                }
+                if matches!(job.state, JobState::Failed | JobState::Cancelled) {
+                    button { onclick: move |_| on_retry.call(id), "Retry" }
+                }
+                button { onclick: toggle_log,
+                    if log.read().is_some() {
+                        "Hide log"
+                    } else {
+                        "Log"
+                    }
+                }
+            }
+        }
+        match log() {
+            Some(Ok(entries)) => rsx! {
+                tr {
+                    td { colspan: 5,
+                        ul { class: "job-log",
+                            for (index , entry) in entries.into_iter().enumerate() {
+                                li { key: "{index}",
+                                    {entry.at.format("%Y-%m-%d %H:%M:%S").to_string()}
+                                    " {entry.message}"
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            Some(Err(e)) => rsx! {
+                tr {
+                    td { colspan: 5, "Loading the log failed: {e}" }
+                }
+            },
+            None => rsx! {},
+        }
+    }
+}
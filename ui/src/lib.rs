@@ -1,3 +1,19 @@
 pub mod app;
+pub mod backups;
 pub mod books;
+pub mod checker;
+pub mod convert;
+pub mod covers;
+pub mod duplicates;
+pub mod editor;
+pub mod export;
+pub mod formatting;
+pub mod import;
+pub mod jobs;
+pub mod libraries;
+pub mod metadata;
 pub mod path_picker;
+pub mod replace;
+pub mod rules;
+pub mod settings;
+pub mod statistics;
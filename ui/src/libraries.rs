@@ -0,0 +1,98 @@
+use api::config::{add_library, list_libraries, switch_library};
+use dioxus::prelude::*;
+use std::path::PathBuf;
+
+/// Dropdown to switch between the registered libraries, plus a form to register new ones
+#[component]
+pub fn LibrarySwitcher(active_dir: Option<PathBuf>, on_switch: EventHandler<()>) -> Element {
+    let mut reload_key = use_signal(|| 0u64);
+    let libraries = use_server_future(move || {
+        let _k = reload_key();
+        list_libraries()
+    })?;
+
+    let mut new_name = use_signal(String::new);
+    let mut new_path = use_signal(String::new);
+    let mut error = use_signal(|| None::<String>);
+
+    let active_name = match libraries() {
+        Some(Ok(libs)) => libs
+            .iter()
+            .find(|l| Some(&l.data_dir) == active_dir.as_ref())
+            .map(|l| l.name.clone())
+            .unwrap_or_default(),
+        _ => String::new(),
+    };
+
+    rsx! {
+        div { id: "library-switcher",
+            match libraries() {
+                None => rsx! {
+                    span { "Loading libraries..." }
+                },
+                Some(Err(e)) => rsx! {
+                    span { "Error: {e}" }
+                },
+                Some(Ok(libs)) => rsx! {
+                    label { r#for: "library-select", "Library: " }
+                    select {
+                        id: "library-select",
+                        value: "{active_name}",
+                        onchange: move |e| {
+                            let name = e.value();
+                            spawn(async move {
+                                match switch_library(name).await {
+                                    Ok(_) => {
+                                        error.set(None);
+                                        on_switch.call(());
+                                    }
+                                    Err(e) => error.set(Some(e.to_string())),
+                                }
+                            });
+                        },
+                        for lib in libs {
+                            option {
+                                value: "{lib.name}",
+                                selected: lib.name == active_name,
+                                "{lib.name}"
+                            }
+                        }
+                    }
+                },
+            }
+            input {
+                r#type: "text",
+                placeholder: "Name",
+                value: "{new_name}",
+                oninput: move |e| new_name.set(e.value()),
+            }
+            input {
+                r#type: "text",
+                placeholder: "C:/path/to/library",
+                value: "{new_path}",
+                oninput: move |e| new_path.set(e.value()),
+            }
+            button {
+                onclick: move |_| {
+                    let name = new_name();
+                    let path = PathBuf::from(new_path());
+                    spawn(async move {
+                        match add_library(name, path).await {
+                            Ok(_) => {
+                                error.set(None);
+                                new_name.set(String::new());
+                                new_path.set(String::new());
+                                reload_key.set(reload_key() + 1);
+                            }
+                            Err(e) => error.set(Some(e.to_string())),
+                        }
+                    });
+                },
+                "Add library"
+            }
+            if let Some(e) = error() {
+                span { class: "error", "{e}" }
+            }
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use api::config::{read_config, write_config};
+use api::metadata::{apply_metadata, embed_metadata, lock_metadata_field, propose_metadata};
+use dioxus::prelude::*;
+use itertools::Itertools;
+use shared::types::{BookMetadata, EmbedReport, MetadataChange, MetadataField, MetadataProposal};
+use std::collections::{BTreeSet, HashMap};
+
+/// A field's value for display
+fn field_text(metadata: &BookMetadata, field: MetadataField) -> String {
+    match field {
+        MetadataField::Title => metadata.title.clone().unwrap_or_default(),
+        MetadataField::Authors => metadata.authors.join(", "),
+        MetadataField::Series => match (&metadata.series, metadata.series_index) {
+            (Some(series), Some(index)) => format!("{series} #{index}"),
+            (Some(series), None) => series.clone(),
+            _ => String::new(),
+        },
+        MetadataField::Description => metadata.description.clone().unwrap_or_default(),
+        MetadataField::DatePublished => metadata
+            .date_published
+            .map(|d| d.date_naive().to_string())
+            .unwrap_or_default(),
+        MetadataField::NumberOfPages => metadata
+            .number_of_pages
+            .map(|n| n.to_string())
+            .unwrap_or_default(),
+        MetadataField::Isbn => metadata.isbn.clone().unwrap_or_default(),
+        MetadataField::GoodreadsId => metadata
+            .goodreads_id
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+        MetadataField::Tags => metadata.tags.iter().join(", "),
+    }
+}
+
+/// Fetches metadata for the books matching a search query and shows the current and proposed
+/// values side by side, so changes can be accepted field by field and applied in bulk
+#[component]
+pub fn MetadataReview(on_apply: EventHandler<()>) -> Element {
+    let mut query = use_signal(String::new);
+    let mut running = use_signal(|| false);
+    let mut proposals = use_signal(Vec::<MetadataProposal>::new);
+    // Accepted fields and cover per book; all proposed changes are accepted initially
+    let mut accepted = use_signal(HashMap::<i64, BTreeSet<MetadataField>>::new);
+    let mut covers = use_signal(HashMap::<i64, String>::new);
+    let mut status = use_signal(|| None::<String>);
+
+    let fetch = move |_| async move {
+        running.set(true);
+        status.set(None);
+        match propose_metadata(query()).await {
+            Ok(found) => {
+                accepted.set(
+                    found
+                        .iter()
+                        .map(|p| (p.book, p.changes.keys().copied().collect()))
+                        .collect(),
+                );
+                covers.set(HashMap::new());
+                let changed = found
+                    .iter()
+                    .filter(|p| !p.changes.is_empty() || !p.covers.is_empty())
+                    .count();
+                status.set(Some(format!(
+                    "{changed} of {} books have proposed changes",
+                    found.len()
+                )));
+                proposals.set(found);
+            }
+            Err(e) => status.set(Some(format!("Fetching metadata failed: {e}"))),
+        }
+        running.set(false);
+    };
+
+    let apply = move |_| async move {
+        let changes: Vec<MetadataChange> = proposals
+            .read()
+            .iter()
+            .filter_map(|p| {
+                let fields = accepted.read().get(&p.book).cloned().unwrap_or_default();
+                let cover = covers.read().get(&p.book).cloned();
+                (!fields.is_empty() || cover.is_some()).then(|| MetadataChange {
+                    book: p.book,
+                    metadata: p.proposed.clone(),
+                    accepted: fields,
+                    cover,
+                })
+            })
+            .collect();
+        running.set(true);
+        match apply_metadata(changes).await {
+            Ok(count) => {
+                status.set(Some(format!("Updated {count} books")));
+                proposals.set(Vec::new());
+                on_apply.call(());
+            }
+            Err(e) => {
+                status.set(Some(format!("Applying metadata failed: {e}")));
+                on_apply.call(());
+            }
+        }
+        running.set(false);
+    };
+
+    let mut set_all = move |accept: bool| {
+        accepted.set(
+            proposals
+                .read()
+                .iter()
+                .map(|p| {
+                    let fields = if accept {
+                        p.changes.keys().copied().collect()
+                    } else {
+                        BTreeSet::new()
+                    };
+                    (p.book, fields)
+                })
+                .collect(),
+        );
+        if !accept {
+            covers.set(HashMap::new());
+        }
+    };
+
+    rsx! {
+        div { id: "metadata-review",
+            input {
+                r#type: "text",
+                placeholder: "Books to fetch metadata for, e.g., author:sanderson -has:isbn",
+                value: "{query}",
+                oninput: move |e| query.set(e.value()),
+            }
+            button { disabled: running(), onclick: fetch,
+                if running() {
+                    "Working..."
+                } else {
+                    "Fetch metadata"
+                }
+            }
+            if !proposals.read().is_empty() {
+                button { onclick: move |_| set_all(true), "Accept all" }
+                button { onclick: move |_| set_all(false), "Reject all" }
+                button { disabled: running(), onclick: apply, "Apply accepted changes" }
+            }
+            if let Some(s) = status() {
+                div { "{s}" }
+            }
+            for (index, proposal) in proposals().into_iter().enumerate() {
+                if !proposal.changes.is_empty() || !proposal.covers.is_empty() {
+                    ProposalReview {
+                        key: "{proposal.book}",
+                        proposal: proposal.clone(),
+                        accepted: accepted.read().get(&proposal.book).cloned().unwrap_or_default(),
+                        cover: covers.read().get(&proposal.book).cloned(),
+                        on_toggle: move |(field, accept): (MetadataField, bool)| {
+                            let mut accepted = accepted.write();
+                            let fields = accepted.entry(proposal.book).or_default();
+                            if accept {
+                                fields.insert(field);
+                            } else {
+                                fields.remove(&field);
+                            }
+                        },
+                        on_cover: move |url: Option<String>| {
+                            let mut covers = covers.write();
+                            match url {
+                                Some(url) => covers.insert(proposal.book, url),
+                                None => covers.remove(&proposal.book),
+                            };
+                        },
+                        on_lock: move |(field, locked): (MetadataField, bool)| async move {
+                            match lock_metadata_field(proposal.book, field, locked).await {
+                                Ok(()) => {
+                                    let mut proposals = proposals.write();
+                                    let fields = &mut proposals[index].locked;
+                                    if locked {
+                                        fields.insert(field);
+                                        accepted.write().entry(proposal.book).or_default().remove(&field);
+                                    } else {
+                                        fields.remove(&field);
+                                    }
+                                }
+                                Err(e) => status.set(Some(format!("Locking {field} failed: {e}"))),
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Current and proposed metadata of a single book
+#[component]
+fn ProposalReview(
+    proposal: MetadataProposal,
+    accepted: BTreeSet<MetadataField>,
+    cover: Option<String>,
+    on_toggle: EventHandler<(MetadataField, bool)>,
+    on_cover: EventHandler<Option<String>>,
+    on_lock: EventHandler<(MetadataField, bool)>,
+) -> Element {
+    let title = proposal.current.title.clone().unwrap_or_default();
+    let cover_name = format!("cover-{}", proposal.book);
+
+    rsx! {
+        fieldset { class: "metadata-proposal",
+            legend { "{title}" }
+            table {
+                thead {
+                    tr {
+                        th { "Field" }
+                        th { "Current" }
+                        th { "Proposed" }
+                        th { "Accept" }
+                        th { "Locked" }
+                    }
+                }
+                tbody {
+                    for field in MetadataField::ALL {
+                        tr { key: "{field:?}",
+                            class: if proposal.changes.contains_key(&field) { "changed" },
+                            td { "{field}" }
+                            td { {field_text(&proposal.current, field)} }
+                            td {
+                                if let Some(provider) = proposal.changes.get(&field) {
+                                    {field_text(&proposal.proposed, field)}
+                                    " "
+                                    small { "({provider})" }
+                                }
+                            }
+                            td {
+                                if proposal.changes.contains_key(&field) {
+                                    input {
+                                        r#type: "checkbox",
+                                        checked: accepted.contains(&field),
+                                        onchange: move |e| on_toggle.call((field, e.checked())),
+                                    }
+                                }
+                            }
+                            td {
+                                input {
+                                    r#type: "checkbox",
+                                    checked: proposal.locked.contains(&field),
+                                    onchange: move |e| on_lock.call((field, e.checked())),
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            if !proposal.covers.is_empty() {
+                div { class: "cover-candidates",
+                    label {
+                        input {
+                            r#type: "radio",
+                            name: "{cover_name}",
+                            checked: cover.is_none(),
+                            onchange: move |_| on_cover.call(None),
+                        }
+                        if proposal.has_cover {
+                            "Keep current cover"
+                        } else {
+                            "No cover"
+                        }
+                    }
+                    for candidate in proposal.covers.clone() {
+                        label { key: "{candidate.url}",
+                            input {
+                                r#type: "radio",
+                                name: "{cover_name}",
+                                checked: cover.as_deref() == Some(candidate.url.as_str()),
+                                onchange: {
+                                    let url = candidate.url.clone();
+                                    move |_| on_cover.call(Some(url.clone()))
+                                },
+                            }
+                            img { src: "{candidate.url}", height: "160" }
+                            small {
+                                "{candidate.provider}"
+                                if let (Some(width), Some(height)) = (candidate.width, candidate.height) {
+                                    " {width}×{height}"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Writes the metadata and covers of books into their EPUB files, on demand or automatically
+/// whenever they are edited
+#[component]
+pub fn EmbedMetadata() -> Element {
+    let mut query = use_signal(String::new);
+    let mut on_edit = use_signal(|| false);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<EmbedReport, String>>);
+
+    use_future(move || async move {
+        if let Ok(config) = read_config().await {
+            on_edit.set(config.metadata.embed_on_edit);
+        }
+    });
+
+    let set_on_edit = move |enabled: bool| async move {
+        let outcome = async {
+            let mut config = read_config().await?;
+            config.metadata.embed_on_edit = enabled;
+            write_config(config).await
+        }
+        .await;
+        match outcome {
+            Ok(()) => on_edit.set(enabled),
+            Err(e) => result.set(Some(Err(e.to_string()))),
+        }
+    };
+
+    let embed = move |_| async move {
+        running.set(true);
+        let outcome = embed_metadata(query()).await;
+        running.set(false);
+        result.set(Some(outcome.map_err(|e| e.to_string())));
+    };
+
+    rsx! {
+        div { id: "embed-metadata",
+            label {
+                input {
+                    r#type: "checkbox",
+                    checked: on_edit(),
+                    onchange: move |e| set_on_edit(e.checked()),
+                }
+                "Embed metadata into EPUB files whenever it is edited"
+            }
+            input {
+                r#type: "text",
+                placeholder: "Books to embed metadata into, all books if empty",
+                value: "{query}",
+                oninput: move |e| query.set(e.value()),
+            }
+            button { disabled: running(), onclick: embed,
+                if running() {
+                    "Embedding..."
+                } else {
+                    "Embed metadata"
+                }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    div { "Wrote {report.files} files, {report.failed.len()} books failed" }
+                    ul {
+                        for failure in report.failed {
+                            li { "{failure}" }
+                        }
+                    }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Embedding failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
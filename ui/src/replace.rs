@@ -0,0 +1,236 @@
+use api::config::{read_config, write_config};
+use api::replace::{apply_replacement, preview_replacement};
+use dioxus::prelude::*;
+use shared::types::{BookMatches, ReplacementReport, TextReplacement};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Find and replace in the text of the EPUB files of one book, a selection or a saved search.
+/// Every match is previewed with its context first, and only the books left checked are changed.
+#[component]
+pub fn FindReplace(on_change: EventHandler<()>) -> Element {
+    let mut query = use_signal(String::new);
+    let mut saved_searches = use_signal(BTreeMap::<String, String>::new);
+    let mut search_name = use_signal(String::new);
+    let mut replacement = use_signal(TextReplacement::default);
+    // The replacement the preview was made for, which is what gets applied
+    let mut previewed = use_signal(|| None::<TextReplacement>);
+    let mut preview = use_signal(|| None::<Result<Vec<BookMatches>, String>>);
+    let mut excluded = use_signal(BTreeSet::<i64>::new);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<ReplacementReport, String>>);
+    let mut status = use_signal(|| None::<String>);
+
+    use_future(move || async move {
+        match read_config().await {
+            Ok(config) => saved_searches.set(config.saved_searches),
+            Err(e) => status.set(Some(format!("Loading saved searches failed: {e}"))),
+        }
+    });
+
+    let save_search = move |_| async move {
+        let name = search_name().trim().to_string();
+        if name.is_empty() {
+            return;
+        }
+        let outcome = async {
+            let mut config = read_config().await?;
+            config.saved_searches.insert(name.clone(), query());
+            write_config(config.clone()).await?;
+            Ok::<_, ServerFnError>(config.saved_searches)
+        }
+        .await;
+        match outcome {
+            Ok(searches) => {
+                saved_searches.set(searches);
+                status.set(Some(format!("Saved search {name:?}")));
+            }
+            Err(e) => status.set(Some(format!("Saving the search failed: {e}"))),
+        }
+    };
+
+    let find = move |_| async move {
+        running.set(true);
+        result.set(None);
+        let used = replacement();
+        let outcome = preview_replacement(query(), used.clone()).await;
+        running.set(false);
+        excluded.set(BTreeSet::new());
+        previewed.set(outcome.is_ok().then_some(used));
+        preview.set(Some(outcome.map_err(|e| e.to_string())));
+    };
+
+    let replace = move |_| async move {
+        let (Some(Ok(found)), Some(previewed)) = (preview(), previewed()) else {
+            return;
+        };
+        let books: Vec<i64> = found
+            .iter()
+            .map(|matches| matches.book)
+            .filter(|book| !excluded.read().contains(book))
+            .collect();
+        running.set(true);
+        let outcome = apply_replacement(books, previewed).await;
+        running.set(false);
+        preview.set(None);
+        result.set(Some(outcome.map_err(|e| e.to_string())));
+        on_change.call(());
+    };
+
+    let chosen = preview().and_then(Result::ok).map_or(0, |found| {
+        found
+            .iter()
+            .filter(|matches| !excluded.read().contains(&matches.book))
+            .count()
+    });
+
+    rsx! {
+        div { id: "find-replace",
+            div {
+                input {
+                    r#type: "text",
+                    size: 50,
+                    placeholder: "Books to search, e.g., series:discworld or id:12,15, all books if empty",
+                    value: "{query}",
+                    oninput: move |e| query.set(e.value()),
+                }
+                select {
+                    onchange: move |e| {
+                        if let Some(saved) = saved_searches.read().get(&e.value()) {
+                            query.set(saved.clone());
+                            search_name.set(e.value());
+                        }
+                    },
+                    option { value: "", "Saved searches" }
+                    for (name , _) in saved_searches() {
+                        option { key: "{name}", value: "{name}", "{name}" }
+                    }
+                }
+                input {
+                    r#type: "text",
+                    placeholder: "Name",
+                    value: "{search_name}",
+                    oninput: move |e| search_name.set(e.value()),
+                }
+                button { onclick: save_search, "Save search" }
+            }
+            div {
+                input {
+                    r#type: "text",
+                    placeholder: "Find",
+                    value: "{replacement.read().find}",
+                    oninput: move |e| replacement.write().find = e.value(),
+                }
+                input {
+                    r#type: "text",
+                    placeholder: "Replace with",
+                    value: "{replacement.read().replace}",
+                    oninput: move |e| replacement.write().replace = e.value(),
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: replacement.read().regex,
+                        onchange: move |e| replacement.write().regex = e.checked(),
+                    }
+                    "Regular expression"
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: replacement.read().case_sensitive,
+                        onchange: move |e| replacement.write().case_sensitive = e.checked(),
+                    }
+                    "Match case"
+                }
+                button {
+                    disabled: running() || replacement.read().find.is_empty(),
+                    onclick: find,
+                    if running() {
+                        "Working..."
+                    } else {
+                        "Preview matches"
+                    }
+                }
+            }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+            match preview() {
+                Some(Ok(found)) if found.is_empty() => rsx! {
+                    div { "No matches" }
+                },
+                Some(Ok(found)) => rsx! {
+                    for matches in found {
+                        fieldset { key: "{matches.book}",
+                            legend {
+                                label {
+                                    input {
+                                        r#type: "checkbox",
+                                        checked: !excluded.read().contains(&matches.book),
+                                        onchange: move |e| {
+                                            if e.checked() {
+                                                excluded.write().remove(&matches.book);
+                                            } else {
+                                                excluded.write().insert(matches.book);
+                                            }
+                                        },
+                                    }
+                                    "{matches.title}: {matches.total} matches"
+                                }
+                            }
+                            table {
+                                tbody {
+                                    for (index , found) in matches.matches.iter().enumerate() {
+                                        tr { key: "{index}",
+                                            td { "{found.document}" }
+                                            td {
+                                                "…{found.before}"
+                                                del { "{found.found}" }
+                                                ins { "{found.replacement}" }
+                                                "{found.after}…"
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            if matches.total > matches.matches.len() {
+                                div { "and {matches.total - matches.matches.len()} more" }
+                            }
+                            if !matches.skipped.is_empty() {
+                                div {
+                                    "Left out, not UTF-8: "
+                                    {matches.skipped.join(", ")}
+                                }
+                            }
+                        }
+                    }
+                    button {
+                        disabled: running() || chosen == 0,
+                        onclick: replace,
+                        "Replace in {chosen} books"
+                    }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Searching failed: {e}" }
+                },
+                None => rsx! {},
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    div {
+                        "Made {report.replacements} replacements in {report.books} books, {report.failed.len()} books failed"
+                    }
+                    ul {
+                        for failure in report.failed {
+                            li { "{failure}" }
+                        }
+                    }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Replacing failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}
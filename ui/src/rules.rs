@@ -0,0 +1,290 @@
+use api::rules::{delete_rule, list_rules, rule_runs, save_rule};
+use dioxus::prelude::*;
+use shared::types::{Rule, RuleAction, RuleRun, RuleTrigger};
+use std::path::PathBuf;
+
+/// Kinds of actions a rule can run, with what their parameter is, if they take one
+const ACTIONS: &[(&str, Option<&str>)] = &[
+    ("Add tag", Some("Tag")),
+    ("Move to shelf", Some("Shelf")),
+    ("Convert to KEPUB", None),
+    ("Rename files", None),
+    ("Send to device", Some("Directory the device is mounted at")),
+    ("Run webhook", Some("URL to POST the book to")),
+];
+
+/// Index of the action's kind in [`ACTIONS`] and its parameter
+fn action_parts(action: &RuleAction) -> (usize, String) {
+    match action {
+        RuleAction::AddTag { tag } => (0, tag.clone()),
+        RuleAction::MoveToShelf { shelf } => (1, shelf.clone()),
+        RuleAction::ConvertToKepub => (2, String::new()),
+        RuleAction::RenameFiles => (3, String::new()),
+        RuleAction::SendToDevice { path } => (4, path.display().to_string()),
+        RuleAction::RunWebhook { url } => (5, url.clone()),
+    }
+}
+
+fn action_of(kind: usize, parameter: String) -> RuleAction {
+    match kind {
+        0 => RuleAction::AddTag { tag: parameter },
+        1 => RuleAction::MoveToShelf { shelf: parameter },
+        2 => RuleAction::ConvertToKepub,
+        3 => RuleAction::RenameFiles,
+        4 => RuleAction::SendToDevice {
+            path: PathBuf::from(parameter),
+        },
+        _ => RuleAction::RunWebhook { url: parameter },
+    }
+}
+
+fn new_rule() -> Rule {
+    Rule {
+        id: 0,
+        name: String::new(),
+        enabled: true,
+        trigger: RuleTrigger::BookImported,
+        tag: None,
+        condition: String::new(),
+        action: RuleAction::AddTag { tag: String::new() },
+    }
+}
+
+/// The automation rules of the library: when a trigger occurs for a book that matches a rule's
+/// search query, the rule's action runs for it. Below the rules, the log of their runs.
+#[component]
+pub fn Rules(reload_key: ReadOnlySignal<u64>) -> Element {
+    let mut rules = use_signal(Vec::<Rule>::new);
+    let mut runs = use_signal(Vec::<RuleRun>::new);
+    let mut editing = use_signal(|| None::<Rule>);
+    let mut status = use_signal(|| None::<String>);
+
+    // Runs are logged in the database, so they are reloaded with every change of the library
+    use_effect(move || {
+        reload_key();
+        spawn(async move {
+            match list_rules().await {
+                Ok(list) => rules.set(list),
+                Err(e) => status.set(Some(format!("Loading rules failed: {e}"))),
+            }
+            match rule_runs().await {
+                Ok(list) => runs.set(list),
+                Err(e) => status.set(Some(format!("Loading rule runs failed: {e}"))),
+            }
+        });
+    });
+
+    let on_saved = move |rule: Rule| {
+        let mut rules = rules.write();
+        match rules.iter_mut().find(|known| known.id == rule.id) {
+            Some(known) => *known = rule,
+            None => rules.push(rule),
+        }
+        editing.set(None);
+    };
+
+    rsx! {
+        div { id: "rules",
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+            if rules.read().is_empty() {
+                div { "No rules yet" }
+            }
+            table {
+                tbody {
+                    for rule in rules() {
+                        tr { key: "{rule.id}",
+                            td { "{rule.name}" }
+                            td {
+                                "{rule.trigger}"
+                                if let Some(tag) = &rule.tag {
+                                    " {tag:?}"
+                                }
+                            }
+                            td { "{rule.condition}" }
+                            td { "{rule.action}" }
+                            td {
+                                if !rule.enabled {
+                                    "disabled"
+                                }
+                            }
+                            td {
+                                button {
+                                    onclick: {
+                                        let rule = rule.clone();
+                                        move |_| editing.set(Some(rule.clone()))
+                                    },
+                                    "Edit"
+                                }
+                                button {
+                                    onclick: move |_| async move {
+                                        match delete_rule(rule.id).await {
+                                            Ok(()) => rules.write().retain(|known| known.id != rule.id),
+                                            Err(e) => status.set(Some(format!("Deleting failed: {e}"))),
+                                        }
+                                    },
+                                    "Delete"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            match editing() {
+                Some(rule) => rsx! {
+                    RuleEditor {
+                        key: "{rule.id}",
+                        rule,
+                        on_saved,
+                        on_cancel: move |_| editing.set(None),
+                    }
+                },
+                None => rsx! {
+                    button { onclick: move |_| editing.set(Some(new_rule())), "New rule" }
+                },
+            }
+            h4 { "Runs" }
+            if runs.read().is_empty() {
+                div { "No rule ran yet" }
+            }
+            table {
+                tbody {
+                    for (index , run) in runs().into_iter().enumerate() {
+                        tr { key: "{index}",
+                            td { {run.at.format("%Y-%m-%d %H:%M:%S").to_string()} }
+                            td { "{run.rule_name}" }
+                            td { "{run.trigger}" }
+                            td { "{run.title}" }
+                            td {
+                                if run.success {
+                                    "done"
+                                } else {
+                                    "failed"
+                                }
+                            }
+                            td { "{run.message}" }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Form for a new or existing rule
+#[component]
+fn RuleEditor(rule: Rule, on_saved: EventHandler<Rule>, on_cancel: EventHandler<()>) -> Element {
+    let id = rule.id;
+    let (kind, parameter) = action_parts(&rule.action);
+    let mut name = use_signal(|| rule.name.clone());
+    let mut enabled = use_signal(|| rule.enabled);
+    let mut trigger = use_signal(|| rule.trigger);
+    let mut tag = use_signal(|| rule.tag.clone().unwrap_or_default());
+    let mut condition = use_signal(|| rule.condition.clone());
+    let mut action_kind = use_signal(|| kind);
+    let mut action_parameter = use_signal(|| parameter);
+    let mut status = use_signal(|| None::<String>);
+
+    let save = move |_| async move {
+        let rule = Rule {
+            id,
+            name: name(),
+            enabled: enabled(),
+            trigger: trigger(),
+            tag: Some(tag()),
+            condition: condition(),
+            action: action_of(action_kind(), action_parameter()),
+        };
+        match save_rule(rule).await {
+            Ok(saved) => on_saved.call(saved),
+            Err(e) => status.set(Some(format!("Saving failed: {e}"))),
+        }
+    };
+
+    rsx! {
+        div { class: "rule-editor",
+            div {
+                input {
+                    r#type: "text",
+                    placeholder: "Name",
+                    value: "{name}",
+                    oninput: move |e| name.set(e.value()),
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: enabled(),
+                        onchange: move |e| enabled.set(e.checked()),
+                    }
+                    "Enabled"
+                }
+            }
+            div {
+                "When "
+                select {
+                    onchange: move |e| {
+                        if let Ok(index) = e.value().parse::<usize>() {
+                            trigger.set(RuleTrigger::ALL[index]);
+                        }
+                    },
+                    for (index , option_trigger) in RuleTrigger::ALL.into_iter().enumerate() {
+                        option {
+                            value: "{index}",
+                            selected: option_trigger == trigger(),
+                            "{option_trigger}"
+                        }
+                    }
+                }
+                if trigger() == RuleTrigger::TagAdded {
+                    input {
+                        r#type: "text",
+                        placeholder: "Tag, any tag if empty",
+                        value: "{tag}",
+                        oninput: move |e| tag.set(e.value()),
+                    }
+                }
+            }
+            div {
+                "for books matching "
+                input {
+                    r#type: "text",
+                    size: 40,
+                    placeholder: "Search query, all books if empty",
+                    value: "{condition}",
+                    oninput: move |e| condition.set(e.value()),
+                }
+            }
+            div {
+                select {
+                    onchange: move |e| {
+                        if let Ok(index) = e.value().parse::<usize>() {
+                            action_kind.set(index);
+                        }
+                    },
+                    for (index , (label , _)) in ACTIONS.iter().enumerate() {
+                        option {
+                            value: "{index}",
+                            selected: index == action_kind(),
+                            "{label}"
+                        }
+                    }
+                }
+                if let Some(placeholder) = ACTIONS[action_kind()].1 {
+                    input {
+                        r#type: "text",
+                        size: 40,
+                        placeholder,
+                        value: "{action_parameter}",
+                        oninput: move |e| action_parameter.set(e.value()),
+                    }
+                }
+            }
+            button { onclick: save, "Save" }
+            button { onclick: move |_| on_cancel.call(()), "Cancel" }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use api::config::{read_config, write_config};
+use api::import::test_filename_patterns;
+use dioxus::prelude::*;
+use shared::types::JobsConfig;
+
+#[component]
+pub fn Settings() -> Element {
+    rsx! {
+        FilenamePatterns {}
+        EbookConvert {}
+        JobSettings {}
+    }
+}
+
+/// Where Calibre's `ebook-convert` is found, for converting the formats IronScribe can't convert
+/// itself
+#[component]
+pub fn EbookConvert() -> Element {
+    let mut program = use_signal(String::new);
+    let mut status = use_signal(|| None::<String>);
+
+    use_future(move || async move {
+        match read_config().await {
+            Ok(config) => program.set(config.conversion.ebook_convert),
+            Err(e) => status.set(Some(format!("Loading settings failed: {e}"))),
+        }
+    });
+
+    let save = move |_| async move {
+        let outcome = async {
+            let mut config = read_config().await?;
+            config.conversion.ebook_convert = program().trim().to_string();
+            write_config(config).await
+        }
+        .await;
+        status.set(Some(match outcome {
+            Ok(()) => "Saved".to_string(),
+            Err(e) => format!("Saving failed: {e}"),
+        }));
+    };
+
+    rsx! {
+        fieldset { id: "ebook-convert",
+            legend { "Conversion" }
+            label {
+                "Calibre's ebook-convert, used for MOBI and AZW3 "
+                input {
+                    r#type: "text",
+                    size: 60,
+                    value: "{program}",
+                    oninput: move |e| program.set(e.value()),
+                }
+            }
+            button { onclick: save, "Save" }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+        }
+    }
+}
+
+/// How many background jobs run at once and how failed ones are retried
+#[component]
+pub fn JobSettings() -> Element {
+    let mut jobs = use_signal(JobsConfig::default);
+    let mut status = use_signal(|| None::<String>);
+
+    use_future(move || async move {
+        match read_config().await {
+            Ok(config) => jobs.set(config.jobs),
+            Err(e) => status.set(Some(format!("Loading settings failed: {e}"))),
+        }
+    });
+
+    let save = move |_| async move {
+        let outcome = async {
+            let mut config = read_config().await?;
+            config.jobs = jobs();
+            write_config(config).await
+        }
+        .await;
+        status.set(Some(match outcome {
+            Ok(()) => "Saved".to_string(),
+            Err(e) => format!("Saving failed: {e}"),
+        }));
+    };
+
+    rsx! {
+        fieldset { id: "job-settings",
+            legend { "Background jobs" }
+            label {
+                "Jobs running at once "
+                input {
+                    r#type: "number",
+                    min: 1,
+                    value: "{jobs().workers}",
+                    oninput: move |e| {
+                        if let Ok(workers) = e.value().parse() {
+                            jobs.write().workers = workers;
+                        }
+                    },
+                }
+            }
+            label {
+                "Attempts per job "
+                input {
+                    r#type: "number",
+                    min: 1,
+                    value: "{jobs().max_attempts}",
+                    oninput: move |e| {
+                        if let Ok(attempts) = e.value().parse() {
+                            jobs.write().max_attempts = attempts;
+                        }
+                    },
+                }
+            }
+            label {
+                "Seconds before the first retry "
+                input {
+                    r#type: "number",
+                    min: 0,
+                    value: "{jobs().retry_delay_seconds}",
+                    oninput: move |e| {
+                        if let Ok(seconds) = e.value().parse() {
+                            jobs.write().retry_delay_seconds = seconds;
+                        }
+                    },
+                }
+            }
+            button { onclick: save, "Save" }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+        }
+    }
+}
+
+/// Editor for the file name patterns the import falls back to, with a tester showing what each
+/// pattern extracts from a sample file name
+#[component]
+pub fn FilenamePatterns() -> Element {
+    let mut patterns = use_signal(Vec::<String>::new);
+    let mut sample =
+        use_signal(|| "Terry Pratchett - Discworld 01 - The Colour of Magic.epub".to_string());
+    let mut status = use_signal(|| None::<String>);
+
+    use_future(move || async move {
+        match read_config().await {
+            Ok(config) => patterns.set(config.import.filename_patterns),
+            Err(e) => status.set(Some(format!("Loading settings failed: {e}"))),
+        }
+    });
+    let tests = use_resource(move || test_filename_patterns(patterns(), sample()));
+
+    let save = move |_| async move {
+        let outcome = async {
+            let mut config = read_config().await?;
+            config.import.filename_patterns = patterns()
+                .into_iter()
+                .filter(|pattern| !pattern.trim().is_empty())
+                .collect();
+            write_config(config).await
+        }
+        .await;
+        status.set(Some(match outcome {
+            Ok(()) => "Saved".to_string(),
+            Err(e) => format!("Saving failed: {e}"),
+        }));
+    };
+
+    rsx! {
+        fieldset { id: "filename-patterns",
+            legend { "File name patterns" }
+            p {
+                "Regular expressions tried in order on the names of imported files whose metadata is missing or a placeholder. "
+                "Named groups title, author, series, series_index, isbn and year become the respective fields."
+            }
+            ol {
+                for (index , pattern) in patterns().into_iter().enumerate() {
+                    li { key: "{index}",
+                        input {
+                            r#type: "text",
+                            size: 80,
+                            value: "{pattern}",
+                            oninput: move |e| patterns.write()[index] = e.value(),
+                        }
+                        button {
+                            disabled: index == 0,
+                            onclick: move |_| patterns.write().swap(index - 1, index),
+                            "Up"
+                        }
+                        button {
+                            disabled: index + 1 == patterns().len(),
+                            onclick: move |_| patterns.write().swap(index, index + 1),
+                            "Down"
+                        }
+                        button {
+                            onclick: move |_| {
+                                patterns.write().remove(index);
+                            },
+                            "Remove"
+                        }
+                    }
+                }
+            }
+            button { onclick: move |_| patterns.write().push(String::new()), "Add pattern" }
+            button { onclick: save, "Save" }
+            if let Some(status) = status() {
+                div { "{status}" }
+            }
+            label {
+                "Sample file name "
+                input {
+                    r#type: "text",
+                    size: 60,
+                    value: "{sample}",
+                    oninput: move |e| sample.set(e.value()),
+                }
+            }
+            match tests() {
+                None => rsx! {},
+                Some(Err(e)) => rsx! {
+                    div { "Testing failed: {e}" }
+                },
+                Some(Ok(tests)) => rsx! {
+                    table { class: "pattern-tests",
+                        thead {
+                            tr {
+                                th { "#" }
+                                th { "Result" }
+                            }
+                        }
+                        tbody {
+                            for (index , test) in tests.into_iter().enumerate() {
+                                tr { key: "{index}",
+                                    td { "{index + 1}" }
+                                    td {
+                                        if let Some(error) = test.error {
+                                            "Invalid: {error}"
+                                        } else if !test.matched {
+                                            "No match"
+                                        } else if test.fields.is_empty() {
+                                            "Matches, but extracts nothing"
+                                        } else {
+                                            for (group , value) in test.fields {
+                                                div { "{group}: {value}" }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use api::config::{read_config, write_config};
+use api::statistics::compute_statistics;
+use dioxus::prelude::*;
+use shared::types::{PageCountSource, StatisticsReport};
+
+/// Word, character and page counts computed from the content of books
+#[component]
+pub fn Statistics() -> Element {
+    let mut query = use_signal(String::new);
+    let mut words_per_page = use_signal(String::new);
+    let mut running = use_signal(|| false);
+    let mut result = use_signal(|| None::<Result<StatisticsReport, String>>);
+
+    use_future(move || async move {
+        if let Ok(config) = read_config().await {
+            words_per_page.set(config.statistics.words_per_page.to_string());
+        }
+    });
+
+    let compute = move |_| async move {
+        running.set(true);
+        let outcome = async {
+            if let Ok(words) = words_per_page().trim().parse::<u32>() {
+                let mut config = read_config().await?;
+                if words > 0 && config.statistics.words_per_page != words {
+                    config.statistics.words_per_page = words;
+                    write_config(config).await?;
+                }
+            }
+            compute_statistics(query()).await
+        }
+        .await;
+        running.set(false);
+        result.set(Some(outcome.map_err(|e| e.to_string())));
+    };
+
+    rsx! {
+        div { id: "statistics",
+            input {
+                r#type: "text",
+                placeholder: "Books to count, e.g., format:epub, all books if empty",
+                value: "{query}",
+                oninput: move |e| query.set(e.value()),
+            }
+            label {
+                "Words per page "
+                input {
+                    r#type: "number",
+                    min: "1",
+                    value: "{words_per_page}",
+                    oninput: move |e| words_per_page.set(e.value()),
+                }
+            }
+            button { disabled: running(), onclick: compute,
+                if running() {
+                    "Counting..."
+                } else {
+                    "Count words and pages"
+                }
+            }
+            match result() {
+                Some(Ok(report)) => rsx! {
+                    div { "Counted {report.computed.len()} books, {report.failed.len()} failed" }
+                    table {
+                        thead {
+                            tr {
+                                th { "Title" }
+                                th { "Counted in" }
+                                th { "Words" }
+                                th { "Characters" }
+                                th { "Pages (entered)" }
+                                th { "Pages (real)" }
+                                th { "Pages (estimated)" }
+                            }
+                        }
+                        tbody {
+                            for (title , statistics) in report.computed {
+                                tr { key: "{statistics.book}",
+                                    td { "{title}" }
+                                    td { "{statistics.format}" }
+                                    td { {statistics.word_count.map(|n| n.to_string()).unwrap_or_default()} }
+                                    td { {statistics.char_count.map(|n| n.to_string()).unwrap_or_default()} }
+                                    for source in [PageCountSource::Manual, PageCountSource::Real, PageCountSource::Estimated] {
+                                        td { {statistics.pages(source).map(|n| n.to_string()).unwrap_or_default()} }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    ul {
+                        for failure in report.failed {
+                            li { "{failure}" }
+                        }
+                    }
+                },
+                Some(Err(e)) => rsx! {
+                    div { "Counting failed: {e}" }
+                },
+                None => rsx! {},
+            }
+        }
+    }
+}